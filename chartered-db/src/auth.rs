@@ -0,0 +1,233 @@
+//! Works out which user is behind a request from the credentials they gave us. Each way of
+//! authenticating is an [`Authenticator`] and the ones in use are picked by the operator, so
+//! adding a new method is a matter of implementing the trait rather than editing every
+//! place a user is authenticated.
+
+use super::{
+    users::{SessionScopes, User, UserSshKey},
+    ConnectionPool, Error, Result,
+};
+use async_trait::async_trait;
+use std::str::FromStr;
+
+/// Credentials given by a client, the web API takes a session key and git takes an SSH key.
+#[derive(Debug)]
+pub enum Credential {
+    SessionKey(String),
+    /// Public key bytes the client authenticated the SSH connection with.
+    SshKey(Vec<u8>),
+}
+
+#[derive(Debug)]
+pub struct Authenticated {
+    pub user: User,
+    /// The key the user authenticated with, if they authenticated with an SSH key.
+    pub ssh_key: Option<UserSshKey>,
+    /// When the credential stops being valid, `None` if it doesn't expire.
+    pub expires_at: Option<chrono::NaiveDateTime>,
+    /// What the credential allows the user to do, SSH keys are allowed to do everything.
+    pub scopes: SessionScopes,
+}
+
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Returns the user `credential` belongs to, or `None` if it doesn't belong to anyone or
+    /// isn't a kind of credential this authenticator deals with.
+    async fn authenticate(
+        &self,
+        conn: ConnectionPool,
+        credential: &Credential,
+    ) -> Result<Option<Authenticated>>;
+}
+
+/// Looks session keys up in the database, these are handed out by the login endpoint and
+/// in each SSH key's index `config.json`.
+pub struct SessionKeyAuthenticator;
+
+#[async_trait]
+impl Authenticator for SessionKeyAuthenticator {
+    async fn authenticate(
+        &self,
+        conn: ConnectionPool,
+        credential: &Credential,
+    ) -> Result<Option<Authenticated>> {
+        let key = match credential {
+            Credential::SessionKey(key) => key.clone(),
+            Credential::SshKey(_) => return Ok(None),
+        };
+
+        Ok(User::find_by_session_key(conn, key)
+            .await?
+            .map(|(session, user)| Authenticated {
+                user,
+                ssh_key: None,
+                expires_at: session.expires_at,
+                scopes: session.scopes,
+            }))
+    }
+}
+
+/// Looks SSH keys up against the ones users have added to their accounts.
+pub struct SshKeyAuthenticator;
+
+#[async_trait]
+impl Authenticator for SshKeyAuthenticator {
+    async fn authenticate(
+        &self,
+        conn: ConnectionPool,
+        credential: &Credential,
+    ) -> Result<Option<Authenticated>> {
+        let key = match credential {
+            Credential::SshKey(key) => key.clone(),
+            Credential::SessionKey(_) => return Ok(None),
+        };
+
+        Ok(User::find_by_ssh_key(conn, key)
+            .await?
+            .map(|(ssh_key, user)| Authenticated {
+                user,
+                ssh_key: Some(ssh_key),
+                expires_at: None,
+                scopes: SessionScopes::all(),
+            }))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthenticatorKind {
+    SessionKey,
+    SshKey,
+}
+
+impl FromStr for AuthenticatorKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "session-key" => Ok(Self::SessionKey),
+            "ssh-key" => Ok(Self::SshKey),
+            _ => Err(format!(
+                "unknown authenticator `{}`, expected `session-key` or `ssh-key`",
+                s
+            )),
+        }
+    }
+}
+
+impl AuthenticatorKind {
+    fn build(self) -> Box<dyn Authenticator> {
+        match self {
+            Self::SessionKey => Box::new(SessionKeyAuthenticator),
+            Self::SshKey => Box::new(SshKeyAuthenticator),
+        }
+    }
+}
+
+/// Every authenticator in use, tried in order until one of them recognises the credential.
+pub struct Authenticators(Vec<Box<dyn Authenticator>>);
+
+impl Authenticators {
+    #[must_use]
+    pub fn new(kinds: &[AuthenticatorKind]) -> Self {
+        Self(kinds.iter().map(|kind| kind.build()).collect())
+    }
+
+    /// Reads the authenticators to use from `key`, a comma separated list of `session-key`
+    /// and `ssh-key`, falling back to `default` if it isn't set. Each binary reads its own
+    /// key, so the web API and git server can be configured separately.
+    pub fn from_env(key: &'static str, default: &[AuthenticatorKind]) -> Result<Self> {
+        let kinds = match std::env::var(key) {
+            Ok(v) => v
+                .split(',')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::parse)
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|e| Error::InvalidConfigValue(key, e))?,
+            Err(_) => default.to_vec(),
+        };
+
+        Ok(Self::new(&kinds))
+    }
+
+    pub async fn authenticate(
+        &self,
+        conn: ConnectionPool,
+        credential: &Credential,
+    ) -> Result<Option<Authenticated>> {
+        for authenticator in &self.0 {
+            if let Some(authenticated) =
+                authenticator.authenticate(conn.clone(), credential).await?
+            {
+                return Ok(Some(authenticated));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuthenticatorKind, Authenticators, Credential};
+    use crate::users::User;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn dispatches_to_configured_authenticators() {
+        let db = crate::tests::init();
+        let admin = Arc::new(
+            User::find_by_username(db.clone(), "admin".to_string())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+        admin
+            .clone()
+            .insert_ssh_key(
+                db.clone(),
+                "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f",
+            )
+            .await
+            .unwrap();
+        let ssh_key = admin.clone().list_ssh_keys(db.clone()).await.unwrap()[0]
+            .ssh_key
+            .clone();
+
+        let all = Authenticators::new(&[AuthenticatorKind::SessionKey, AuthenticatorKind::SshKey]);
+        let authenticated = all
+            .authenticate(db.clone(), &Credential::SshKey(ssh_key.clone()))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(authenticated.user.id, admin.id);
+        assert!(authenticated.ssh_key.is_some());
+        assert!(all
+            .authenticate(db.clone(), &Credential::SessionKey("nope".to_string()))
+            .await
+            .unwrap()
+            .is_none());
+
+        let session_only = Authenticators::new(&[AuthenticatorKind::SessionKey]);
+        assert!(session_only
+            .authenticate(db.clone(), &Credential::SshKey(ssh_key))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn unknown_kinds_are_named() {
+        assert_eq!(
+            "ssh-key".parse::<AuthenticatorKind>(),
+            Ok(AuthenticatorKind::SshKey)
+        );
+        assert_eq!(
+            "password".parse::<AuthenticatorKind>(),
+            Err(
+                "unknown authenticator `password`, expected `session-key` or `ssh-key`".to_string()
+            )
+        );
+    }
+}
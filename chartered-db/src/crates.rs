@@ -1,10 +1,10 @@
 use crate::users::{Organisation, User, UserCratePermission};
 
 use super::{
-    coalesce,
-    schema::{crate_versions, crates, organisations, users},
+    coalesce, lower, replace,
+    schema::{crate_versions, crates, crates_search, organisations, users},
     users::UserCratePermissionValue as Permissions,
-    BitwiseExpressionMethods, ConnectionPool, Error, Result,
+    BitwiseExpressionMethods, ConnectionPool, Error, FullTextExpressionMethods, Result,
 };
 use diesel::{insert_into, prelude::*, Associations, Identifiable, Queryable};
 use itertools::Itertools;
@@ -22,6 +22,107 @@ pub struct Crate {
     pub repository: Option<String>,
     pub homepage: Option<String>,
     pub documentation: Option<String>,
+    /// Keywords given in the latest version's manifest, comma separated.
+    pub keywords: Option<String>,
+    /// Categories given in the latest version's manifest, comma separated.
+    pub categories: Option<String>,
+}
+
+/// How much a search term matching each part of a crate counts towards its position in the
+/// search results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchWeights {
+    pub name: u32,
+    pub description: u32,
+    pub keywords: u32,
+}
+
+impl Default for SearchWeights {
+    fn default() -> Self {
+        Self {
+            name: 100,
+            description: 10,
+            keywords: 1,
+        }
+    }
+}
+
+impl SearchWeights {
+    /// Scores `crate_` against the lowercased `terms` of a search, a crate named exactly as
+    /// the whole query gets its name counted twice so it always comes out on top.
+    #[must_use]
+    pub fn score(&self, crate_: &Crate, query: &str, terms: &[String]) -> u32 {
+        let name = crate_.name.to_lowercase();
+        let description = crate_
+            .description
+            .as_deref()
+            .unwrap_or_default()
+            .to_lowercase();
+        let keywords = crate_
+            .keywords
+            .as_deref()
+            .unwrap_or_default()
+            .to_lowercase();
+        let keywords: Vec<_> = keywords.split(',').collect();
+
+        let mut score = 0;
+
+        if name == query.trim().to_lowercase() {
+            score += self.name;
+        }
+
+        for term in terms {
+            if name.contains(term.as_str()) {
+                score += self.name;
+            }
+
+            if description.contains(term.as_str()) {
+                score += self.description;
+            }
+
+            if keywords.contains(&term.as_str()) {
+                score += self.keywords;
+            }
+        }
+
+        score
+    }
+}
+
+/// Escapes the wildcards in `s` so it can be used as a literal in a `LIKE` pattern, using `\`
+/// as the escape character.
+fn escape_like(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+
+    out
+}
+
+/// Terms shorter than this can't be looked up in `crates_search`, its trigram tokenizer
+/// needs at least three characters to match on.
+const MIN_INDEXED_TERM_CHARS: usize = 3;
+
+/// Builds an FTS5 query requiring every one of `terms` to appear somewhere in the crate, each
+/// quoted so nothing in them is taken as query syntax. Returns `None` if none of the terms are
+/// long enough to be looked up in the index.
+fn full_text_query(terms: &[String]) -> Option<String> {
+    let query = terms
+        .iter()
+        .filter(|term| term.chars().count() >= MIN_INDEXED_TERM_CHARS)
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .join(" ");
+
+    if query.is_empty() {
+        None
+    } else {
+        Some(query)
+    }
 }
 
 macro_rules! crate_with_permissions {
@@ -47,6 +148,18 @@ macro_rules! crate_with_permissions {
     };
 }
 
+/// Filters a query built from [`crate_with_permissions!`] down to crates in organisations the
+/// user is a member of, regardless of any permissions they've been given on the crate itself.
+/// This is checked against the database on every call so removing a user from an organisation
+/// takes effect immediately.
+macro_rules! member_of_organisation {
+    () => {
+        crate::schema::user_organisation_permissions::user_id
+            .nullable()
+            .is_not_null()
+    };
+}
+
 macro_rules! select_permissions {
     () => {
         coalesce(
@@ -74,6 +187,7 @@ impl Crate {
             let crate_versions = crate_with_permissions!(requesting_user_id)
                 .inner_join(organisations)
                 .filter(org_name.eq(given_org_name))
+                .filter(member_of_organisation!())
                 .filter(
                     select_permissions!()
                         .bitwise_and(Permissions::VISIBLE.bits())
@@ -88,6 +202,86 @@ impl Crate {
         .await?
     }
 
+    /// Searches the crates in the organisation visible to the user for ones matching every
+    /// whitespace separated term in `query` by name, description or keyword, ordered by how
+    /// well they matched according to `weights`. Terms are looked up in the `crates_search`
+    /// full text index, apart from those too short for it which fall back to a table scan.
+    pub async fn search(
+        conn: ConnectionPool,
+        requesting_user_id: i32,
+        given_org_name: String,
+        query: String,
+        weights: SearchWeights,
+    ) -> Result<Vec<(Crate, Vec<CrateVersion<'static>>)>> {
+        use crate::schema::organisations::dsl::{name as org_name, organisations};
+
+        let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            let mut search = crate_with_permissions!(requesting_user_id)
+                .inner_join(organisations)
+                .filter(org_name.eq(given_org_name))
+                .filter(member_of_organisation!())
+                .filter(
+                    select_permissions!()
+                        .bitwise_and(Permissions::VISIBLE.bits())
+                        .eq(Permissions::VISIBLE.bits()),
+                )
+                .inner_join(crate_versions::table)
+                .select((crates::all_columns, crate_versions::all_columns))
+                .into_boxed();
+
+            if let Some(full_text_query) = full_text_query(&terms) {
+                search = search.filter(
+                    crates::id.eq_any(
+                        crates_search::table
+                            .select(crates_search::rowid)
+                            .filter(crates_search::search.matches(full_text_query)),
+                    ),
+                );
+            }
+
+            for term in terms
+                .iter()
+                .filter(|term| term.chars().count() < MIN_INDEXED_TERM_CHARS)
+            {
+                let pattern = format!("%{}%", escape_like(term));
+
+                search = search.filter(
+                    crates::name
+                        .like(pattern.clone())
+                        .escape('\\')
+                        .or(crates::description.like(pattern.clone()).escape('\\'))
+                        .or(crates::keywords.like(pattern).escape('\\')),
+                );
+            }
+
+            let crate_versions: HashMap<Crate, Vec<CrateVersion<'static>>> = search
+                .load::<(Crate, CrateVersion<'static>)>(&conn)?
+                .into_iter()
+                .into_grouping_map()
+                .collect();
+
+            Ok(crate_versions
+                .into_iter()
+                .map(|(crate_, versions)| {
+                    (weights.score(&crate_, &query, &terms), crate_, versions)
+                })
+                .sorted_by(|(a_score, a, _), (b_score, b, _)| {
+                    b_score.cmp(a_score).then_with(|| a.name.cmp(&b.name))
+                })
+                .map(|(_, crate_, versions)| (crate_, versions))
+                .collect())
+        })
+        .await?
+    }
+
     pub async fn list_recently_updated(
         conn: ConnectionPool,
         requesting_user_id: i32,
@@ -117,6 +311,40 @@ impl Crate {
         .await?
     }
 
+    /// Gets the most recently published version in the organisation that's visible to the
+    /// user, along with the crate it belongs to and the user that published it.
+    pub async fn latest_version(
+        conn: ConnectionPool,
+        requesting_user_id: i32,
+        given_org_name: String,
+    ) -> Result<Option<(Crate, CrateVersion<'static>, User)>> {
+        use crate::schema::organisations::dsl::name as org_name;
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(crate_with_permissions!(requesting_user_id)
+                .inner_join(organisations::table)
+                .filter(org_name.eq(given_org_name))
+                .filter(member_of_organisation!())
+                .filter(
+                    select_permissions!()
+                        .bitwise_and(Permissions::VISIBLE.bits())
+                        .eq(Permissions::VISIBLE.bits()),
+                )
+                .inner_join(crate_versions::table.inner_join(users::table))
+                .select((
+                    crates::all_columns,
+                    crate_versions::all_columns,
+                    users::all_columns,
+                ))
+                .order_by((crate_versions::created_at.desc(), crate_versions::id.desc()))
+                .first(&conn)
+                .optional()?)
+        })
+        .await?
+    }
+
     pub async fn find_by_name(
         conn: ConnectionPool,
         requesting_user_id: i32,
@@ -150,6 +378,46 @@ impl Crate {
         .await?
     }
 
+    /// Every version number published for each of the crates in the organisation named in
+    /// `given_crate_names`, yanked or not, keyed by the crate's name. Crates that don't exist
+    /// or can't be seen by the user are left out, crates without any versions are included
+    /// with none. All the crates are looked up at once, rather than calling
+    /// [`Crate::find_by_name`] and [`CrateWithPermissions::version_numbers`] for each.
+    pub async fn version_numbers_by_name(
+        conn: ConnectionPool,
+        requesting_user_id: i32,
+        given_org_name: String,
+        given_crate_names: Vec<String>,
+    ) -> Result<HashMap<String, Vec<String>>> {
+        use crate::schema::crates::dsl::name as crate_name;
+        use crate::schema::organisations::dsl::{name as org_name, organisations};
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            let versions = crate_with_permissions!(requesting_user_id)
+                .inner_join(organisations)
+                .filter(org_name.eq(given_org_name))
+                .filter(crate_name.eq_any(given_crate_names))
+                .filter(
+                    select_permissions!()
+                        .bitwise_and(Permissions::VISIBLE.bits())
+                        .eq(Permissions::VISIBLE.bits()),
+                )
+                .left_join(crate_versions::table)
+                .select((crate_name, crate_versions::version.nullable()))
+                .load::<(String, Option<String>)>(&conn)?;
+
+            let mut found: HashMap<String, Vec<String>> = HashMap::new();
+            for (name, version) in versions {
+                found.entry(name).or_default().extend(version);
+            }
+
+            Ok(found)
+        })
+        .await?
+    }
+
     pub async fn create(
         conn: ConnectionPool,
         requesting_user_id: i32,
@@ -179,19 +447,48 @@ impl Crate {
                 Err(Error::MissingPermission(Permissions::CREATE_CRATE))
             } else {
                 use crate::schema::crates::dsl::{crates, name, organisation_id};
+                use diesel::result::{DatabaseErrorKind, Error as DieselError};
 
-                insert_into(crates)
-                    .values((name.eq(&given_crate_name), organisation_id.eq(org_id)))
-                    .execute(&conn)?;
-
-                let crate_ = crates
-                    .filter(name.eq(given_crate_name).and(organisation_id.eq(org_id)))
-                    .select(crate::schema::crates::all_columns)
-                    .first::<Crate>(&conn)?;
+                // cargo treats these names as the same crate, so we can't allow both
+                let confusable = || {
+                    crates
+                        .filter(organisation_id.eq(org_id))
+                        .filter(
+                            lower(replace(name, "-", "_")).eq(normalise_name(&given_crate_name)),
+                        )
+                        .select(name)
+                        .first::<String>(&conn)
+                        .optional()
+                };
+
+                conn.transaction::<_, crate::Error, _>(|| {
+                    if let Some(existing) = confusable()? {
+                        return Err(Error::ConfusableCrateName(existing));
+                    }
 
-                Ok(CrateWithPermissions {
-                    crate_,
-                    permissions: perms,
+                    // the unique index on the normalised name catches a confusable crate
+                    // created between the check above and now
+                    match insert_into(crates)
+                        .values((name.eq(&given_crate_name), organisation_id.eq(org_id)))
+                        .execute(&conn)
+                    {
+                        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                            return Err(Error::ConfusableCrateName(
+                                confusable()?.unwrap_or_else(|| given_crate_name.clone()),
+                            ));
+                        }
+                        v => v?,
+                    };
+
+                    let crate_ = crates
+                        .filter(name.eq(&given_crate_name).and(organisation_id.eq(org_id)))
+                        .select(crate::schema::crates::all_columns)
+                        .first::<Crate>(&conn)?;
+
+                    Ok(CrateWithPermissions {
+                        crate_,
+                        permissions: perms,
+                    })
                 })
             }
         })
@@ -199,6 +496,13 @@ impl Crate {
     }
 }
 
+/// Normalises a crate name the way cargo compares them, ignoring case and treating `-` and `_`
+/// as the same character.
+#[must_use]
+pub fn normalise_name(name: &str) -> String {
+    name.to_ascii_lowercase().replace('-', "_")
+}
+
 #[derive(Debug)]
 pub struct CrateWithPermissions {
     pub crate_: Crate,
@@ -224,6 +528,20 @@ impl CrateWithPermissions {
         .await?
     }
 
+    /// Every version number published for the crate, yanked or not.
+    pub async fn version_numbers(self: Arc<Self>, conn: ConnectionPool) -> Result<Vec<String>> {
+        use crate::schema::crate_versions::version;
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(CrateVersion::belonging_to(&self.crate_)
+                .select(version)
+                .load(&conn)?)
+        })
+        .await?
+    }
+
     pub async fn versions_with_uploader(
         self: Arc<Self>,
         conn: ConnectionPool,
@@ -238,21 +556,45 @@ impl CrateWithPermissions {
         .await?
     }
 
+    /// Users able to manage the crate, either through their permissions on the crate itself
+    /// or through their permissions on the organisation it belongs to. These are who cargo
+    /// considers the crate's owners.
     pub async fn owners(self: Arc<Self>, conn: ConnectionPool) -> Result<Vec<crate::users::User>> {
         tokio::task::spawn_blocking(move || {
-            use crate::schema::user_crate_permissions::dsl::permissions;
+            use crate::schema::{user_crate_permissions, user_organisation_permissions};
 
             let conn = conn.get()?;
+            let manage_users = crate::users::UserCratePermissionValue::MANAGE_USERS.bits();
 
-            Ok(UserCratePermission::belonging_to(&self.crate_)
+            let crate_owners = UserCratePermission::belonging_to(&self.crate_)
                 .filter(
-                    permissions
-                        .bitwise_and(crate::users::UserCratePermissionValue::MANAGE_USERS.bits())
+                    user_crate_permissions::permissions
+                        .bitwise_and(manage_users)
                         .ne(0),
                 )
-                .inner_join(crate::schema::users::dsl::users)
-                .select(crate::schema::users::all_columns)
-                .load::<crate::users::User>(&conn)?)
+                .inner_join(users::table)
+                .select(users::all_columns)
+                .load::<crate::users::User>(&conn)?;
+
+            let organisation_owners = user_organisation_permissions::table
+                .filter(
+                    user_organisation_permissions::organisation_id.eq(self.crate_.organisation_id),
+                )
+                .filter(
+                    user_organisation_permissions::permissions
+                        .bitwise_and(manage_users)
+                        .ne(0),
+                )
+                .inner_join(users::table)
+                .select(users::all_columns)
+                .load::<crate::users::User>(&conn)?;
+
+            Ok(crate_owners
+                .into_iter()
+                .chain(organisation_owners)
+                .unique_by(|user| user.id)
+                .sorted_by(|a, b| a.username.cmp(&b.username))
+                .collect())
         })
         .await?
     }
@@ -279,6 +621,51 @@ impl CrateWithPermissions {
         .await?
     }
 
+    /// Same as [`CrateWithPermissions::members`] but a page at a time, ordered by username,
+    /// along with how many members there are across every page. Pages start from 0, and
+    /// every member is returned on the first page if `per_page` is `None`.
+    pub async fn paginated_members(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        page: u32,
+        per_page: Option<u32>,
+    ) -> Result<(
+        Vec<(crate::users::User, crate::users::UserCratePermissionValue)>,
+        i64,
+    )> {
+        if !self.permissions.contains(Permissions::MANAGE_USERS) {
+            return Err(Error::MissingPermission(Permissions::MANAGE_USERS));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            let total = UserCratePermission::belonging_to(&self.crate_)
+                .count()
+                .get_result(&conn)?;
+
+            let mut members = UserCratePermission::belonging_to(&self.crate_)
+                .inner_join(crate::schema::users::dsl::users)
+                .select((
+                    crate::schema::users::all_columns,
+                    crate::schema::user_crate_permissions::permissions,
+                ))
+                .order_by(crate::schema::users::username.asc())
+                .into_boxed();
+
+            if let Some(per_page) = per_page {
+                members = members
+                    .limit(i64::from(per_page))
+                    .offset(i64::from(page) * i64::from(per_page));
+            }
+
+            let members = members.load(&conn)?;
+
+            Ok((members, total))
+        })
+        .await?
+    }
+
     pub async fn update_permissions(
         self: Arc<Self>,
         conn: ConnectionPool,
@@ -296,13 +683,19 @@ impl CrateWithPermissions {
 
             let conn = conn.get()?;
 
-            Ok(diesel::update(
-                user_crate_permissions
-                    .filter(user_id.eq(given_user_id))
-                    .filter(crate_id.eq(self.crate_.id)),
-            )
-            .set(permissions.eq(given_permissions.bits()))
-            .execute(&conn)?)
+            conn.transaction::<_, crate::Error, _>(|| {
+                let rows = diesel::update(
+                    user_crate_permissions
+                        .filter(user_id.eq(given_user_id))
+                        .filter(crate_id.eq(self.crate_.id)),
+                )
+                .set(permissions.eq(given_permissions.bits()))
+                .execute(&conn)?;
+
+                bump_index_generation(&conn, self.crate_.organisation_id)?;
+
+                Ok(rows)
+            })
         })
         .await?
     }
@@ -324,13 +717,91 @@ impl CrateWithPermissions {
 
             let conn = conn.get()?;
 
-            Ok(diesel::insert_into(user_crate_permissions)
-                .values((
-                    user_id.eq(given_user_id),
-                    crate_id.eq(self.crate_.id),
-                    permissions.eq(given_permissions.bits()),
-                ))
-                .execute(&conn)?)
+            conn.transaction::<_, crate::Error, _>(|| {
+                let rows = diesel::insert_into(user_crate_permissions)
+                    .values((
+                        user_id.eq(given_user_id),
+                        crate_id.eq(self.crate_.id),
+                        permissions.eq(given_permissions.bits()),
+                    ))
+                    .execute(&conn)?;
+
+                bump_index_generation(&conn, self.crate_.organisation_id)?;
+
+                Ok(rows)
+            })
+        })
+        .await?
+    }
+
+    /// Gives the user `given_permissions` on the crate, whether they're already a member of
+    /// it or not. Returns `true` if the user wasn't a member before.
+    pub async fn grant_permissions(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        given_user_id: i32,
+        given_permissions: crate::users::UserCratePermissionValue,
+    ) -> Result<bool> {
+        Ok(
+            Self::grant_permissions_on_all(conn, vec![self], given_user_id, given_permissions)
+                .await?[0],
+        )
+    }
+
+    /// Gives the user `given_permissions` on every one of `crates` in a single transaction, so
+    /// either all of them are granted or none are. Returns whether the user wasn't a member
+    /// before for each crate, in the same order as `crates`.
+    pub async fn grant_permissions_on_all(
+        conn: ConnectionPool,
+        crates: Vec<Arc<Self>>,
+        given_user_id: i32,
+        given_permissions: crate::users::UserCratePermissionValue,
+    ) -> Result<Vec<bool>> {
+        if crates
+            .iter()
+            .any(|v| !v.permissions.contains(Permissions::MANAGE_USERS))
+        {
+            return Err(Error::MissingPermission(Permissions::MANAGE_USERS));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            use crate::schema::user_crate_permissions::dsl::{
+                crate_id, permissions, user_crate_permissions, user_id,
+            };
+
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let mut added = Vec::with_capacity(crates.len());
+
+                for crate_with_permissions in &crates {
+                    let crate_ = &crate_with_permissions.crate_;
+
+                    let updated = diesel::update(
+                        user_crate_permissions
+                            .filter(user_id.eq(given_user_id))
+                            .filter(crate_id.eq(crate_.id)),
+                    )
+                    .set(permissions.eq(given_permissions.bits()))
+                    .execute(&conn)?;
+
+                    if updated == 0 {
+                        diesel::insert_into(user_crate_permissions)
+                            .values((
+                                user_id.eq(given_user_id),
+                                crate_id.eq(crate_.id),
+                                permissions.eq(given_permissions.bits()),
+                            ))
+                            .execute(&conn)?;
+                    }
+
+                    bump_index_generation(&conn, crate_.organisation_id)?;
+
+                    added.push(updated == 0);
+                }
+
+                Ok(added)
+            })
         })
         .await?
     }
@@ -351,18 +822,60 @@ impl CrateWithPermissions {
 
             let conn = conn.get()?;
 
-            diesel::delete(
-                user_crate_permissions
-                    .filter(user_id.eq(given_user_id))
-                    .filter(crate_id.eq(self.crate_.id)),
-            )
-            .execute(&conn)?;
+            conn.transaction::<_, crate::Error, _>(|| {
+                diesel::delete(
+                    user_crate_permissions
+                        .filter(user_id.eq(given_user_id))
+                        .filter(crate_id.eq(self.crate_.id)),
+                )
+                .execute(&conn)?;
 
-            Ok(())
+                bump_index_generation(&conn, self.crate_.organisation_id)?;
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+
+    /// Checks `given_version` could be published without actually publishing it, so callers
+    /// can turn away a republish before going to the effort of storing the tarball.
+    /// [`Self::publish_version`] still checks again for itself, this is only an early out.
+    pub async fn check_version_available(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        given_version: String,
+        allow_yanked_overwrite: bool,
+    ) -> Result<()> {
+        use crate::schema::crate_versions::dsl::{
+            checksum, crate_id, crate_versions, version, yanked,
+        };
+
+        if !self.permissions.contains(Permissions::PUBLISH_VERSION) {
+            return Err(Error::MissingPermission(Permissions::PUBLISH_VERSION));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            let existing = crate_versions
+                .filter(crate_id.eq(self.crate_.id))
+                .filter(version.eq(&given_version))
+                .select((checksum, yanked, filesystem_object))
+                .first::<(String, bool, String)>(&conn)
+                .optional()?;
+
+            publish_outcome(existing, &given_version, allow_yanked_overwrite).map(|_| ())
         })
         .await?
     }
 
+    /// Inserts a new version of the crate. Versions are immutable once published, even after
+    /// being yanked, as lockfiles will have already pinned the checksum of the original upload.
+    ///
+    /// `allow_yanked_overwrite` allows a yanked version to be replaced regardless, callers
+    /// should only ever set this when explicitly configured to by an operator and should keep
+    /// an audit trail of any [`PublishedVersion::ReplacedYanked`] returned.
     #[allow(clippy::too_many_arguments)]
     pub async fn publish_version(
         self: Arc<Self>,
@@ -373,13 +886,15 @@ impl CrateWithPermissions {
         file_size: i32,
         given: chartered_types::cargo::CrateVersion<'static>,
         metadata: chartered_types::cargo::CrateVersionMetadata,
-    ) -> Result<()> {
+        allow_yanked_overwrite: bool,
+    ) -> Result<PublishedVersion> {
         use crate::schema::crate_versions::dsl::{
             checksum, crate_id, crate_versions, dependencies, features, filesystem_object, links,
-            size, user_id, version,
+            size, user_id, version, yanked,
         };
         use crate::schema::crates::dsl::{
-            crates, description, documentation, homepage, id, name, readme, repository,
+            categories, crates, description, documentation, homepage, id, keywords, name, readme,
+            repository,
         };
 
         if !self.permissions.contains(Permissions::PUBLISH_VERSION) {
@@ -390,6 +905,15 @@ impl CrateWithPermissions {
             let conn = conn.get()?;
 
             conn.transaction::<_, crate::Error, _>(|| {
+                let existing = crate_versions
+                    .filter(crate_id.eq(self.crate_.id))
+                    .filter(version.eq(given.vers.as_ref()))
+                    .select((checksum, yanked, filesystem_object))
+                    .first::<(String, bool, String)>(&conn)
+                    .optional()?;
+
+                let outcome = publish_outcome(existing, &given.vers, allow_yanked_overwrite)?;
+
                 diesel::update(crates.filter(id.eq(self.crate_.id)))
                     .set((
                         name.eq(given.name),
@@ -398,38 +922,70 @@ impl CrateWithPermissions {
                         repository.eq(metadata.repository),
                         homepage.eq(metadata.homepage),
                         documentation.eq(metadata.documentation),
+                        keywords.eq(if metadata.keywords.is_empty() {
+                            None
+                        } else {
+                            Some(metadata.keywords.join(","))
+                        }),
+                        categories.eq(if metadata.categories.is_empty() {
+                            None
+                        } else {
+                            Some(metadata.categories.join(","))
+                        }),
                     ))
                     .execute(&conn)?;
 
-                let res = insert_into(crate_versions)
-                    .values((
-                        crate_id.eq(self.crate_.id),
+                let res = if let PublishedVersion::ReplacedYanked { .. } = outcome {
+                    diesel::update(
+                        crate_versions
+                            .filter(crate_id.eq(self.crate_.id))
+                            .filter(version.eq(given.vers.as_ref())),
+                    )
+                    .set((
                         filesystem_object.eq(file_identifier.to_string()),
                         size.eq(file_size),
                         checksum.eq(file_checksum),
-                        version.eq(&given.vers),
                         dependencies.eq(CrateDependencies(given.deps)),
                         features.eq(CrateFeatures(given.features)),
                         links.eq(given.links),
                         user_id.eq(user.id),
+                        yanked.eq(false),
                     ))
-                    .execute(&conn);
+                    .execute(&conn)
+                } else {
+                    insert_into(crate_versions)
+                        .values((
+                            crate_id.eq(self.crate_.id),
+                            filesystem_object.eq(file_identifier.to_string()),
+                            size.eq(file_size),
+                            checksum.eq(file_checksum),
+                            version.eq(&given.vers),
+                            dependencies.eq(CrateDependencies(given.deps)),
+                            features.eq(CrateFeatures(given.features)),
+                            links.eq(given.links),
+                            user_id.eq(user.id),
+                        ))
+                        .execute(&conn)
+                };
 
                 use diesel::result::{DatabaseErrorKind, Error as DieselError};
                 match res {
-                    Ok(_) => Ok(()),
+                    Ok(_) => {
+                        bump_index_generation(&conn, self.crate_.organisation_id)?;
+                        Ok(outcome)
+                    }
                     Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
                         Err(Error::VersionConflict(given.vers.into_owned()))
                     }
                     Err(e) => Err(e.into()),
                 }
-            })?;
-
-            Ok(())
+            })
         })
         .await?
     }
 
+    /// Sets whether a version is yanked, erroring with [`Error::MissingVersion`] if the crate
+    /// has no such version.
     pub async fn yank_version(
         self: Arc<Self>,
         conn: ConnectionPool,
@@ -445,20 +1001,78 @@ impl CrateWithPermissions {
         tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
 
-            diesel::update(
-                crate_versions
-                    .filter(crate_id.eq(self.crate_.id))
-                    .filter(version.eq(given_version)),
-            )
-            .set(yanked.eq(yank))
-            .execute(&conn)?;
+            conn.transaction::<_, crate::Error, _>(|| {
+                let updated = diesel::update(
+                    crate_versions
+                        .filter(crate_id.eq(self.crate_.id))
+                        .filter(version.eq(&given_version)),
+                )
+                .set(yanked.eq(yank))
+                .execute(&conn)?;
 
-            Ok(())
+                if updated == 0 {
+                    return Err(Error::MissingVersion(given_version));
+                }
+
+                bump_index_generation(&conn, self.crate_.organisation_id)?;
+
+                Ok(())
+            })
         })
         .await?
     }
 }
 
+/// Bumps the organisation's `index_generation`, this needs to be called whenever anything that
+/// could change the contents of the organisation's index is modified so cached copies of it are
+/// thrown away.
+pub(crate) fn bump_index_generation(
+    conn: &diesel::SqliteConnection,
+    org_id: i32,
+) -> QueryResult<usize> {
+    use crate::schema::organisations::dsl::{id, index_generation};
+
+    diesel::update(organisations::table.filter(id.eq(org_id)))
+        .set(index_generation.eq(index_generation + 1))
+        .execute(conn)
+}
+
+/// Decides what publishing a version would do given the checksum and yanked state of any
+/// version already published with the same number.
+fn publish_outcome(
+    existing: Option<(String, bool, String)>,
+    given_version: &str,
+    allow_yanked_overwrite: bool,
+) -> Result<PublishedVersion> {
+    match existing {
+        None => Ok(PublishedVersion::Created),
+        Some((_, false, _)) => Err(Error::VersionConflict(given_version.to_string())),
+        Some((_, true, _)) if !allow_yanked_overwrite => {
+            Err(Error::YankedVersionConflict(given_version.to_string()))
+        }
+        Some((previous_checksum, true, previous_filesystem_object)) => {
+            Ok(PublishedVersion::ReplacedYanked {
+                previous_checksum,
+                previous_filesystem_object,
+            })
+        }
+    }
+}
+
+/// Outcome of a successful [`CrateWithPermissions::publish_version`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum PublishedVersion {
+    /// A brand new version was inserted.
+    Created,
+    /// A yanked version was overwritten with a new upload, only possible when explicitly
+    /// allowed by the caller. Nothing refers to the previous upload's storage object any more
+    /// unless it was content addressed and shared with another version.
+    ReplacedYanked {
+        previous_checksum: String,
+        previous_filesystem_object: String,
+    },
+}
+
 #[derive(Identifiable, Queryable, Associations, PartialEq, Debug)]
 #[belongs_to(Crate)]
 #[belongs_to(User)]
@@ -475,6 +1089,7 @@ pub struct CrateVersion<'a> {
     pub links: Option<String>,
     pub user_id: i32,
     pub created_at: chrono::NaiveDateTime,
+    pub downloads: i32,
 }
 
 impl<'a> CrateVersion<'a> {
@@ -490,6 +1105,72 @@ impl<'a> CrateVersion<'a> {
     }
 }
 
+impl CrateVersion<'static> {
+    /// Every version across every organisation along with the crate it belongs to, regardless
+    /// of anyone's permissions. Only for maintenance tasks, such as checking the tarballs
+    /// recorded here against what's actually in storage.
+    pub async fn list_all(conn: ConnectionPool) -> Result<Vec<(Crate, CrateVersion<'static>)>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(crate_versions::table
+                .inner_join(crates::table)
+                .select((crates::all_columns, crate_versions::all_columns))
+                .load(&conn)?)
+        })
+        .await?
+    }
+
+    /// Counts a download of the version. Permissions are expected to have been checked when
+    /// the version was looked up to be downloaded.
+    pub async fn record_download(conn: ConnectionPool, version_id: i32) -> Result<()> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            diesel::update(crate_versions::table.filter(crate_versions::id.eq(version_id)))
+                .set(crate_versions::downloads.eq(crate_versions::downloads + 1))
+                .execute(&conn)?;
+
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Yanks the given versions regardless of anyone's permissions, for maintenance tasks that
+    /// have found the versions can no longer be downloaded. Returns the amount of versions
+    /// that weren't already yanked.
+    pub async fn yank_by_id(conn: ConnectionPool, version_ids: Vec<i32>) -> Result<usize> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let organisation_ids: Vec<i32> = crate_versions::table
+                    .inner_join(crates::table)
+                    .filter(crate_versions::id.eq_any(&version_ids))
+                    .filter(crate_versions::yanked.eq(false))
+                    .select(crates::organisation_id)
+                    .distinct()
+                    .load(&conn)?;
+
+                let yanked = diesel::update(
+                    crate_versions::table
+                        .filter(crate_versions::id.eq_any(&version_ids))
+                        .filter(crate_versions::yanked.eq(false)),
+                )
+                .set(crate_versions::yanked.eq(true))
+                .execute(&conn)?;
+
+                for organisation_id in organisation_ids {
+                    bump_index_generation(&conn, organisation_id)?;
+                }
+
+                Ok(yanked)
+            })
+        })
+        .await?
+    }
+}
+
 #[derive(Serialize, Deserialize, FromSqlRow, AsExpression, Debug, Clone, PartialEq, Eq)]
 #[sql_type = "diesel::sql_types::Blob"]
 pub struct CrateDependencies<'a>(pub Vec<chartered_types::cargo::CrateDependency<'a>>);
@@ -513,3 +1194,915 @@ impl<'a> From<chartered_types::cargo::CrateFeatures> for CrateFeatures {
         Self(o)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Crate, CrateVersion, CrateWithPermissions, PublishedVersion};
+    use crate::{users::User, Error};
+    use chartered_fs::FileSystem;
+    use std::{collections::BTreeMap, sync::Arc};
+
+    fn version(vers: &'static str) -> chartered_types::cargo::CrateVersion<'static> {
+        chartered_types::cargo::CrateVersion {
+            name: "foo".into(),
+            vers: vers.into(),
+            deps: vec![],
+            features: chartered_types::cargo::CrateFeatures(BTreeMap::new()),
+            links: None,
+        }
+    }
+
+    fn metadata() -> chartered_types::cargo::CrateVersionMetadata {
+        chartered_types::cargo::CrateVersionMetadata {
+            description: None,
+            readme: None,
+            repository: None,
+            homepage: None,
+            documentation: None,
+            keywords: vec![],
+            categories: vec![],
+        }
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn publish_stores_categories() {
+        let db = crate::tests::init();
+        let user = Arc::new(
+            User::find_by_username(db.clone(), "admin".to_string())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+        let crate_ = Arc::new(
+            Crate::create(db.clone(), user.id, "core".to_string(), "foo".to_string())
+                .await
+                .unwrap(),
+        );
+
+        crate_
+            .publish_version(
+                db.clone(),
+                user.clone(),
+                chartered_fs::Memory::new().create_ref(),
+                "aaaa".to_string(),
+                1,
+                version("1.0.0"),
+                chartered_types::cargo::CrateVersionMetadata {
+                    categories: vec!["networking".to_string(), "internal-tools".to_string()],
+                    ..metadata()
+                },
+                false,
+            )
+            .await
+            .unwrap();
+
+        let found = Crate::find_by_name(db, user.id, "core".to_string(), "foo".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            found.crate_.categories.as_deref(),
+            Some("networking,internal-tools")
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn version_numbers_by_name() {
+        let db = crate::tests::init();
+        crate::tests::insert_users(&db, &["outsider"]);
+        let user = Arc::new(
+            User::find_by_username(db.clone(), "admin".to_string())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+        let foo = Arc::new(
+            Crate::create(db.clone(), user.id, "core".to_string(), "foo".to_string())
+                .await
+                .unwrap(),
+        );
+        Crate::create(db.clone(), user.id, "core".to_string(), "bar".to_string())
+            .await
+            .unwrap();
+
+        for vers in ["1.0.0", "1.1.0"] {
+            foo.clone()
+                .publish_version(
+                    db.clone(),
+                    user.clone(),
+                    chartered_fs::Memory::new().create_ref(),
+                    "aaaa".to_string(),
+                    1,
+                    version(vers),
+                    metadata(),
+                    false,
+                )
+                .await
+                .unwrap();
+        }
+
+        let names = || vec!["foo".to_string(), "bar".to_string(), "baz".to_string()];
+
+        let mut found =
+            Crate::version_numbers_by_name(db.clone(), user.id, "core".to_string(), names())
+                .await
+                .unwrap();
+        found.values_mut().for_each(|v| v.sort());
+        assert_eq!(found.len(), 2);
+        assert_eq!(found["foo"], ["1.0.0", "1.1.0"]);
+        assert!(found["bar"].is_empty());
+
+        // crates the user can't see are treated the same as ones that don't exist
+        assert!(
+            Crate::version_numbers_by_name(db, 2, "core".to_string(), names())
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn published_versions_are_paged() {
+        let db = crate::tests::init();
+        let user = Arc::new(
+            User::find_by_username(db.clone(), "admin".to_string())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+        let crate_ = Arc::new(
+            Crate::create(db.clone(), user.id, "core".to_string(), "foo".to_string())
+                .await
+                .unwrap(),
+        );
+
+        for vers in ["1.0.0", "1.1.0", "2.0.0"] {
+            crate_
+                .clone()
+                .publish_version(
+                    db.clone(),
+                    user.clone(),
+                    chartered_fs::Memory::new().create_ref(),
+                    "aaaa".to_string(),
+                    1,
+                    version(vers),
+                    metadata(),
+                    false,
+                )
+                .await
+                .unwrap();
+        }
+
+        let page = |after| user.clone().published_versions(db.clone(), after, 2);
+        let versions = |page: &[(String, String, CrateVersion<'static>)]| {
+            page.iter()
+                .map(|(org, name, v)| format!("{}/{}@{}", org, name, v.version))
+                .collect::<Vec<_>>()
+        };
+
+        let first = page(None).await.unwrap();
+        assert_eq!(versions(&first), ["core/foo@1.0.0", "core/foo@1.1.0"]);
+
+        let second = page(Some(first[1].2.id)).await.unwrap();
+        assert_eq!(versions(&second), ["core/foo@2.0.0"]);
+
+        assert!(page(Some(second[0].2.id)).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn republish_yanked_version() {
+        let db = crate::tests::init();
+        let user = Arc::new(
+            User::find_by_username(db.clone(), "admin".to_string())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+        let crate_ = Arc::new(
+            Crate::create(db.clone(), user.id, "core".to_string(), "foo".to_string())
+                .await
+                .unwrap(),
+        );
+
+        let first_upload = chartered_fs::Memory::new().create_ref();
+        let publish = |checksum: &'static str, allow_yanked_overwrite| {
+            crate_.clone().publish_version(
+                db.clone(),
+                user.clone(),
+                if checksum == "aaaa" {
+                    first_upload.clone()
+                } else {
+                    chartered_fs::Memory::new().create_ref()
+                },
+                checksum.to_string(),
+                1,
+                version("1.0.0"),
+                metadata(),
+                allow_yanked_overwrite,
+            )
+        };
+
+        let check = |allow_yanked_overwrite| {
+            crate_.clone().check_version_available(
+                db.clone(),
+                "1.0.0".to_string(),
+                allow_yanked_overwrite,
+            )
+        };
+
+        assert!(check(false).await.is_ok());
+        assert_eq!(
+            publish("aaaa", false).await.unwrap(),
+            PublishedVersion::Created
+        );
+        assert!(matches!(
+            check(false).await,
+            Err(Error::VersionConflict(v)) if v == "1.0.0"
+        ));
+        assert!(matches!(
+            publish("bbbb", false).await,
+            Err(Error::VersionConflict(v)) if v == "1.0.0"
+        ));
+
+        crate_
+            .clone()
+            .yank_version(db.clone(), "1.0.0".to_string(), true)
+            .await
+            .unwrap();
+        assert!(matches!(
+            crate_
+                .clone()
+                .yank_version(db.clone(), "9.9.9".to_string(), true)
+                .await,
+            Err(Error::MissingVersion(v)) if v == "9.9.9"
+        ));
+
+        // yanked versions are still immutable unless explicitly allowed
+        assert!(matches!(
+            check(false).await,
+            Err(Error::YankedVersionConflict(v)) if v == "1.0.0"
+        ));
+        assert!(matches!(
+            publish("bbbb", false).await,
+            Err(Error::YankedVersionConflict(v)) if v == "1.0.0"
+        ));
+        assert!(check(true).await.is_ok());
+        assert_eq!(
+            publish("bbbb", true).await.unwrap(),
+            PublishedVersion::ReplacedYanked {
+                previous_checksum: "aaaa".to_string(),
+                previous_filesystem_object: first_upload.to_string(),
+            }
+        );
+
+        let version = crate_
+            .clone()
+            .version(db.clone(), "1.0.0".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(version.checksum, "bbbb");
+        assert!(!version.yanked);
+        assert_eq!(version.downloads, 0);
+
+        CrateVersion::record_download(db.clone(), version.id)
+            .await
+            .unwrap();
+        CrateVersion::record_download(db.clone(), version.id)
+            .await
+            .unwrap();
+        let version = crate_
+            .version(db, "1.0.0".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(version.downloads, 2);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn list_with_versions_is_scoped_to_organisation() {
+        use diesel::connection::SimpleConnection;
+
+        let db = crate::tests::init();
+        db.get()
+            .unwrap()
+            .batch_execute(
+                "INSERT INTO organisations (id, uuid, name) VALUES (2, X'00000000000000000000000000000002', 'other');
+                 INSERT INTO user_organisation_permissions (user_id, organisation_id, permissions) VALUES (1, 2, -1);",
+            )
+            .unwrap();
+
+        let user = Arc::new(
+            User::find_by_username(db.clone(), "admin".to_string())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+
+        for (org, checksum) in [("core", "aaaa"), ("other", "bbbb")] {
+            Arc::new(
+                Crate::create(db.clone(), user.id, org.to_string(), "foo".to_string())
+                    .await
+                    .unwrap(),
+            )
+            .publish_version(
+                db.clone(),
+                user.clone(),
+                chartered_fs::Memory::new().create_ref(),
+                checksum.to_string(),
+                1,
+                version("1.0.0"),
+                metadata(),
+                false,
+            )
+            .await
+            .unwrap();
+        }
+
+        for (org, org_id, checksum) in [("core", 1, "aaaa"), ("other", 2, "bbbb")] {
+            let crates = Crate::list_with_versions(db.clone(), user.id, org.to_string())
+                .await
+                .unwrap();
+
+            assert_eq!(crates.len(), 1);
+            let (crate_, versions) = crates.into_iter().next().unwrap();
+            assert_eq!(crate_.organisation_id, org_id);
+            assert_eq!(versions.len(), 1);
+            assert_eq!(versions[0].checksum, checksum);
+        }
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn confusable_names_are_rejected() {
+        use diesel::connection::SimpleConnection;
+
+        let db = crate::tests::init();
+
+        Crate::create(db.clone(), 1, "core".to_string(), "foo-bar".to_string())
+            .await
+            .unwrap();
+
+        for name in ["foo_bar", "Foo-Bar", "FOO_BAR"] {
+            match Crate::create(db.clone(), 1, "core".to_string(), name.to_string()).await {
+                Err(Error::ConfusableCrateName(existing)) => assert_eq!(existing, "foo-bar"),
+                v => panic!("expected {} to be rejected, got {:?}", name, v),
+            }
+        }
+
+        Crate::create(db.clone(), 1, "core".to_string(), "foo-bars".to_string())
+            .await
+            .unwrap();
+
+        // enforced by the database too, for a crate created between the check and the insert
+        assert!(db
+            .get()
+            .unwrap()
+            .batch_execute("INSERT INTO crates (name, organisation_id) VALUES ('Foo_Bar', 1);")
+            .is_err());
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn index_requires_organisation_membership() {
+        use crate::users::UserCratePermissionValue as Permissions;
+        use diesel::connection::SimpleConnection;
+
+        let db = crate::tests::init();
+        crate::tests::insert_users(&db, &["member"]);
+        db.get()
+            .unwrap()
+            .batch_execute(
+                "INSERT INTO user_organisation_permissions (user_id, organisation_id, permissions) VALUES (2, 1, 0);",
+            )
+            .unwrap();
+
+        let user = Arc::new(
+            User::find_by_username(db.clone(), "admin".to_string())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+
+        let crate_ = Arc::new(
+            Crate::create(db.clone(), user.id, "core".to_string(), "foo".to_string())
+                .await
+                .unwrap(),
+        );
+        crate_
+            .clone()
+            .publish_version(
+                db.clone(),
+                user.clone(),
+                chartered_fs::Memory::new().create_ref(),
+                "aaaa".to_string(),
+                1,
+                version("1.0.0"),
+                metadata(),
+                false,
+            )
+            .await
+            .unwrap();
+        crate_
+            .insert_permissions(db.clone(), 2, Permissions::VISIBLE)
+            .await
+            .unwrap();
+
+        let visible = || {
+            let db = db.clone();
+
+            async move {
+                let crates = Crate::list_with_versions(db.clone(), 2, "core".to_string())
+                    .await
+                    .unwrap();
+                let latest = Crate::latest_version(db, 2, "core".to_string())
+                    .await
+                    .unwrap();
+                assert_eq!(crates.is_empty(), latest.is_none());
+                crates.len()
+            }
+        };
+
+        assert_eq!(visible().await, 1);
+
+        // permissions on the crate itself don't count for anything once the user has been
+        // removed from the organisation
+        db.get()
+            .unwrap()
+            .batch_execute("DELETE FROM user_organisation_permissions WHERE user_id = 2")
+            .unwrap();
+
+        assert_eq!(visible().await, 0);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn search_ranks_name_above_description_above_keywords() {
+        use super::SearchWeights;
+
+        let db = crate::tests::init();
+        let user = Arc::new(
+            User::find_by_username(db.clone(), "admin".to_string())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+
+        for (name, description, keywords) in [
+            ("by-keyword", None, vec!["parser".to_string()]),
+            ("by-description", Some("a parser for things"), vec![]),
+            ("parser-utils", None, vec![]),
+            ("parser", None, vec![]),
+            ("unrelated", Some("100% something else"), vec![]),
+        ] {
+            let crate_ = Arc::new(
+                Crate::create(db.clone(), user.id, "core".to_string(), name.to_string())
+                    .await
+                    .unwrap(),
+            );
+            let mut vers = version("1.0.0");
+            vers.name = name.into();
+
+            crate_
+                .publish_version(
+                    db.clone(),
+                    user.clone(),
+                    chartered_fs::Memory::new().create_ref(),
+                    "aaaa".to_string(),
+                    1,
+                    vers,
+                    chartered_types::cargo::CrateVersionMetadata {
+                        description: description.map(ToString::to_string),
+                        keywords,
+                        ..metadata()
+                    },
+                    false,
+                )
+                .await
+                .unwrap();
+        }
+
+        let search = |query: &str| {
+            let db = db.clone();
+            let query = query.to_string();
+
+            async move {
+                Crate::search(db, 1, "core".to_string(), query, SearchWeights::default())
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|(crate_, _)| crate_.name)
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(
+            search("Parser").await,
+            ["parser", "parser-utils", "by-description", "by-keyword"]
+        );
+        assert_eq!(search("parser things").await, ["by-description"]);
+        // wildcards are matched literally
+        assert_eq!(search("%").await, ["unrelated"]);
+        assert!(search("% parse").await.is_empty());
+        assert_eq!(search("% some").await, ["unrelated"]);
+        assert!(search("  ").await.is_empty());
+        // as is anything that looks like full text query syntax
+        assert!(search("\"parser\"").await.is_empty());
+        assert!(search("parser*").await.is_empty());
+    }
+
+    #[test]
+    fn full_text_query_quotes_terms() {
+        use super::full_text_query;
+
+        assert_eq!(
+            full_text_query(&["serde".to_string(), "a\"b\"c".to_string()]),
+            Some(r#""serde" "a""b""c""#.to_string())
+        );
+        assert_eq!(full_text_query(&["%".to_string(), "ab".to_string()]), None);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn search_only_finds_visible_crates() {
+        use super::SearchWeights;
+        use crate::users::UserCratePermissionValue as Permissions;
+        use diesel::connection::SimpleConnection;
+
+        let db = crate::tests::init();
+        crate::tests::insert_users(&db, &["member"]);
+        db.get()
+            .unwrap()
+            .batch_execute(
+                "INSERT INTO organisations (id, uuid, name) VALUES (2, X'00000000000000000000000000000002', 'other');
+                 INSERT INTO user_organisation_permissions (user_id, organisation_id, permissions) VALUES (1, 2, -1);
+                 INSERT INTO user_organisation_permissions (user_id, organisation_id, permissions) VALUES (2, 1, 0);",
+            )
+            .unwrap();
+
+        let user = Arc::new(
+            User::find_by_username(db.clone(), "admin".to_string())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+
+        for (org, name) in [
+            ("core", "search-visible"),
+            ("core", "search-hidden"),
+            ("other", "search-elsewhere"),
+        ] {
+            let crate_ = Arc::new(
+                Crate::create(db.clone(), user.id, org.to_string(), name.to_string())
+                    .await
+                    .unwrap(),
+            );
+            let mut vers = version("1.0.0");
+            vers.name = name.into();
+
+            crate_
+                .clone()
+                .publish_version(
+                    db.clone(),
+                    user.clone(),
+                    chartered_fs::Memory::new().create_ref(),
+                    "aaaa".to_string(),
+                    1,
+                    vers,
+                    metadata(),
+                    false,
+                )
+                .await
+                .unwrap();
+
+            if name == "search-visible" {
+                crate_
+                    .insert_permissions(db.clone(), 2, Permissions::VISIBLE)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let search = |user_id: i32, org: &str| {
+            let db = db.clone();
+            let org = org.to_string();
+
+            async move {
+                Crate::search(
+                    db,
+                    user_id,
+                    org,
+                    "search".to_string(),
+                    SearchWeights::default(),
+                )
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(crate_, _)| crate_.name)
+                .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(search(1, "core").await.len(), 2);
+        assert_eq!(search(1, "other").await, ["search-elsewhere"]);
+        assert_eq!(search(2, "core").await, ["search-visible"]);
+        assert!(search(2, "other").await.is_empty());
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn yank_by_id() {
+        use super::CrateVersion;
+
+        let db = crate::tests::init();
+        let user = Arc::new(
+            User::find_by_username(db.clone(), "admin".to_string())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+        let crate_ = Arc::new(
+            Crate::create(db.clone(), user.id, "core".to_string(), "foo".to_string())
+                .await
+                .unwrap(),
+        );
+
+        for vers in ["1.0.0", "1.0.1"] {
+            crate_
+                .clone()
+                .publish_version(
+                    db.clone(),
+                    user.clone(),
+                    chartered_fs::Memory::new().create_ref(),
+                    "aaaa".to_string(),
+                    1,
+                    version(vers),
+                    metadata(),
+                    false,
+                )
+                .await
+                .unwrap();
+        }
+
+        let versions = CrateVersion::list_all(db.clone()).await.unwrap();
+        assert_eq!(versions.len(), 2);
+        let id = versions
+            .iter()
+            .find(|(_, v)| v.version == "1.0.0")
+            .unwrap()
+            .1
+            .id;
+
+        assert_eq!(
+            CrateVersion::yank_by_id(db.clone(), vec![id])
+                .await
+                .unwrap(),
+            1
+        );
+        // already yanked versions aren't counted again
+        assert_eq!(
+            CrateVersion::yank_by_id(db.clone(), vec![id])
+                .await
+                .unwrap(),
+            0
+        );
+
+        for (_, v) in CrateVersion::list_all(db).await.unwrap() {
+            assert_eq!(v.yanked, v.version == "1.0.0");
+        }
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn find_missing_crate() {
+        let db = crate::tests::init();
+
+        Crate::create(db.clone(), 1, "core".to_string(), "foo".to_string())
+            .await
+            .unwrap();
+
+        assert!(
+            Crate::find_by_name(db.clone(), 1, "core".to_string(), "foo".to_string())
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            Crate::find_by_name(db.clone(), 1, "core".to_string(), "bar".to_string()).await,
+            Err(Error::MissingCrate)
+        ));
+        assert!(matches!(
+            Crate::find_by_name(db.clone(), 1, "nope".to_string(), "foo".to_string()).await,
+            Err(Error::MissingCrate)
+        ));
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn paginated_members() {
+        use crate::users::UserCratePermissionValue as Permissions;
+
+        let db = crate::tests::init();
+        crate::tests::insert_users(&db, &["carol", "alice", "bob"]);
+
+        let crate_ = Arc::new(
+            Crate::create(db.clone(), 1, "core".to_string(), "foo".to_string())
+                .await
+                .unwrap(),
+        );
+
+        for user_id in 2..=4 {
+            crate_
+                .clone()
+                .grant_permissions(db.clone(), user_id, Permissions::VISIBLE)
+                .await
+                .unwrap();
+        }
+
+        let mut all: Vec<_> = crate_
+            .clone()
+            .members(db.clone())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(user, _)| user.username)
+            .collect();
+        all.sort();
+        // the creator is a member too
+        assert_eq!(all, ["admin", "alice", "bob", "carol"]);
+
+        let (members, total) = crate_
+            .clone()
+            .paginated_members(db.clone(), 0, None)
+            .await
+            .unwrap();
+        assert_eq!(total, 4);
+        assert_eq!(
+            members
+                .into_iter()
+                .map(|(user, _)| user.username)
+                .collect::<Vec<_>>(),
+            all
+        );
+
+        let mut paged = Vec::new();
+        for page in 0.. {
+            let (members, total) = crate_
+                .clone()
+                .paginated_members(db.clone(), page, Some(2))
+                .await
+                .unwrap();
+            assert_eq!(total, all.len() as i64);
+
+            if members.is_empty() {
+                break;
+            }
+            assert!(members.len() <= 2);
+            paged.extend(members.into_iter().map(|(user, _)| user.username));
+        }
+
+        assert_eq!(paged, all);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn grant_permissions() {
+        use crate::users::UserCratePermissionValue as Permissions;
+
+        let db = crate::tests::init();
+        crate::tests::insert_users(&db, &["member"]);
+
+        let crate_ = Arc::new(
+            Crate::create(db.clone(), 1, "core".to_string(), "foo".to_string())
+                .await
+                .unwrap(),
+        );
+
+        let permissions = || async {
+            crate_
+                .clone()
+                .members(db.clone())
+                .await
+                .unwrap()
+                .into_iter()
+                .find(|(user, _)| user.id == 2)
+                .map(|(_, permissions)| permissions)
+        };
+
+        assert!(crate_
+            .clone()
+            .grant_permissions(db.clone(), 2, Permissions::VISIBLE)
+            .await
+            .unwrap());
+        assert_eq!(permissions().await, Some(Permissions::VISIBLE));
+
+        assert!(!crate_
+            .clone()
+            .grant_permissions(
+                db.clone(),
+                2,
+                Permissions::VISIBLE | Permissions::PUBLISH_VERSION
+            )
+            .await
+            .unwrap());
+        assert_eq!(
+            permissions().await,
+            Some(Permissions::VISIBLE | Permissions::PUBLISH_VERSION)
+        );
+
+        let bar = Arc::new(
+            Crate::create(db.clone(), 1, "core".to_string(), "bar".to_string())
+                .await
+                .unwrap(),
+        );
+
+        // nothing's granted if any one of the crates can't be
+        let unmanageable = Arc::new(CrateWithPermissions {
+            crate_: Crate::find_by_name(db.clone(), 1, "core".to_string(), "bar".to_string())
+                .await
+                .unwrap()
+                .crate_,
+            permissions: Permissions::VISIBLE,
+        });
+        assert!(matches!(
+            CrateWithPermissions::grant_permissions_on_all(
+                db.clone(),
+                vec![crate_.clone(), unmanageable],
+                2,
+                Permissions::VISIBLE,
+            )
+            .await,
+            Err(Error::MissingPermission(Permissions::MANAGE_USERS))
+        ));
+        assert_eq!(
+            permissions().await,
+            Some(Permissions::VISIBLE | Permissions::PUBLISH_VERSION)
+        );
+
+        assert_eq!(
+            CrateWithPermissions::grant_permissions_on_all(
+                db.clone(),
+                vec![crate_.clone(), bar.clone()],
+                2,
+                Permissions::VISIBLE,
+            )
+            .await
+            .unwrap(),
+            [false, true]
+        );
+        assert_eq!(permissions().await, Some(Permissions::VISIBLE));
+        assert!(bar
+            .members(db.clone())
+            .await
+            .unwrap()
+            .into_iter()
+            .any(|(user, permissions)| user.id == 2 && permissions == Permissions::VISIBLE));
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn owners_include_organisation_managers() {
+        use crate::users::UserCratePermissionValue as Permissions;
+        use diesel::connection::SimpleConnection;
+
+        let db = crate::tests::init();
+        crate::tests::insert_users(&db, &["manager", "member"]);
+        db.get()
+            .unwrap()
+            .batch_execute(
+                "INSERT INTO user_organisation_permissions (user_id, organisation_id, permissions) VALUES (2, 1, 0);
+                 INSERT INTO user_organisation_permissions (user_id, organisation_id, permissions) VALUES (3, 1, 0);",
+            )
+            .unwrap();
+
+        let crate_ = Arc::new(
+            Crate::create(db.clone(), 1, "core".to_string(), "foo".to_string())
+                .await
+                .unwrap(),
+        );
+        crate_
+            .clone()
+            .insert_permissions(db.clone(), 2, Permissions::MANAGE_USERS)
+            .await
+            .unwrap();
+        crate_
+            .clone()
+            .insert_permissions(db.clone(), 3, Permissions::VISIBLE)
+            .await
+            .unwrap();
+
+        // admin manages the crate through both the crate and the organisation but should
+        // only be listed once
+        let owners = crate_.owners(db).await.unwrap();
+        assert_eq!(
+            owners
+                .iter()
+                .map(|v| v.username.as_str())
+                .collect::<Vec<_>>(),
+            ["admin", "manager"]
+        );
+    }
+}
@@ -31,28 +31,123 @@ macro_rules! derive_diesel_json {
     };
 }
 
+pub mod auth;
 pub mod crates;
 pub mod schema;
 pub mod users;
 pub mod uuid;
+pub mod webhooks;
 
 #[macro_use]
 extern crate diesel;
 
+#[cfg(test)]
+#[macro_use]
+extern crate diesel_migrations;
+
 use diesel::{
     expression::{grouped::Grouped, AsExpression, Expression},
     r2d2::{ConnectionManager, Pool},
     sql_types::{Integer, Nullable},
 };
 use displaydoc::Display;
-use std::sync::Arc;
+use serde::Serialize;
+use std::{str::FromStr, sync::Arc, time::Duration};
 use thiserror::Error;
 
 pub type ConnectionPool = Arc<Pool<ConnectionManager<diesel::SqliteConnection>>>;
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub fn init() -> Result<ConnectionPool> {
-    Ok(Arc::new(Pool::new(ConnectionManager::new("chartered.db"))?))
+/// Tunables for the database connection pool. The pool runs a reaper in the
+/// background which recycles any connection that has been idle for longer than
+/// `idle_timeout` or has been open for longer than `max_lifetime`.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub max_size: u32,
+    pub min_idle: Option<u32>,
+    pub idle_timeout: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 10,
+            min_idle: None,
+            idle_timeout: Some(Duration::from_secs(10 * 60)),
+            max_lifetime: Some(Duration::from_secs(30 * 60)),
+        }
+    }
+}
+
+impl PoolConfig {
+    /// Reads the pool configuration from `CHARTERED_DB_POOL_*` environment variables, falling
+    /// back to the defaults for any that aren't set. Timeouts are given in seconds, with `0`
+    /// disabling them.
+    pub fn from_env() -> Result<Self> {
+        let default = Self::default();
+
+        Ok(Self {
+            max_size: env_or("CHARTERED_DB_POOL_MAX_SIZE", default.max_size)?,
+            min_idle: std::env::var("CHARTERED_DB_POOL_MIN_IDLE")
+                .ok()
+                .map(|v| v.parse())
+                .transpose()
+                .map_err(|_| Error::InvalidConfig("CHARTERED_DB_POOL_MIN_IDLE"))?,
+            idle_timeout: duration_env_or(
+                "CHARTERED_DB_POOL_IDLE_TIMEOUT_SECS",
+                default.idle_timeout,
+            )?,
+            max_lifetime: duration_env_or(
+                "CHARTERED_DB_POOL_MAX_LIFETIME_SECS",
+                default.max_lifetime,
+            )?,
+        })
+    }
+}
+
+fn env_or<T: FromStr>(key: &'static str, default: T) -> Result<T> {
+    match std::env::var(key) {
+        Ok(v) => v.parse().map_err(|_| Error::InvalidConfig(key)),
+        Err(_) => Ok(default),
+    }
+}
+
+fn duration_env_or(key: &'static str, default: Option<Duration>) -> Result<Option<Duration>> {
+    match env_or(key, default.map_or(0, |v| v.as_secs()))? {
+        0 => Ok(None),
+        secs => Ok(Some(Duration::from_secs(secs))),
+    }
+}
+
+pub fn init(config: &PoolConfig) -> Result<ConnectionPool> {
+    Ok(Arc::new(
+        Pool::builder()
+            .max_size(config.max_size)
+            .min_idle(config.min_idle)
+            .idle_timeout(config.idle_timeout)
+            .max_lifetime(config.max_lifetime)
+            .build(ConnectionManager::new("chartered.db"))?,
+    ))
+}
+
+/// A snapshot of the connection pool's current state, for operators tuning [`PoolConfig`].
+#[derive(Debug, Serialize)]
+pub struct PoolStats {
+    pub max_size: u32,
+    pub connections: u32,
+    pub idle_connections: u32,
+}
+
+#[must_use]
+pub fn pool_stats(pool: &ConnectionPool) -> PoolStats {
+    let state = pool.state();
+
+    PoolStats {
+        max_size: pool.max_size(),
+        connections: state.connections,
+        idle_connections: state.idle_connections,
+    }
 }
 
 #[derive(Error, Display, Debug)]
@@ -65,35 +160,60 @@ pub enum Error {
     TaskJoin(#[from] tokio::task::JoinError),
     /// Key parse failure: `{0}`
     KeyParse(#[from] thrussh_keys::Error),
+    /// Invalid value given for `{0}`
+    InvalidConfig(&'static str),
+    /// Invalid value given for `{0}`: {1}
+    InvalidConfigValue(&'static str, String),
     /// You don't have the {0:?} permission for this crate
     MissingPermission(crate::users::UserCratePermissionValue),
     /// The requested crate does not exist
     MissingCrate,
-    /// Version {0} already exists for this crate
+    /// The requested organisation does not exist
+    MissingOrganisation,
+    /// Version {0} of this crate does not exist
+    MissingVersion(String),
+    /// Version {0} already exists for this crate, published versions are immutable
     VersionConflict(String),
+    /// Version {0} was previously published and yanked, published versions can't be overwritten
+    YankedVersionConflict(String),
+    /// You're the last user able to manage {0:?}, another user needs to be given permission to manage them first
+    LastManager(Vec<String>),
+    /// This is your only SSH key, deleting it would leave you unable to fetch the index
+    LastSshKey,
+    /// A crate named `{0}` already exists, crate names can't differ from another only by case or by `-` and `_`
+    ConfusableCrateName(String),
 }
 
 impl Error {
     #[must_use]
     pub fn status_code(&self) -> http::StatusCode {
         match self {
-            Self::MissingCrate => http::StatusCode::NOT_FOUND,
+            Self::MissingCrate | Self::MissingOrganisation | Self::MissingVersion(_) => {
+                http::StatusCode::NOT_FOUND
+            }
             Self::MissingPermission(v)
                 if v.contains(crate::users::UserCratePermissionValue::VISIBLE) =>
             {
                 http::StatusCode::NOT_FOUND
             }
             Self::MissingPermission(_) => http::StatusCode::FORBIDDEN,
-            Self::KeyParse(_) | Self::VersionConflict(_) => http::StatusCode::BAD_REQUEST,
+            Self::KeyParse(_)
+            | Self::VersionConflict(_)
+            | Self::YankedVersionConflict(_)
+            | Self::ConfusableCrateName(_) => http::StatusCode::BAD_REQUEST,
+            Self::LastManager(_) | Self::LastSshKey => http::StatusCode::CONFLICT,
             _ => http::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
 
 sql_function!(fn coalesce(x: Nullable<Integer>, y: Integer) -> Integer);
+sql_function!(fn lower(x: Text) -> Text);
+sql_function!(fn replace(x: Text, from: Text, to: Text) -> Text);
 
 diesel_infix_operator!(BitwiseAnd, " & ", Integer);
 diesel_infix_operator!(BitwiseOr, " | ", Integer);
+diesel_infix_operator!(Matches, " MATCH ");
 
 trait BitwiseExpressionMethods: Expression<SqlType = Integer> + Sized {
     fn bitwise_and<T: AsExpression<Integer>>(
@@ -112,3 +232,60 @@ trait BitwiseExpressionMethods: Expression<SqlType = Integer> + Sized {
 }
 
 impl<T: Expression<SqlType = Integer>> BitwiseExpressionMethods for T {}
+
+trait FullTextExpressionMethods: Expression<SqlType = Text> + Sized {
+    /// Matches an FTS5 query against the table this column is named after.
+    fn matches<T: AsExpression<Text>>(self, query: T) -> Matches<Self, T::Expression> {
+        Matches::new(self, query.as_expression())
+    }
+}
+
+impl<T: Expression<SqlType = Text>> FullTextExpressionMethods for T {}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::ConnectionPool;
+    use diesel::{
+        connection::SimpleConnection,
+        r2d2::{ConnectionManager, Pool},
+    };
+    use std::sync::Arc;
+
+    embed_migrations!("../migrations");
+
+    /// Creates a fresh in-memory database with every migration applied, the seeded `admin`
+    /// user is given every permission on the seeded `core` organisation.
+    pub fn init() -> ConnectionPool {
+        // in-memory databases are per-connection so we can only ever have one
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(ConnectionManager::new(":memory:"))
+            .unwrap();
+
+        let conn = pool.get().unwrap();
+        embedded_migrations::run(&conn).unwrap();
+        conn.batch_execute(
+            "INSERT INTO user_organisation_permissions (user_id, organisation_id, permissions) VALUES (1, 1, -1)",
+        )
+        .unwrap();
+
+        Arc::new(pool)
+    }
+
+    /// Inserts a user for each of `usernames`, given IDs counting up from 2 after the seeded
+    /// `admin` and a UUID made from their ID.
+    pub fn insert_users(db: &ConnectionPool, usernames: &[&str]) {
+        let sql: String = usernames
+            .iter()
+            .zip(2..)
+            .map(|(username, id)| {
+                format!(
+                    "INSERT INTO users (id, uuid, username) VALUES ({0}, X'{0:032x}', '{1}');",
+                    id, username
+                )
+            })
+            .collect();
+
+        db.get().unwrap().batch_execute(&sql).unwrap();
+    }
+}
@@ -1,3 +1,11 @@
+table! {
+    crate_name_conflicts (crate_id) {
+        crate_id -> Integer,
+        original_name -> Text,
+        conflicts_with -> Integer,
+    }
+}
+
 table! {
     crate_versions (id) {
         id -> Integer,
@@ -12,6 +20,7 @@ table! {
         links -> Nullable<Text>,
         user_id -> Integer,
         created_at -> Timestamp,
+        downloads -> Integer,
     }
 }
 
@@ -25,6 +34,31 @@ table! {
         repository -> Nullable<Text>,
         homepage -> Nullable<Text>,
         documentation -> Nullable<Text>,
+        keywords -> Nullable<Text>,
+        categories -> Nullable<Text>,
+    }
+}
+
+table! {
+    organisation_webhook_deliveries (id) {
+        id -> Integer,
+        webhook_id -> Integer,
+        event -> Text,
+        status_code -> Nullable<Integer>,
+        error -> Nullable<Text>,
+        delivered_at -> Timestamp,
+    }
+}
+
+table! {
+    organisation_webhooks (id) {
+        id -> Integer,
+        uuid -> Binary,
+        organisation_id -> Integer,
+        url -> Text,
+        secret -> Text,
+        events -> Integer,
+        created_at -> Timestamp,
     }
 }
 
@@ -33,6 +67,8 @@ table! {
         id -> Integer,
         uuid -> Binary,
         name -> Text,
+        fetch_message -> Nullable<Text>,
+        index_generation -> Integer,
     }
 }
 
@@ -63,6 +99,9 @@ table! {
         expires_at -> Nullable<Timestamp>,
         user_agent -> Nullable<Text>,
         ip -> Nullable<Text>,
+        last_used_at -> Nullable<Timestamp>,
+        scopes -> Integer,
+        session_key_prefix -> Text,
     }
 }
 
@@ -83,12 +122,26 @@ table! {
         id -> Integer,
         uuid -> Binary,
         username -> Text,
+        deleted_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    crates_search (rowid) {
+        rowid -> Integer,
+        #[sql_name = "crates_search"]
+        search -> Text,
+        name -> Text,
+        description -> Nullable<Text>,
+        keywords -> Nullable<Text>,
     }
 }
 
 joinable!(crate_versions -> crates (crate_id));
 joinable!(crate_versions -> users (user_id));
 joinable!(crates -> organisations (organisation_id));
+joinable!(organisation_webhook_deliveries -> organisation_webhooks (webhook_id));
+joinable!(organisation_webhooks -> organisations (organisation_id));
 joinable!(user_crate_permissions -> crates (crate_id));
 joinable!(user_crate_permissions -> users (user_id));
 joinable!(user_organisation_permissions -> organisations (organisation_id));
@@ -98,8 +151,12 @@ joinable!(user_sessions -> users (user_id));
 joinable!(user_ssh_keys -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    crate_name_conflicts,
     crate_versions,
     crates,
+    crates_search,
+    organisation_webhook_deliveries,
+    organisation_webhooks,
     organisations,
     user_crate_permissions,
     user_organisation_permissions,
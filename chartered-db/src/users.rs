@@ -1,12 +1,12 @@
 use super::{
     schema::{organisations, user_crate_permissions, user_sessions, user_ssh_keys, users},
     uuid::SqlUuid,
-    ConnectionPool, Result,
+    BitwiseExpressionMethods, ConnectionPool, Error, Result,
 };
 use bitflags::bitflags;
 use diesel::{insert_into, prelude::*, Associations, Identifiable, Queryable};
 use option_set::{option_set, OptionSet};
-use rand::{thread_rng, Rng};
+use rand::{thread_rng, RngCore};
 use std::sync::Arc;
 use thrussh_keys::PublicKeyBase64;
 
@@ -15,6 +15,126 @@ pub struct Organisation {
     pub id: i32,
     pub uuid: SqlUuid,
     pub name: String,
+    /// Message sent to users over the git sideband whenever they fetch this organisation's
+    /// index, for onboarding notes, policy links, etc.
+    pub fetch_message: Option<String>,
+    /// Bumped whenever something that could change the organisation's index is modified,
+    /// so consumers can tell when a cached copy of the index is stale.
+    pub index_generation: i32,
+}
+
+impl Organisation {
+    pub async fn find_by_name(
+        conn: ConnectionPool,
+        given_name: String,
+    ) -> Result<Option<Organisation>> {
+        use crate::schema::organisations::dsl::name;
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(organisations::table
+                .filter(name.eq(given_name))
+                .get_result(&conn)
+                .optional()?)
+        })
+        .await?
+    }
+
+    /// Looks up an organisation by name on behalf of `requesting_user_id`, distinguishing
+    /// between an organisation that doesn't exist and one the user just isn't a member of so
+    /// they can be told why they can't see it.
+    pub async fn membership(
+        conn: ConnectionPool,
+        requesting_user_id: i32,
+        given_name: String,
+    ) -> Result<Membership> {
+        use crate::schema::user_organisation_permissions::dsl::{
+            organisation_id, permissions, user_id, user_organisation_permissions,
+        };
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            let organisation = match organisations::table
+                .filter(organisations::name.eq(given_name))
+                .get_result::<Organisation>(&conn)
+                .optional()?
+            {
+                Some(v) => v,
+                None => return Ok(Membership::Missing),
+            };
+
+            let perms = user_organisation_permissions
+                .filter(organisation_id.eq(organisation.id))
+                .filter(user_id.eq(requesting_user_id))
+                .select(permissions)
+                .get_result::<UserCratePermissionValue>(&conn)
+                .optional()?;
+
+            match perms {
+                Some(perms) if perms.contains(UserCratePermissionValue::VISIBLE) => {
+                    Ok(Membership::Member(organisation))
+                }
+                _ => Ok(Membership::NotMember),
+            }
+        })
+        .await?
+    }
+
+    /// Looks up an organisation along with the permissions `requesting_user_id` has been
+    /// given on it, organisations the user can't see are reported as missing.
+    pub async fn find_by_name_with_permissions(
+        conn: ConnectionPool,
+        requesting_user_id: i32,
+        given_name: String,
+    ) -> Result<OrganisationWithPermissions> {
+        use crate::schema::organisations::dsl::{id, name};
+        use crate::schema::user_organisation_permissions::dsl::{
+            organisation_id, permissions, user_id,
+        };
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            let (organisation, perms) = organisations::table
+                .filter(name.eq(given_name))
+                .inner_join(
+                    crate::schema::user_organisation_permissions::table
+                        .on(organisation_id.eq(id).and(user_id.eq(requesting_user_id))),
+                )
+                .select((organisations::all_columns, permissions))
+                .first::<(Organisation, UserCratePermissionValue)>(&conn)
+                .optional()?
+                .ok_or(Error::MissingOrganisation)?;
+
+            if perms.contains(UserCratePermissionValue::VISIBLE) {
+                Ok(OrganisationWithPermissions {
+                    organisation,
+                    permissions: perms,
+                })
+            } else {
+                Err(Error::MissingOrganisation)
+            }
+        })
+        .await?
+    }
+}
+
+/// Whether a user is able to see an organisation, see [`Organisation::membership`].
+#[derive(Debug)]
+pub enum Membership {
+    Member(Organisation),
+    /// The organisation exists, but the user isn't a member of it.
+    NotMember,
+    /// There's no organisation by the name given.
+    Missing,
+}
+
+#[derive(Debug)]
+pub struct OrganisationWithPermissions {
+    pub organisation: Organisation,
+    pub permissions: UserCratePermissionValue,
 }
 
 #[derive(Identifiable, Queryable, Associations, PartialEq, Eq, Hash, Debug)]
@@ -22,6 +142,9 @@ pub struct User {
     pub id: i32,
     pub uuid: SqlUuid,
     pub username: String,
+    /// Set once the user has deleted their account, the row itself is kept around so the
+    /// versions they published still have an uploader.
+    pub deleted_at: Option<chrono::NaiveDateTime>,
 }
 
 impl User {
@@ -30,13 +153,14 @@ impl User {
         given_query: String,
         limit: i64,
     ) -> Result<Vec<User>> {
-        use crate::schema::users::dsl::username;
+        use crate::schema::users::dsl::{deleted_at, username};
 
         tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
 
             Ok(crate::schema::users::table
                 .filter(username.like(format!("%{}%", given_query)))
+                .filter(deleted_at.is_null())
                 .limit(limit)
                 .load(&conn)?)
         })
@@ -47,13 +171,14 @@ impl User {
         conn: ConnectionPool,
         given_username: String,
     ) -> Result<Option<User>> {
-        use crate::schema::users::dsl::username;
+        use crate::schema::users::dsl::{deleted_at, username};
 
         tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
 
             Ok(crate::schema::users::table
                 .filter(username.eq(given_username))
+                .filter(deleted_at.is_null())
                 .get_result(&conn)
                 .optional()?)
         })
@@ -64,13 +189,14 @@ impl User {
         conn: ConnectionPool,
         given_uuid: uuid::Uuid,
     ) -> Result<Option<User>> {
-        use crate::schema::users::dsl::uuid;
+        use crate::schema::users::dsl::{deleted_at, uuid};
 
         tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
 
             Ok(crate::schema::users::table
                 .filter(uuid.eq(SqlUuid(given_uuid)))
+                .filter(deleted_at.is_null())
                 .get_result(&conn)
                 .optional()?)
         })
@@ -80,23 +206,35 @@ impl User {
     pub async fn find_by_session_key(
         conn: ConnectionPool,
         given_session_key: String,
-    ) -> Result<Option<User>> {
-        use crate::schema::user_sessions::dsl::{expires_at, session_key};
+    ) -> Result<Option<(UserSession, User)>> {
+        use crate::schema::user_sessions::dsl::{expires_at, session_key_prefix};
+        use subtle::ConstantTimeEq;
 
         tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
 
-            Ok(user_sessions::table
+            // only narrow candidates down by a prefix of the key in the database, the full key
+            // is compared in constant time below so how long the lookup takes doesn't give away
+            // how much of the key was right
+            let candidates: Vec<(UserSession, User)> = user_sessions::table
                 .filter(
                     expires_at
                         .is_null()
                         .or(expires_at.gt(chrono::Utc::now().naive_utc())),
                 )
-                .filter(session_key.eq(given_session_key))
+                .filter(session_key_prefix.eq(lookup_prefix(&given_session_key)))
                 .inner_join(users::table)
-                .select(users::all_columns)
-                .get_result(&conn)
-                .optional()?)
+                .select((user_sessions::all_columns, users::all_columns))
+                .load(&conn)?;
+
+            Ok(candidates.into_iter().find(|(session, _)| {
+                bool::from(
+                    session
+                        .session_key
+                        .as_bytes()
+                        .ct_eq(given_session_key.as_bytes()),
+                )
+            }))
         })
         .await?
     }
@@ -156,24 +294,47 @@ impl User {
         .await?
     }
 
+    /// Deletes one of the user's SSH keys along with the session handed out in its index
+    /// `config.json`, so a compromised key can't keep using the registry through the session
+    /// either. Returns `false` if the user has no key with that uuid.
+    ///
+    /// Deleting the user's only key leaves them unable to fetch the index, so that's refused
+    /// with [`Error::LastSshKey`] unless `allow_last` is set.
     pub async fn delete_user_ssh_key_by_uuid(
         self: Arc<Self>,
         conn: ConnectionPool,
         ssh_key_id: uuid::Uuid,
+        allow_last: bool,
     ) -> Result<bool> {
-        use crate::schema::user_ssh_keys::dsl::{user_id, user_ssh_keys, uuid};
+        use crate::schema::{user_sessions, user_ssh_keys};
 
         tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
 
-            let rows = diesel::delete(
-                user_ssh_keys
-                    .filter(user_id.eq(self.id))
-                    .filter(uuid.eq(SqlUuid(ssh_key_id))),
-            )
-            .execute(&conn)?;
+            conn.transaction::<_, crate::Error, _>(|| {
+                let keys: Vec<(i32, SqlUuid)> = user_ssh_keys::table
+                    .filter(user_ssh_keys::user_id.eq(self.id))
+                    .select((user_ssh_keys::id, user_ssh_keys::uuid))
+                    .load(&conn)?;
 
-            Ok(rows > 0)
+                let key_id = match keys.iter().find(|(_, uuid)| uuid.0 == ssh_key_id) {
+                    Some((id, _)) => *id,
+                    None => return Ok(false),
+                };
+
+                if keys.len() == 1 && !allow_last {
+                    return Err(Error::LastSshKey);
+                }
+
+                diesel::delete(
+                    user_sessions::table.filter(user_sessions::user_ssh_key_id.eq(key_id)),
+                )
+                .execute(&conn)?;
+                diesel::delete(user_ssh_keys::table.filter(user_ssh_keys::id.eq(key_id)))
+                    .execute(&conn)?;
+
+                Ok(true)
+            })
         })
         .await?
     }
@@ -196,6 +357,73 @@ impl User {
         .await?
     }
 
+    /// Get all the sessions the user has, including expired ones that haven't been cleaned up.
+    pub async fn list_sessions(self: Arc<Self>, conn: ConnectionPool) -> Result<Vec<UserSession>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(UserSession::belonging_to(&*self).load(&conn)?)
+        })
+        .await?
+    }
+
+    /// Revokes one of the user's sessions, returning `false` if the user has no session with
+    /// that id. Sessions belonging to an SSH key are generated again the next time the index is
+    /// fetched with that key, so revoking one only gets rid of the old key.
+    pub async fn delete_session(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        session_id: i32,
+    ) -> Result<bool> {
+        use crate::schema::user_sessions::dsl::{id, user_id, user_sessions};
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            let rows = diesel::delete(
+                user_sessions
+                    .filter(user_id.eq(self.id))
+                    .filter(id.eq(session_id)),
+            )
+            .execute(&conn)?;
+
+            Ok(rows > 0)
+        })
+        .await?
+    }
+
+    /// Revokes every one of the user's sessions, logging them out everywhere. Returns the
+    /// amount of sessions revoked.
+    pub async fn delete_all_sessions(self: Arc<Self>, conn: ConnectionPool) -> Result<usize> {
+        use crate::schema::user_sessions::dsl::{user_id, user_sessions};
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(diesel::delete(user_sessions.filter(user_id.eq(self.id))).execute(&conn)?)
+        })
+        .await?
+    }
+
+    /// Get all the organisations the user has been given permissions on.
+    pub async fn list_organisations(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+    ) -> Result<Vec<(UserCratePermissionValue, Organisation)>> {
+        use crate::schema::user_organisation_permissions::dsl::{permissions, user_id};
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(crate::schema::user_organisation_permissions::table
+                .filter(user_id.eq(self.id))
+                .inner_join(organisations::table)
+                .select((permissions, organisations::all_columns))
+                .load(&conn)?)
+        })
+        .await?
+    }
+
     pub async fn accessible_crates(
         self: Arc<Self>,
         conn: ConnectionPool,
@@ -213,6 +441,242 @@ impl User {
         .await?
     }
 
+    /// Same as [`User::accessible_crates`] but also returns the organisation each crate
+    /// belongs to.
+    pub async fn accessible_crates_with_organisation(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+    ) -> Result<Vec<(UserCratePermissionValue, crate::crates::Crate, Organisation)>> {
+        use crate::schema::crates;
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(UserCratePermission::belonging_to(&*self)
+                .inner_join(crates::table.inner_join(organisations::table))
+                .select((
+                    user_crate_permissions::permissions,
+                    crates::all_columns,
+                    organisations::all_columns,
+                ))
+                .load(&conn)?)
+        })
+        .await?
+    }
+
+    /// Get up to `limit` of the versions the user has published, along with the names of the
+    /// organisation and crate each was published to. Versions are ordered by id, starting
+    /// after the version with the id `after` so the next page can be fetched with the id of the
+    /// last version returned, or from the first version if it's `None`.
+    pub async fn published_versions(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        after: Option<i32>,
+        limit: u32,
+    ) -> Result<Vec<(String, String, crate::crates::CrateVersion<'static>)>> {
+        use crate::schema::{crate_versions, crates};
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(crate_versions::table
+                .filter(crate_versions::user_id.eq(self.id))
+                .filter(crate_versions::id.gt(after.unwrap_or(0)))
+                .inner_join(crates::table.inner_join(organisations::table))
+                .select((
+                    organisations::name,
+                    crates::name,
+                    crate_versions::all_columns,
+                ))
+                .order_by(crate_versions::id.asc())
+                .limit(i64::from(limit))
+                .load(&conn)?)
+        })
+        .await?
+    }
+
+    /// Deletes the user's account, revoking all of their sessions, SSH keys and permissions.
+    ///
+    /// Crates the user is the last one able to manage would be left without anyone able to
+    /// administer them, so the deletion is refused with [`Error::LastManager`] unless
+    /// `reassign_to` is given, in which case that user is given the departing user's
+    /// permissions on those crates. The names of any reassigned crates are returned.
+    ///
+    /// The `users` row itself is kept, with its username freed up, so the versions the user
+    /// published keep an uploader.
+    pub async fn delete(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        reassign_to: Option<Arc<User>>,
+    ) -> Result<Vec<String>> {
+        use crate::schema::{crate_versions, crates, user_organisation_permissions};
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let manage_users = UserCratePermissionValue::MANAGE_USERS.bits();
+
+                let mut managed: Vec<(UserCratePermissionValue, crate::crates::Crate)> =
+                    UserCratePermission::belonging_to(&*self)
+                        .filter(
+                            user_crate_permissions::permissions
+                                .bitwise_and(manage_users)
+                                .ne(0),
+                        )
+                        .inner_join(crates::table)
+                        .select((user_crate_permissions::permissions, crates::all_columns))
+                        .load(&conn)?;
+
+                // managing an organisation means managing every crate in it, just the same as
+                // being given the permission on each crate individually
+                let organisation_managed: Vec<(UserCratePermissionValue, crate::crates::Crate)> =
+                    user_organisation_permissions::table
+                        .filter(user_organisation_permissions::user_id.eq(self.id))
+                        .filter(
+                            user_organisation_permissions::permissions
+                                .bitwise_and(manage_users)
+                                .ne(0),
+                        )
+                        .inner_join(
+                            crates::table.on(crates::organisation_id
+                                .eq(user_organisation_permissions::organisation_id)),
+                        )
+                        .select((
+                            user_organisation_permissions::permissions,
+                            crates::all_columns,
+                        ))
+                        .load(&conn)?;
+
+                for (permissions, crate_) in organisation_managed {
+                    match managed.iter_mut().find(|(_, v)| v.id == crate_.id) {
+                        Some((existing, _)) => *existing |= permissions,
+                        None => managed.push((permissions, crate_)),
+                    }
+                }
+
+                let mut orphaned = Vec::new();
+
+                for (permissions, crate_) in managed {
+                    let crate_managers: i64 = user_crate_permissions::table
+                        .filter(user_crate_permissions::crate_id.eq(crate_.id))
+                        .filter(user_crate_permissions::user_id.ne(self.id))
+                        .filter(
+                            user_crate_permissions::permissions
+                                .bitwise_and(manage_users)
+                                .ne(0),
+                        )
+                        .count()
+                        .get_result(&conn)?;
+                    let organisation_managers: i64 = user_organisation_permissions::table
+                        .filter(
+                            user_organisation_permissions::organisation_id
+                                .eq(crate_.organisation_id),
+                        )
+                        .filter(user_organisation_permissions::user_id.ne(self.id))
+                        .filter(
+                            user_organisation_permissions::permissions
+                                .bitwise_and(manage_users)
+                                .ne(0),
+                        )
+                        .count()
+                        .get_result(&conn)?;
+
+                    if crate_managers == 0 && organisation_managers == 0 {
+                        orphaned.push((permissions, crate_));
+                    }
+                }
+
+                if !orphaned.is_empty() {
+                    let reassign_to = reassign_to.ok_or_else(|| {
+                        Error::LastManager(
+                            orphaned
+                                .iter()
+                                .map(|(_, crate_)| crate_.name.clone())
+                                .collect(),
+                        )
+                    })?;
+
+                    for (permissions, crate_) in &orphaned {
+                        let existing = UserCratePermission::belonging_to(&*reassign_to)
+                            .filter(user_crate_permissions::crate_id.eq(crate_.id))
+                            .get_result::<UserCratePermission>(&conn)
+                            .optional()?;
+
+                        if let Some(existing) = existing {
+                            diesel::update(
+                                user_crate_permissions::table
+                                    .filter(user_crate_permissions::id.eq(existing.id)),
+                            )
+                            .set(
+                                user_crate_permissions::permissions
+                                    .eq((existing.permissions | *permissions).bits()),
+                            )
+                            .execute(&conn)?;
+                        } else {
+                            insert_into(user_crate_permissions::table)
+                                .values((
+                                    user_crate_permissions::user_id.eq(reassign_to.id),
+                                    user_crate_permissions::crate_id.eq(crate_.id),
+                                    user_crate_permissions::permissions.eq(permissions.bits()),
+                                ))
+                                .execute(&conn)?;
+                        }
+                    }
+                }
+
+                // the user's name shows up as the author of index commits and their permissions
+                // decide what's visible in them, so every index they were part of needs rebuilding
+                let mut affected_organisations: Vec<i32> =
+                    UserCratePermission::belonging_to(&*self)
+                        .inner_join(crates::table)
+                        .select(crates::organisation_id)
+                        .load(&conn)?;
+                affected_organisations.extend(
+                    user_organisation_permissions::table
+                        .filter(user_organisation_permissions::user_id.eq(self.id))
+                        .select(user_organisation_permissions::organisation_id)
+                        .load::<i32>(&conn)?,
+                );
+                affected_organisations.extend(
+                    crate_versions::table
+                        .filter(crate_versions::user_id.eq(self.id))
+                        .inner_join(crates::table)
+                        .select(crates::organisation_id)
+                        .load::<i32>(&conn)?,
+                );
+                affected_organisations.sort_unstable();
+                affected_organisations.dedup();
+
+                for organisation_id in affected_organisations {
+                    crate::crates::bump_index_generation(&conn, organisation_id)?;
+                }
+
+                diesel::delete(UserSession::belonging_to(&*self)).execute(&conn)?;
+                diesel::delete(UserSshKey::belonging_to(&*self)).execute(&conn)?;
+                diesel::delete(UserCratePermission::belonging_to(&*self)).execute(&conn)?;
+                diesel::delete(
+                    user_organisation_permissions::table
+                        .filter(user_organisation_permissions::user_id.eq(self.id)),
+                )
+                .execute(&conn)?;
+
+                diesel::update(users::table.filter(users::id.eq(self.id)))
+                    .set((
+                        users::username.eq(format!("deleted-{}", self.uuid.0)),
+                        users::deleted_at.eq(chrono::Utc::now().naive_utc()),
+                    ))
+                    .execute(&conn)?;
+
+                Ok(orphaned
+                    .into_iter()
+                    .map(|(_, crate_)| crate_.name)
+                    .collect())
+            })
+        })
+        .await?
+    }
+
     pub async fn get_crate_permissions(
         self: Arc<Self>,
         conn: ConnectionPool,
@@ -236,38 +700,111 @@ pub struct UserSession {
     pub expires_at: Option<chrono::NaiveDateTime>,
     pub user_agent: Option<String>,
     pub ip: Option<String>,
+    pub last_used_at: Option<chrono::NaiveDateTime>,
+    /// What the session key can be used for, sessions handed out for CI and the like can be
+    /// limited to only what they need.
+    pub scopes: SessionScopes,
+    /// The start of `session_key`, indexed so sessions can be looked up without the database
+    /// ever comparing the full key.
+    pub session_key_prefix: String,
+}
+
+option_set! {
+    #[derive(FromSqlRow, AsExpression)]
+    pub struct SessionScopes: Identity + i32 {
+        const READ    = 0b0000_0000_0000_0000_0000_0000_0000_0001;
+        const PUBLISH = 0b0000_0000_0000_0000_0000_0000_0000_0010;
+        const MANAGE  = 0b0000_0000_0000_0000_0000_0000_0000_0100;
+    }
+}
+
+impl SessionScopes {
+    #[must_use]
+    pub fn names() -> &'static [&'static str] {
+        Self::NAMES
+    }
+}
+
+impl<B: diesel::backend::Backend> diesel::deserialize::FromSql<diesel::sql_types::Integer, B>
+    for SessionScopes
+where
+    i32: diesel::deserialize::FromSql<diesel::sql_types::Integer, B>,
+{
+    fn from_sql(
+        bytes: Option<&B::RawValue>,
+    ) -> std::result::Result<SessionScopes, Box<dyn std::error::Error + Send + Sync>> {
+        let val = i32::from_sql(bytes)?;
+        Ok(SessionScopes::from_bits_truncate(val))
+    }
+}
+
+/// Default amount of random bytes in a session key, 36 bytes encodes to 48 characters.
+pub const DEFAULT_SESSION_KEY_BYTES: usize = 36;
+
+/// Session keys shorter than this are refused, anything less than 128 bits of entropy is
+/// within reach of brute forcing.
+pub const MIN_SESSION_KEY_BYTES: usize = 16;
+
+/// Characters of a session key the database is allowed to match on when looking it up, the
+/// rest of the key is only ever compared in constant time. Existing keys were split up using
+/// this when `session_key_prefix` was added, so it can't change without migrating them.
+const SESSION_KEY_LOOKUP_PREFIX: usize = 12;
+
+/// How out of date a session's last used time is allowed to get, so a busy session isn't
+/// written to on every request.
+const SESSION_LAST_USED_GRANULARITY_SECS: i64 = 60;
+
+fn lookup_prefix(session_key: &str) -> String {
+    session_key
+        .chars()
+        .take(SESSION_KEY_LOOKUP_PREFIX)
+        .collect()
+}
+
+/// Generates a new session key from `bytes` random bytes, encoded as unpadded URL-safe base64.
+///
+/// Keys end up in both the `/a/{key}/` path of the web API and the `config.json` of the index
+/// so they're restricted to `A-Z`, `a-z`, `0-9`, `-` and `_`, none of which need escaping in
+/// either.
+#[must_use]
+pub fn generate_session_key(bytes: usize) -> String {
+    let mut key = vec![0; bytes];
+    thread_rng().fill_bytes(&mut key);
+    base64::encode_config(key, base64::URL_SAFE_NO_PAD)
 }
 
 impl UserSession {
+    #[allow(clippy::too_many_arguments)]
     pub async fn generate(
         conn: ConnectionPool,
+        key_bytes: usize,
         given_user_id: i32,
         given_user_ssh_key_id: Option<i32>,
         given_expires_at: Option<chrono::NaiveDateTime>,
         given_user_agent: Option<String>,
         given_ip: Option<String>,
+        given_scopes: SessionScopes,
     ) -> Result<Self> {
         use crate::schema::user_sessions::dsl::{
-            expires_at, ip, session_key, user_agent, user_id, user_sessions, user_ssh_key_id,
+            expires_at, ip, scopes, session_key, session_key_prefix, user_agent, user_id,
+            user_sessions, user_ssh_key_id,
         };
 
         tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
 
-            let generated_session_key: String = thread_rng()
-                .sample_iter(&rand::distributions::Alphanumeric)
-                .take(48)
-                .map(char::from)
-                .collect();
+            let generated_session_key = generate_session_key(key_bytes);
 
             insert_into(user_sessions)
                 .values((
                     user_id.eq(given_user_id),
                     session_key.eq(&generated_session_key),
+                    session_key_prefix.eq(lookup_prefix(&generated_session_key)),
                     user_ssh_key_id.eq(given_user_ssh_key_id),
                     expires_at.eq(given_expires_at),
                     user_agent.eq(given_user_agent),
                     ip.eq(given_ip),
+                    scopes.eq(given_scopes.bits()),
                 ))
                 .execute(&conn)?;
 
@@ -277,6 +814,96 @@ impl UserSession {
         })
         .await?
     }
+
+    /// Revokes the session `given_session_key` belongs to, returning the amount of sessions
+    /// revoked.
+    pub async fn delete_by_key(conn: ConnectionPool, given_session_key: String) -> Result<usize> {
+        use crate::schema::user_sessions::dsl::{session_key, user_sessions};
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(
+                diesel::delete(user_sessions.filter(session_key.eq(given_session_key)))
+                    .execute(&conn)?,
+            )
+        })
+        .await?
+    }
+
+    /// Updates the last used time of the session `given_session_key` belongs to for reporting
+    /// purposes in the dashboard. Sessions used within the last minute are left alone.
+    pub async fn update_last_used(conn: ConnectionPool, given_session_key: String) -> Result<()> {
+        use crate::schema::user_sessions::dsl::{last_used_at, session_key, user_sessions};
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            let now = chrono::Utc::now().naive_utc();
+            let stale_before = now - chrono::Duration::seconds(SESSION_LAST_USED_GRANULARITY_SECS);
+
+            diesel::update(
+                user_sessions
+                    .filter(session_key.eq(given_session_key))
+                    .filter(last_used_at.is_null().or(last_used_at.lt(stale_before))),
+            )
+            .set(last_used_at.eq(now))
+            .execute(&conn)
+            .map(|_| ())
+            .map_err(Into::into)
+        })
+        .await?
+    }
+
+    /// Swaps `given_session_key` out for a newly generated key, keeping everything else about
+    /// the session the same including when it expires. The old key stops working straight
+    /// away. Returns `None` if the key doesn't belong to a session that's still valid.
+    pub async fn rotate(
+        conn: ConnectionPool,
+        key_bytes: usize,
+        given_session_key: String,
+    ) -> Result<Option<Self>> {
+        use crate::schema::user_sessions::dsl::{
+            expires_at, id, session_key, session_key_prefix, user_sessions,
+        };
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let session = user_sessions
+                    .filter(session_key.eq(given_session_key))
+                    .filter(
+                        expires_at
+                            .is_null()
+                            .or(expires_at.gt(chrono::Utc::now().naive_utc())),
+                    )
+                    .get_result::<Self>(&conn)
+                    .optional()?;
+
+                let session = match session {
+                    Some(v) => v,
+                    None => return Ok(None),
+                };
+
+                let generated_session_key = generate_session_key(key_bytes);
+
+                diesel::update(user_sessions.filter(id.eq(session.id)))
+                    .set((
+                        session_key.eq(&generated_session_key),
+                        session_key_prefix.eq(lookup_prefix(&generated_session_key)),
+                    ))
+                    .execute(&conn)?;
+
+                Ok(Some(Self {
+                    session_key_prefix: lookup_prefix(&generated_session_key),
+                    session_key: generated_session_key,
+                    ..session
+                }))
+            })
+        })
+        .await?
+    }
 }
 
 option_set! {
@@ -295,6 +922,29 @@ impl UserCratePermissionValue {
     pub fn names() -> &'static [&'static str] {
         Self::NAMES
     }
+
+    /// Every individual permission along with its name and a description of what it grants,
+    /// built from the flags themselves so it can't drift from them.
+    #[must_use]
+    pub fn describe() -> Vec<(&'static str, Self, &'static str)> {
+        // flags are defined in ascending order, which is also the order `NAMES` is in
+        (0..i32::BITS)
+            .filter_map(|bit| Self::from_bits(1 << bit))
+            .zip(Self::NAMES.iter().copied())
+            .map(|(flag, name)| (name, flag, flag.description()))
+            .collect()
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::VISIBLE => "View the crate and download its versions",
+            Self::PUBLISH_VERSION => "Publish new versions of the crate",
+            Self::YANK_VERSION => "Yank and unyank versions of the crate",
+            Self::MANAGE_USERS => "Add and remove members and change their permissions",
+            Self::CREATE_CRATE => "Create new crates within the organisation",
+            _ => "",
+        }
+    }
 }
 
 impl<B: diesel::backend::Backend> diesel::deserialize::FromSql<diesel::sql_types::Integer, B>
@@ -362,6 +1012,7 @@ impl UserSshKey {
     pub async fn get_or_insert_session(
         self: Arc<Self>,
         conn: ConnectionPool,
+        key_bytes: usize,
         ip: Option<String>,
     ) -> Result<UserSession> {
         use crate::schema::user_sessions::dsl::{expires_at, user_id};
@@ -389,7 +1040,18 @@ impl UserSshKey {
         if let Some(res) = res {
             Ok(res)
         } else {
-            UserSession::generate(conn, self.user_id, Some(self.id), None, None, ip).await
+            // cargo uses the key in the index's config.json for everything
+            UserSession::generate(
+                conn,
+                key_bytes,
+                self.user_id,
+                Some(self.id),
+                None,
+                None,
+                ip,
+                SessionScopes::all(),
+            )
+            .await
         }
     }
 
@@ -431,3 +1093,455 @@ impl UserSshKey {
         Ok(hex)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        generate_session_key, SessionScopes, User, UserCratePermissionValue as Permissions,
+        UserSession,
+    };
+    use crate::{crates::Crate, Error};
+    use diesel::connection::SimpleConnection;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn membership() {
+        use super::{Membership, Organisation};
+
+        let db = crate::tests::init();
+        crate::tests::insert_users(&db, &["outsider", "invisible"]);
+        db.get()
+            .unwrap()
+            .batch_execute(
+                "INSERT INTO user_organisation_permissions (user_id, organisation_id, permissions) VALUES (3, 1, 0);",
+            )
+            .unwrap();
+
+        let membership =
+            |user_id, name: &str| Organisation::membership(db.clone(), user_id, name.to_string());
+
+        assert!(matches!(
+            membership(1, "core").await.unwrap(),
+            Membership::Member(org) if org.name == "core"
+        ));
+        assert!(matches!(
+            membership(2, "core").await.unwrap(),
+            Membership::NotMember
+        ));
+        // a membership without even the visible permission doesn't count
+        assert!(matches!(
+            membership(3, "core").await.unwrap(),
+            Membership::NotMember
+        ));
+        assert!(matches!(
+            membership(1, "nonexistent").await.unwrap(),
+            Membership::Missing
+        ));
+    }
+
+    #[test]
+    fn session_keys_are_url_safe() {
+        for bytes in [16, 36, 37, 38, 64] {
+            let key = generate_session_key(bytes);
+
+            assert_eq!(key.len(), (bytes * 4 + 2) / 3);
+            assert!(key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        }
+
+        assert_ne!(generate_session_key(36), generate_session_key(36));
+    }
+
+    #[test]
+    fn permissions_describe_every_flag() {
+        let described = Permissions::describe();
+        assert_eq!(described.len(), Permissions::names().len());
+
+        for (name, flag, description) in described {
+            assert_eq!(
+                serde_json::to_value(flag).unwrap(),
+                serde_json::json!([name])
+            );
+            assert!(!description.is_empty(), "{} has no description", name);
+        }
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn delete_requires_reassigning_sole_managed_crates() {
+        let db = crate::tests::init();
+        crate::tests::insert_users(&db, &["other", "leaver", "successor"]);
+
+        let find = |username: &'static str| {
+            let db = db.clone();
+            async move {
+                User::find_by_username(db, username.to_string())
+                    .await
+                    .unwrap()
+                    .map(Arc::new)
+            }
+        };
+
+        let admin = find("admin").await.unwrap();
+        let other = find("other").await.unwrap();
+        let leaver = find("leaver").await.unwrap();
+        let successor = find("successor").await.unwrap();
+
+        let crate_ = Arc::new(
+            Crate::create(db.clone(), admin.id, "core".to_string(), "foo".to_string())
+                .await
+                .unwrap(),
+        );
+        crate_
+            .clone()
+            .insert_permissions(
+                db.clone(),
+                leaver.id,
+                Permissions::VISIBLE | Permissions::MANAGE_USERS,
+            )
+            .await
+            .unwrap();
+        leaver.clone().insert_ssh_key(db.clone(), "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f leaver").await.unwrap();
+
+        // admin can manage every crate in the organisation, so the leaver isn't the last manager
+        assert!(leaver
+            .clone()
+            .delete(db.clone(), None)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(find("leaver").await.is_none());
+        assert!(leaver
+            .clone()
+            .list_ssh_keys(db.clone())
+            .await
+            .unwrap()
+            .is_empty());
+
+        // once the organisation's admin is gone, other is left solely managing the crate
+        crate_
+            .clone()
+            .insert_permissions(
+                db.clone(),
+                other.id,
+                Permissions::VISIBLE | Permissions::MANAGE_USERS,
+            )
+            .await
+            .unwrap();
+        admin.clone().delete(db.clone(), None).await.unwrap();
+
+        assert!(matches!(
+            other.clone().delete(db.clone(), None).await,
+            Err(Error::LastManager(v)) if v == vec!["foo".to_string()]
+        ));
+        assert!(find("other").await.is_some());
+
+        assert_eq!(
+            other
+                .clone()
+                .delete(db.clone(), Some(successor.clone()))
+                .await
+                .unwrap(),
+            vec!["foo".to_string()]
+        );
+        assert!(find("other").await.is_none());
+        assert!(successor
+            .get_crate_permissions(db, crate_.crate_.id)
+            .await
+            .unwrap()
+            .contains(Permissions::MANAGE_USERS));
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn delete_considers_organisation_managers() {
+        let db = crate::tests::init();
+        crate::tests::insert_users(&db, &["successor"]);
+
+        let admin = Arc::new(
+            User::find_by_username(db.clone(), "admin".to_string())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+        let successor = Arc::new(
+            User::find_by_username(db.clone(), "successor".to_string())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+
+        // admin only manages the crate through their permissions on the organisation
+        let crate_ = Crate::create(db.clone(), admin.id, "core".to_string(), "foo".to_string())
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            admin.clone().delete(db.clone(), None).await,
+            Err(Error::LastManager(v)) if v == vec!["foo".to_string()]
+        ));
+        assert!(User::find_by_username(db.clone(), "admin".to_string())
+            .await
+            .unwrap()
+            .is_some());
+
+        assert_eq!(
+            admin
+                .delete(db.clone(), Some(successor.clone()))
+                .await
+                .unwrap(),
+            vec!["foo".to_string()]
+        );
+        assert!(successor
+            .get_crate_permissions(db, crate_.crate_.id)
+            .await
+            .unwrap()
+            .contains(Permissions::MANAGE_USERS));
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn expired_sessions_are_rejected() {
+        use crate::auth::{AuthenticatorKind, Authenticators, Credential};
+
+        let db = crate::tests::init();
+        let authenticators = Authenticators::new(&[AuthenticatorKind::SessionKey]);
+        let authenticate = |key: &str| {
+            let db = db.clone();
+            let credential = Credential::SessionKey(key.to_string());
+            let authenticators = &authenticators;
+
+            async move {
+                authenticators
+                    .authenticate(db, &credential)
+                    .await
+                    .unwrap()
+                    .map(|v| v.user.id)
+            }
+        };
+
+        let hour = chrono::Duration::hours(1);
+        let expired = UserSession::generate(
+            db.clone(),
+            16,
+            1,
+            None,
+            Some((chrono::Utc::now() - hour).naive_utc()),
+            None,
+            None,
+            SessionScopes::all(),
+        )
+        .await
+        .unwrap();
+        let valid = UserSession::generate(
+            db.clone(),
+            16,
+            1,
+            None,
+            Some((chrono::Utc::now() + hour).naive_utc()),
+            None,
+            None,
+            SessionScopes::READ | SessionScopes::PUBLISH,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(authenticate(&expired.session_key).await, None);
+        assert_eq!(authenticate(&valid.session_key).await, Some(1));
+
+        // sharing the prefix sessions are looked up by isn't enough
+        assert_eq!(valid.session_key_prefix, valid.session_key[..12]);
+        let same_prefix = format!(
+            "{}{}",
+            valid.session_key_prefix,
+            "A".repeat(valid.session_key.len() - 12)
+        );
+        assert_eq!(authenticate(&same_prefix).await, None);
+
+        // expired sessions can't be rotated back to life
+        assert!(
+            UserSession::rotate(db.clone(), 16, expired.session_key.clone())
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        let rotated = UserSession::rotate(db.clone(), 16, valid.session_key.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rotated.id, valid.id);
+        assert_eq!(rotated.expires_at, valid.expires_at);
+        assert_ne!(rotated.session_key, valid.session_key);
+        assert_eq!(authenticate(&valid.session_key).await, None);
+        assert_eq!(authenticate(&rotated.session_key).await, Some(1));
+
+        // rotating a key doesn't give it any more than it could do before
+        let authenticated = authenticators
+            .authenticate(
+                db.clone(),
+                &Credential::SessionKey(rotated.session_key.clone()),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            authenticated.scopes,
+            SessionScopes::READ | SessionScopes::PUBLISH
+        );
+
+        let user = Arc::new(
+            User::find_by_username(db.clone(), "admin".to_string())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+        assert!(user
+            .clone()
+            .delete_session(db.clone(), rotated.id)
+            .await
+            .unwrap());
+        assert!(!user.delete_session(db.clone(), rotated.id).await.unwrap());
+        assert_eq!(authenticate(&rotated.session_key).await, None);
+
+        let mut keys = Vec::new();
+        for _ in 0..3 {
+            let session = UserSession::generate(
+                db.clone(),
+                16,
+                1,
+                None,
+                None,
+                None,
+                None,
+                SessionScopes::all(),
+            )
+            .await
+            .unwrap();
+            assert!(session.last_used_at.is_none());
+            keys.push(session.session_key);
+        }
+
+        let last_used = || async {
+            user.clone()
+                .list_sessions(db.clone())
+                .await
+                .unwrap()
+                .into_iter()
+                .find(|v| v.session_key == keys[0])
+                .unwrap()
+                .last_used_at
+        };
+
+        UserSession::update_last_used(db.clone(), keys[0].clone())
+            .await
+            .unwrap();
+        let first_used = last_used().await;
+        assert!(first_used.is_some());
+
+        // used again straight away, the recorded time is close enough already
+        UserSession::update_last_used(db.clone(), keys[0].clone())
+            .await
+            .unwrap();
+        assert_eq!(last_used().await, first_used);
+
+        assert_eq!(
+            UserSession::delete_by_key(db.clone(), keys[0].clone())
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(authenticate(&keys[0]).await, None);
+        assert_eq!(authenticate(&keys[1]).await, Some(1));
+
+        // the expired session is revoked along with the rest
+        assert_eq!(user.delete_all_sessions(db.clone()).await.unwrap(), 3);
+        assert_eq!(authenticate(&keys[1]).await, None);
+        assert_eq!(authenticate(&keys[2]).await, None);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn ssh_keys() {
+        let db = crate::tests::init();
+        let user = Arc::new(
+            User::find_by_username(db.clone(), "admin".to_string())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+
+        assert!(matches!(
+            user.clone()
+                .insert_ssh_key(db.clone(), "ssh-ed25519 not-a-key")
+                .await,
+            Err(Error::KeyParse(_))
+        ));
+
+        for (key, name) in [
+            (
+                "AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f",
+                "first",
+            ),
+            (
+                "AAAAC3NzaC1lZDI1NTE5AAAAIAEBAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f",
+                "second",
+            ),
+        ] {
+            user.clone()
+                .insert_ssh_key(db.clone(), &format!("ssh-ed25519 {} {}", key, name))
+                .await
+                .unwrap();
+        }
+
+        let keys = user.clone().list_ssh_keys(db.clone()).await.unwrap();
+        assert_eq!(
+            keys.iter().map(|v| v.name.as_str()).collect::<Vec<_>>(),
+            ["first", "second"]
+        );
+
+        let first = Arc::new(keys.into_iter().next().unwrap());
+        let session = first
+            .clone()
+            .get_or_insert_session(db.clone(), 16, None)
+            .await
+            .unwrap();
+
+        // deleting a key takes the session from its index config with it
+        assert!(user
+            .clone()
+            .delete_user_ssh_key_by_uuid(db.clone(), first.uuid.0, false)
+            .await
+            .unwrap());
+        assert!(User::find_by_session_key(db.clone(), session.session_key)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(!user
+            .clone()
+            .delete_user_ssh_key_by_uuid(db.clone(), first.uuid.0, false)
+            .await
+            .unwrap());
+
+        let last = user
+            .clone()
+            .list_ssh_keys(db.clone())
+            .await
+            .unwrap()
+            .remove(0);
+        assert!(matches!(
+            user.clone()
+                .delete_user_ssh_key_by_uuid(db.clone(), last.uuid.0, false)
+                .await,
+            Err(Error::LastSshKey)
+        ));
+        assert!(user
+            .clone()
+            .delete_user_ssh_key_by_uuid(db.clone(), last.uuid.0, true)
+            .await
+            .unwrap());
+        assert!(user.list_ssh_keys(db).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,330 @@
+use crate::users::{Organisation, OrganisationWithPermissions};
+
+use super::{
+    schema::{organisation_webhook_deliveries, organisation_webhooks},
+    users::UserCratePermissionValue as Permissions,
+    uuid::SqlUuid,
+    BitwiseExpressionMethods, ConnectionPool, Error, Result,
+};
+use diesel::{insert_into, prelude::*, Associations, Identifiable, Queryable};
+use option_set::{option_set, OptionSet};
+use std::sync::Arc;
+
+option_set! {
+    #[derive(FromSqlRow, AsExpression)]
+    pub struct WebhookEvents: Identity + i32 {
+        const PUBLISH           = 0b0000_0000_0000_0000_0000_0000_0000_0001;
+        const YANK              = 0b0000_0000_0000_0000_0000_0000_0000_0010;
+        const PERMISSION_CHANGE = 0b0000_0000_0000_0000_0000_0000_0000_0100;
+    }
+}
+
+impl WebhookEvents {
+    #[must_use]
+    pub fn names() -> &'static [&'static str] {
+        Self::NAMES
+    }
+}
+
+impl<B: diesel::backend::Backend> diesel::deserialize::FromSql<diesel::sql_types::Integer, B>
+    for WebhookEvents
+where
+    i32: diesel::deserialize::FromSql<diesel::sql_types::Integer, B>,
+{
+    fn from_sql(
+        bytes: Option<&B::RawValue>,
+    ) -> std::result::Result<WebhookEvents, Box<dyn std::error::Error + Send + Sync>> {
+        let val = i32::from_sql(bytes)?;
+        Ok(WebhookEvents::from_bits_truncate(val))
+    }
+}
+
+/// An endpoint an organisation has asked to be notified on whenever one of the subscribed
+/// `events` happens within it. Payloads are signed using `secret` so the receiver can verify
+/// they came from us.
+#[derive(Identifiable, Queryable, Associations, PartialEq, Eq, Hash)]
+#[belongs_to(Organisation)]
+#[table_name = "organisation_webhooks"]
+pub struct Webhook {
+    pub id: i32,
+    pub uuid: SqlUuid,
+    pub organisation_id: i32,
+    pub url: String,
+    pub secret: String,
+    pub events: WebhookEvents,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl std::fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Webhook")
+            .field("id", &self.id)
+            .field("uuid", &self.uuid)
+            .field("organisation_id", &self.organisation_id)
+            .field("url", &self.url)
+            .field("secret", &"[snip]")
+            .field("events", &self.events)
+            .field("created_at", &self.created_at)
+            .finish()
+    }
+}
+
+impl Webhook {
+    /// Gets all the webhooks in the organisation that have subscribed to any of `given_events`.
+    pub async fn subscribed_to(
+        conn: ConnectionPool,
+        given_organisation_id: i32,
+        given_events: WebhookEvents,
+    ) -> Result<Vec<Webhook>> {
+        use crate::schema::organisation_webhooks::dsl::{events, organisation_id};
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(organisation_webhooks::table
+                .filter(organisation_id.eq(given_organisation_id))
+                .filter(events.bitwise_and(given_events.bits()).ne(0))
+                .load(&conn)?)
+        })
+        .await?
+    }
+
+    /// Records the outcome of an attempt to deliver `given_event` to this webhook, so
+    /// organisation admins can debug their endpoints.
+    pub async fn record_delivery(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        given_event: String,
+        given_status_code: Option<i32>,
+        given_error: Option<String>,
+    ) -> Result<()> {
+        use crate::schema::organisation_webhook_deliveries::dsl::{
+            error, event, organisation_webhook_deliveries, status_code, webhook_id,
+        };
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            insert_into(organisation_webhook_deliveries)
+                .values((
+                    webhook_id.eq(self.id),
+                    event.eq(given_event),
+                    status_code.eq(given_status_code),
+                    error.eq(given_error),
+                ))
+                .execute(&conn)?;
+
+            Ok(())
+        })
+        .await?
+    }
+}
+
+#[derive(Identifiable, Queryable, Associations, PartialEq, Eq, Hash, Debug)]
+#[belongs_to(Webhook)]
+#[table_name = "organisation_webhook_deliveries"]
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub webhook_id: i32,
+    pub event: String,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub delivered_at: chrono::NaiveDateTime,
+}
+
+impl OrganisationWithPermissions {
+    pub async fn webhooks(self: Arc<Self>, conn: ConnectionPool) -> Result<Vec<Webhook>> {
+        if !self.permissions.contains(Permissions::MANAGE_USERS) {
+            return Err(Error::MissingPermission(Permissions::MANAGE_USERS));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(Webhook::belonging_to(&self.organisation).load(&conn)?)
+        })
+        .await?
+    }
+
+    pub async fn insert_webhook(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        given_url: String,
+        given_secret: String,
+        given_events: WebhookEvents,
+    ) -> Result<uuid::Uuid> {
+        use crate::schema::organisation_webhooks::dsl::{
+            events, organisation_id, secret, url, uuid,
+        };
+
+        if !self.permissions.contains(Permissions::MANAGE_USERS) {
+            return Err(Error::MissingPermission(Permissions::MANAGE_USERS));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            let generated_uuid = SqlUuid::random();
+
+            insert_into(organisation_webhooks::table)
+                .values((
+                    uuid.eq(&generated_uuid),
+                    organisation_id.eq(self.organisation.id),
+                    url.eq(given_url),
+                    secret.eq(given_secret),
+                    events.eq(given_events.bits()),
+                ))
+                .execute(&conn)?;
+
+            Ok(generated_uuid.0)
+        })
+        .await?
+    }
+
+    /// Deletes the webhook along with its delivery history, returns `false` if the organisation
+    /// has no webhook with the given uuid.
+    pub async fn delete_webhook(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        given_uuid: uuid::Uuid,
+    ) -> Result<bool> {
+        use crate::schema::organisation_webhooks::dsl::{id, organisation_id, uuid};
+
+        if !self.permissions.contains(Permissions::MANAGE_USERS) {
+            return Err(Error::MissingPermission(Permissions::MANAGE_USERS));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let webhook_id = organisation_webhooks::table
+                    .filter(organisation_id.eq(self.organisation.id))
+                    .filter(uuid.eq(SqlUuid(given_uuid)))
+                    .select(id)
+                    .first::<i32>(&conn)
+                    .optional()?;
+
+                let webhook_id = match webhook_id {
+                    Some(v) => v,
+                    None => return Ok(false),
+                };
+
+                diesel::delete(
+                    organisation_webhook_deliveries::table
+                        .filter(organisation_webhook_deliveries::webhook_id.eq(webhook_id)),
+                )
+                .execute(&conn)?;
+                diesel::delete(organisation_webhooks::table.filter(id.eq(webhook_id)))
+                    .execute(&conn)?;
+
+                Ok(true)
+            })
+        })
+        .await?
+    }
+
+    /// Gets the most recent delivery attempts across all of the organisation's webhooks.
+    pub async fn webhook_deliveries(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        limit: i64,
+    ) -> Result<Vec<(WebhookDelivery, Webhook)>> {
+        use crate::schema::organisation_webhooks::dsl::organisation_id;
+
+        if !self.permissions.contains(Permissions::MANAGE_USERS) {
+            return Err(Error::MissingPermission(Permissions::MANAGE_USERS));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(organisation_webhook_deliveries::table
+                .inner_join(organisation_webhooks::table)
+                .filter(organisation_id.eq(self.organisation.id))
+                .order_by(organisation_webhook_deliveries::id.desc())
+                .limit(limit)
+                .load(&conn)?)
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Webhook, WebhookEvents};
+    use crate::users::Organisation;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn deliveries_respect_event_filter() {
+        let db = crate::tests::init();
+
+        let org = Arc::new(
+            Organisation::find_by_name_with_permissions(db.clone(), 1, "core".into())
+                .await
+                .unwrap(),
+        );
+
+        org.clone()
+            .insert_webhook(
+                db.clone(),
+                "https://example.com/publish".into(),
+                "secret".into(),
+                WebhookEvents::PUBLISH,
+            )
+            .await
+            .unwrap();
+        let yank_uuid = org
+            .clone()
+            .insert_webhook(
+                db.clone(),
+                "https://example.com/yank".into(),
+                "secret".into(),
+                WebhookEvents::YANK | WebhookEvents::PERMISSION_CHANGE,
+            )
+            .await
+            .unwrap();
+
+        let subscribed = Webhook::subscribed_to(db.clone(), 1, WebhookEvents::PUBLISH)
+            .await
+            .unwrap();
+        assert_eq!(subscribed.len(), 1);
+        assert_eq!(subscribed[0].url, "https://example.com/publish");
+
+        let subscribed = Webhook::subscribed_to(db.clone(), 1, WebhookEvents::YANK)
+            .await
+            .unwrap();
+        assert_eq!(subscribed.len(), 1);
+        assert_eq!(subscribed[0].url, "https://example.com/yank");
+        assert!(!format!("{:?}", subscribed[0]).contains("\"secret\""));
+
+        Arc::new(subscribed.into_iter().next().unwrap())
+            .record_delivery(db.clone(), "yank".into(), Some(500), Some("oops".into()))
+            .await
+            .unwrap();
+
+        let deliveries = org
+            .clone()
+            .webhook_deliveries(db.clone(), 10)
+            .await
+            .unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].0.status_code, Some(500));
+        assert_eq!(deliveries[0].1.url, "https://example.com/yank");
+
+        assert!(org
+            .clone()
+            .delete_webhook(db.clone(), yank_uuid)
+            .await
+            .unwrap());
+        assert!(org
+            .clone()
+            .webhook_deliveries(db.clone(), 10)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(!org.delete_webhook(db, yank_uuid).await.unwrap());
+    }
+}
@@ -17,6 +17,7 @@ import {
   CheckSquare,
   Check,
   Square,
+  Download,
 } from "react-bootstrap-icons";
 import { useParams, NavLink, Redirect } from "react-router-dom";
 import { useAuthenticatedRequest } from "../../util";
@@ -37,6 +38,7 @@ export interface CrateInfo {
   repository?: string;
   homepage?: string;
   documentation?: string;
+  downloads: number;
   versions: CrateInfoVersion[];
 }
 
@@ -45,6 +47,7 @@ export interface CrateInfoVersion {
   deps: CrateInfoVersionDependency[];
   features: any[];
   size: number;
+  downloads: number;
   uploader: string;
   created_at: string;
 }
@@ -283,6 +286,10 @@ function Versions(props: { crate: CrateInfo }) {
                   <Hdd /> {humanFileSize(version.size)}
                 </div>
 
+                <div className="ms-3 d-inline-block">
+                  <Download /> {version.downloads.toLocaleString()}
+                </div>
+
                 <div className="ms-3 d-inline-block">
                   <OverlayTrigger
                     overlay={
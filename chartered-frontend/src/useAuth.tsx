@@ -1,6 +1,6 @@
 import React = require("react");
 import { useState, useEffect, useContext, createContext } from "react";
-import { unauthenticatedEndpoint } from "./util";
+import { authenticatedEndpoint, unauthenticatedEndpoint } from "./util";
 
 export interface AuthContext {
   authKey?: string;
@@ -51,7 +51,15 @@ function useProvideAuth(): AuthContext {
   };
 
   const logout = async () => {
-    // todo call the service so we can purge the key from the db
+    try {
+      await fetch(authenticatedEndpoint({ authKey } as AuthContext, "sessions"), {
+        method: "DELETE",
+      });
+    } catch (e) {
+      // the key's forgotten about locally regardless, it'll expire on its own
+      console.error("Failed to revoke session", e);
+    }
+
     localStorage.removeItem("charteredAuthentication");
     setExpires(null);
     setAuthKey(null);
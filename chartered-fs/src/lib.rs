@@ -1,22 +1,38 @@
 #![deny(clippy::pedantic)]
 #![deny(clippy::pedantic)]
 
+mod memory;
+mod s3;
+mod verify;
+
+pub use memory::Memory;
+pub use s3::{S3Config, S3};
+
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{path::PathBuf, pin::Pin, str::FromStr};
 use tokio::{
     fs::File,
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
 };
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A file being read a bit at a time, as returned by [`FileSystem::open`].
+pub type Reader = Pin<Box<dyn AsyncRead + Send>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum FileSystemKind {
     Local,
+    S3,
+    Memory,
 }
 
 impl std::fmt::Display for FileSystemKind {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Self::Local => f.write_str("local"),
+            Self::S3 => f.write_str("s3"),
+            Self::Memory => f.write_str("memory"),
         }
     }
 }
@@ -27,6 +43,8 @@ impl std::str::FromStr for FileSystemKind {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "local" => Ok(Self::Local),
+            "s3" => Ok(Self::S3),
+            "memory" => Ok(Self::Memory),
             _ => Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
                 "unknown filesystemkind",
@@ -35,10 +53,69 @@ impl std::str::FromStr for FileSystemKind {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// What a file is stored as within its file system.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+enum ObjectName {
+    /// A random name, given to each file as it's written.
+    Positional(uuid::Uuid),
+    /// The hex encoded SHA-256 of the file's contents, so the same contents are only ever
+    /// stored once.
+    ContentAddressed(String),
+}
+
+impl ObjectName {
+    fn content_addressed(data: &[u8]) -> Self {
+        Self::ContentAddressed(hex::encode(Sha256::digest(data)))
+    }
+}
+
+impl std::fmt::Display for ObjectName {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Positional(uuid) => write!(f, "{}", uuid),
+            Self::ContentAddressed(hash) => write!(f, "sha256-{}", hash),
+        }
+    }
+}
+
+impl std::str::FromStr for ObjectName {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("sha256-") {
+            Some(hash) if hash.len() == 64 && hash.bytes().all(|v| v.is_ascii_hexdigit()) => {
+                Ok(Self::ContentAddressed(hash.to_ascii_lowercase()))
+            }
+            Some(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "invalid sha256 object name",
+            )),
+            None => uuid::Uuid::from_str(s)
+                .map(Self::Positional)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct FileReference {
     file_system: FileSystemKind,
-    reference: uuid::Uuid,
+    reference: ObjectName,
+}
+
+impl FileReference {
+    /// The file system this file was written to, and so has to be read back from.
+    #[must_use]
+    pub fn file_system(&self) -> FileSystemKind {
+        self.file_system
+    }
+
+    /// Whether this file is named after its contents, in which case it may be shared with
+    /// any number of other writes of the same contents.
+    #[must_use]
+    pub fn is_content_addressed(&self) -> bool {
+        matches!(self.reference, ObjectName::ContentAddressed(_))
+    }
 }
 
 impl std::fmt::Display for FileReference {
@@ -53,8 +130,7 @@ impl std::str::FromStr for FileReference {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut split = s.splitn(2, ':');
         let file_system = FileSystemKind::from_str(split.next().unwrap_or_default())?;
-        let reference = uuid::Uuid::from_str(split.next().unwrap_or_default())
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let reference = ObjectName::from_str(split.next().unwrap_or_default())?;
         Ok(FileReference {
             file_system,
             reference,
@@ -62,30 +138,138 @@ impl std::str::FromStr for FileReference {
     }
 }
 
+/// A place files can be stored. Implementations are used as trait objects so the backend can
+/// be picked at runtime, which is why nothing here is generic.
 #[async_trait]
-pub trait FileSystem {
-    const KIND: FileSystemKind;
+pub trait FileSystem: Send + Sync {
+    fn kind(&self) -> FileSystemKind;
 
     async fn read(&self, file_ref: FileReference) -> Result<Vec<u8>, std::io::Error>;
+    /// Opens a file to be read a bit at a time, along with its length in bytes, for files too
+    /// large to be held in memory.
+    async fn open(&self, file_ref: FileReference) -> Result<(Reader, u64), std::io::Error>;
     async fn write(&self, data: &[u8]) -> Result<FileReference, std::io::Error>;
+    /// Writes `data` to a file named after its contents, unless the same contents have been
+    /// written already, returning the file's reference either way.
+    async fn write_content_addressed(&self, data: &[u8]) -> Result<FileReference, std::io::Error>;
+    /// Writes everything from `reader` to a new file, for data too large to be held in memory.
+    async fn write_reader(
+        &self,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<FileReference, std::io::Error>;
+    /// Moves the positional file `from` to the content addressed `to`, for files that were too
+    /// large to hash before they were written. If the same contents are already stored at `to`
+    /// then `from` is deleted instead. The caller is trusted to have hashed `from` correctly.
+    async fn rename_content_addressed(
+        &self,
+        from: FileReference,
+        to: &FileReference,
+    ) -> Result<(), std::io::Error>;
+    /// Lists every file currently held by the file system.
+    async fn list(&self) -> Result<Vec<FileReference>, std::io::Error>;
+    async fn delete(&self, file_ref: FileReference) -> Result<(), std::io::Error>;
+
+    /// Same as [`FileSystem::read`], but errors if the file's contents don't hash to the hex
+    /// encoded SHA-256 `checksum`, so corruption in storage is never handed back as if it were
+    /// the file.
+    async fn read_verified(
+        &self,
+        file_ref: FileReference,
+        checksum: &str,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        let data = self.read(file_ref).await?;
+        verify::verify(&data, checksum)?;
+
+        Ok(data)
+    }
+
+    /// Same as [`FileSystem::open`], but the file is hashed as it's read and errors instead
+    /// of ending if its contents don't hash to the hex encoded SHA-256 `checksum`.
+    async fn open_verified(
+        &self,
+        file_ref: FileReference,
+        checksum: &str,
+    ) -> Result<(Reader, u64), std::io::Error> {
+        let (reader, len) = self.open(file_ref).await?;
+
+        Ok((
+            Box::pin(verify::VerifyingReader::new(reader, checksum.to_string())),
+            len,
+        ))
+    }
+
+    #[must_use]
+    fn create_ref(&self) -> FileReference {
+        FileReference {
+            file_system: self.kind(),
+            reference: ObjectName::Positional(uuid::Uuid::new_v4()),
+        }
+    }
 
     #[must_use]
-    fn create_ref() -> FileReference {
+    fn create_content_addressed_ref(&self, data: &[u8]) -> FileReference {
         FileReference {
-            file_system: Self::KIND,
-            reference: uuid::Uuid::new_v4(),
+            file_system: self.kind(),
+            reference: ObjectName::content_addressed(data),
         }
     }
+
+    /// Same as [`FileSystem::create_content_addressed_ref`] for contents that have already
+    /// been hashed to the hex encoded SHA-256 `checksum`.
+    fn content_addressed_ref(&self, checksum: &str) -> Result<FileReference, std::io::Error> {
+        Ok(FileReference {
+            file_system: self.kind(),
+            reference: ObjectName::from_str(&format!("sha256-{}", checksum))?,
+        })
+    }
+}
+
+/// Keeps files on the local disk, as direct children of `root`.
+pub struct Local {
+    root: PathBuf,
 }
 
-pub struct Local;
+impl Local {
+    /// Creates `root` if it doesn't already exist and checks that files can be written to it,
+    /// so a misconfigured root is found on startup rather than by the first publish.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `root` couldn't be created or a file couldn't be written to it, with the
+    /// error's message naming `root`.
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self, std::io::Error> {
+        let root = root.into();
+        let context = |e: std::io::Error| {
+            std::io::Error::new(
+                e.kind(),
+                format!("{} can't be used for storage: {}", root.display(), e),
+            )
+        };
+
+        std::fs::create_dir_all(&root).map_err(context)?;
+
+        let probe = root.join(format!(".{}", uuid::Uuid::new_v4()));
+        std::fs::write(&probe, b"").map_err(context)?;
+        std::fs::remove_file(&probe).map_err(context)?;
+
+        Ok(Self { root })
+    }
+
+    /// Where `name` is kept on disk. Names are only ever a UUID or a hex encoded hash, so
+    /// this can never point outside of `root`.
+    fn path(&self, name: &ObjectName) -> PathBuf {
+        self.root.join(name.to_string())
+    }
+}
 
 #[async_trait]
 impl FileSystem for Local {
-    const KIND: FileSystemKind = FileSystemKind::Local;
+    fn kind(&self) -> FileSystemKind {
+        FileSystemKind::Local
+    }
 
     async fn read(&self, file_ref: FileReference) -> Result<Vec<u8>, std::io::Error> {
-        let mut file = File::open(format!("/tmp/{}", file_ref.reference)).await?;
+        let mut file = File::open(self.path(&file_ref.reference)).await?;
 
         let mut contents = vec![];
         file.read_to_end(&mut contents).await?;
@@ -93,25 +277,328 @@ impl FileSystem for Local {
         Ok(contents)
     }
 
+    async fn open(&self, file_ref: FileReference) -> Result<(Reader, u64), std::io::Error> {
+        let file = File::open(self.path(&file_ref.reference)).await?;
+        let len = file.metadata().await?.len();
+
+        Ok((Box::pin(file), len))
+    }
+
     async fn write(&self, data: &[u8]) -> Result<FileReference, std::io::Error> {
-        let file_ref = Self::create_ref();
+        let file_ref = self.create_ref();
+
+        let mut file = File::create(self.path(&file_ref.reference)).await?;
+        file.write_all(data).await?;
+
+        Ok(file_ref)
+    }
+
+    async fn write_content_addressed(&self, data: &[u8]) -> Result<FileReference, std::io::Error> {
+        let file_ref = self.create_content_addressed_ref(data);
+        let path = self.path(&file_ref.reference);
+
+        if tokio::fs::metadata(&path).await.is_ok() {
+            return Ok(file_ref);
+        }
 
-        let mut file = File::create(format!("/tmp/{}", file_ref.reference)).await?;
+        // written under another name first and moved into place, so a concurrent write of the
+        // same contents can never see the file half written and assume it's already there
+        let temp_path = self.root.join(format!(".{}", uuid::Uuid::new_v4()));
+        let mut file = File::create(&temp_path).await?;
         file.write_all(data).await?;
+        file.flush().await?;
+        tokio::fs::rename(&temp_path, &path).await?;
 
         Ok(file_ref)
     }
+
+    async fn write_reader(
+        &self,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<FileReference, std::io::Error> {
+        let file_ref = self.create_ref();
+
+        let mut file = File::create(self.path(&file_ref.reference)).await?;
+        tokio::io::copy(reader, &mut file).await?;
+        file.flush().await?;
+
+        Ok(file_ref)
+    }
+
+    async fn rename_content_addressed(
+        &self,
+        from: FileReference,
+        to: &FileReference,
+    ) -> Result<(), std::io::Error> {
+        let path = self.path(&to.reference);
+
+        if tokio::fs::metadata(&path).await.is_ok() {
+            return tokio::fs::remove_file(self.path(&from.reference)).await;
+        }
+
+        // renames replace the destination in one go, so the file is never seen half written
+        // even if the same contents are being renamed into place at the same time
+        tokio::fs::rename(self.path(&from.reference), &path).await
+    }
+
+    async fn list(&self) -> Result<Vec<FileReference>, std::io::Error> {
+        let mut entries = tokio::fs::read_dir(&self.root).await?;
+        let mut file_refs = Vec::new();
+
+        // the root may well be shared with everything else on the machine, so only files
+        // named as we name them are ours
+        while let Some(entry) = entries.next_entry().await? {
+            let reference = entry
+                .file_name()
+                .to_str()
+                .and_then(|v| ObjectName::from_str(v).ok());
+
+            if let Some(reference) = reference {
+                if entry.file_type().await?.is_file() {
+                    file_refs.push(FileReference {
+                        file_system: self.kind(),
+                        reference,
+                    });
+                }
+            }
+        }
+
+        Ok(file_refs)
+    }
+
+    async fn delete(&self, file_ref: FileReference) -> Result<(), std::io::Error> {
+        tokio::fs::remove_file(self.path(&file_ref.reference)).await
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::FileSystem;
+    use sha2::Digest;
+    use tokio::io::AsyncReadExt;
+
+    /// A directory of its own for each test, so none of them see each other's files. It's
+    /// removed along with everything in it once dropped.
+    struct TempRoot(std::path::PathBuf);
+
+    impl TempRoot {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("chartered-fs-test-{}", uuid::Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for TempRoot {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn temp_local() -> (TempRoot, super::Local) {
+        let root = TempRoot::new();
+        let fs = super::Local::new(&root.0).unwrap();
+        (root, fs)
+    }
 
     #[tokio::test]
     #[allow(clippy::pedantic)]
     async fn local() {
-        let fs = super::Local;
+        let (_root, fs) = temp_local();
         let file_ref = fs.write(b"abcdef").await.unwrap();
         assert_eq!(fs.read(file_ref).await.unwrap(), b"abcdef");
+
+        let file_ref = fs.write_reader(&mut &b"ghijkl"[..]).await.unwrap();
+        assert_eq!(fs.read(file_ref.clone()).await.unwrap(), b"ghijkl");
+
+        let (mut reader, len) = fs.open(file_ref).await.unwrap();
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).await.unwrap();
+        assert_eq!(len, 6);
+        assert_eq!(contents, b"ghijkl");
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn local_root() {
+        // created along with any missing parents
+        let temp = TempRoot::new();
+        let root = temp.0.join("nested");
+        let fs = super::Local::new(&root).unwrap();
+
+        let file_ref = fs.write(b"abcdef").await.unwrap();
+        assert_eq!(
+            std::fs::read(root.join(file_ref.reference.to_string())).unwrap(),
+            b"abcdef"
+        );
+        assert_eq!(fs.read(file_ref.clone()).await.unwrap(), b"abcdef");
+        assert_eq!(fs.list().await.unwrap(), [file_ref]);
+
+        // a file where the root should be can't be written to
+        let file = root.join("file");
+        std::fs::write(&file, b"").unwrap();
+        let err = super::Local::new(&file).err().unwrap();
+        assert!(err.to_string().contains(&file.display().to_string()));
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn local_list_and_delete() {
+        let (_root, fs) = temp_local();
+        let file_ref = fs.write(b"abcdef").await.unwrap();
+        assert!(fs.list().await.unwrap().contains(&file_ref));
+
+        fs.delete(file_ref.clone()).await.unwrap();
+        assert!(!fs.list().await.unwrap().contains(&file_ref));
+        assert!(fs.read(file_ref).await.is_err());
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn content_addressed() {
+        let (_root, local) = temp_local();
+        let backends: [Box<dyn FileSystem>; 2] = [Box::new(local), Box::new(super::Memory::new())];
+
+        for fs in backends {
+            let data = uuid::Uuid::new_v4().to_string();
+
+            let first = fs.write_content_addressed(data.as_bytes()).await.unwrap();
+            let second = fs.write_content_addressed(data.as_bytes()).await.unwrap();
+            assert!(first.is_content_addressed());
+            assert_eq!(first, second);
+            assert_eq!(
+                first.to_string().parse::<super::FileReference>().unwrap(),
+                first
+            );
+            assert_eq!(fs.read(first.clone()).await.unwrap(), data.as_bytes());
+
+            // one object, however many times it was written
+            let listed = fs.list().await.unwrap();
+            assert_eq!(listed.iter().filter(|v| **v == first).count(), 1);
+
+            let positional = fs.write(data.as_bytes()).await.unwrap();
+            assert!(!positional.is_content_addressed());
+            assert_ne!(positional, first);
+
+            fs.delete(first).await.unwrap();
+            fs.delete(positional).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn renamed_content_addressed() {
+        let (_root, local) = temp_local();
+        let backends: [Box<dyn FileSystem>; 2] = [Box::new(local), Box::new(super::Memory::new())];
+
+        for fs in backends {
+            let data = uuid::Uuid::new_v4().to_string();
+            let checksum = hex::encode(sha2::Sha256::digest(data.as_bytes()));
+            let to = fs.content_addressed_ref(&checksum).unwrap();
+            assert_eq!(to, fs.create_content_addressed_ref(data.as_bytes()));
+
+            let first = fs.write_reader(&mut data.as_bytes()).await.unwrap();
+            fs.rename_content_addressed(first.clone(), &to)
+                .await
+                .unwrap();
+            assert_eq!(fs.read(to.clone()).await.unwrap(), data.as_bytes());
+
+            // the second copy is dropped in favour of the first
+            let second = fs.write_reader(&mut data.as_bytes()).await.unwrap();
+            fs.rename_content_addressed(second.clone(), &to)
+                .await
+                .unwrap();
+            assert_eq!(fs.list().await.unwrap(), [to.clone()]);
+            assert!(fs.read(first).await.is_err());
+            assert!(fs.read(second).await.is_err());
+
+            fs.delete(to).await.unwrap();
+        }
+
+        let fs = super::Memory::new();
+        assert!(fs.content_addressed_ref("abc").is_err());
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn verified_reads() {
+        let (_root, fs) = temp_local();
+        let data = b"abcdef";
+        let checksum = hex::encode(sha2::Sha256::digest(data));
+
+        let file_ref = fs.write(data).await.unwrap();
+        assert_eq!(
+            fs.read_verified(file_ref.clone(), &checksum).await.unwrap(),
+            data
+        );
+
+        let (mut reader, _) = fs.open_verified(file_ref.clone(), &checksum).await.unwrap();
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).await.unwrap();
+        assert_eq!(contents, data);
+
+        // corrupt the file behind the file system's back
+        tokio::fs::write(fs.path(&file_ref.reference), b"abcdeg")
+            .await
+            .unwrap();
+
+        assert!(fs.read(file_ref.clone()).await.is_ok());
+        let err = fs
+            .read_verified(file_ref.clone(), &checksum)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        let (mut reader, _) = fs.open_verified(file_ref.clone(), &checksum).await.unwrap();
+        let err = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        fs.delete(file_ref).await.unwrap();
+    }
+
+    #[test]
+    fn parse_reference() {
+        let hash = "a".repeat(64);
+        let file_ref: super::FileReference = format!("s3:sha256-{}", hash).parse().unwrap();
+        assert!(file_ref.is_content_addressed());
+        assert_eq!(file_ref.to_string(), format!("s3:sha256-{}", hash));
+
+        assert!(!"local:3fa85f64-5717-4562-b3fc-2c963f66afa6"
+            .parse::<super::FileReference>()
+            .unwrap()
+            .is_content_addressed());
+        assert!("local:sha256-abc".parse::<super::FileReference>().is_err());
+        assert!("local:sha256-".parse::<super::FileReference>().is_err());
+        // nothing but a name can ever end up in a path
+        assert!("local:../../etc/passwd"
+            .parse::<super::FileReference>()
+            .is_err());
+        assert!(format!("local:sha256-../{}", &hash[3..])
+            .parse::<super::FileReference>()
+            .is_err());
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn memory() {
+        let fs: Box<dyn FileSystem> = Box::new(super::Memory::new());
+        let file_ref = fs.write(b"abcdef").await.unwrap();
+        assert_eq!(
+            file_ref
+                .to_string()
+                .parse::<super::FileReference>()
+                .unwrap(),
+            file_ref
+        );
+        assert_eq!(fs.read(file_ref.clone()).await.unwrap(), b"abcdef");
+
+        let other = fs.write_reader(&mut &b"ghijkl"[..]).await.unwrap();
+        let (mut reader, len) = fs.open(other.clone()).await.unwrap();
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).await.unwrap();
+        assert_eq!(len, 6);
+        assert_eq!(contents, b"ghijkl");
+
+        fs.delete(file_ref.clone()).await.unwrap();
+        assert_eq!(fs.list().await.unwrap(), [other]);
+        assert!(fs.read(file_ref).await.is_err());
     }
 }
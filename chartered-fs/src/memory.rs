@@ -0,0 +1,113 @@
+use async_trait::async_trait;
+use std::{collections::HashMap, io::ErrorKind, sync::Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{FileReference, FileSystem, FileSystemKind, ObjectName, Reader};
+
+/// Keeps files in memory, for tests that need somewhere to store files without touching the
+/// disk or the network. Everything is lost once this is dropped.
+#[derive(Default)]
+pub struct Memory {
+    files: Mutex<HashMap<ObjectName, Vec<u8>>>,
+}
+
+impl Memory {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn get(&self, file_ref: &FileReference) -> Result<Vec<u8>, std::io::Error> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(&file_ref.reference)
+            .cloned()
+            .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "no such file"))
+    }
+}
+
+#[async_trait]
+impl FileSystem for Memory {
+    fn kind(&self) -> FileSystemKind {
+        FileSystemKind::Memory
+    }
+
+    async fn read(&self, file_ref: FileReference) -> Result<Vec<u8>, std::io::Error> {
+        self.get(&file_ref)
+    }
+
+    async fn open(&self, file_ref: FileReference) -> Result<(Reader, u64), std::io::Error> {
+        let contents = self.get(&file_ref)?;
+        let len = contents.len() as u64;
+
+        Ok((Box::pin(std::io::Cursor::new(contents)), len))
+    }
+
+    async fn write(&self, data: &[u8]) -> Result<FileReference, std::io::Error> {
+        let file_ref = self.create_ref();
+        self.files
+            .lock()
+            .unwrap()
+            .insert(file_ref.reference.clone(), data.to_vec());
+
+        Ok(file_ref)
+    }
+
+    async fn write_content_addressed(&self, data: &[u8]) -> Result<FileReference, std::io::Error> {
+        let file_ref = self.create_content_addressed_ref(data);
+        self.files
+            .lock()
+            .unwrap()
+            .entry(file_ref.reference.clone())
+            .or_insert_with(|| data.to_vec());
+
+        Ok(file_ref)
+    }
+
+    async fn write_reader(
+        &self,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<FileReference, std::io::Error> {
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).await?;
+
+        self.write(&contents).await
+    }
+
+    async fn rename_content_addressed(
+        &self,
+        from: FileReference,
+        to: &FileReference,
+    ) -> Result<(), std::io::Error> {
+        let mut files = self.files.lock().unwrap();
+        let contents = files
+            .remove(&from.reference)
+            .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "no such file"))?;
+        files.entry(to.reference.clone()).or_insert(contents);
+
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<FileReference>, std::io::Error> {
+        Ok(self
+            .files
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|reference| FileReference {
+                file_system: self.kind(),
+                reference: reference.clone(),
+            })
+            .collect())
+    }
+
+    async fn delete(&self, file_ref: FileReference) -> Result<(), std::io::Error> {
+        self.files
+            .lock()
+            .unwrap()
+            .remove(&file_ref.reference)
+            .map(|_| ())
+            .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "no such file"))
+    }
+}
@@ -0,0 +1,547 @@
+use async_trait::async_trait;
+use rusoto_core::{
+    credential::{DefaultCredentialsProvider, StaticProvider},
+    Client, HttpClient, Region, RusotoError,
+};
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CopyObjectRequest, CreateMultipartUploadRequest, DeleteObjectRequest,
+    GetObjectError, GetObjectOutput, GetObjectRequest, HeadObjectRequest, ListObjectsV2Request,
+    PutObjectRequest, S3Client, UploadPartRequest, S3 as _,
+};
+use std::{convert::TryFrom, fmt::Write, io::ErrorKind, str::FromStr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{FileReference, FileSystem, FileSystemKind, ObjectName, Reader};
+
+/// Objects are uploaded in parts of this many bytes when their size isn't known up front, S3
+/// requires every part but the last to be at least 5MiB.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Clone)]
+pub struct S3Config {
+    pub bucket: String,
+    /// Region the bucket is in, such as `eu-west-2`.
+    pub region: String,
+    /// Endpoint to use instead of AWS's own, for S3-compatible stores such as MinIO.
+    pub endpoint: Option<String>,
+    /// Access key ID and secret access key to sign requests with. The usual AWS credential
+    /// chain is used if these aren't given, so instance roles and the like work.
+    pub credentials: Option<(String, String)>,
+    /// Prepended to the key of every object, so a bucket can be shared with other things.
+    pub prefix: String,
+}
+
+impl std::fmt::Debug for S3Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("S3Config")
+            .field("bucket", &self.bucket)
+            .field("region", &self.region)
+            .field("endpoint", &self.endpoint)
+            .field(
+                "credentials",
+                &self.credentials.as_ref().map(|(id, _)| (id, "[snip]")),
+            )
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+/// Stores files as objects in an S3 bucket, named after their reference.
+pub struct S3 {
+    client: S3Client,
+    bucket: String,
+    prefix: String,
+}
+
+impl S3 {
+    /// Sets up a client for the bucket described by `config`, nothing is sent to S3 until the
+    /// first file is written or read.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `config.region` isn't a known AWS region when no endpoint is given, or if the
+    /// HTTP client or AWS credential chain couldn't be set up.
+    pub fn new(config: S3Config) -> Result<Self, std::io::Error> {
+        let region = match config.endpoint {
+            Some(endpoint) => Region::Custom {
+                name: config.region,
+                endpoint,
+            },
+            None => config.region.parse().map_err(other_error)?,
+        };
+
+        let http = HttpClient::new().map_err(other_error)?;
+        let client = match config.credentials {
+            Some((access_key_id, secret_access_key)) => Client::new_with(
+                StaticProvider::new_minimal(access_key_id, secret_access_key),
+                http,
+            ),
+            None => Client::new_with(
+                DefaultCredentialsProvider::new().map_err(other_error)?,
+                http,
+            ),
+        };
+
+        Ok(Self {
+            client: S3Client::new_with_client(client, region),
+            bucket: config.bucket,
+            prefix: config.prefix,
+        })
+    }
+
+    fn key(&self, file_ref: &FileReference) -> String {
+        format!("{}{}", self.prefix, file_ref.reference)
+    }
+
+    /// The reverse of [`S3::key`], `None` if the object under `key` isn't one of ours as the
+    /// bucket might be shared.
+    fn reference(&self, key: &str) -> Option<ObjectName> {
+        key.strip_prefix(self.prefix.as_str())
+            .and_then(|v| ObjectName::from_str(v).ok())
+    }
+
+    async fn get(&self, file_ref: &FileReference) -> Result<GetObjectOutput, std::io::Error> {
+        self.client
+            .get_object(GetObjectRequest {
+                bucket: self.bucket.clone(),
+                key: self.key(file_ref),
+                ..GetObjectRequest::default()
+            })
+            .await
+            .map_err(|e| match e {
+                e @ RusotoError::Service(GetObjectError::NoSuchKey(_)) => {
+                    std::io::Error::new(ErrorKind::NotFound, e)
+                }
+                e => other_error(e),
+            })
+    }
+
+    async fn exists(&self, key: String) -> bool {
+        self.client
+            .head_object(HeadObjectRequest {
+                bucket: self.bucket.clone(),
+                key,
+                ..HeadObjectRequest::default()
+            })
+            .await
+            .is_ok()
+    }
+
+    async fn put(&self, key: String, data: Vec<u8>) -> Result<(), std::io::Error> {
+        self.client
+            .put_object(PutObjectRequest {
+                bucket: self.bucket.clone(),
+                key,
+                content_length: Some(i64::try_from(data.len()).map_err(other_error)?),
+                body: Some(data.into()),
+                ..PutObjectRequest::default()
+            })
+            .await
+            .map_err(other_error)?;
+
+        Ok(())
+    }
+
+    /// Uploads `first` followed by everything left in `reader` as the parts of the multipart
+    /// upload `upload_id`, returning the parts to complete it with.
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        first: Vec<u8>,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<Vec<CompletedPart>, std::io::Error> {
+        let mut parts = Vec::new();
+        let mut part = first;
+
+        loop {
+            let part_number = i64::try_from(parts.len() + 1).map_err(other_error)?;
+            let len = part.len();
+
+            let output = self
+                .client
+                .upload_part(UploadPartRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.to_string(),
+                    upload_id: upload_id.to_string(),
+                    part_number,
+                    content_length: Some(i64::try_from(len).map_err(other_error)?),
+                    body: Some(part.into()),
+                    ..UploadPartRequest::default()
+                })
+                .await
+                .map_err(other_error)?;
+
+            parts.push(CompletedPart {
+                e_tag: output.e_tag,
+                part_number: Some(part_number),
+            });
+
+            if len < PART_SIZE {
+                break;
+            }
+
+            part = read_part(reader).await?;
+            if part.is_empty() {
+                break;
+            }
+        }
+
+        Ok(parts)
+    }
+}
+
+#[async_trait]
+impl FileSystem for S3 {
+    fn kind(&self) -> FileSystemKind {
+        FileSystemKind::S3
+    }
+
+    async fn read(&self, file_ref: FileReference) -> Result<Vec<u8>, std::io::Error> {
+        let body = self
+            .get(&file_ref)
+            .await?
+            .body
+            .ok_or_else(|| other_error("object has no body"))?;
+
+        let mut contents = vec![];
+        body.into_async_read().read_to_end(&mut contents).await?;
+
+        Ok(contents)
+    }
+
+    async fn open(&self, file_ref: FileReference) -> Result<(Reader, u64), std::io::Error> {
+        let output = self.get(&file_ref).await?;
+        let len = output
+            .content_length
+            .and_then(|v| u64::try_from(v).ok())
+            .ok_or_else(|| other_error("object has no content length"))?;
+        let body = output
+            .body
+            .ok_or_else(|| other_error("object has no body"))?;
+
+        Ok((Box::pin(body.into_async_read()), len))
+    }
+
+    async fn write(&self, data: &[u8]) -> Result<FileReference, std::io::Error> {
+        let file_ref = self.create_ref();
+        self.put(self.key(&file_ref), data.to_vec()).await?;
+
+        Ok(file_ref)
+    }
+
+    async fn write_content_addressed(&self, data: &[u8]) -> Result<FileReference, std::io::Error> {
+        let file_ref = self.create_content_addressed_ref(data);
+        let key = self.key(&file_ref);
+
+        // puts replace objects whole, so even if two writes of the same contents race each
+        // other the object only ever holds those contents
+        if !self.exists(key.clone()).await {
+            self.put(key, data.to_vec()).await?;
+        }
+
+        Ok(file_ref)
+    }
+
+    async fn write_reader(
+        &self,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<FileReference, std::io::Error> {
+        let file_ref = self.create_ref();
+        let key = self.key(&file_ref);
+
+        // S3 needs to know how large an object is before it's uploaded, so anything that fits
+        // in a single part is sent in one go and only larger objects use a multipart upload
+        let first = read_part(reader).await?;
+        if first.len() < PART_SIZE {
+            self.put(key, first).await?;
+            return Ok(file_ref);
+        }
+
+        let upload_id = self
+            .client
+            .create_multipart_upload(CreateMultipartUploadRequest {
+                bucket: self.bucket.clone(),
+                key: key.clone(),
+                ..CreateMultipartUploadRequest::default()
+            })
+            .await
+            .map_err(other_error)?
+            .upload_id
+            .ok_or_else(|| other_error("multipart upload has no id"))?;
+
+        let parts = match self.upload_parts(&key, &upload_id, first, reader).await {
+            Ok(parts) => parts,
+            Err(e) => {
+                // otherwise the parts uploaded so far are kept (and billed for) forever
+                let _ = self
+                    .client
+                    .abort_multipart_upload(AbortMultipartUploadRequest {
+                        bucket: self.bucket.clone(),
+                        key,
+                        upload_id,
+                        ..AbortMultipartUploadRequest::default()
+                    })
+                    .await;
+                return Err(e);
+            }
+        };
+
+        self.client
+            .complete_multipart_upload(CompleteMultipartUploadRequest {
+                bucket: self.bucket.clone(),
+                key,
+                upload_id,
+                multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+                ..CompleteMultipartUploadRequest::default()
+            })
+            .await
+            .map_err(other_error)?;
+
+        Ok(file_ref)
+    }
+
+    async fn rename_content_addressed(
+        &self,
+        from: FileReference,
+        to: &FileReference,
+    ) -> Result<(), std::io::Error> {
+        let key = self.key(to);
+
+        // S3 has no rename, the object is copied over and the original deleted. copies
+        // replace objects whole just like puts, so racing copies are fine too
+        if !self.exists(key.clone()).await {
+            self.client
+                .copy_object(CopyObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key,
+                    copy_source: copy_source(&self.bucket, &self.key(&from)),
+                    ..CopyObjectRequest::default()
+                })
+                .await
+                .map_err(other_error)?;
+        }
+
+        self.delete(from).await
+    }
+
+    async fn list(&self) -> Result<Vec<FileReference>, std::io::Error> {
+        let mut file_refs = Vec::new();
+        let mut continuation_token = None;
+
+        loop {
+            let output = self
+                .client
+                .list_objects_v2(ListObjectsV2Request {
+                    bucket: self.bucket.clone(),
+                    prefix: Some(self.prefix.clone()),
+                    continuation_token,
+                    ..ListObjectsV2Request::default()
+                })
+                .await
+                .map_err(other_error)?;
+
+            for object in output.contents.unwrap_or_default() {
+                if let Some(reference) = object.key.as_deref().and_then(|v| self.reference(v)) {
+                    file_refs.push(FileReference {
+                        file_system: self.kind(),
+                        reference,
+                    });
+                }
+            }
+
+            continuation_token = output.next_continuation_token;
+            if continuation_token.is_none() {
+                break;
+            }
+        }
+
+        Ok(file_refs)
+    }
+
+    async fn delete(&self, file_ref: FileReference) -> Result<(), std::io::Error> {
+        self.client
+            .delete_object(DeleteObjectRequest {
+                bucket: self.bucket.clone(),
+                key: self.key(&file_ref),
+                ..DeleteObjectRequest::default()
+            })
+            .await
+            .map_err(other_error)?;
+
+        Ok(())
+    }
+}
+
+/// Reads up to [`PART_SIZE`] bytes from `reader`, only returning less if it's run out.
+async fn read_part(reader: &mut (dyn AsyncRead + Unpin + Send)) -> Result<Vec<u8>, std::io::Error> {
+    let mut part = Vec::new();
+    (&mut *reader)
+        .take(PART_SIZE as u64)
+        .read_to_end(&mut part)
+        .await?;
+
+    Ok(part)
+}
+
+/// The `x-amz-copy-source` header for the object `key` in `bucket`, which S3 expects to be
+/// URL encoded.
+fn copy_source(bucket: &str, key: &str) -> String {
+    let mut out = format!("{}/", bucket);
+
+    for b in key.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~' | b'/') {
+            out.push(char::from(b));
+        } else {
+            // writing to a string can't fail
+            let _ = write!(out, "%{:02X}", b);
+        }
+    }
+
+    out
+}
+
+fn other_error<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> std::io::Error {
+    std::io::Error::new(ErrorKind::Other, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy_source, read_part, S3Config, PART_SIZE, S3};
+    use crate::FileSystem;
+    use tokio::io::AsyncReadExt;
+
+    fn unconnected(prefix: &str) -> S3 {
+        S3::new(S3Config {
+            bucket: "chartered-test".to_string(),
+            region: "us-east-1".to_string(),
+            endpoint: Some("http://localhost:1".to_string()),
+            credentials: Some(("test".to_string(), "test".to_string())),
+            prefix: prefix.to_string(),
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn parts() {
+        // readers handing back less than was asked for still make for full parts
+        let data: Vec<u8> = (0..PART_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+        let mut reader = tokio::io::BufReader::with_capacity(1000, &data[..]);
+
+        let mut parts = Vec::new();
+        loop {
+            let part = read_part(&mut reader).await.unwrap();
+            if part.is_empty() {
+                break;
+            }
+            parts.push(part);
+        }
+
+        assert_eq!(
+            parts.iter().map(Vec::len).collect::<Vec<_>>(),
+            [PART_SIZE, PART_SIZE, 100]
+        );
+        assert!(parts.concat() == data);
+
+        // a full last part is followed by an empty one, rather than an empty upload
+        let data = vec![0; PART_SIZE];
+        let mut reader = &data[..];
+        assert_eq!(read_part(&mut reader).await.unwrap().len(), PART_SIZE);
+        assert!(read_part(&mut reader).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn keys() {
+        for prefix in ["", "crates/"] {
+            let fs = unconnected(prefix);
+
+            for file_ref in [fs.create_ref(), fs.create_content_addressed_ref(b"abc")] {
+                let key = fs.key(&file_ref);
+                assert!(key.starts_with(prefix));
+                assert_eq!(fs.reference(&key), Some(file_ref.reference));
+            }
+        }
+
+        // anything else sharing the bucket is left alone
+        let fs = unconnected("crates/");
+        let uuid = uuid::Uuid::new_v4();
+        assert_eq!(fs.reference(&uuid.to_string()), None);
+        assert_eq!(fs.reference(&format!("other/{}", uuid)), None);
+        assert_eq!(fs.reference(&format!("crates/nested/{}", uuid)), None);
+        assert_eq!(fs.reference("crates/readme.md"), None);
+        assert_eq!(fs.reference("crates/sha256-abc"), None);
+    }
+
+    #[test]
+    fn copy_sources() {
+        assert_eq!(
+            copy_source("bucket", "crates/sha256-abc"),
+            "bucket/crates/sha256-abc"
+        );
+        assert_eq!(
+            copy_source("bucket", "my crates/+ü"),
+            "bucket/my%20crates/%2B%C3%BC"
+        );
+    }
+
+    /// Runs against an S3-compatible store such as localstack, which isn't around by default
+    /// so this has to be asked for with `cargo test -- --ignored`. Start one with
+    /// `docker run -p 4566:4566 localstack/localstack` and create the bucket with
+    /// `aws --endpoint-url http://localhost:4566 s3 mb s3://chartered-test`.
+    #[tokio::test]
+    #[ignore]
+    #[allow(clippy::pedantic)]
+    async fn s3() {
+        let fs = S3::new(S3Config {
+            bucket: std::env::var("CHARTERED_FS_TEST_S3_BUCKET")
+                .unwrap_or_else(|_| "chartered-test".to_string()),
+            region: "us-east-1".to_string(),
+            endpoint: Some(
+                std::env::var("CHARTERED_FS_TEST_S3_ENDPOINT")
+                    .unwrap_or_else(|_| "http://localhost:4566".to_string()),
+            ),
+            credentials: Some(("test".to_string(), "test".to_string())),
+            prefix: "crates/".to_string(),
+        })
+        .unwrap();
+
+        let file_ref = fs.write(b"abcdef").await.unwrap();
+        assert_eq!(fs.read(file_ref.clone()).await.unwrap(), b"abcdef");
+        assert!(fs.list().await.unwrap().contains(&file_ref));
+
+        fs.delete(file_ref.clone()).await.unwrap();
+        assert!(!fs.list().await.unwrap().contains(&file_ref));
+        assert_eq!(
+            fs.read(file_ref).await.unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+
+        // large enough to need a multipart upload
+        let large: Vec<u8> = (0..PART_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+        let file_ref = fs.write_reader(&mut &large[..]).await.unwrap();
+
+        let (mut reader, len) = fs.open(file_ref.clone()).await.unwrap();
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).await.unwrap();
+        assert_eq!(len, large.len() as u64);
+        assert!(contents == large);
+
+        // moved over to be named after its contents, and only stored once
+        let to = fs.create_content_addressed_ref(&large);
+        fs.rename_content_addressed(file_ref.clone(), &to)
+            .await
+            .unwrap();
+        let again = fs.write_reader(&mut &large[..]).await.unwrap();
+        fs.rename_content_addressed(again.clone(), &to)
+            .await
+            .unwrap();
+        assert!(fs.read(to.clone()).await.unwrap() == large);
+        let listed = fs.list().await.unwrap();
+        assert!(!listed.contains(&file_ref) && !listed.contains(&again));
+
+        fs.delete(to).await.unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+use sha2::{Digest, Sha256};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::Reader;
+
+fn mismatch(expected: &str, actual: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "file contents don't match their checksum, expected {} but got {}",
+            expected, actual
+        ),
+    )
+}
+
+/// Errors if `data` doesn't hash to the hex encoded SHA-256 `expected`.
+pub(crate) fn verify(data: &[u8], expected: &str) -> Result<(), std::io::Error> {
+    let actual = hex::encode(Sha256::digest(data));
+
+    if actual.eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        Err(mismatch(expected, &actual))
+    }
+}
+
+/// Hashes a file as it's read, and errors in place of reaching the end of it if its contents
+/// turn out not to match `expected`. Anything read before the end still has to be treated as
+/// untrusted until then.
+pub(crate) struct VerifyingReader {
+    inner: Reader,
+    hasher: Sha256,
+    expected: String,
+    done: bool,
+}
+
+impl VerifyingReader {
+    pub(crate) fn new(inner: Reader, expected: String) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            expected,
+            done: false,
+        }
+    }
+}
+
+impl AsyncRead for VerifyingReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.done {
+            return Poll::Ready(Ok(()));
+        }
+
+        let before = buf.filled().len();
+        match this.inner.as_mut().poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+        let read = &buf.filled()[before..];
+
+        // an empty read with room left in the buffer is the end of the file
+        if read.is_empty() && buf.remaining() > 0 {
+            this.done = true;
+
+            let actual = hex::encode(std::mem::take(&mut this.hasher).finalize());
+            if !actual.eq_ignore_ascii_case(&this.expected) {
+                return Poll::Ready(Err(mismatch(&this.expected, &actual)));
+            }
+        } else {
+            this.hasher.update(read);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Limits how many times a single IP can attempt to authenticate within a window, every
+/// attempt is a database lookup so a misbehaving client could otherwise hammer the database.
+///
+/// Windows are fixed, starting from an IP's first attempt, and are forgotten about once
+/// they've passed so the map only ever holds IPs that have been seen recently.
+pub struct AuthLimiter {
+    window: Duration,
+    max_attempts: u32,
+    attempts: Mutex<HashMap<IpAddr, Attempts>>,
+}
+
+struct Attempts {
+    window_start: Instant,
+    count: u32,
+}
+
+impl AuthLimiter {
+    pub fn new(window: Duration, max_attempts: u32) -> Self {
+        Self {
+            window,
+            max_attempts,
+            attempts: Mutex::default(),
+        }
+    }
+
+    /// Records an attempt from `ip`, returning `false` if it's gone over the limit.
+    pub fn attempt(&self, ip: IpAddr) -> bool {
+        self.attempt_at(ip, Instant::now())
+    }
+
+    fn attempt_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut attempts = self.attempts.lock().unwrap();
+
+        attempts.retain(|_, v| now.saturating_duration_since(v.window_start) < self.window);
+
+        let attempts = attempts.entry(ip).or_insert(Attempts {
+            window_start: now,
+            count: 0,
+        });
+        attempts.count = attempts.count.saturating_add(1);
+
+        attempts.count <= self.max_attempts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AuthLimiter;
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn limits_per_ip_and_expires() {
+        let limiter = AuthLimiter::new(Duration::from_secs(60), 2);
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other_ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let now = Instant::now();
+
+        assert!(limiter.attempt_at(ip, now));
+        assert!(limiter.attempt_at(ip, now));
+        assert!(!limiter.attempt_at(ip, now));
+        assert!(limiter.attempt_at(other_ip, now));
+
+        let later = now + Duration::from_secs(61);
+        assert!(limiter.attempt_at(ip, later));
+        assert_eq!(limiter.attempts.lock().unwrap().len(), 1);
+    }
+}
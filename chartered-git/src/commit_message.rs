@@ -0,0 +1,184 @@
+use std::{fmt::Write, str::FromStr};
+
+/// Template for the message of the synthetic commit at the head of each index, parsed once on
+/// startup so a bad template fails fast rather than on the first fetch. Placeholders are
+/// written as `{name}` and braces can be escaped by doubling them (`{{`, `}}`).
+///
+/// Available placeholders:
+///
+/// - `{org}`: name of the organisation the index belongs to
+/// - `{count}`: number of crates in the index
+/// - `{versions}`: number of versions across every crate in the index
+/// - `{date}`: date of the most recently published version, as `YYYY-MM-DD`
+/// - `{crate}`: name of the crate the most recently published version belongs to
+/// - `{version}`: the most recently published version
+///
+/// An index with nothing published to it yet always gets the message `Initial commit`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitMessageTemplate(Vec<Segment>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Org,
+    Count,
+    Versions,
+    Date,
+    Crate,
+    Version,
+}
+
+/// Values available to a [`CommitMessageTemplate`] when the index is built.
+pub struct CommitMessageValues<'a> {
+    pub org: &'a str,
+    pub count: usize,
+    pub versions: usize,
+    pub last_updated: Option<chrono::NaiveDateTime>,
+    /// Crate name and version of the most recent publish.
+    pub latest: Option<(&'a str, &'a str)>,
+}
+
+impl Default for CommitMessageTemplate {
+    fn default() -> Self {
+        "Updating crate `{crate}#{version}`".parse().unwrap()
+    }
+}
+
+impl FromStr for CommitMessageTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| "unclosed `{` in template".to_string())?;
+
+                    let segment = match &rest[..end] {
+                        "org" => Segment::Org,
+                        "count" => Segment::Count,
+                        "versions" => Segment::Versions,
+                        "date" => Segment::Date,
+                        "crate" => Segment::Crate,
+                        "version" => Segment::Version,
+                        other => return Err(format!("unknown placeholder `{{{}}}`", other)),
+                    };
+
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(segment);
+
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err("unmatched `}` in template, use `}}` to escape it".to_string()),
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        if segments.is_empty() {
+            return Err("template must not be empty".to_string());
+        }
+
+        Ok(Self(segments))
+    }
+}
+
+impl CommitMessageTemplate {
+    #[must_use]
+    pub fn render(&self, values: &CommitMessageValues<'_>) -> String {
+        let (latest_crate, latest_version) = match values.latest {
+            Some(v) => v,
+            None => return "Initial commit".to_string(),
+        };
+
+        let mut out = String::new();
+
+        for segment in &self.0 {
+            match segment {
+                Segment::Literal(v) => out.push_str(v),
+                Segment::Org => out.push_str(values.org),
+                Segment::Count => write!(out, "{}", values.count).unwrap(),
+                Segment::Versions => write!(out, "{}", values.versions).unwrap(),
+                Segment::Date => match values.last_updated {
+                    Some(v) => write!(out, "{}", v.format("%Y-%m-%d")).unwrap(),
+                    None => out.push_str("never"),
+                },
+                Segment::Crate => out.push_str(latest_crate),
+                Segment::Version => out.push_str(latest_version),
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CommitMessageTemplate, CommitMessageValues};
+
+    #[test]
+    fn render() {
+        let values = CommitMessageValues {
+            org: "core",
+            count: 3,
+            versions: 10,
+            last_updated: Some(chrono::NaiveDate::from_ymd(2021, 9, 24).and_hms(12, 0, 0)),
+            latest: Some(("serde", "1.0.2")),
+        };
+
+        assert_eq!(
+            CommitMessageTemplate::default().render(&values),
+            "Updating crate `serde#1.0.2`"
+        );
+        assert_eq!(
+            "{{{org}}}: {count} crates, {versions} versions as of {date}"
+                .parse::<CommitMessageTemplate>()
+                .unwrap()
+                .render(&values),
+            "{core}: 3 crates, 10 versions as of 2021-09-24"
+        );
+    }
+
+    #[test]
+    fn render_empty() {
+        let values = CommitMessageValues {
+            org: "core",
+            count: 0,
+            versions: 0,
+            last_updated: None,
+            latest: None,
+        };
+
+        assert_eq!(
+            CommitMessageTemplate::default().render(&values),
+            "Initial commit"
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert!("".parse::<CommitMessageTemplate>().is_err());
+        assert!("{count".parse::<CommitMessageTemplate>().is_err());
+        assert!("count}".parse::<CommitMessageTemplate>().is_err());
+        assert!("{unknown}".parse::<CommitMessageTemplate>().is_err());
+    }
+}
@@ -0,0 +1,304 @@
+//! Runtime configuration for chartered-git, read from the environment on startup.
+
+use crate::{commit_message::CommitMessageTemplate, git::packfile::ObjectFormat};
+
+use anyhow::Context;
+use std::{fmt::Display, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+
+pub struct Config {
+    /// Address the SSH server listens on.
+    pub bind_address: SocketAddr,
+    /// Message sent to the client over the sideband on every fetch, used unless the
+    /// organisation being fetched has set its own.
+    pub fetch_message: String,
+    /// How long to wait on the database for the user's session key before giving up on
+    /// the fetch.
+    pub session_lookup_timeout: Duration,
+    /// Amount of random bytes that go into each newly generated session key.
+    pub session_key_bytes: usize,
+    /// How long a connection can go without receiving anything from the client before it's
+    /// dropped.
+    pub idle_timeout: Duration,
+    /// Clones taking longer than this, from the exec request through to the packfile being
+    /// sent, are logged as slow.
+    pub slow_clone_threshold: Duration,
+    /// Most connections served at once, any more are turned away until one finishes.
+    pub max_connections: usize,
+    /// How long to wait for connections to finish on shutdown before exiting anyway.
+    pub shutdown_grace_period: Duration,
+    /// Most bytes of input buffered for a single command before the connection is dropped.
+    pub max_command_bytes: usize,
+    /// How many times a single IP can try to authenticate within `auth_attempt_window`
+    /// before any further attempts are rejected.
+    pub max_auth_attempts: u32,
+    pub auth_attempt_window: Duration,
+    /// Host keys offered to clients, in order of preference, along with the path each is
+    /// stored at. Keys are generated on first run if they don't exist.
+    pub host_keys: Vec<(HostKeyAlgorithm, PathBuf)>,
+    /// Message given to the commit at the head of each index.
+    pub commit_message: CommitMessageTemplate,
+    /// What to send to clients fetching the index of an organisation with no crates in it.
+    pub empty_index: EmptyIndex,
+    /// Address chartered-web is publicly reachable at, given to cargo in the index's
+    /// `config.json` as the base of the download and API URLs.
+    pub web_base_url: WebBaseUrl,
+    /// Sets `auth-required` in the index's `config.json`, telling cargo to send its token
+    /// with downloads too.
+    pub index_auth_required: bool,
+    /// zlib level objects in the packfile are deflated with, from 0 to 9. Lower levels spend
+    /// less CPU on every fetch but send more over the wire, so registries on fast networks
+    /// may want to turn it down and those on constrained links turn it up.
+    pub packfile_compression: flate2::Compression,
+    /// Object format the index is hashed with, advertised to clients through the protocol v2
+    /// `object-format` capability. Clients cloning the index pick it up from there, and any
+    /// command asking for a different format is refused.
+    pub object_format: ObjectFormat,
+}
+
+/// An absolute `http` or `https` URL, stored without a trailing slash so paths can be
+/// appended to it directly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebBaseUrl(String);
+
+impl WebBaseUrl {
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for WebBaseUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = url::Url::parse(s).map_err(|e| e.to_string())?;
+
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(format!("expected an http or https URL, got `{}`", s));
+        }
+
+        if !url.has_host() {
+            return Err(format!("`{}` has no host", s));
+        }
+
+        if url.query().is_some() || url.fragment().is_some() {
+            return Err(format!("`{}` can't have a query string or fragment", s));
+        }
+
+        Ok(Self(url.as_str().trim_end_matches('/').to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostKeyAlgorithm {
+    Ed25519,
+    Rsa,
+}
+
+impl HostKeyAlgorithm {
+    /// Environment variable the path to this algorithm's host key is read from, and the path
+    /// used if it isn't set. ed25519 keeps the unsuffixed names it's always used.
+    fn path_config(self) -> (&'static str, &'static str) {
+        match self {
+            Self::Ed25519 => ("CHARTERED_HOST_KEY", "chartered_host_key"),
+            Self::Rsa => ("CHARTERED_HOST_KEY_RSA", "chartered_host_key_rsa"),
+        }
+    }
+}
+
+impl FromStr for HostKeyAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ed25519" => Ok(Self::Ed25519),
+            "rsa" => Ok(Self::Rsa),
+            "ecdsa" => Err("ecdsa host keys aren't supported, use `ed25519` or `rsa`".to_string()),
+            _ => Err(format!("expected `ed25519` or `rsa`, got `{}`", s)),
+        }
+    }
+}
+
+impl Display for HostKeyAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Ed25519 => "ed25519",
+            Self::Rsa => "rsa",
+        })
+    }
+}
+
+/// Comma separated list of [`HostKeyAlgorithm`]s, such as `ed25519,rsa`.
+struct HostKeyAlgorithms(Vec<HostKeyAlgorithm>);
+
+impl FromStr for HostKeyAlgorithms {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut algorithms = Vec::new();
+
+        for algorithm in s.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            let algorithm = algorithm.parse()?;
+
+            if !algorithms.contains(&algorithm) {
+                algorithms.push(algorithm);
+            }
+        }
+
+        if algorithms.is_empty() {
+            return Err("at least one host key algorithm is required".to_string());
+        }
+
+        Ok(Self(algorithms))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmptyIndex {
+    /// Send an index containing only the `config.json`, which cargo is able to use straight
+    /// away.
+    Commit,
+    /// Advertise `HEAD` as unborn, as an empty git repository would.
+    Unborn,
+}
+
+impl FromStr for EmptyIndex {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "commit" => Ok(Self::Commit),
+            "unborn" => Ok(Self::Unborn),
+            _ => Err(format!("expected `commit` or `unborn`, got `{}`", s)),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            bind_address: env_or(
+                "CHARTERED_SSH_BIND_ADDRESS",
+                SocketAddr::from(([127, 0, 0, 1], 2233)),
+            )?,
+            fetch_message: std::env::var("CHARTERED_FETCH_MESSAGE")
+                .unwrap_or_else(|_| "Hello from chartered!".to_string()),
+            session_lookup_timeout: Duration::from_secs(env_or(
+                "CHARTERED_SESSION_LOOKUP_TIMEOUT_SECS",
+                5,
+            )?),
+            session_key_bytes: match env_or(
+                "CHARTERED_SESSION_KEY_BYTES",
+                chartered_db::users::DEFAULT_SESSION_KEY_BYTES,
+            )? {
+                v if v < chartered_db::users::MIN_SESSION_KEY_BYTES => anyhow::bail!(
+                    "invalid value for `CHARTERED_SESSION_KEY_BYTES`: must be at least {}",
+                    chartered_db::users::MIN_SESSION_KEY_BYTES
+                ),
+                v => v,
+            },
+            idle_timeout: Duration::from_secs(env_or("CHARTERED_SSH_IDLE_TIMEOUT_SECS", 60)?),
+            slow_clone_threshold: Duration::from_millis(env_or(
+                "CHARTERED_SSH_SLOW_CLONE_THRESHOLD_MS",
+                5000,
+            )?),
+            max_connections: match env_or("CHARTERED_SSH_MAX_CONNECTIONS", 512)? {
+                0 => anyhow::bail!(
+                    "invalid value for `CHARTERED_SSH_MAX_CONNECTIONS`: must be at least 1"
+                ),
+                v => v,
+            },
+            shutdown_grace_period: Duration::from_secs(env_or(
+                "CHARTERED_SSH_SHUTDOWN_GRACE_PERIOD_SECS",
+                30,
+            )?),
+            max_command_bytes: match env_or(
+                "CHARTERED_SSH_MAX_COMMAND_BYTES",
+                crate::git::codec::DEFAULT_MAX_BUFFERED,
+            )? {
+                // anything less would refuse perfectly valid pkt-lines
+                v if v < 65520 => anyhow::bail!(
+                    "invalid value for `CHARTERED_SSH_MAX_COMMAND_BYTES`: must be at least 65520"
+                ),
+                v => v,
+            },
+            max_auth_attempts: env_or("CHARTERED_SSH_MAX_AUTH_ATTEMPTS", 30)?,
+            auth_attempt_window: Duration::from_secs(env_or(
+                "CHARTERED_SSH_AUTH_ATTEMPT_WINDOW_SECS",
+                60,
+            )?),
+            host_keys: env_or(
+                "CHARTERED_HOST_KEY_ALGORITHMS",
+                HostKeyAlgorithms(vec![HostKeyAlgorithm::Ed25519]),
+            )?
+            .0
+            .into_iter()
+            .map(|algorithm| {
+                let (key, default) = algorithm.path_config();
+                Ok((algorithm, env_or(key, PathBuf::from(default))?))
+            })
+            .collect::<Result<_, anyhow::Error>>()?,
+            commit_message: env_or(
+                "CHARTERED_INDEX_COMMIT_MESSAGE",
+                CommitMessageTemplate::default(),
+            )?,
+            empty_index: env_or("CHARTERED_EMPTY_INDEX", EmptyIndex::Commit)?,
+            web_base_url: env_or(
+                "CHARTERED_WEB_BASE_URL",
+                WebBaseUrl("http://127.0.0.1:8888".to_string()),
+            )?,
+            index_auth_required: env_or("CHARTERED_INDEX_AUTH_REQUIRED", false)?,
+            packfile_compression: match env_or("CHARTERED_PACKFILE_COMPRESSION_LEVEL", 6)? {
+                v if v > 9 => anyhow::bail!(
+                    "invalid value for `CHARTERED_PACKFILE_COMPRESSION_LEVEL`: must be between 0 and 9"
+                ),
+                v => flate2::Compression::new(v),
+            },
+            object_format: env_or("CHARTERED_INDEX_OBJECT_FORMAT", ObjectFormat::Sha1)?,
+        })
+    }
+}
+
+fn env_or<T: FromStr>(key: &'static str, default: T) -> Result<T, anyhow::Error>
+where
+    T::Err: Display,
+{
+    match std::env::var(key) {
+        Ok(v) => v
+            .parse()
+            .map_err(|e: T::Err| anyhow::anyhow!("{}", e))
+            .with_context(|| format!("invalid value for `{}`", key)),
+        Err(_) => Ok(default),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::WebBaseUrl;
+
+    #[test]
+    fn web_base_url() {
+        for (given, expected) in [
+            (
+                "https://registry.example.com",
+                "https://registry.example.com",
+            ),
+            (
+                "https://registry.example.com/",
+                "https://registry.example.com",
+            ),
+            (
+                "http://127.0.0.1:8888/chartered/",
+                "http://127.0.0.1:8888/chartered",
+            ),
+        ] {
+            assert_eq!(given.parse::<WebBaseUrl>().unwrap().as_str(), expected);
+        }
+
+        assert!("registry.example.com".parse::<WebBaseUrl>().is_err());
+        assert!("ftp://registry.example.com".parse::<WebBaseUrl>().is_err());
+        assert!("https://registry.example.com/?a=b"
+            .parse::<WebBaseUrl>()
+            .is_err());
+    }
+}
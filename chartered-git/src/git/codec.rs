@@ -24,9 +24,93 @@ pub struct GitCommand {
     pub metadata: Vec<Bytes>,
 }
 
-#[derive(Default)]
+/// How much input a [`GitCodec`] will hold on to by default while waiting for a command to be
+/// completed, every command we support fits comfortably within this.
+pub const DEFAULT_MAX_BUFFERED: usize = 1024 * 1024;
+
+/// The largest a pkt-line can be, including its 4 byte length prefix.
+const MAX_PKT_LINE_LENGTH: usize = 65520;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The client sent a length prefix that isn't valid for a pkt-line.
+    InvalidLength(usize),
+    /// The client has sent, or has said it's going to send, more than we're willing to hold
+    /// on to for a single command.
+    TooLarge { limit: usize },
+    /// The client sent a delim-pkt or response-end-pkt somewhere the protocol doesn't allow
+    /// one, `expected` describes what should have been sent instead.
+    MisplacedControlPacket {
+        length: usize,
+        expected: &'static str,
+    },
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidLength(length) => write!(f, "invalid pkt-line length {}", length),
+            Self::TooLarge { limit } => write!(f, "command exceeds the {} byte limit", limit),
+            Self::MisplacedControlPacket { length, expected } => write!(
+                f,
+                "unexpected {} ({:04x}), expected {}",
+                control_packet_name(*length),
+                length,
+                expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn control_packet_name(length: usize) -> &'static str {
+    match length {
+        0 => "flush-pkt",
+        1 => "delim-pkt",
+        2 => "response-end-pkt",
+        _ => "pkt-line",
+    }
+}
+
 pub struct GitCodec {
     command: GitCommand,
+    /// Bytes already taken out of the input and held in `command`.
+    buffered: usize,
+    /// Most bytes of input that'll be held for a single command, whether they're part of the
+    /// command decoded so far or still waiting in the input buffer.
+    max_buffered: usize,
+    /// Whether the delim-pkt separating the command's capabilities from its arguments has
+    /// been seen, there can only be one per command.
+    seen_delim: bool,
+}
+
+impl Default for GitCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_BUFFERED)
+    }
+}
+
+impl GitCodec {
+    #[must_use]
+    pub fn new(max_buffered: usize) -> Self {
+        Self {
+            command: GitCommand::default(),
+            buffered: 0,
+            max_buffered,
+            seen_delim: false,
+        }
+    }
+
+    fn check_limit(&self, length: usize) -> Result<(), DecodeError> {
+        if self.buffered + length > self.max_buffered {
+            Err(DecodeError::TooLarge {
+                limit: self.max_buffered,
+            })
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl codec::Decoder for GitCodec {
@@ -35,6 +119,9 @@ impl codec::Decoder for GitCodec {
 
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         loop {
+            // catches a client sending a flood of small pkt-lines that never get to a flush
+            self.check_limit(src.len())?;
+
             if src.len() < 4 {
                 return Ok(None);
             }
@@ -46,18 +133,44 @@ impl codec::Decoder for GitCodec {
             if length == 0 {
                 // flush
                 src.advance(4);
+                self.buffered = 0;
+                self.seen_delim = false;
                 return Ok(Some(std::mem::take(&mut self.command)));
-            } else if length == 1 || length == 2 {
+            } else if length == 1 {
+                // a delim-pkt separates the command and its capabilities from the command's
+                // arguments, so it's only valid once per command and only after the command
+                if self.command.command.is_empty() {
+                    return Err(DecodeError::MisplacedControlPacket {
+                        length,
+                        expected: "a command",
+                    }
+                    .into());
+                } else if self.seen_delim {
+                    return Err(DecodeError::MisplacedControlPacket {
+                        length,
+                        expected: "command arguments or a flush-pkt",
+                    }
+                    .into());
+                }
+
                 src.advance(4);
-                eprintln!("magic packet = {}", length);
+                self.seen_delim = true;
                 continue;
-            } else if !(4..=65520).contains(&length) {
-                eprintln!("protocol abuse");
-                return Err(
-                    std::io::Error::new(std::io::ErrorKind::InvalidData, "protocol abuse").into(),
-                );
+            } else if length == 2 {
+                // only ever sent by the server to end its response
+                return Err(DecodeError::MisplacedControlPacket {
+                    length,
+                    expected: "a command or a flush-pkt",
+                }
+                .into());
+            } else if !(4..=MAX_PKT_LINE_LENGTH).contains(&length) {
+                return Err(DecodeError::InvalidLength(length).into());
             }
 
+            // refuse the frame before reserving any space for it, so a client can't have us
+            // allocate for a body that's never going to arrive
+            self.check_limit(length)?;
+
             // not enough bytes in the buffer yet, ask for more
             if src.len() < length {
                 src.reserve(length - src.len());
@@ -67,6 +180,7 @@ impl codec::Decoder for GitCodec {
             // length is inclusive of the 4 bytes that makes up itself
             let mut data = src.split_to(length).freeze();
             data.advance(4);
+            self.buffered += length;
 
             // strip newlines for conformity
             if data.ends_with(b"\n") {
@@ -122,7 +236,6 @@ mod test {
             })
         );
 
-        bytes.write_str("0002").unwrap();
         bytes.write_str("0005a").unwrap();
         bytes.write_str("0001").unwrap();
         bytes.write_str("0005b").unwrap();
@@ -137,4 +250,117 @@ mod test {
             })
         );
     }
+
+    #[test]
+    fn decode_rejects_oversized_input() {
+        let mut codec = super::GitCodec::new(16);
+        let mut bytes = BytesMut::new();
+        bytes.write_str("0015agent").unwrap();
+        assert_eq!(
+            codec
+                .decode(&mut bytes)
+                .unwrap_err()
+                .downcast_ref::<super::DecodeError>(),
+            Some(&super::DecodeError::TooLarge { limit: 16 })
+        );
+
+        // each of these fits in the limit by itself, but not once they're added together
+        let mut codec = super::GitCodec::new(16);
+        let mut bytes = BytesMut::new();
+        bytes.write_str("0006a\n0006b\n").unwrap();
+        assert_eq!(codec.decode(&mut bytes).unwrap(), None);
+        bytes.write_str("0006c\n").unwrap();
+        assert!(codec.decode(&mut bytes).is_err());
+
+        let mut codec = super::GitCodec::default();
+        let mut bytes = BytesMut::new();
+        bytes.write_str("fff1").unwrap();
+        assert_eq!(
+            codec
+                .decode(&mut bytes)
+                .unwrap_err()
+                .downcast_ref::<super::DecodeError>(),
+            Some(&super::DecodeError::InvalidLength(0xfff1))
+        );
+    }
+
+    #[test]
+    fn decode_rejects_misplaced_control_packets() {
+        let misplaced = |input: &str| {
+            let mut codec = super::GitCodec::default();
+            let mut bytes = BytesMut::new();
+            bytes.write_str(input).unwrap();
+
+            match codec.decode(&mut bytes) {
+                Err(e) => e.downcast::<super::DecodeError>().unwrap(),
+                Ok(v) => panic!("expected an error, got {:?}", v),
+            }
+        };
+
+        // a delim-pkt where the command should be
+        assert_eq!(
+            misplaced("0001"),
+            super::DecodeError::MisplacedControlPacket {
+                length: 1,
+                expected: "a command",
+            }
+        );
+
+        // a second delim-pkt where arguments should be
+        assert_eq!(
+            misplaced("0012command=fetch\n00010009done\n0001"),
+            super::DecodeError::MisplacedControlPacket {
+                length: 1,
+                expected: "command arguments or a flush-pkt",
+            }
+        );
+
+        let error = misplaced("0012command=fetch\n0002");
+        assert_eq!(
+            error,
+            super::DecodeError::MisplacedControlPacket {
+                length: 2,
+                expected: "a command or a flush-pkt",
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "unexpected response-end-pkt (0002), expected a command or a flush-pkt"
+        );
+
+        // the delim-pkt is allowed again once the previous command has been flushed
+        let mut codec = super::GitCodec::default();
+        let mut bytes = BytesMut::new();
+        bytes
+            .write_str("0012command=fetch\n00010000000ecommand=a\n00010000")
+            .unwrap();
+        assert!(codec.decode(&mut bytes).unwrap().is_some());
+        assert!(codec.decode(&mut bytes).unwrap().is_some());
+    }
+
+    #[test]
+    fn decode_object_info() {
+        let mut codec = super::GitCodec::default();
+
+        let mut bytes = BytesMut::new();
+        bytes.write_str("0018command=object-info\n").unwrap();
+        bytes.write_str("0001").unwrap();
+        bytes.write_str("0009size\n").unwrap();
+        bytes
+            .write_str("0031oid d24d8020163b5fee57c9babfd0c595b8c90ba253\n")
+            .unwrap();
+        bytes.write_str("0000").unwrap();
+
+        let res = codec.decode(&mut bytes).unwrap();
+        assert_eq!(
+            res,
+            Some(super::GitCommand {
+                command: Bytes::from_static(b"command=object-info"),
+                metadata: vec![
+                    Bytes::from_static(b"size"),
+                    Bytes::from_static(b"oid d24d8020163b5fee57c9babfd0c595b8c90ba253"),
+                ],
+            })
+        );
+    }
 }
@@ -0,0 +1,237 @@
+//! git's delta format, which `OBJ_OFS_DELTA` entries use to describe an object as a series of
+//! copies from another object earlier in the packfile and literal inserts.
+//!
+//! A delta starts with the size of the base object and the size of the object it produces,
+//! each a little-endian base 128 varint, followed by instructions:
+//!
+//! - `1xxxxxxx`: copy from the base. The low four bits say which of the four offset bytes
+//!   follow and the next three which of the three size bytes follow, any that are missing
+//!   are zero. A size of zero means `0x10000`.
+//! - `0xxxxxxx`: insert the next `x` bytes of the delta as they are, `x` being 1 to 127.
+
+use std::collections::HashMap;
+
+/// How many bytes have to match before they're copied from the base, anything shorter is
+/// cheaper to insert.
+const BLOCK_SIZE: usize = 16;
+
+/// Most a single copy instruction is used for, longer matches are split across several.
+const MAX_COPY: usize = 0x10000;
+
+/// Most a single insert instruction can carry.
+const MAX_INSERT: usize = 0x7f;
+
+/// Builds a delta that produces `target` when applied to `base`. Both are expected to be well
+/// under 4GiB, as anything in the index is.
+#[must_use]
+pub fn encode(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_varint(&mut out, base.len());
+    write_varint(&mut out, target.len());
+
+    // the first place every block appears in the base, so matches can be found in a single
+    // pass over the target
+    let mut blocks: HashMap<&[u8], usize> = HashMap::new();
+    for (i, block) in base.windows(BLOCK_SIZE).enumerate() {
+        blocks.entry(block).or_insert(i);
+    }
+
+    let mut insert_from = 0;
+    let mut i = 0;
+
+    while i + BLOCK_SIZE <= target.len() {
+        let start = match blocks.get(&target[i..i + BLOCK_SIZE]) {
+            Some(&start) => start,
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+
+        let len = base[start..]
+            .iter()
+            .zip(&target[i..])
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        write_insert(&mut out, &target[insert_from..i]);
+        write_copy(&mut out, start, len);
+
+        i += len;
+        insert_from = i;
+    }
+
+    write_insert(&mut out, &target[insert_from..]);
+
+    out
+}
+
+/// Applies `delta` to `base`, returning `None` if the delta is malformed or wasn't built
+/// against a base of this size.
+#[must_use]
+pub fn apply(base: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut delta = delta;
+
+    if read_varint(&mut delta)? != base.len() {
+        return None;
+    }
+
+    let target_len = read_varint(&mut delta)?;
+    let mut out = Vec::with_capacity(target_len);
+
+    while let Some((&op, rest)) = delta.split_first() {
+        delta = rest;
+
+        if op & 0b1000_0000 != 0 {
+            let mut offset = 0_usize;
+            let mut size = 0_usize;
+
+            for i in 0..7 {
+                if op & (1 << i) != 0 {
+                    let (&byte, rest) = delta.split_first()?;
+                    delta = rest;
+
+                    if i < 4 {
+                        offset |= usize::from(byte) << (8 * i);
+                    } else {
+                        size |= usize::from(byte) << (8 * (i - 4));
+                    }
+                }
+            }
+
+            if size == 0 {
+                size = MAX_COPY;
+            }
+
+            out.extend_from_slice(base.get(offset..offset.checked_add(size)?)?);
+        } else if op != 0 {
+            let len = usize::from(op);
+            out.extend_from_slice(delta.get(..len)?);
+            delta = &delta[len..];
+        } else {
+            // reserved by git
+            return None;
+        }
+    }
+
+    if out.len() == target_len {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        #[allow(clippy::cast_possible_truncation)] // value is masked
+        let byte = (value & 0b111_1111) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+
+        out.push(byte | 0b1000_0000);
+    }
+}
+
+fn read_varint(input: &mut &[u8]) -> Option<usize> {
+    let mut value = 0_usize;
+    let mut shift = 0;
+
+    loop {
+        let (&byte, rest) = input.split_first()?;
+        *input = rest;
+
+        value |= usize::from(byte & 0b111_1111).checked_shl(shift)?;
+        shift += 7;
+
+        if byte & 0b1000_0000 == 0 {
+            return Some(value);
+        }
+    }
+}
+
+fn write_insert(out: &mut Vec<u8>, data: &[u8]) {
+    for chunk in data.chunks(MAX_INSERT) {
+        #[allow(clippy::cast_possible_truncation)] // chunks are at most 127 bytes
+        out.push(chunk.len() as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+fn write_copy(out: &mut Vec<u8>, mut offset: usize, mut len: usize) {
+    while len > 0 {
+        let size = len.min(MAX_COPY);
+
+        let op_at = out.len();
+        let mut op = 0b1000_0000_u8;
+        out.push(op);
+
+        #[allow(clippy::cast_possible_truncation)] // the base is under 4GiB
+        for (i, byte) in (offset as u32).to_le_bytes().iter().enumerate() {
+            if *byte != 0 {
+                op |= 1 << i;
+                out.push(*byte);
+            }
+        }
+
+        // `MAX_COPY` is written as a size of zero, which leaves out the size bytes entirely
+        #[allow(clippy::cast_possible_truncation)] // size is at most `MAX_COPY`
+        for (i, byte) in ((size % MAX_COPY) as u32).to_le_bytes()[..3]
+            .iter()
+            .enumerate()
+        {
+            if *byte != 0 {
+                op |= 1 << (4 + i);
+                out.push(*byte);
+            }
+        }
+
+        out[op_at] = op;
+
+        offset += size;
+        len -= size;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply, encode, MAX_COPY};
+    use std::convert::TryFrom;
+
+    #[test]
+    fn round_trip() {
+        let base = br#"{"name":"foo","vers":"0.1.0","deps":[],"cksum":"abc","features":{},"yanked":false}"#;
+        let target = br#"{"name":"foo","vers":"0.2.0","deps":[],"cksum":"def","features":{},"yanked":false}"#;
+
+        let delta = encode(base, target);
+        assert!(delta.len() < target.len());
+        assert_eq!(apply(base, &delta).as_deref(), Some(&target[..]));
+
+        // nothing in common with the base, so it's all inserts
+        let delta = encode(b"", target);
+        assert_eq!(apply(b"", &delta).as_deref(), Some(&target[..]));
+
+        // a match longer than a single copy can cover
+        let base: Vec<u8> = (0..MAX_COPY * 2 + 100)
+            .map(|i| u8::try_from(i % 251).unwrap())
+            .collect();
+        let mut target = base.clone();
+        target.extend_from_slice(b"trailer");
+        let delta = encode(&base, &target);
+        assert!(delta.len() < 32);
+        assert_eq!(apply(&base, &delta), Some(target));
+    }
+
+    #[test]
+    fn apply_rejects_mismatched_base() {
+        let delta = encode(b"0123456789abcdef0123", b"0123456789abcdef");
+        assert_eq!(apply(b"0123456789abcdef", &delta), None);
+        assert_eq!(
+            apply(b"0123456789abcdef0123", &delta[..delta.len() - 1]),
+            None
+        );
+    }
+}
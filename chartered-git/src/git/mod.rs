@@ -1,4 +1,5 @@
 pub mod codec;
+pub mod delta;
 pub mod packfile;
 
 use bytes::{BufMut, BytesMut};
@@ -6,13 +7,23 @@ use std::fmt::Write;
 
 use self::packfile::PackFile;
 
+/// The most data a single pkt-line can carry, a pkt-line can be at most 65520 bytes and 4 of
+/// those are taken up by the length prefix.
+const MAX_PKT_LINE_DATA: usize = 65520 - 4;
+
+/// The most data a single sideband data packet can carry, a pkt-line can be at most 65520
+/// bytes and 5 of those are taken up by the length and sideband prefixes.
+pub const MAX_SIDEBAND_DATA: usize = MAX_PKT_LINE_DATA - 1;
+
 pub enum PktLine<'a> {
     Data(&'a [u8]),
     /// Similar to a data packet, but used during packfile sending to indicate this
-    /// packet is a block of data by appending a byte containing the u8 `1`.
-    SidebandData(PackFile<'a>),
+    /// packet is a block of data by appending a byte containing the u8 `1`. Anything over
+    /// [`MAX_SIDEBAND_DATA`] bytes is split across as many packets as it takes when encoded.
+    SidebandData(&'a [u8]),
     /// Similar to a data packet, but used during packfile sending to indicate this
-    /// packet is a status message by appending a byte containing the u8 `2`.
+    /// packet is a status message by appending a byte containing the u8 `2`. Split up the
+    /// same way as [`PktLine::SidebandData`].
     SidebandMsg(&'a [u8]),
     Flush,
     Delimiter,
@@ -23,26 +34,21 @@ impl PktLine<'_> {
     pub fn encode_to(&self, buf: &mut BytesMut) -> Result<(), anyhow::Error> {
         match self {
             Self::Data(data) => {
+                // unlike sideband packets there's no way to split these, the receiver would
+                // see each piece as a line of its own
+                if data.len() > MAX_PKT_LINE_DATA {
+                    anyhow::bail!(
+                        "pkt-line of {} bytes exceeds the {} byte limit",
+                        data.len(),
+                        MAX_PKT_LINE_DATA
+                    );
+                }
+
                 write!(buf, "{:04x}", data.len() + 4)?;
                 buf.extend_from_slice(data);
             }
-            Self::SidebandData(packfile) => {
-                // split the buf off so the cost of counting the bytes to put in the
-                // data line prefix is just the cost of `unsplit` (an atomic decrement)
-                let mut data_buf = buf.split_off(buf.len());
-
-                data_buf.put_u8(1); // sideband, 1 = data
-                packfile.encode_to(&mut data_buf)?;
-
-                // write into the buf not the data buf so it's at the start of the msg
-                write!(buf, "{:04x}", data_buf.len() + 4)?;
-                buf.unsplit(data_buf);
-            }
-            Self::SidebandMsg(msg) => {
-                write!(buf, "{:04x}", msg.len() + 4 + 1)?;
-                buf.put_u8(2); // sideband, 2 = msg
-                buf.extend_from_slice(msg);
-            }
+            Self::SidebandData(data) => encode_sideband(buf, 1, data)?, // sideband, 1 = data
+            Self::SidebandMsg(msg) => encode_sideband(buf, 2, msg)?,    // sideband, 2 = msg
             Self::Flush => buf.extend_from_slice(b"0000"),
             Self::Delimiter => buf.extend_from_slice(b"0001"),
             Self::ResponseEnd => buf.extend_from_slice(b"0002"),
@@ -52,6 +58,91 @@ impl PktLine<'_> {
     }
 }
 
+/// Writes `data` to the given sideband, split across as many packets as it takes to keep
+/// each within the pkt-line size limit.
+fn encode_sideband(buf: &mut BytesMut, band: u8, data: &[u8]) -> Result<(), anyhow::Error> {
+    for chunk in data.chunks(MAX_SIDEBAND_DATA) {
+        write!(buf, "{:04x}", chunk.len() + 4 + 1)?;
+        buf.put_u8(band);
+        buf.extend_from_slice(chunk);
+    }
+
+    Ok(())
+}
+
+/// Sends `packfile` to the client as a series of sideband data packets, each handed to `write`
+/// as soon as it's full so the caller can send it on without waiting for the rest of the
+/// packfile to be encoded. If `progress` is set, the client is also sent messages over the
+/// sideband as each object is compressed.
+pub fn write_packfile(
+    packfile: &PackFile<'_>,
+    progress: bool,
+    mut write: impl FnMut(PktLine<'_>) -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    let mut pending = BytesMut::new();
+    let mut compressing = Progress::new("Compressing objects");
+
+    packfile.encode_with(|data, written| {
+        pending.extend_from_slice(data);
+
+        while pending.len() >= MAX_SIDEBAND_DATA {
+            let chunk = pending.split_to(MAX_SIDEBAND_DATA);
+            write(PktLine::SidebandData(&chunk))?;
+        }
+
+        if progress && written > 0 {
+            if let Some(msg) = compressing.update(written, packfile.len()) {
+                write(PktLine::SidebandMsg(msg.as_bytes()))?;
+            }
+        }
+
+        Ok(())
+    })?;
+
+    if !pending.is_empty() {
+        write(PktLine::SidebandData(&pending))?;
+    }
+
+    Ok(())
+}
+
+/// Tracks how far along a stage of building a packfile is, formatting it into messages the
+/// same way git does so the client can render them like it would for any other remote.
+pub struct Progress {
+    title: &'static str,
+    last_percent: Option<usize>,
+}
+
+impl Progress {
+    #[must_use]
+    pub fn new(title: &'static str) -> Self {
+        Self {
+            title,
+            last_percent: None,
+        }
+    }
+
+    /// Returns a message to send to the client for `n` out of `total`, or `None` if nothing's
+    /// changed enough since the last message to be worth sending.
+    pub fn update(&mut self, n: usize, total: usize) -> Option<String> {
+        let percent = if total == 0 { 100 } else { n * 100 / total };
+
+        if self.last_percent == Some(percent) {
+            return None;
+        }
+        self.last_percent = Some(percent);
+
+        if n >= total {
+            Some(format!("{}: 100% ({}/{}), done.\n", self.title, n, total))
+        } else {
+            Some(format!(
+                "{}: {:>3}% ({}/{})\r",
+                self.title, percent, n, total
+            ))
+        }
+    }
+}
+
 impl<'a> From<&'a str> for PktLine<'a> {
     fn from(val: &'a str) -> Self {
         PktLine::Data(val.as_bytes())
@@ -60,7 +151,146 @@ impl<'a> From<&'a str> for PktLine<'a> {
 
 #[cfg(test)]
 mod test {
+    use super::{
+        packfile::{ObjectFormat, PackFileEntry},
+        write_packfile, PackFile, Progress, MAX_SIDEBAND_DATA,
+    };
     use bytes::BytesMut;
+    use flate2::Compression;
+    use sha1::{Digest, Sha1};
+    use std::io::Read;
+
+    #[test]
+    fn progress() {
+        let mut progress = Progress::new("Compressing objects");
+        assert_eq!(
+            progress.update(1, 3).as_deref(),
+            Some("Compressing objects:  33% (1/3)\r")
+        );
+        assert_eq!(
+            progress.update(3, 3).as_deref(),
+            Some("Compressing objects: 100% (3/3), done.\n")
+        );
+
+        let mut progress = Progress::new("Compressing objects");
+        assert!(progress.update(1, 300).is_some());
+        assert!(progress.update(2, 300).is_none());
+        assert!(progress.update(3, 300).is_some());
+    }
+
+    #[test]
+    fn packfile_reports_progress() {
+        let packfile = PackFile::new(
+            vec![PackFileEntry::Blob(b"a"), PackFileEntry::Blob(b"b")],
+            ObjectFormat::Sha1,
+        );
+
+        let mut buffer = BytesMut::new();
+        write_packfile(&packfile, true, |line| line.encode_to(&mut buffer)).unwrap();
+
+        assert!(buffer.starts_with(b"0025\x02Compressing objects:  50% (1/2)\r"));
+        let done = b"002c\x02Compressing objects: 100% (2/2), done.\n";
+        assert_eq!(&buffer[0x25..0x25 + done.len()], done);
+        assert_eq!(buffer[0x25 + done.len() + 4], 1);
+    }
+
+    #[test]
+    fn packfile_is_split_across_sideband_packets() {
+        // random-ish data so the blob doesn't compress down to under a single packet
+        let blob: Vec<u8> = (0..200_000_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13).to_le_bytes()[0])
+            .collect();
+        let packfile = PackFile::new(vec![PackFileEntry::Blob(&blob)], ObjectFormat::Sha1);
+
+        let mut expected = BytesMut::new();
+        packfile.encode_to(&mut expected).unwrap();
+        assert!(expected.len() > MAX_SIDEBAND_DATA);
+
+        let mut buffer = BytesMut::new();
+        let mut packets = 0;
+        write_packfile(&packfile, false, |line| {
+            packets += 1;
+            line.encode_to(&mut buffer)
+        })
+        .unwrap();
+        assert_eq!(
+            packets,
+            (expected.len() + MAX_SIDEBAND_DATA - 1) / MAX_SIDEBAND_DATA
+        );
+
+        // strip the pkt-line framing back off and we should be left with the packfile
+        let mut rest = &buffer[..];
+        let mut reassembled = Vec::new();
+        while !rest.is_empty() {
+            let len = usize::from_str_radix(std::str::from_utf8(&rest[..4]).unwrap(), 16).unwrap();
+            assert!(len <= 65520);
+            assert_eq!(rest[4], 1);
+            reassembled.extend_from_slice(&rest[5..len]);
+            rest = &rest[len..];
+        }
+        assert_eq!(reassembled, expected.as_ref());
+    }
+
+    #[test]
+    fn packfile_is_valid_at_every_compression_level() {
+        let large = vec![b'a'; 4096];
+        let objects: [&[u8]; 2] = [b"hello", &large];
+
+        for level in 0..=9 {
+            let packfile = PackFile::new(
+                objects.iter().copied().map(PackFileEntry::Blob).collect(),
+                ObjectFormat::Sha1,
+            )
+            .with_compression(Compression::new(level));
+
+            let mut buffer = BytesMut::new();
+            packfile.encode_to(&mut buffer).unwrap();
+
+            let (body, footer) = buffer.split_at(buffer.len() - packfile.footer_size());
+            assert_eq!(Sha1::digest(body).as_slice(), footer);
+            assert_eq!(&body[..12], b"PACK\0\0\0\x02\0\0\0\x02");
+
+            // each object is its header followed by a zlib stream of its contents
+            let mut rest = &body[12..];
+            for expected in objects {
+                while rest[0] & 0b1000_0000 != 0 {
+                    rest = &rest[1..];
+                }
+                rest = &rest[1..];
+
+                let mut data = Vec::new();
+                flate2::bufread::ZlibDecoder::new(&mut rest)
+                    .read_to_end(&mut data)
+                    .unwrap();
+                assert_eq!(data, expected, "level {}", level);
+            }
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[test]
+    fn oversized_sideband_data_is_split() {
+        let data = vec![b'a'; MAX_SIDEBAND_DATA * 2 + 10];
+
+        let mut buffer = BytesMut::new();
+        super::PktLine::SidebandData(&data)
+            .encode_to(&mut buffer)
+            .unwrap();
+
+        let mut rest = &buffer[..];
+        let mut lengths = Vec::new();
+        while !rest.is_empty() {
+            let len = usize::from_str_radix(std::str::from_utf8(&rest[..4]).unwrap(), 16).unwrap();
+            assert_eq!(rest[4], 1);
+            lengths.push(len - 5);
+            rest = &rest[len..];
+        }
+        assert_eq!(lengths, [MAX_SIDEBAND_DATA, MAX_SIDEBAND_DATA, 10]);
+
+        assert!(super::PktLine::Data(&data)
+            .encode_to(&mut BytesMut::new())
+            .is_err());
+    }
 
     #[test]
     fn test_pkt_line() {
@@ -1,10 +1,107 @@
 use bytes::{BufMut, BytesMut};
 use flate2::{write::ZlibEncoder, Compression};
-use sha1::{
-    digest::{generic_array::GenericArray, FixedOutputDirty},
-    Digest, Sha1,
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::{
+    collections::VecDeque, convert::TryInto, fmt::Write, io::Write as IoWrite, str::FromStr,
 };
-use std::{convert::TryInto, fmt::Write, io::Write as IoWrite};
+
+use super::delta;
+
+/// How many of the most recently written blobs each blob is compared against when looking for
+/// a base to delta it against.
+const DELTA_WINDOW: usize = 10;
+
+/// Blobs outside of this range of sizes are always written whole. Small ones have too little
+/// to gain and large ones take too long to compare.
+const DELTA_MIN_SIZE: usize = 64;
+const DELTA_MAX_SIZE: usize = 1024 * 1024;
+
+/// Longest chain of deltas the client will have to follow to get back to a whole blob, as
+/// each link is another delta it has to apply.
+const MAX_DELTA_DEPTH: usize = 50;
+
+/// Hash function objects are named by, negotiated with the client through the `object-format`
+/// capability. Everything is SHA-1 unless the client asks otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObjectFormat {
+    Sha1,
+    Sha256,
+}
+
+impl Default for ObjectFormat {
+    fn default() -> Self {
+        Self::Sha1
+    }
+}
+
+impl ObjectFormat {
+    /// Name of the format as it's given in the `object-format` capability.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+        }
+    }
+
+    /// Length in bytes of an object id in this format.
+    #[must_use]
+    pub const fn hash_len(self) -> usize {
+        match self {
+            Self::Sha1 => 20,
+            Self::Sha256 => 32,
+        }
+    }
+
+    /// Hashes `data` in one go.
+    #[must_use]
+    pub fn digest(self, data: &[u8]) -> Vec<u8> {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    fn hasher(self) -> Hasher {
+        match self {
+            Self::Sha1 => Hasher::Sha1(Sha1::new()),
+            Self::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+}
+
+impl FromStr for ObjectFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha1" => Ok(Self::Sha1),
+            "sha256" => Ok(Self::Sha256),
+            _ => Err(format!("unsupported object format `{}`", s)),
+        }
+    }
+}
+
+enum Hasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha1(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::Sha1(hasher) => hasher.finalize().to_vec(),
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
 
 // The packfile itself is a very simple format. There is a header, a
 // series of packed objects (each with it's own header and body) and
@@ -14,12 +111,46 @@ use std::{convert::TryInto, fmt::Write, io::Write as IoWrite};
 // number and then a 4-byte number of entries in that file.
 pub struct PackFile<'a> {
     entries: Vec<PackFileEntry<'a>>,
+    object_format: ObjectFormat,
+    compression: Compression,
+    ofs_delta: bool,
 }
 
 impl<'a> PackFile<'a> {
     #[must_use]
-    pub fn new(entries: Vec<PackFileEntry<'a>>) -> Self {
-        Self { entries }
+    pub fn new(entries: Vec<PackFileEntry<'a>>, object_format: ObjectFormat) -> Self {
+        Self {
+            entries,
+            object_format,
+            compression: Compression::default(),
+            ofs_delta: false,
+        }
+    }
+
+    /// Allows blobs to be written as deltas against a similar blob earlier in the packfile,
+    /// which the client has to have asked for with the `ofs-delta` fetch argument.
+    #[must_use]
+    pub fn with_ofs_delta(mut self, ofs_delta: bool) -> Self {
+        self.ofs_delta = ofs_delta;
+        self
+    }
+
+    /// Sets the zlib level each object is deflated with, from 0 (stored as-is) to 9. Higher
+    /// levels make for a smaller packfile at the cost of the CPU time spent compressing it.
+    #[must_use]
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
     }
 
     #[must_use]
@@ -28,36 +159,196 @@ impl<'a> PackFile<'a> {
     }
 
     #[must_use]
-    pub const fn footer_size() -> usize {
-        20
+    pub const fn footer_size(&self) -> usize {
+        self.object_format.hash_len()
     }
 
-    pub fn encode_to(&self, original_buf: &mut BytesMut) -> Result<(), anyhow::Error> {
-        let mut buf = original_buf.split_off(original_buf.len());
-        buf.reserve(Self::header_size() + Self::footer_size());
+    pub fn encode_to(&self, buf: &mut BytesMut) -> Result<(), anyhow::Error> {
+        buf.reserve(Self::header_size() + self.footer_size());
+
+        self.encode_with(|data, _| {
+            buf.extend_from_slice(data);
+            Ok(())
+        })
+    }
+
+    /// Encodes the packfile a piece at a time so the whole thing never has to be held in
+    /// memory at once. `write` is called with the header, each entry in turn and then the
+    /// footer, along with the amount of entries that have been written so far.
+    pub fn encode_with(
+        &self,
+        mut write: impl FnMut(&[u8], usize) -> Result<(), anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
+        // the footer is a checksum of everything before it
+        let mut hasher = self.object_format.hasher();
+        let mut buf = BytesMut::with_capacity(Self::header_size());
 
         // header
         buf.extend_from_slice(b"PACK"); // magic header
         buf.put_u32(2); // version
         buf.put_u32(self.entries.len().try_into()?); // number of entries in the packfile
 
+        hasher.update(&buf);
+        write(&buf, 0)?;
+
+        // where in the packfile the next entry starts, deltas refer to their base by offset
+        let mut offset = buf.len();
+        // blobs that could be used as a base for a delta
+        let mut delta_bases: VecDeque<DeltaBase<'_>> = VecDeque::with_capacity(DELTA_WINDOW);
+
         // body
-        for entry in &self.entries {
-            entry.encode_to(&mut buf)?;
+        for (i, entry) in self.entries.iter().enumerate() {
+            buf.clear();
+
+            match entry {
+                PackFileEntry::Blob(data)
+                    if self.ofs_delta
+                        && (DELTA_MIN_SIZE..=DELTA_MAX_SIZE).contains(&data.len()) =>
+                {
+                    let depth = match best_delta(&delta_bases, data) {
+                        Some((base, delta)) => {
+                            encode_ofs_delta(
+                                &mut buf,
+                                offset - base.offset,
+                                &delta,
+                                self.compression,
+                            )?;
+                            base.depth + 1
+                        }
+                        None => {
+                            entry.encode_to(&mut buf, self.compression)?;
+                            0
+                        }
+                    };
+
+                    if delta_bases.len() == DELTA_WINDOW {
+                        delta_bases.pop_front();
+                    }
+                    delta_bases.push_back(DeltaBase {
+                        offset,
+                        data: *data,
+                        depth,
+                    });
+                }
+                _ => entry.encode_to(&mut buf, self.compression)?,
+            }
+
+            offset += buf.len();
+
+            hasher.update(&buf);
+            write(&buf, i + 1)?;
         }
 
         // footer
-        buf.extend_from_slice(&sha1::Sha1::digest(&buf[..]));
+        write(&hasher.finalize(), self.entries.len())
+    }
+}
 
-        original_buf.unsplit(buf);
+/// A blob already written to the packfile that later blobs can be written as a delta against.
+struct DeltaBase<'a> {
+    /// Where in the packfile the blob starts.
+    offset: usize,
+    data: &'a [u8],
+    /// How many deltas deep the blob itself is, 0 if it was written whole.
+    depth: usize,
+}
 
-        Ok(())
+/// Finds the base that gives the smallest delta for `data`, if any of them make a delta that's
+/// worth sending over the whole blob.
+fn best_delta<'a, 'b>(
+    bases: &'a VecDeque<DeltaBase<'b>>,
+    data: &[u8],
+) -> Option<(&'a DeltaBase<'b>, Vec<u8>)> {
+    bases
+        .iter()
+        .filter(|base| base.depth < MAX_DELTA_DEPTH)
+        .map(|base| (base, delta::encode(base.data, data)))
+        .filter(|(_, delta)| delta.len() < data.len() / 2)
+        .min_by_key(|(_, delta)| delta.len())
+}
+
+/// Writes an `OBJ_OFS_DELTA` entry, `distance` being how many bytes back from the start of
+/// this entry its base starts.
+fn encode_ofs_delta(
+    out: &mut BytesMut,
+    distance: usize,
+    delta: &[u8],
+    compression: Compression,
+) -> Result<(), anyhow::Error> {
+    write_object_header(out, 0b110, delta.len());
+
+    // big-endian base 128, where every byte but the last has one added to it before it's
+    // shifted away so that no two encodings give the same distance
+    let mut encoded = [0_u8; 10];
+    let mut pos = encoded.len() - 1;
+    let mut distance = distance;
+
+    #[allow(clippy::cast_possible_truncation)] // values are masked
+    {
+        encoded[pos] = (distance & 0b111_1111) as u8;
+        distance >>= 7;
+
+        while distance != 0 {
+            distance -= 1;
+            pos -= 1;
+            encoded[pos] = 0b1000_0000 | (distance & 0b111_1111) as u8;
+            distance >>= 7;
+        }
+    }
+
+    out.extend_from_slice(&encoded[pos..]);
+
+    let mut e = ZlibEncoder::new(Vec::new(), compression);
+    e.write_all(delta)?;
+    out.extend_from_slice(&e.finish()?);
+
+    Ok(())
+}
+
+/// Writes the type and uncompressed size that starts every entry in the packfile.
+fn write_object_header(buf: &mut BytesMut, kind: u8, size: usize) {
+    let mut size = size;
+
+    // write header
+    {
+        let mut val = kind << 4;
+
+        // pack the 4 LSBs of the size into the header
+        #[allow(clippy::cast_possible_truncation)] // value is masked
+        {
+            val |= (size & 0b1111) as u8;
+        }
+        size >>= 4;
+
+        // objects under 16 bytes fit entirely in the first byte, anything bigger has
+        // the MSB set to say more size bytes follow
+        if size != 0 {
+            val |= 1 << 7;
+        }
+
+        buf.put_u8(val);
+    }
+
+    // write size bytes
+    while size != 0 {
+        // read 7 LSBs from the `size` and push them off for the next iteration
+        #[allow(clippy::cast_possible_truncation)] // value is masked
+        let mut val = (size & 0b111_1111) as u8;
+        size >>= 7;
+
+        if size != 0 {
+            // MSB set to 1 implies there's more size bytes to come, otherwise
+            // the data starts after this byte
+            val |= 1 << 7;
+        }
+
+        buf.put_u8(val);
     }
 }
 
 #[derive(Debug)]
 pub struct Commit<'a> {
-    pub tree: GenericArray<u8, <Sha1 as FixedOutputDirty>::OutputSize>, // [u8; 20], but sha-1 returns a GenericArray
+    pub tree: Vec<u8>,
     // pub parent: [u8; 20],
     pub author: CommitUserInfo<'a>,
     pub committer: CommitUserInfo<'a>,
@@ -67,12 +358,7 @@ pub struct Commit<'a> {
 
 impl Commit<'_> {
     fn encode_to(&self, out: &mut BytesMut) -> Result<(), anyhow::Error> {
-        let mut tree_hex = [0_u8; 20 * 2];
-        hex::encode_to_slice(self.tree, &mut tree_hex)?;
-
-        out.write_str("tree ")?;
-        out.extend_from_slice(&tree_hex);
-        out.write_char('\n')?;
+        writeln!(out, "tree {}", hex::encode(&self.tree))?;
 
         writeln!(out, "author {}", self.author.encode())?;
         writeln!(out, "committer {}", self.committer.encode())?;
@@ -143,7 +429,7 @@ impl TreeItemKind {
 pub struct TreeItem<'a> {
     pub kind: TreeItemKind,
     pub name: &'a str,
-    pub hash: GenericArray<u8, <Sha1 as FixedOutputDirty>::OutputSize>, // [u8; 20] - but we have to deal with GenericArrays
+    pub hash: Vec<u8>,
 }
 
 // `[mode] [name]\0[hash]`
@@ -201,49 +487,23 @@ pub enum PackFileEntry<'a> {
 
 impl PackFileEntry<'_> {
     fn write_header(&self, buf: &mut BytesMut) {
-        let mut size = self.uncompressed_size();
-
-        // write header
-        {
-            let mut val = 0b1000_0000_u8;
-
-            val |= match self {
-                Self::Commit(_) => 0b001,
-                Self::Tree(_) => 0b010,
-                Self::Blob(_) => 0b011,
-                // Self::Tag => 0b100,
-                // Self::OfsDelta => 0b110,
-                // Self::RefDelta => 0b111,
-            } << 4;
-
-            // pack the 4 LSBs of the size into the header
-            #[allow(clippy::cast_possible_truncation)] // value is masked
-            {
-                val |= (size & 0b1111) as u8;
-            }
-            size >>= 4;
-
-            buf.put_u8(val);
-        }
-
-        // write size bytes
-        while size != 0 {
-            // read 7 LSBs from the `size` and push them off for the next iteration
-            #[allow(clippy::cast_possible_truncation)] // value is masked
-            let mut val = (size & 0b111_1111) as u8;
-            size >>= 7;
-
-            if size != 0 {
-                // MSB set to 1 implies there's more size bytes to come, otherwise
-                // the data starts after this byte
-                val |= 1 << 7;
-            }
+        let kind = match self {
+            Self::Commit(_) => 0b001,
+            Self::Tree(_) => 0b010,
+            Self::Blob(_) => 0b011,
+            // Self::Tag => 0b100,
+            // Self::OfsDelta => 0b110,
+            // Self::RefDelta => 0b111,
+        };
 
-            buf.put_u8(val);
-        }
+        write_object_header(buf, kind, self.uncompressed_size());
     }
 
-    pub fn encode_to(&self, original_out: &mut BytesMut) -> Result<(), anyhow::Error> {
+    pub fn encode_to(
+        &self,
+        original_out: &mut BytesMut,
+        compression: Compression,
+    ) -> Result<(), anyhow::Error> {
         self.write_header(original_out); // TODO: this needs space reserving for it
 
         // todo is there a way to stream through the zlibencoder so we don't have to
@@ -271,7 +531,7 @@ impl PackFileEntry<'_> {
 
         debug_assert_eq!(out.len(), size);
 
-        let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
+        let mut e = ZlibEncoder::new(Vec::new(), compression);
         e.write_all(&out)?;
         let compressed_data = e.finish()?;
 
@@ -289,10 +549,8 @@ impl PackFileEntry<'_> {
         }
     }
 
-    // wen const generics for RustCrypto? :-(
-    pub fn hash(
-        &self,
-    ) -> Result<GenericArray<u8, <Sha1 as FixedOutputDirty>::OutputSize>, anyhow::Error> {
+    /// Id of the object in the given format, the hash of its type, size and contents.
+    pub fn hash(&self, object_format: ObjectFormat) -> Result<Vec<u8>, anyhow::Error> {
         let size = self.uncompressed_size();
 
         let file_prefix = match self {
@@ -321,6 +579,6 @@ impl PackFileEntry<'_> {
             }
         }
 
-        Ok(sha1::Sha1::digest(&out))
+        Ok(object_format.digest(&out))
     }
 }
@@ -0,0 +1,79 @@
+use crate::git::packfile::ObjectFormat;
+
+use std::{collections::HashMap, sync::Mutex};
+
+/// Caches the HEAD commit hash of each user's view of an organisation's index, so `ls-refs`
+/// can be answered without having to build the whole index.
+///
+/// Entries are tagged with the organisation's `index_generation`, which chartered-db bumps
+/// whenever anything that could change the index is modified (publishes, yanks, permission
+/// changes), and the session key embedded in the index's `config.json`. A lookup only hits if
+/// both still match. Each object format gets its own entry, as the commit hash differs
+/// between them.
+#[derive(Default)]
+pub struct HeadCache(Mutex<HashMap<(i32, String, ObjectFormat), CachedHead>>);
+
+struct CachedHead {
+    generation: i32,
+    session_key: String,
+    commit_hash: String,
+}
+
+impl HeadCache {
+    pub fn get(
+        &self,
+        user_id: i32,
+        org_name: &str,
+        object_format: ObjectFormat,
+        generation: i32,
+        session_key: &str,
+    ) -> Option<String> {
+        let cache = self.0.lock().unwrap();
+
+        cache
+            .get(&(user_id, org_name.to_string(), object_format))
+            .filter(|v| v.generation == generation && v.session_key == session_key)
+            .map(|v| v.commit_hash.clone())
+    }
+
+    pub fn insert(
+        &self,
+        user_id: i32,
+        org_name: String,
+        object_format: ObjectFormat,
+        generation: i32,
+        session_key: String,
+        commit_hash: String,
+    ) {
+        self.0.lock().unwrap().insert(
+            (user_id, org_name, object_format),
+            CachedHead {
+                generation,
+                session_key,
+                commit_hash,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HeadCache;
+    use crate::git::packfile::ObjectFormat::{Sha1, Sha256};
+
+    #[test]
+    fn stale_generation_misses() {
+        let cache = HeadCache::default();
+        cache.insert(1, "core".into(), Sha1, 1, "key".into(), "abcdef".into());
+
+        assert_eq!(
+            cache.get(1, "core", Sha1, 1, "key").as_deref(),
+            Some("abcdef")
+        );
+        assert_eq!(cache.get(1, "core", Sha1, 2, "key"), None);
+        assert_eq!(cache.get(1, "core", Sha1, 1, "other-key"), None);
+        assert_eq!(cache.get(2, "core", Sha1, 1, "key"), None);
+        assert_eq!(cache.get(1, "other-org", Sha1, 1, "key"), None);
+        assert_eq!(cache.get(1, "core", Sha256, 1, "key"), None);
+    }
+}
@@ -0,0 +1,62 @@
+use crate::config::HostKeyAlgorithm;
+
+use anyhow::Context;
+use log::warn;
+use std::{fs::OpenOptions, io::Write, os::unix::fs::OpenOptionsExt, path::Path};
+use thrussh_keys::key::{KeyPair, SignatureHash};
+
+/// Size of newly generated RSA host keys, in bits.
+const RSA_KEY_BITS: usize = 3072;
+
+/// Loads one of the server's host keys from `path`, accepting OpenSSH, PKCS#8 and (for RSA)
+/// PKCS#1 formatted private keys. If the file doesn't exist yet a new key is generated for
+/// `algorithm` and written there, as PKCS#8, so clients see the same host key across restarts.
+pub fn load_or_generate(
+    algorithm: HostKeyAlgorithm,
+    path: &Path,
+) -> Result<KeyPair, anyhow::Error> {
+    if path.exists() {
+        let key = thrussh_keys::load_secret_key(path, None)
+            .with_context(|| format!("failed to load host key from {}", path.display()))?;
+
+        if !matches!(
+            (algorithm, &key),
+            (HostKeyAlgorithm::Ed25519, KeyPair::Ed25519(_))
+                | (HostKeyAlgorithm::Rsa, KeyPair::RSA { .. })
+        ) {
+            anyhow::bail!(
+                "expected an {} host key in {} but found {}",
+                algorithm,
+                path.display(),
+                key.name()
+            );
+        }
+
+        return Ok(key);
+    }
+
+    warn!(
+        "No {} host key found at {}, generating a new one",
+        algorithm,
+        path.display()
+    );
+
+    let key = match algorithm {
+        HostKeyAlgorithm::Ed25519 => KeyPair::generate_ed25519(),
+        HostKeyAlgorithm::Rsa => KeyPair::generate_rsa(RSA_KEY_BITS, SignatureHash::SHA2_256),
+    }
+    .with_context(|| format!("failed to generate {} host key", algorithm))?;
+
+    let mut encoded = Vec::new();
+    thrussh_keys::encode_pkcs8_pem(&key, &mut encoded).context("failed to encode host key")?;
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(&encoded))
+        .with_context(|| format!("failed to write host key to {}", path.display()))?;
+
+    Ok(key)
+}
@@ -1,18 +1,35 @@
 #![deny(clippy::pedantic)]
+mod auth_limiter;
+mod commit_message;
+mod config;
 #[allow(clippy::missing_errors_doc)]
 pub mod git;
-
+mod head_cache;
+mod host_key;
+mod selftest;
+mod shutdown;
+
+use crate::auth_limiter::AuthLimiter;
+use crate::commit_message::CommitMessageValues;
+use crate::config::{Config, EmptyIndex};
 use crate::git::{
-    codec::{Encoder, GitCodec},
-    packfile::{Commit, CommitUserInfo, PackFileEntry, TreeItem, TreeItemKind},
+    codec::{DecodeError, Encoder, GitCodec},
+    packfile::{Commit, CommitUserInfo, ObjectFormat, PackFileEntry, TreeItem, TreeItemKind},
     PktLine,
 };
+use crate::head_cache::HeadCache;
+use crate::shutdown::ActiveConnections;
 
 use bytes::BytesMut;
+use chartered_db::{
+    auth::{Authenticated, AuthenticatorKind, Authenticators, Credential},
+    users::Membership,
+};
+use chartered_types::cargo::RegistryConfig;
 use chrono::TimeZone;
 use futures::future::Future;
-use log::warn;
-use std::collections::BTreeMap;
+use log::{debug, error, info, trace, warn};
+use std::collections::{BTreeMap, HashMap};
 use std::{fmt::Write, pin::Pin, sync::Arc};
 use thrussh::{
     server::{self, Auth, Session},
@@ -26,24 +43,161 @@ use tokio_util::codec::{Decoder, Encoder as TokioEncoder};
 async fn main() {
     env_logger::init();
 
-    let config = Arc::new(thrussh::server::Config {
+    // checked before anything else so a mistyped flag isn't hidden behind config errors
+    let selftest_args = match selftest::Args::parse(std::env::args().skip(1)) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let config = Arc::new(Config::from_env().unwrap());
+
+    if let Some(args) = selftest_args {
+        let db = chartered_db::init(&chartered_db::PoolConfig::from_env().unwrap()).unwrap();
+
+        if let Err(e) = selftest::run(&args, &config, db).await {
+            eprintln!("Self-test failed: {:?}", e);
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
+    // thrussh resets the timeout whenever anything is received from the client, so this only
+    // drops connections that have gone completely quiet
+    let ssh_config = Arc::new(thrussh::server::Config {
         methods: thrussh::MethodSet::PUBLICKEY,
-        keys: vec![key::KeyPair::generate_ed25519().unwrap()],
+        keys: config
+            .host_keys
+            .iter()
+            .map(|(algorithm, path)| host_key::load_or_generate(*algorithm, path).unwrap())
+            .collect(),
+        connection_timeout: Some(config.idle_timeout),
         ..thrussh::server::Config::default()
     });
 
-    let server = Server {
-        db: chartered_db::init().unwrap(),
+    let listener = tokio::net::TcpListener::bind(config.bind_address)
+        .await
+        .unwrap();
+
+    let mut server = Server {
+        db: chartered_db::init(&chartered_db::PoolConfig::from_env().unwrap()).unwrap(),
+        head_cache: Arc::new(HeadCache::default()),
+        authenticators: Arc::new(
+            Authenticators::from_env("CHARTERED_GIT_AUTHENTICATORS", &[AuthenticatorKind::SshKey])
+                .unwrap(),
+        ),
+        auth_limiter: Arc::new(AuthLimiter::new(
+            config.auth_attempt_window,
+            config.max_auth_attempts,
+        )),
+        config,
     };
 
-    thrussh::server::run(config, "127.0.0.1:2233", server)
+    let active_connections = Arc::new(ActiveConnections::default());
+    let max_connections = server.config.max_connections;
+    let connection_limit = Arc::new(tokio::sync::Semaphore::new(max_connections));
+    let shutdown = shutdown::signal();
+    tokio::pin!(shutdown);
+
+    // this is what `thrussh::server::run` does, but we want to know why connections end and
+    // to be able to stop accepting them on shutdown
+    loop {
+        let socket = tokio::select! {
+            res = listener.accept() => match res {
+                Ok((socket, _)) => socket,
+                // the listener itself isn't usable anymore, there's no waiting this one out
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+                    error!("Listener can no longer accept connections: {}", e);
+                    break;
+                }
+                Err(e) => {
+                    // these are almost always down to running out of file descriptors, which
+                    // free up again as connections close, so back off rather than spinning on
+                    // the error or taking every connection we already have down with us
+                    error!("Failed to accept connection: {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let ip = socket.peer_addr().ok();
+        let peer = ip.map_or_else(|| "unknown".to_string(), |v| v.to_string());
+
+        // each connection holds on to a file descriptor and, while fetching, a database
+        // connection, so past the limit we'd rather turn clients away than run out of either
+        let permit = if let Ok(permit) = connection_limit.clone().try_acquire_owned() {
+            permit
+        } else {
+            warn!(
+                "{} - rejecting connection, all {} connection slots are in use",
+                peer, max_connections
+            );
+            tokio::spawn(reject_connection(socket));
+            continue;
+        };
+
+        debug!(
+            "{} - accepted connection, {}/{} connection slots in use",
+            peer,
+            max_connections - connection_limit.available_permits(),
+            max_connections
+        );
+
+        let ssh_config = ssh_config.clone();
+        let idle_timeout = server.config.idle_timeout;
+        let handler = server::Server::new(&mut server, ip);
+        let connection = active_connections.track();
+
+        tokio::spawn(async move {
+            let _connection = connection;
+            let _permit = permit;
+            let start = std::time::Instant::now();
+
+            match thrussh::server::run_stream(ssh_config, socket, handler).await {
+                Ok(_) => info!("{} - connection closed after {:?}", peer, start.elapsed()),
+                Err(e) => match e.downcast_ref::<thrussh::Error>() {
+                    Some(thrussh::Error::ConnectionTimeout) => info!(
+                        "{} - connection dropped after {:?} of inactivity",
+                        peer, idle_timeout
+                    ),
+                    _ => info!("{} - connection closed with error: {}", peer, e),
+                },
+            }
+        });
+    }
+
+    drop(listener);
+
+    let grace_period = server.config.shutdown_grace_period;
+    info!(
+        "No longer accepting connections, waiting up to {:?} for {} active connections to finish",
+        grace_period,
+        active_connections.count()
+    );
+
+    if tokio::time::timeout(grace_period, active_connections.wait_idle())
         .await
-        .unwrap();
+        .is_err()
+    {
+        warn!(
+            "Shutting down with {} connections still active",
+            active_connections.count()
+        );
+    }
 }
 
 #[derive(Clone)]
 struct Server {
     db: chartered_db::ConnectionPool,
+    config: Arc<Config>,
+    head_cache: Arc<HeadCache>,
+    authenticators: Arc<Authenticators>,
+    auth_limiter: Arc<AuthLimiter>,
 }
 
 impl server::Server for Server {
@@ -52,13 +206,19 @@ impl server::Server for Server {
     fn new(&mut self, ip: Option<std::net::SocketAddr>) -> Self::Handler {
         Handler {
             ip,
-            codec: GitCodec::default(),
+            codec: GitCodec::new(self.config.max_command_bytes),
             input_bytes: BytesMut::default(),
             output_bytes: BytesMut::default(),
             db: self.db.clone(),
+            config: self.config.clone(),
+            head_cache: self.head_cache.clone(),
+            authenticators: self.authenticators.clone(),
+            auth_limiter: self.auth_limiter.clone(),
             user: None,
             user_ssh_key: None,
-            organisation: None,
+            session_key: None,
+            channels: HashMap::new(),
+            env: HashMap::new(),
         }
     }
 }
@@ -69,11 +229,39 @@ struct Handler {
     input_bytes: BytesMut,
     output_bytes: BytesMut,
     db: chartered_db::ConnectionPool,
+    config: Arc<Config>,
+    head_cache: Arc<HeadCache>,
+    authenticators: Arc<Authenticators>,
+    auth_limiter: Arc<AuthLimiter>,
     user: Option<chartered_db::users::User>,
     user_ssh_key: Option<Arc<chartered_db::users::UserSshKey>>,
-    organisation: Option<String>,
+    /// Session key put in the index's `config.json`, looked up on the first request of the
+    /// connection and reused for every request after it.
+    session_key: Option<String>,
+    /// State of each channel that's been opened for an organisation, so a single connection
+    /// can fetch several organisations' indices.
+    channels: HashMap<ChannelId, ChannelState>,
+    /// Environment variables sent by the client, limited to those in [`ACCEPTED_ENV`].
+    env: HashMap<&'static str, String>,
 }
 
+struct ChannelState {
+    /// Organisation the channel is fetching the index of, taken from the path given in its
+    /// exec request.
+    organisation: String,
+    /// When the exec request was received, for timing how long the whole clone takes.
+    opened_at: std::time::Instant,
+    /// Hash function objects are named by, agreed on before the capability advertisement.
+    object_format: ObjectFormat,
+}
+
+/// How long to stop accepting connections for after failing to accept one.
+const ACCEPT_ERROR_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Environment variables we'll hold on to when sent by the client, anything else is dropped
+/// so a client can't have us store an unbounded amount of them.
+const ACCEPTED_ENV: &[&str] = &["GIT_PROTOCOL"];
+
 impl Handler {
     fn write(&mut self, packet: PktLine<'_>) -> Result<(), anyhow::Error> {
         Encoder {}.encode(packet, &mut self.output_bytes)
@@ -93,19 +281,753 @@ impl Handler {
         }
     }
 
-    fn org_name(&self) -> Result<&str, anyhow::Error> {
-        match self.organisation {
-            Some(ref org) => Ok(org.as_str()),
-            None => anyhow::bail!("org not set after auth"),
-        }
-    }
-
     fn user_ssh_key(&self) -> Result<&Arc<chartered_db::users::UserSshKey>, anyhow::Error> {
         match self.user_ssh_key {
             Some(ref ssh_key) => Ok(ssh_key),
             None => anyhow::bail!("user not set after auth"),
         }
     }
+
+    fn org_name(&self, channel: ChannelId) -> Result<&str, anyhow::Error> {
+        match self.channels.get(&channel) {
+            Some(state) => Ok(state.organisation.as_str()),
+            None => anyhow::bail!("org not set for channel"),
+        }
+    }
+
+    fn object_format(&self, channel: ChannelId) -> Result<ObjectFormat, anyhow::Error> {
+        match self.channels.get(&channel) {
+            Some(state) => Ok(state.object_format),
+            None => anyhow::bail!("object format not set for channel"),
+        }
+    }
+
+    /// Looks up the organisation `name` on behalf of the authenticated user, giving back the
+    /// message to send to the client if it doesn't exist or they aren't a member of it.
+    async fn find_organisation(
+        &self,
+        name: &str,
+    ) -> Result<Result<chartered_db::users::Organisation, String>, anyhow::Error> {
+        match chartered_db::users::Organisation::membership(
+            self.db.clone(),
+            self.user()?.id,
+            name.to_string(),
+        )
+        .await?
+        {
+            Membership::Member(organisation) => Ok(Ok(organisation)),
+            Membership::NotMember => Ok(Err(format!(
+                "you are not a member of organisation `{}`",
+                name
+            ))),
+            Membership::Missing => Ok(Err("organisation does not exist".to_string())),
+        }
+    }
+
+    /// Fetches the session key for the user's SSH key, creating one if it doesn't already
+    /// have one, giving back the message to send to the client if it couldn't be fetched.
+    async fn lookup_session_key(&self) -> Result<Result<String, &'static str>, anyhow::Error> {
+        let user_session = tokio::time::timeout(
+            self.config.session_lookup_timeout,
+            self.user_ssh_key()?.clone().get_or_insert_session(
+                self.db.clone(),
+                self.config.session_key_bytes,
+                self.ip.map(|v| v.to_string()),
+            ),
+        )
+        .await;
+
+        match user_session {
+            Ok(Ok(user_session)) => Ok(Ok(user_session.session_key)),
+            Ok(Err(e)) => {
+                error!("Failed to fetch session key for user: {}", e);
+                Ok(Err("failed to fetch credentials for index"))
+            }
+            Err(_) => {
+                error!(
+                    "Timed out after {:?} fetching session key for user",
+                    self.config.session_lookup_timeout
+                );
+                Ok(Err("timed out fetching credentials for index"))
+            }
+        }
+    }
+
+    /// Sends `message` to the client's stderr and closes the channel with git's fatal exit
+    /// status, so the user gets a readable error rather than the connection just dropping.
+    fn fatal(&mut self, session: &mut Session, channel: ChannelId, message: &str) {
+        // anything already written needs to go out before the error
+        self.flush(session, channel);
+
+        session.extended_data(
+            channel,
+            1,
+            CryptoVec::from_slice(format!("\r\nfatal: {}\r\n", message).as_bytes()),
+        );
+        session.exit_status_request(channel, 128);
+        session.eof(channel);
+        session.close(channel);
+    }
+
+    /// Responds to an `object-info` command with the requested attributes of each object, any
+    /// object that isn't part of the index gets an empty value, as git itself does.
+    fn write_object_info(
+        &mut self,
+        pack_file_entries: &[PackFileEntry<'_>],
+        request: &ObjectInfoRequest,
+        object_format: ObjectFormat,
+    ) -> Result<(), anyhow::Error> {
+        let sizes = pack_file_entries
+            .iter()
+            .map(|entry| {
+                Ok((
+                    hex::encode(entry.hash(object_format)?),
+                    entry.uncompressed_size(),
+                ))
+            })
+            .collect::<Result<HashMap<_, _>, anyhow::Error>>()?;
+
+        if request.size {
+            self.write(PktLine::Data(b"size\n"))?;
+        }
+
+        for oid in &request.oids {
+            let mut line = oid.clone();
+
+            if request.size {
+                line.push(' ');
+                if let Some(size) = sizes.get(oid) {
+                    write!(line, "{}", size)?;
+                }
+            }
+
+            line.push('\n');
+            self.write(PktLine::Data(line.as_bytes()))?;
+        }
+
+        self.write(PktLine::Flush)
+    }
+
+    /// Writes everything a response to a `fetch` has ahead of the packfile itself, up to and
+    /// including the fetch message shown to the user.
+    fn write_fetch_preamble(
+        &mut self,
+        request: &FetchRequest,
+        shallow_info: &[String],
+        fetch_message: &str,
+    ) -> Result<(), anyhow::Error> {
+        if !request.done {
+            self.write(PktLine::Data(b"acknowledgments\n"))?;
+            self.write(PktLine::Data(b"ready\n"))?;
+            self.write(PktLine::Delimiter)?;
+        }
+
+        if !shallow_info.is_empty() {
+            self.write(PktLine::Data(b"shallow-info\n"))?;
+            for line in shallow_info {
+                self.write(PktLine::Data(line.as_bytes()))?;
+            }
+            self.write(PktLine::Delimiter)?;
+        }
+
+        self.write(PktLine::Data(b"packfile\n"))?;
+
+        for line in fetch_message.lines() {
+            self.write(PktLine::SidebandMsg(format!("{}\n", line).as_bytes()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Advertises the refs the client asked for, `commit_hash` is `None` if the index is being
+    /// advertised as an empty repository.
+    fn write_ls_refs(
+        &mut self,
+        commit_hash: Option<&str>,
+        request: &LsRefsRequest,
+    ) -> Result<(), anyhow::Error> {
+        for line in request.advertise(commit_hash) {
+            self.write(PktLine::Data(line.as_bytes()))?;
+        }
+
+        self.write(PktLine::Flush)
+    }
+
+    /// Reports an error hit while handling a request to the client as a git `fatal` and
+    /// closes the channel, rather than dropping the whole connection and leaving the client
+    /// with nothing more than "connection closed by remote".
+    fn fail(&mut self, session: &mut Session, channel: ChannelId, e: &anyhow::Error) {
+        error!("Failed to handle request from {:?}: {:?}", self.ip, e);
+        self.fatal(session, channel, &e.to_string());
+    }
+
+    async fn handle_exec(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+        command: &str,
+    ) -> Result<(), anyhow::Error> {
+        debug!("{:?} - requested to run `{}`", self.ip, command);
+
+        // a `git push` to the index, which isn't an error on our part so it's only worth a
+        // readable explanation rather than a log
+        if is_receive_pack(command) {
+            self.fatal(
+                session,
+                channel,
+                "the index is managed by chartered and can't be pushed to, crates are \
+                 published with `cargo publish` instead",
+            );
+            return Ok(());
+        }
+
+        let org = parse_upload_pack(command)?;
+
+        // protocol v2 is all we speak, a client expecting anything else would just choke
+        // on the capability advertisement
+        if !wants_protocol_v2(self.env.get("GIT_PROTOCOL").map(String::as_str)) {
+            self.fatal(
+                session,
+                channel,
+                "chartered only supports git protocol version 2, make sure cargo is set to \
+                 fetch using the git CLI (`net.git-fetch-with-cli = true`) and that git is \
+                 version 2.26 or newer",
+            );
+            return Ok(());
+        }
+
+        // the format is only negotiated through the capability advertisement below, the
+        // client echoes it back on every command
+        let object_format = self.config.object_format;
+
+        if let Some(org) = org {
+            // check up front so the client gets a clear error rather than an empty index
+            if let Err(message) = self.find_organisation(&org).await? {
+                self.fatal(session, channel, &message);
+                return Ok(());
+            }
+
+            self.channels.insert(
+                channel,
+                ChannelState {
+                    organisation: org,
+                    opened_at: std::time::Instant::now(),
+                    object_format,
+                },
+            );
+        } else {
+            session.extended_data(channel, 1, CryptoVec::from_slice(indoc::indoc! {b"
+                \r\nNo organisation was given in the path part of the SSH URI. A chartered registry should be defined in your .cargo/config.toml as follows:
+                    [registries]
+                    chartered = {{ index = \"ssh://domain.to.registry.com/my-organisation\" }}\r\n
+            "}));
+            session.close(channel);
+            return Ok(());
+        }
+
+        self.write(PktLine::Data(b"version 2\n"))?;
+        self.write(PktLine::Data(b"agent=chartered/0.1.0\n"))?;
+        self.write(PktLine::Data(b"ls-refs=unborn\n"))?;
+        self.write(PktLine::Data(b"fetch=shallow wait-for-done\n"))?;
+        self.write(PktLine::Data(b"server-option\n"))?;
+        self.write(PktLine::Data(b"object-info\n"))?;
+        self.write(PktLine::Data(
+            format!("object-format={}\n", object_format.name()).as_bytes(),
+        ))?;
+        self.write(PktLine::Flush)?;
+        self.flush(session, channel);
+
+        Ok(())
+    }
+
+    async fn handle_data(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), anyhow::Error> {
+        let mut ls_refs = None;
+        let mut object_info = None;
+        let mut fetch = None;
+        let object_format = self.object_format(channel)?;
+
+        loop {
+            let frame = match self.codec.decode(&mut self.input_bytes) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => match e.downcast_ref::<DecodeError>() {
+                    Some(decode_error) => {
+                        warn!("Dropping connection from {:?}: {}", self.ip, decode_error);
+                        self.input_bytes.clear();
+                        self.fatal(session, channel, &decode_error.to_string());
+                        return Ok(());
+                    }
+                    None => return Err(e),
+                },
+            };
+
+            // frames are the client's raw request, so they're only ever logged at trace
+            trace!("{:?} - received {:#?}", self.ip, frame);
+
+            // if the client flushed without giving us a command, we're expected to close
+            // the connection or else the client will just hang
+            if frame.command.is_empty() {
+                session.exit_status_request(channel, 0);
+                session.eof(channel);
+                session.close(channel);
+                return Ok(());
+            }
+
+            // the client echoes back the format we advertised, anything else means it's
+            // going to misread every object id we send it
+            if let Some(requested) = frame
+                .metadata
+                .iter()
+                .find_map(|arg| arg.strip_prefix(b"object-format="))
+            {
+                if requested != object_format.name().as_bytes() {
+                    self.fatal(
+                        session,
+                        channel,
+                        &format!(
+                            "client requested object format `{}` but `{}` was advertised",
+                            String::from_utf8_lossy(requested),
+                            object_format.name()
+                        ),
+                    );
+                    return Ok(());
+                }
+            }
+
+            if frame.command.as_ref() == "command=ls-refs".as_bytes() {
+                ls_refs = Some(LsRefsRequest::parse(&frame.metadata));
+            } else if frame.command.as_ref() == "command=object-info".as_bytes() {
+                object_info = Some(ObjectInfoRequest::parse(&frame.metadata));
+            } else if frame.command.as_ref() == "command=fetch".as_bytes() {
+                fetch = Some(FetchRequest::parse(&frame.metadata));
+            }
+        }
+
+        if ls_refs.is_none() && object_info.is_none() && fetch.is_none() {
+            return Ok(());
+        }
+
+        // echo -ne "0012command=fetch\n0001000ethin-pack\n0010include-tag\n000eofs-delta\n0032want d24d8020163b5fee57c9babfd0c595b8c90ba253\n0009done\n"
+
+        // membership is checked again as the user may have been removed from the
+        // organisation since the channel was opened
+        let organisation = match self.find_organisation(self.org_name(channel)?).await? {
+            Ok(v) => v,
+            Err(message) => {
+                self.fatal(session, channel, &message);
+                return Ok(());
+            }
+        };
+        let index_generation = organisation.index_generation;
+
+        let session_key = match self.session_key.clone() {
+            Some(session_key) => session_key,
+            None => match self.lookup_session_key().await? {
+                Ok(session_key) => {
+                    self.session_key = Some(session_key.clone());
+                    session_key
+                }
+                Err(message) => {
+                    self.fatal(session, channel, message);
+                    return Ok(());
+                }
+            },
+        };
+
+        // if the client only wants to know where HEAD is and nothing has changed since we
+        // last built this index, there's no need to build the whole thing again
+        if let (Some(ls_refs), None, None) = (&ls_refs, &object_info, &fetch) {
+            if let Some(commit_hash) = self.head_cache.get(
+                self.user()?.id,
+                self.org_name(channel)?,
+                object_format,
+                index_generation,
+                &session_key,
+            ) {
+                self.write_ls_refs(Some(&commit_hash), ls_refs)?;
+                self.flush(session, channel);
+                return Ok(());
+            }
+        }
+
+        let config = registry_config(&self.config, &session_key, self.org_name(channel)?)?;
+
+        // todo: the whole tree needs caching and then we can filter in code rather than at
+        //  the database
+        let (tree, summary) = fetch_tree(
+            self.db.clone(),
+            self.user()?.id,
+            self.org_name(channel)?.to_string(),
+            Some(organisation.id),
+        )
+        .await;
+
+        // nothing has been published to the organisation yet, if we've been configured to
+        // we'll tell the client the repository is empty rather than sending it an index
+        // with nothing but a `config.json` in it
+        if summary.crates == 0 && self.config.empty_index == EmptyIndex::Unborn {
+            if fetch.is_some() {
+                self.fatal(session, channel, "the index is empty");
+                return Ok(());
+            }
+
+            if let Some(object_info) = &object_info {
+                self.write_object_info(&[], object_info, object_format)?;
+            }
+
+            if let Some(ls_refs) = &ls_refs {
+                self.write_ls_refs(None, ls_refs)?;
+            }
+            self.flush(session, channel);
+            return Ok(());
+        }
+
+        let head = IndexHead::fetch(
+            self.db.clone(),
+            &self.config,
+            self.user()?.id,
+            self.org_name(channel)?,
+            &summary,
+        )
+        .await?;
+
+        let fetch_message = organisation
+            .fetch_message
+            .unwrap_or_else(|| self.config.fetch_message.clone());
+
+        // progress can only be sent once the packfile section has started. that can happen
+        // before the index is built as long as nothing ahead of it needs the commit, which is
+        // the case for any fetch but a shallow one, so the client sees the objects being
+        // counted as it happens. otherwise it's held on to and sent along with the packfile
+        let packfile_started = match &fetch {
+            Some(fetch) if ls_refs.is_none() && object_info.is_none() && !fetch.is_shallow() => {
+                self.write_fetch_preamble(fetch, &[], &fetch_message)?;
+                self.flush(session, channel);
+                true
+            }
+            _ => false,
+        };
+        let send_progress = fetch.as_ref().map_or(false, |fetch| !fetch.no_progress);
+
+        let mut counting_progress = Vec::new();
+        let (pack_file_entries, commit_hash) = {
+            let mut progress = git::Progress::new("Counting objects");
+            build_index(
+                &config,
+                &tree,
+                head.commit_user(),
+                &head.message,
+                object_format,
+                &mut |n, total| {
+                    if let Some(msg) = progress.update(n, total).filter(|_| send_progress) {
+                        if packfile_started {
+                            self.write(PktLine::SidebandMsg(msg.as_bytes()))?;
+                            self.flush(session, channel);
+                        } else {
+                            counting_progress.push(msg);
+                        }
+                    }
+
+                    Ok(())
+                },
+            )?
+        };
+
+        debug!(
+            "{:?} - built index for {} at {}",
+            self.ip,
+            self.org_name(channel)?,
+            commit_hash
+        );
+
+        self.head_cache.insert(
+            self.user()?.id,
+            self.org_name(channel)?.to_string(),
+            object_format,
+            index_generation,
+            session_key,
+            commit_hash.clone(),
+        );
+
+        // echo -ne "0014command=ls-refs\n0014agent=git/2.321\n00010009peel\n000csymrefs\n000bunborn\n0014ref-prefix HEAD\n0019ref-prefix refs/HEAD\n001eref-prefix refs/tags/HEAD\n001fref-prefix refs/heads/HEAD\n0021ref-prefix refs/remotes/HEAD\n0026ref-prefix refs/remotes/HEAD/HEAD\n001aref-prefix refs/tags/\n0000"
+        // GIT_PROTOCOL=version=2 ssh -o SendEnv=GIT_PROTOCOL git@github.com git-upload-pack '/w4/chartered.git'
+        // ''.join([('{:04x}'.format(len(v) + 5)), v, "\n"])
+        // echo -ne "0012command=fetch\n0001000ethin-pack\n0010no-progress\n0010include-tag\n000eofs-delta\n0032want f6046cf6372e0d8ab845f6dec1602c303a66ee91\n"
+        // sends a 000dpackfile back
+        // https://shafiul.github.io/gitbook/7_the_packfile.html
+        if let Some(ls_refs) = &ls_refs {
+            self.write_ls_refs(Some(&commit_hash), ls_refs)?;
+            self.flush(session, channel);
+        }
+
+        if let Some(object_info) = &object_info {
+            self.write_object_info(&pack_file_entries, object_info, object_format)?;
+            self.flush(session, channel);
+        }
+
+        if let Some(fetch) = &fetch {
+            if !packfile_started {
+                self.write_fetch_preamble(
+                    fetch,
+                    &fetch.shallow_info(&commit_hash),
+                    &fetch_message,
+                )?;
+
+                for msg in &counting_progress {
+                    self.write(PktLine::SidebandMsg(msg.as_bytes()))?;
+                }
+                self.flush(session, channel);
+            }
+
+            // hand the packfile over to the session as it's encoded rather than building
+            // the whole thing up in `output_bytes` first
+            let packfile = git::packfile::PackFile::new(pack_file_entries, object_format)
+                .with_compression(self.config.packfile_compression)
+                .with_ofs_delta(fetch.ofs_delta);
+            git::write_packfile(&packfile, !fetch.no_progress, |line| {
+                self.write(line)?;
+                if self.output_bytes.len() >= git::MAX_SIDEBAND_DATA {
+                    self.flush(session, channel);
+                }
+                Ok(())
+            })?;
+            self.write(PktLine::Flush)?;
+            self.flush(session, channel);
+
+            if let Some(state) = self.channels.get(&channel) {
+                let elapsed = state.opened_at.elapsed();
+
+                if elapsed >= self.config.slow_clone_threshold {
+                    warn!(
+                        "{:?} - slow clone of {} with {} crates took {:?}",
+                        self.ip, state.organisation, summary.crates, elapsed
+                    );
+                }
+            }
+
+            session.exit_status_request(channel, 0);
+            session.eof(channel);
+            session.close(channel);
+        }
+
+        Ok(())
+    }
+}
+
+/// Turns away a connection we don't have room for. SSH allows the server to send lines of
+/// text ahead of its version string, which clients show to the user, so this is sent as one
+/// rather than the connection just being dropped.
+async fn reject_connection(mut socket: tokio::net::TcpStream) {
+    use tokio::io::AsyncWriteExt;
+
+    let _ = socket
+        .write_all(b"chartered: too many connections, try again later\r\n")
+        .await;
+    let _ = socket.shutdown().await;
+}
+
+/// Parses the command given by an exec or subsystem request, which must be a
+/// `git-upload-pack`, returning the organisation named in its path if there is one.
+fn parse_upload_pack(command: &str) -> Result<Option<String>, anyhow::Error> {
+    let mut args = shlex::split(command).into_iter().flatten();
+
+    if args.next().as_deref() != Some("git-upload-pack") {
+        anyhow::bail!("not git-upload-pack");
+    }
+
+    Ok(args
+        .next()
+        .map(|org| {
+            org.trim_start_matches('/')
+                .trim_end_matches('/')
+                .to_string()
+        })
+        .filter(|org| !org.is_empty()))
+}
+
+/// Whether the command given by an exec or subsystem request is a `git-receive-pack`, which is
+/// what git runs on the remote end of a push.
+fn is_receive_pack(command: &str) -> bool {
+    shlex::split(command)
+        .and_then(|args| args.into_iter().next())
+        .map_or(false, |v| v == "git-receive-pack")
+}
+
+/// Arguments given to an `ls-refs` command.
+#[derive(Default, Debug, PartialEq, Eq)]
+struct LsRefsRequest {
+    symrefs: bool,
+    unborn: bool,
+    ref_prefixes: Vec<String>,
+}
+
+impl LsRefsRequest {
+    fn parse(metadata: &[bytes::Bytes]) -> Self {
+        let mut request = Self::default();
+
+        for arg in metadata {
+            match arg.as_ref() {
+                b"symrefs" => request.symrefs = true,
+                // there are no tags in the index, so there's never anything to peel
+                b"peel" => {}
+                b"unborn" => request.unborn = true,
+                arg => {
+                    if let Some(prefix) = arg.strip_prefix(b"ref-prefix ") {
+                        request
+                            .ref_prefixes
+                            .push(String::from_utf8_lossy(prefix).into_owned());
+                    }
+                }
+            }
+        }
+
+        request
+    }
+
+    /// Refs are only sent back if they match one of the prefixes the client asked for, or
+    /// if the client didn't give any prefixes at all.
+    fn wants(&self, name: &str) -> bool {
+        self.ref_prefixes.is_empty() || self.ref_prefixes.iter().any(|v| name.starts_with(v))
+    }
+
+    /// Builds the lines to send back for the refs in the index, which are only ever `HEAD` and
+    /// the branch it points to.
+    fn advertise(&self, commit_hash: Option<&str>) -> Vec<String> {
+        let symref_target = if self.symrefs {
+            " symref-target:refs/heads/master"
+        } else {
+            ""
+        };
+
+        let mut lines = Vec::new();
+
+        match commit_hash {
+            Some(commit_hash) => {
+                if self.wants("HEAD") {
+                    lines.push(format!("{} HEAD{}\n", commit_hash, symref_target));
+                }
+
+                if self.wants("refs/heads/master") {
+                    lines.push(format!("{} refs/heads/master\n", commit_hash));
+                }
+            }
+            None => {
+                if self.unborn && self.wants("HEAD") {
+                    lines.push(format!("unborn HEAD{}\n", symref_target));
+                }
+            }
+        }
+
+        lines
+    }
+}
+
+/// Arguments given to a `fetch` command.
+#[derive(Default, Debug, PartialEq, Eq)]
+struct FetchRequest {
+    /// The client has finished negotiating and wants the packfile straight away, rather
+    /// than being sent acknowledgments first.
+    done: bool,
+    /// The client asked for a shallow fetch using any of `deepen`, `deepen-since` or
+    /// `deepen-not`.
+    deepen: bool,
+    /// Commits the client already has as shallow commits.
+    shallow: Vec<String>,
+    /// The client doesn't want any progress messages sent while the packfile is built.
+    no_progress: bool,
+    /// The client can unpack blobs sent as deltas against another object in the packfile.
+    ofs_delta: bool,
+}
+
+impl FetchRequest {
+    fn parse(metadata: &[bytes::Bytes]) -> Self {
+        let mut request = Self::default();
+
+        for arg in metadata {
+            if arg.as_ref() == b"done" {
+                request.done = true;
+            } else if arg.as_ref() == b"no-progress" {
+                request.no_progress = true;
+            } else if arg.as_ref() == b"ofs-delta" {
+                request.ofs_delta = true;
+            } else if let Some(depth) = arg.strip_prefix(b"deepen ") {
+                // a depth of 0 doesn't limit the history at all
+                request.deepen = std::str::from_utf8(depth)
+                    .ok()
+                    .and_then(|v| v.parse::<u32>().ok())
+                    .map_or(false, |v| v > 0);
+            } else if arg.starts_with(b"deepen-since ") || arg.starts_with(b"deepen-not ") {
+                request.deepen = true;
+            } else if let Some(oid) = arg.strip_prefix(b"shallow ") {
+                request
+                    .shallow
+                    .push(String::from_utf8_lossy(oid).into_owned());
+            }
+        }
+
+        request
+    }
+
+    /// Whether the response needs a `shallow-info` section, which can't be written until the
+    /// commit it names has been built.
+    fn is_shallow(&self) -> bool {
+        self.deepen || !self.shallow.is_empty()
+    }
+
+    /// Builds the lines of the `shallow-info` section, empty if the section shouldn't be sent.
+    ///
+    /// The index only ever has the one commit with no parents, so any shallow fetch ends up
+    /// with `commit_hash` as its boundary, whatever depth was asked for. Anything the client
+    /// previously had as shallow is from an older index that's been replaced entirely, so
+    /// it's no longer missing any history.
+    fn shallow_info(&self, commit_hash: &str) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        if self.deepen {
+            lines.push(format!("shallow {}\n", commit_hash));
+        }
+
+        for oid in &self.shallow {
+            if !self.deepen || oid != commit_hash {
+                lines.push(format!("unshallow {}\n", oid));
+            }
+        }
+
+        lines
+    }
+}
+
+/// Arguments given to an `object-info` command.
+#[derive(Default, Debug, PartialEq, Eq)]
+struct ObjectInfoRequest {
+    size: bool,
+    oids: Vec<String>,
+}
+
+impl ObjectInfoRequest {
+    fn parse(metadata: &[bytes::Bytes]) -> Self {
+        let mut request = Self::default();
+
+        for arg in metadata {
+            if arg.as_ref() == b"size" {
+                request.size = true;
+            } else if let Some(oid) = arg.strip_prefix(b"oid ") {
+                request.oids.push(String::from_utf8_lossy(oid).into_owned());
+            }
+        }
+
+        request
+    }
+}
+
+/// `GIT_PROTOCOL` is a colon separated list of parameters, of which we only care about
+/// `version=2`.
+fn wants_protocol_v2(git_protocol: Option<&str>) -> bool {
+    git_protocol.map_or(false, |v| v.split(':').any(|param| param == "version=2"))
 }
 
 type AsyncHandlerFut<T> =
@@ -139,6 +1061,21 @@ impl server::Handler for Handler {
         })
     }
 
+    fn env_request(
+        mut self,
+        _channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        session: Session,
+    ) -> Self::FutureUnit {
+        // ssh clients commonly send the likes of `LANG` unprompted, so these are just ignored
+        if let Some(name) = ACCEPTED_ENV.iter().find(|v| **v == variable_name) {
+            self.env.insert(name, variable_value.to_string());
+        }
+
+        self.finished(session)
+    }
+
     fn exec_request(
         mut self,
         channel: ChannelId,
@@ -147,67 +1084,69 @@ impl server::Handler for Handler {
     ) -> Self::FutureUnit {
         let data = match std::str::from_utf8(data) {
             Ok(data) => data,
-            Err(e) => return Box::pin(futures::future::err(e.into())),
+            Err(_) => {
+                self.fatal(&mut session, channel, "command is not valid UTF-8");
+                return self.finished(session);
+            }
         };
-        let args = shlex::split(data);
+        let command = data.to_string();
 
         Box::pin(async move {
-            let mut args = args.into_iter().map(|v| v.into_iter()).flatten();
-
-            if args.next().as_deref() != Some("git-upload-pack") {
-                anyhow::bail!("not git-upload-pack");
-            }
-
-            if let Some(org) = args.next().filter(|v| v.as_str() != "/") {
-                let org = org
-                    .trim_start_matches('/')
-                    .trim_end_matches('/')
-                    .to_string();
-                self.organisation = Some(org);
-            } else {
-                session.extended_data(channel, 1, CryptoVec::from_slice(indoc::indoc! {b"
-                    \r\nNo organisation was given in the path part of the SSH URI. A chartered registry should be defined in your .cargo/config.toml as follows:
-                        [registries]
-                        chartered = {{ index = \"ssh://domain.to.registry.com/my-organisation\" }}\r\n
-                "}));
-                session.close(channel);
+            if let Err(e) = self.handle_exec(channel, &mut session, &command).await {
+                self.fail(&mut session, channel, &e);
             }
 
-            // TODO: check GIT_PROTOCOL=version=2 set
-            self.write(PktLine::Data(b"version 2\n"))?;
-            self.write(PktLine::Data(b"agent=chartered/0.1.0\n"))?;
-            self.write(PktLine::Data(b"ls-refs=unborn\n"))?;
-            self.write(PktLine::Data(b"fetch=shallow wait-for-done\n"))?;
-            self.write(PktLine::Data(b"server-option\n"))?;
-            self.write(PktLine::Data(b"object-info\n"))?;
-            self.write(PktLine::Flush)?;
-            self.flush(&mut session, channel);
-
             Ok((self, session))
         })
     }
 
+    /// Some git over SSH setups invoke `git-upload-pack` as a subsystem rather than through
+    /// exec, the subsystem name is handled exactly as the command would be.
     fn subsystem_request(
-        self,
-        _channel: ChannelId,
+        mut self,
+        channel: ChannelId,
         data: &str,
-        session: Session,
+        mut session: Session,
     ) -> Self::FutureUnit {
-        eprintln!("subsystem req: {}", data);
-        Box::pin(futures::future::ready(Ok((self, session))))
+        let command = data.to_string();
+
+        Box::pin(async move {
+            if let Err(e) = self.handle_exec(channel, &mut session, &command).await {
+                self.fail(&mut session, channel, &e);
+            }
+
+            Ok((self, session))
+        })
     }
 
     fn auth_publickey(mut self, _username: &str, key: &key::PublicKey) -> Self::FutureAuth {
         let public_key = key.public_key_bytes();
 
+        if let Some(ip) = self.ip.map(|v| v.ip()) {
+            if !self.auth_limiter.attempt(ip) {
+                warn!(
+                    "Rejecting authentication attempt from {}, too many attempts",
+                    ip
+                );
+                return self.finished_auth(server::Auth::Reject);
+            }
+        }
+
         Box::pin(async move {
-            let (ssh_key, login_user) =
-                match chartered_db::users::User::find_by_ssh_key(self.db.clone(), public_key)
-                    .await?
-                {
-                    Some(user) => user,
-                    None => return self.finished_auth(server::Auth::Reject).await,
-                };
+            let (ssh_key, login_user) = match self
+                .authenticators
+                .authenticate(self.db.clone(), &Credential::SshKey(public_key))
+                .await?
+            {
+                Some(Authenticated {
+                    user,
+                    ssh_key: Some(ssh_key),
+                    ..
+                }) => (ssh_key, user),
+                // the index's config.json is tied to the key used to fetch it, so we've no
+                // use for anything that didn't authenticate with one
+                _ => return self.finished_auth(server::Auth::Reject).await,
+            };
             let ssh_key = Arc::new(ssh_key);
 
             if let Err(e) = ssh_key.clone().update_last_used(self.db.clone()).await {
@@ -238,134 +1177,17 @@ impl server::Handler for Handler {
         self.finished_auth(server::Auth::UnsupportedMethod)
     }
 
+    fn channel_close(mut self, channel: ChannelId, session: Session) -> Self::FutureUnit {
+        self.channels.remove(&channel);
+        self.finished(session)
+    }
+
     fn data(mut self, channel: ChannelId, data: &[u8], mut session: Session) -> Self::FutureUnit {
         self.input_bytes.extend_from_slice(data);
 
         Box::pin(async move {
-            let mut ls_refs = false;
-            let mut fetch = false;
-            let mut done = false;
-
-            while let Some(frame) = self.codec.decode(&mut self.input_bytes)? {
-                eprintln!("{:#?}", frame);
-
-                // if the client flushed without giving us a command, we're expected to close
-                // the connection or else the client will just hang
-                if frame.command.is_empty() {
-                    session.exit_status_request(channel, 0);
-                    session.eof(channel);
-                    session.close(channel);
-                    return Ok((self, session));
-                }
-
-                if frame.command.as_ref() == "command=ls-refs".as_bytes() {
-                    ls_refs = true;
-                } else if frame.command.as_ref() == "command=fetch".as_bytes() {
-                    if frame.metadata.iter().any(|v| v.as_ref() == b"done") {
-                        done = true;
-                    } else {
-                        fetch = true;
-                    }
-                }
-            }
-
-            if !ls_refs && !fetch && !done {
-                return Ok((self, session));
-            }
-
-            // echo -ne "0012command=fetch\n0001000ethin-pack\n0010include-tag\n000eofs-delta\n0032want d24d8020163b5fee57c9babfd0c595b8c90ba253\n0009done\n"
-
-            let mut pack_file_entries = Vec::new();
-            let mut root_tree = Vec::new();
-
-            // TODO: key should be cached
-            let config = format!(
-                r#"{{"dl":"http://127.0.0.1:8888/a/{key}/o/{organisation}/api/v1/crates","api":"http://127.0.0.1:8888/a/{key}/o/{organisation}"}}"#,
-                key = self
-                    .user_ssh_key()?
-                    .clone()
-                    .get_or_insert_session(self.db.clone(), self.ip.map(|v| v.to_string()))
-                    .await?
-                    .session_key,
-                organisation = self.org_name()?,
-            );
-            let config_file = PackFileEntry::Blob(config.as_bytes());
-
-            root_tree.push(TreeItem {
-                kind: TreeItemKind::File,
-                name: "config.json",
-                hash: config_file.hash()?,
-            });
-            pack_file_entries.push(config_file);
-
-            // todo: the whole tree needs caching and then we can filter in code rather than at
-            //  the database
-            let tree = fetch_tree(
-                self.db.clone(),
-                self.user()?.id,
-                self.org_name()?.to_string(),
-            )
-            .await;
-            build_tree(&mut root_tree, &mut pack_file_entries, &tree)?;
-
-            let root_tree = PackFileEntry::Tree(root_tree);
-            let root_tree_hash = root_tree.hash()?;
-            pack_file_entries.push(root_tree);
-
-            let commit_user = CommitUserInfo {
-                name: "Jordan Doyle",
-                email: "jordan@doyle.la",
-                time: chrono::Utc.ymd(2021, 9, 8).and_hms(17, 46, 1),
-            };
-            let commit = PackFileEntry::Commit(Commit {
-                tree: root_tree_hash,
-                author: commit_user,
-                committer: commit_user,
-                message: "Most recent crates",
-            });
-            let commit_hash = commit.hash()?;
-            pack_file_entries.push(commit);
-
-            eprintln!("commit hash: {}", hex::encode(&commit_hash));
-
-            // echo -ne "0014command=ls-refs\n0014agent=git/2.321\n00010009peel\n000csymrefs\n000bunborn\n0014ref-prefix HEAD\n0019ref-prefix refs/HEAD\n001eref-prefix refs/tags/HEAD\n001fref-prefix refs/heads/HEAD\n0021ref-prefix refs/remotes/HEAD\n0026ref-prefix refs/remotes/HEAD/HEAD\n001aref-prefix refs/tags/\n0000"
-            // GIT_PROTOCOL=version=2 ssh -o SendEnv=GIT_PROTOCOL git@github.com git-upload-pack '/w4/chartered.git'
-            // ''.join([('{:04x}'.format(len(v) + 5)), v, "\n"])
-            // echo -ne "0012command=fetch\n0001000ethin-pack\n0010no-progress\n0010include-tag\n000eofs-delta\n0032want f6046cf6372e0d8ab845f6dec1602c303a66ee91\n"
-            // sends a 000dpackfile back
-            // https://shafiul.github.io/gitbook/7_the_packfile.html
-            if ls_refs {
-                let commit_hash = hex::encode(&commit_hash);
-                self.write(PktLine::Data(
-                    format!("{} HEAD symref-target:refs/heads/master\n", commit_hash).as_bytes(),
-                ))?;
-                self.write(PktLine::Flush)?;
-                self.flush(&mut session, channel);
-            }
-
-            if fetch {
-                self.write(PktLine::Data(b"acknowledgments\n"))?;
-                self.write(PktLine::Data(b"ready\n"))?;
-                self.write(PktLine::Delimiter)?;
-                // self.write(PktLine::Data(b"shallow-info\n"))?;
-                // self.write(PktLine::Data(b"unshallow\n"))?;
-                done = true;
-            }
-
-            if done {
-                self.write(PktLine::Data(b"packfile\n"))?;
-
-                self.write(PktLine::SidebandMsg(b"Hello from chartered!\n"))?;
-                self.flush(&mut session, channel);
-
-                let packfile = git::packfile::PackFile::new(pack_file_entries);
-                self.write(PktLine::SidebandData(packfile))?;
-                self.write(PktLine::Flush)?;
-                self.flush(&mut session, channel);
-
-                session.exit_status_request(channel, 0);
-                session.eof(channel);
-                session.close(channel);
+            if let Err(e) = self.handle_data(channel, &mut session).await {
+                self.fail(&mut session, channel, &e);
             }
 
             Ok((self, session))
@@ -381,31 +1203,166 @@ pub struct CrateFileEntry<'a> {
     yanked: bool,
 }
 
-pub type TwoCharTree<T> = BTreeMap<[u8; 2], T>;
+/// The directories and files that make up the index, laid out as
+/// [`chartered_types::cargo::index_path`] says cargo expects them to be.
+#[derive(Default)]
+pub struct IndexTree {
+    directories: BTreeMap<String, IndexTree>,
+    files: BTreeMap<String, String>,
+}
+
+impl IndexTree {
+    /// Adds `contents` as the file at `path`, creating each directory leading up to it.
+    pub fn insert(&mut self, path: &str, contents: String) {
+        match path.split_once('/') {
+            Some((directory, rest)) => self
+                .directories
+                .entry(directory.to_string())
+                .or_default()
+                .insert(rest, contents),
+            None => {
+                self.files.insert(path.to_string(), contents);
+            }
+        }
+    }
+
+    /// Amount of files and directories within this one, however deeply nested.
+    fn len(&self) -> usize {
+        self.files.len()
+            + self
+                .directories
+                .values()
+                .map(|v| 1 + v.len())
+                .sum::<usize>()
+    }
+}
+
+/// Totals collected while building the index, for use in the commit message.
+#[derive(Default)]
+struct IndexSummary {
+    crates: usize,
+    versions: usize,
+    last_updated: Option<chrono::NaiveDateTime>,
+}
+
+/// Builds the `config.json` at the root of the index, pointing cargo at chartered-web with the
+/// user's session key embedded in the URLs.
+fn registry_config(
+    config: &Config,
+    session_key: &str,
+    org_name: &str,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let api = format!(
+        "{}/a/{}/o/{}",
+        config.web_base_url.as_str(),
+        session_key,
+        org_name
+    );
+
+    Ok(serde_json::to_vec(&RegistryConfig {
+        dl: format!("{}/api/v1/crates", api),
+        api,
+        auth_required: config.index_auth_required,
+    })?)
+}
+
+/// What goes into the commit at the head of an index. The commit describes and is attributed
+/// to the latest publish to the organisation, so it only changes when the index does.
+struct IndexHead {
+    message: String,
+    latest: Option<(
+        chartered_db::crates::Crate,
+        chartered_db::crates::CrateVersion<'static>,
+        chartered_db::users::User,
+    )>,
+}
+
+impl IndexHead {
+    async fn fetch(
+        db: chartered_db::ConnectionPool,
+        config: &Config,
+        user_id: i32,
+        org_name: &str,
+        summary: &IndexSummary,
+    ) -> Result<Self, anyhow::Error> {
+        let latest =
+            chartered_db::crates::Crate::latest_version(db, user_id, org_name.to_string()).await?;
+
+        let message = config.commit_message.render(&CommitMessageValues {
+            org: org_name,
+            count: summary.crates,
+            versions: summary.versions,
+            last_updated: summary.last_updated,
+            latest: latest
+                .as_ref()
+                .map(|(crate_, version, _)| (crate_.name.as_str(), version.version.as_str())),
+        });
+
+        Ok(Self { message, latest })
+    }
 
+    fn commit_user(&self) -> CommitUserInfo<'_> {
+        match &self.latest {
+            Some((_, version, publisher)) => CommitUserInfo {
+                name: &publisher.username,
+                email: "",
+                time: chrono::Utc.from_utc_datetime(&version.created_at),
+            },
+            None => CommitUserInfo {
+                name: "chartered",
+                email: "",
+                time: chrono::Utc.timestamp(0, 0),
+            },
+        }
+    }
+}
+
+/// Fetches every crate in the organisation visible to the user, `organisation_id` is the id of
+/// the organisation the SSH session was opened for and any crate not belonging to it is left
+/// out of the index, regardless of what the database returned.
 async fn fetch_tree(
     db: chartered_db::ConnectionPool,
     user_id: i32,
     org_name: String,
-) -> TwoCharTree<TwoCharTree<BTreeMap<String, String>>> {
+    organisation_id: Option<i32>,
+) -> (IndexTree, IndexSummary) {
     use chartered_db::crates::Crate;
 
-    let mut tree: TwoCharTree<TwoCharTree<BTreeMap<String, String>>> = BTreeMap::new();
+    let mut tree = IndexTree::default();
+    let mut summary = IndexSummary::default();
 
-    // todo: handle files with 1/2/3 characters
     for (crate_def, versions) in Crate::list_with_versions(db, user_id, org_name)
         .await
         .unwrap()
     {
-        let mut name_chars = crate_def.name.as_bytes().iter();
-        let first_dir = [*name_chars.next().unwrap(), *name_chars.next().unwrap()];
-        let second_dir = [*name_chars.next().unwrap(), *name_chars.next().unwrap()];
+        if Some(crate_def.organisation_id) != organisation_id {
+            error!(
+                "Crate {} (organisation {}) was returned for organisation {:?}, leaving it out of the index",
+                crate_def.name, crate_def.organisation_id, organisation_id
+            );
+            continue;
+        }
+
+        // names are validated on publish so this should never happen, but if one somehow
+        // slipped through we'd rather leave it out than fail the whole fetch
+        let path = match chartered_types::cargo::index_path(&crate_def.name) {
+            Some(v) => v,
+            None => {
+                error!(
+                    "Crate {} has a name that can't be placed in the index, leaving it out",
+                    crate_def.name
+                );
+                continue;
+            }
+        };
 
-        let first_dir = tree.entry(first_dir).or_default();
-        let second_dir = first_dir.entry(second_dir).or_default();
+        summary.crates += 1;
 
         let mut file = String::new();
         for version in versions {
+            summary.versions += 1;
+            summary.last_updated = summary.last_updated.max(Some(version.created_at));
+
             let cksum = version.checksum.clone();
             let yanked = version.yanked;
             let version = version.into_cargo_format(&crate_def);
@@ -420,59 +1377,453 @@ async fn fetch_tree(
             file.push('\n');
         }
 
-        second_dir.insert(crate_def.name, file);
+        tree.insert(&path, file);
     }
 
-    tree
+    (tree, summary)
+}
+
+/// Builds every object that makes up an index, the `config.json`, the crate tree and a commit
+/// pointing to them. Returns the objects along with the hex-encoded hash of the commit.
+///
+/// `progress` is called with the amount of objects built so far and the amount expected in
+/// total as each one is built, any error it returns stops the build.
+fn build_index<'a>(
+    config: &'a [u8],
+    tree: &'a IndexTree,
+    commit_user: CommitUserInfo<'a>,
+    commit_message: &'a str,
+    object_format: ObjectFormat,
+    progress: &mut dyn FnMut(usize, usize) -> Result<(), anyhow::Error>,
+) -> Result<(Vec<PackFileEntry<'a>>, String), anyhow::Error> {
+    let mut pack_file_entries = Vec::new();
+    let mut root_tree = Vec::new();
+
+    // config.json, every directory, every crate, the root tree and the commit
+    let total = 3 + tree.len();
+
+    let config_file = PackFileEntry::Blob(config);
+
+    root_tree.push(TreeItem {
+        kind: TreeItemKind::File,
+        name: "config.json",
+        hash: config_file.hash(object_format)?,
+    });
+    pack_file_entries.push(config_file);
+    progress(pack_file_entries.len(), total)?;
+
+    build_tree(
+        &mut root_tree,
+        &mut pack_file_entries,
+        tree,
+        object_format,
+        &mut |n| progress(n, total),
+    )?;
+
+    let root_tree = PackFileEntry::Tree(root_tree);
+    let root_tree_hash = root_tree.hash(object_format)?;
+    pack_file_entries.push(root_tree);
+    progress(pack_file_entries.len(), total)?;
+
+    let commit = PackFileEntry::Commit(Commit {
+        tree: root_tree_hash,
+        author: commit_user,
+        committer: commit_user,
+        message: commit_message,
+    });
+    let commit_hash = hex::encode(commit.hash(object_format)?);
+    pack_file_entries.push(commit);
+    progress(pack_file_entries.len(), total)?;
+
+    Ok((pack_file_entries, commit_hash))
 }
 
+/// Builds every file and directory within `tree`, adding them to `pack_file_entries` and an
+/// item for each to `items`, the tree `tree` itself is being built into.
 fn build_tree<'a>(
-    root_tree: &mut Vec<TreeItem<'a>>,
+    items: &mut Vec<TreeItem<'a>>,
     pack_file_entries: &mut Vec<PackFileEntry<'a>>,
-    tree: &'a TwoCharTree<TwoCharTree<BTreeMap<String, String>>>,
+    tree: &'a IndexTree,
+    object_format: ObjectFormat,
+    progress: &mut dyn FnMut(usize) -> Result<(), anyhow::Error>,
 ) -> Result<(), anyhow::Error> {
-    root_tree.reserve(tree.len());
-    pack_file_entries.reserve(tree.iter().map(|(_, v)| 1 + v.len()).sum::<usize>() + tree.len());
+    items.reserve(tree.files.len() + tree.directories.len());
+
+    for (name, contents) in &tree.files {
+        let file = PackFileEntry::Blob(contents.as_bytes());
+        let hash = file.hash(object_format)?;
+        pack_file_entries.push(file);
+        progress(pack_file_entries.len())?;
+
+        items.push(TreeItem {
+            kind: TreeItemKind::File,
+            name,
+            hash,
+        });
+    }
 
-    for (first_level_dir, second_level_dirs) in tree.iter() {
-        let mut first_level_tree = Vec::with_capacity(second_level_dirs.len());
+    for (name, directory) in &tree.directories {
+        let mut directory_items = Vec::new();
+        build_tree(
+            &mut directory_items,
+            pack_file_entries,
+            directory,
+            object_format,
+            progress,
+        )?;
+
+        let directory = PackFileEntry::Tree(directory_items);
+        let hash = directory.hash(object_format)?;
+        pack_file_entries.push(directory);
+        progress(pack_file_entries.len())?;
+
+        items.push(TreeItem {
+            kind: TreeItemKind::Directory,
+            name,
+            hash,
+        });
+    }
 
-        for (second_level_dir, crates) in second_level_dirs.iter() {
-            let mut second_level_tree = Vec::with_capacity(crates.len());
+    // git expects a tree's items in order of name, with directories ordered as if their names
+    // ended with a `/`
+    items.sort_by_cached_key(|item| {
+        let suffix: &[u8] = match item.kind {
+            TreeItemKind::File => b"",
+            TreeItemKind::Directory => b"/",
+        };
+        [item.name.as_bytes(), suffix].concat()
+    });
 
-            for (crate_name, versions_def) in crates.iter() {
-                let file = PackFileEntry::Blob(versions_def.as_ref());
-                let file_hash = file.hash()?;
-                pack_file_entries.push(file);
+    Ok(())
+}
 
-                second_level_tree.push(TreeItem {
-                    kind: TreeItemKind::File,
-                    name: crate_name,
-                    hash: file_hash,
-                });
-            }
+#[cfg(test)]
+mod test {
+    use super::{
+        build_index, build_tree, is_receive_pack, parse_upload_pack, wants_protocol_v2,
+        FetchRequest, IndexTree, LsRefsRequest,
+    };
+    use crate::git::packfile::{CommitUserInfo, ObjectFormat, PackFile, PackFileEntry};
+    use bytes::BytesMut;
+    use chrono::TimeZone;
+
+    #[test]
+    fn empty_index_is_clonable() {
+        let tree = IndexTree::default();
+        let (entries, commit_hash) = build_index(
+            br#"{"dl":"","api":""}"#,
+            &tree,
+            CommitUserInfo {
+                name: "chartered",
+                email: "",
+                time: chrono::Utc.timestamp(0, 0),
+            },
+            "Initial commit",
+            ObjectFormat::Sha1,
+            &mut |_, _| Ok(()),
+        )
+        .unwrap();
+
+        assert_eq!(commit_hash.len(), 40);
+        assert_eq!(entries.len(), 3);
+        assert!(matches!(entries[0], PackFileEntry::Blob(_)));
+        assert!(
+            matches!(&entries[1], PackFileEntry::Tree(items) if items.len() == 1 && items[0].name == "config.json")
+        );
+        assert!(matches!(entries[2], PackFileEntry::Commit(_)));
+
+        let mut buf = BytesMut::new();
+        PackFile::new(entries, ObjectFormat::Sha1)
+            .encode_to(&mut buf)
+            .unwrap();
+        assert_eq!(&buf[..12], b"PACK\0\0\0\x02\0\0\0\x03");
+    }
+
+    #[test]
+    fn sha256_index() {
+        let tree = IndexTree::default();
+        let (entries, commit_hash) = build_index(
+            br#"{"dl":"","api":""}"#,
+            &tree,
+            CommitUserInfo {
+                name: "chartered",
+                email: "",
+                time: chrono::Utc.timestamp(0, 0),
+            },
+            "Initial commit",
+            ObjectFormat::Sha256,
+            &mut |_, _| Ok(()),
+        )
+        .unwrap();
+
+        assert_eq!(commit_hash.len(), 64);
+        assert!(matches!(&entries[1], PackFileEntry::Tree(items) if items[0].hash.len() == 32));
+        // the blob's id is the same as `git hash-object --object-format=sha256` gives
+        assert_eq!(
+            hex::encode(PackFileEntry::Blob(b"").hash(ObjectFormat::Sha256).unwrap()),
+            "473a0f4c3be8a93681a267e3b1e9a7dcda1185436fe141f7749120a303721813"
+        );
 
-            let second_level_tree = PackFileEntry::Tree(second_level_tree);
-            let second_level_tree_hash = second_level_tree.hash()?;
-            pack_file_entries.push(second_level_tree);
+        let packfile = PackFile::new(entries, ObjectFormat::Sha256);
+        let mut buf = BytesMut::new();
+        packfile.encode_to(&mut buf).unwrap();
+        assert_eq!(packfile.footer_size(), 32);
+    }
+
+    #[test]
+    fn packfile_is_accepted_by_git() {
+        use std::io::Write;
+
+        // git is the only real authority on whether what we send is valid, but it might not be
+        // installed wherever the tests are being run
+        if std::process::Command::new("git")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            eprintln!("skipping, git isn't on the PATH");
+            return;
+        }
 
-            first_level_tree.push(TreeItem {
-                kind: TreeItemKind::Directory,
-                name: std::str::from_utf8(second_level_dir)?,
-                hash: second_level_tree_hash,
-            });
+        // similar enough files for some of them to be sent as deltas against each other
+        let mut tree = IndexTree::default();
+        for name in ["foo", "foo-bar", "foo-baz", "serde"] {
+            let contents: Vec<_> = (0..5)
+                .map(|patch| {
+                    format!(
+                        r#"{{"name":"{}","vers":"1.0.{}","deps":[],"cksum":"{}","features":{{}},"yanked":false}}"#,
+                        name,
+                        patch,
+                        "a".repeat(64)
+                    )
+                })
+                .collect();
+            tree.insert(
+                &chartered_types::cargo::index_path(name).unwrap(),
+                contents.join("\n"),
+            );
         }
 
-        let first_level_tree = PackFileEntry::Tree(first_level_tree);
-        let first_level_tree_hash = first_level_tree.hash()?;
-        pack_file_entries.push(first_level_tree);
+        for object_format in [ObjectFormat::Sha1, ObjectFormat::Sha256] {
+            let build = || {
+                build_index(
+                    br#"{"dl":"","api":""}"#,
+                    &tree,
+                    CommitUserInfo {
+                        name: "chartered",
+                        email: "",
+                        time: chrono::Utc.timestamp(0, 0),
+                    },
+                    "Update crates",
+                    object_format,
+                    &mut |_, _| Ok(()),
+                )
+                .unwrap()
+            };
+            let (entries, commit_hash) = build();
+
+            let mut without_deltas = BytesMut::new();
+            PackFile::new(build().0, object_format)
+                .encode_to(&mut without_deltas)
+                .unwrap();
+
+            let mut buf = BytesMut::new();
+            PackFile::new(entries, object_format)
+                .with_ofs_delta(true)
+                .encode_to(&mut buf)
+                .unwrap();
+            assert!(buf.len() < without_deltas.len());
+
+            let dir = std::env::temp_dir().join(format!(
+                "chartered-index-pack-{}-{}",
+                std::process::id(),
+                object_format.name()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+
+            let git = |args: &[&str]| {
+                std::process::Command::new("git")
+                    .arg("-C")
+                    .arg(&dir)
+                    .args(args)
+                    .stdin(std::process::Stdio::piped())
+                    .stdout(std::process::Stdio::piped())
+                    .stderr(std::process::Stdio::piped())
+                    .spawn()
+                    .unwrap()
+            };
 
-        root_tree.push(TreeItem {
-            kind: TreeItemKind::Directory,
-            name: std::str::from_utf8(first_level_dir)?,
-            hash: first_level_tree_hash,
-        });
+            std::fs::create_dir_all(&dir).unwrap();
+            let init = git(&[
+                "init",
+                "--bare",
+                &format!("--object-format={}", object_format.name()),
+            ])
+            .wait_with_output()
+            .unwrap();
+            assert!(init.status.success(), "{:?}", init);
+
+            let mut index_pack = git(&["index-pack", "--strict", "--stdin"]);
+            index_pack.stdin.take().unwrap().write_all(&buf).unwrap();
+            let index_pack = index_pack.wait_with_output().unwrap();
+            assert!(
+                index_pack.status.success(),
+                "{}",
+                String::from_utf8_lossy(&index_pack.stderr)
+            );
+
+            // and the commit we'd advertise is the one git sees
+            let cat_file = git(&["cat-file", "-t", &commit_hash])
+                .wait_with_output()
+                .unwrap();
+            assert_eq!(String::from_utf8_lossy(&cat_file.stdout).trim(), "commit");
+
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
     }
 
-    Ok(())
+    #[test]
+    fn build_tree_places_short_names() {
+        let mut tree = IndexTree::default();
+        for name in ["a", "ab", "abc", "Abcd", "serde"] {
+            tree.insert(
+                &chartered_types::cargo::index_path(name).unwrap(),
+                format!("{}\n", name),
+            );
+        }
+
+        let mut root_tree = Vec::new();
+        let mut pack_file_entries = Vec::new();
+        let mut progress = Vec::new();
+        build_tree(
+            &mut root_tree,
+            &mut pack_file_entries,
+            &tree,
+            ObjectFormat::Sha1,
+            &mut |n| {
+                progress.push(n);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        let names: Vec<_> = root_tree.iter().map(|v| v.name).collect();
+        assert_eq!(names, ["1", "2", "3", "ab", "se"]);
+
+        // 5 crates, `1/`, `2/`, `3/`, `3/a/`, `ab/`, `ab/cd/`, `se/` and `se/rd/`
+        assert_eq!(tree.len(), 13);
+        assert_eq!(pack_file_entries.len(), 13);
+        assert_eq!(progress, (1..=13).collect::<Vec<_>>());
+
+        let files: Vec<_> = pack_file_entries
+            .iter()
+            .filter_map(|v| match v {
+                PackFileEntry::Blob(data) => Some(std::str::from_utf8(data).unwrap()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(files, ["a\n", "ab\n", "abc\n", "Abcd\n", "serde\n"]);
+    }
+
+    #[test]
+    fn tree_items_are_ordered_like_git() {
+        let mut tree = IndexTree::default();
+        tree.insert("ab-c", "{}\n".to_string());
+        tree.insert("ab/cd", "{}\n".to_string());
+        tree.insert("ab.d", "{}\n".to_string());
+
+        let mut items = Vec::new();
+        build_tree(
+            &mut items,
+            &mut Vec::new(),
+            &tree,
+            ObjectFormat::Sha1,
+            &mut |_| {},
+        )
+        .unwrap();
+
+        // `ab/` sorts after `ab.d` since `/` comes after `.`
+        let names: Vec<_> = items.iter().map(|v| v.name).collect();
+        assert_eq!(names, ["ab-c", "ab.d", "ab"]);
+    }
+
+    #[test]
+    fn ls_refs_respects_arguments() {
+        let request = LsRefsRequest::parse(&[
+            bytes::Bytes::from_static(b"peel"),
+            bytes::Bytes::from_static(b"symrefs"),
+            bytes::Bytes::from_static(b"ref-prefix HEAD"),
+            bytes::Bytes::from_static(b"ref-prefix refs/heads/"),
+        ]);
+        assert_eq!(
+            request.advertise(Some("abc")),
+            vec![
+                "abc HEAD symref-target:refs/heads/master\n",
+                "abc refs/heads/master\n",
+            ]
+        );
+
+        let request = LsRefsRequest::parse(&[bytes::Bytes::from_static(b"ref-prefix refs/tags/")]);
+        assert!(request.advertise(Some("abc")).is_empty());
+
+        let request = LsRefsRequest::parse(&[bytes::Bytes::from_static(b"unborn")]);
+        assert_eq!(request.advertise(None), vec!["unborn HEAD\n"]);
+        assert_eq!(
+            request.advertise(Some("abc")),
+            vec!["abc HEAD\n", "abc refs/heads/master\n"]
+        );
+    }
+
+    #[test]
+    fn deepen_produces_shallow_info() {
+        let request = FetchRequest::parse(&[
+            bytes::Bytes::from_static(b"thin-pack"),
+            bytes::Bytes::from_static(b"ofs-delta"),
+            bytes::Bytes::from_static(b"want abc"),
+            bytes::Bytes::from_static(b"deepen 1"),
+            bytes::Bytes::from_static(b"done"),
+        ]);
+        assert!(request.done);
+        assert!(request.ofs_delta);
+        assert!(request.is_shallow());
+        assert_eq!(request.shallow_info("abc"), vec!["shallow abc\n"]);
+
+        let request = FetchRequest::parse(&[
+            bytes::Bytes::from_static(b"want def"),
+            bytes::Bytes::from_static(b"shallow abc"),
+        ]);
+        assert!(!request.done);
+        assert!(request.is_shallow());
+        assert_eq!(request.shallow_info("def"), vec!["unshallow abc\n"]);
+
+        // nothing needs the commit ahead of the packfile, so it can be started straight away
+        let request = FetchRequest::parse(&[bytes::Bytes::from_static(b"want abc")]);
+        assert!(!request.is_shallow());
+        assert!(request.shallow_info("abc").is_empty());
+    }
+
+    #[test]
+    fn protocol_version() {
+        assert!(wants_protocol_v2(Some("version=2")));
+        assert!(wants_protocol_v2(Some("object-format=sha1:version=2")));
+        assert!(!wants_protocol_v2(Some("version=1")));
+        assert!(!wants_protocol_v2(Some("version=20")));
+        assert!(!wants_protocol_v2(None));
+    }
+
+    #[test]
+    fn upload_pack_command() {
+        // exec requests and subsystem requests are parsed the same way
+        for command in ["git-upload-pack '/core'", "git-upload-pack /core/"] {
+            assert_eq!(parse_upload_pack(command).unwrap().as_deref(), Some("core"));
+        }
+
+        assert_eq!(parse_upload_pack("git-upload-pack '/'").unwrap(), None);
+        assert_eq!(parse_upload_pack("git-upload-pack").unwrap(), None);
+        assert!(parse_upload_pack("git-receive-pack '/core'").is_err());
+        assert!(parse_upload_pack("sftp").is_err());
+
+        assert!(is_receive_pack("git-receive-pack '/core'"));
+        assert!(!is_receive_pack("git-upload-pack '/core'"));
+    }
 }
@@ -0,0 +1,444 @@
+//! `chartered-git --selftest <org> --user <username>` builds an organisation's index exactly
+//! as a fetch by that user would, then reads the packfile back and checks every object in it
+//! hashes correctly and everything a tree or commit points to is present. No SSH listener is
+//! opened, so packfile bugs can be reproduced without a cargo client.
+//!
+//! `--output <path>` writes the packfile out for inspection with `git index-pack`, and
+//! `--object-format sha256` builds it as a SHA-256 client would receive it.
+
+use crate::{
+    build_index,
+    config::Config,
+    fetch_tree,
+    git::{
+        delta,
+        packfile::{ObjectFormat, PackFile},
+    },
+    registry_config, IndexHead,
+};
+
+use bytes::BytesMut;
+use chartered_db::{
+    users::{Membership, Organisation, User},
+    ConnectionPool,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::{TryFrom, TryInto},
+    io::Read,
+    path::PathBuf,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Args {
+    pub organisation: String,
+    pub username: String,
+    pub output: Option<PathBuf>,
+    pub object_format: ObjectFormat,
+}
+
+impl Args {
+    /// Picks the self-test's options out of the arguments the binary was started with,
+    /// returning `None` if no self-test was asked for.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Option<Self>, String> {
+        let mut args = args.into_iter();
+
+        let mut organisation = None;
+        let mut username = None;
+        let mut output = None;
+        let mut object_format = ObjectFormat::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--selftest" => organisation = Some(value(&mut args, &arg)?),
+                "--user" => username = Some(value(&mut args, &arg)?),
+                "--output" => output = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--object-format" => object_format = value(&mut args, &arg)?.parse()?,
+                other => return Err(format!("unknown argument `{}`", other)),
+            }
+        }
+
+        match (organisation, username) {
+            (Some(organisation), Some(username)) => Ok(Some(Self {
+                organisation,
+                username,
+                output,
+                object_format,
+            })),
+            (Some(_), None) => Err("`--selftest` requires `--user`".to_string()),
+            (None, None) if output.is_none() && object_format == ObjectFormat::default() => {
+                Ok(None)
+            }
+            (None, _) => Err("options given without `--selftest`".to_string()),
+        }
+    }
+}
+
+fn value(args: &mut impl Iterator<Item = String>, name: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("`{}` requires a value", name))
+}
+
+pub async fn run(args: &Args, config: &Config, db: ConnectionPool) -> Result<(), anyhow::Error> {
+    let user = User::find_by_username(db.clone(), args.username.clone())
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("user `{}` doesn't exist", args.username))?;
+
+    let organisation =
+        match Organisation::membership(db.clone(), user.id, args.organisation.clone()).await? {
+            Membership::Member(v) => v,
+            Membership::NotMember => anyhow::bail!(
+                "`{}` is not a member of organisation `{}`",
+                args.username,
+                args.organisation
+            ),
+            Membership::Missing => {
+                anyhow::bail!("organisation `{}` doesn't exist", args.organisation)
+            }
+        };
+
+    let (tree, summary) = fetch_tree(
+        db.clone(),
+        user.id,
+        args.organisation.clone(),
+        Some(organisation.id),
+    )
+    .await;
+
+    // the index never leaves this process so there's no need to issue a real session key
+    let registry_config = registry_config(config, "selftest", &args.organisation)?;
+    let head = IndexHead::fetch(db, config, user.id, &args.organisation, &summary).await?;
+
+    let (entries, commit_hash) = build_index(
+        &registry_config,
+        &tree,
+        head.commit_user(),
+        &head.message,
+        args.object_format,
+        &mut |_, _| {},
+    )?;
+    let expected_objects = entries.len();
+
+    // every git client new enough to speak protocol v2 asks for `ofs-delta`
+    let mut packfile = BytesMut::new();
+    PackFile::new(entries, args.object_format)
+        .with_compression(config.packfile_compression)
+        .with_ofs_delta(true)
+        .encode_to(&mut packfile)?;
+
+    if let Some(path) = &args.output {
+        tokio::fs::write(path, &packfile).await?;
+        println!("Wrote packfile to {}", path.display());
+    }
+
+    let contents = verify(&packfile, args.object_format)?;
+
+    if contents.objects != expected_objects {
+        anyhow::bail!(
+            "packfile contains {} objects, expected {}",
+            contents.objects,
+            expected_objects
+        );
+    }
+
+    if !contents.commits.contains(&commit_hash) {
+        anyhow::bail!("HEAD commit {} is missing from the packfile", commit_hash);
+    }
+
+    println!(
+        "Index for `{}` is valid: {} crates, {} objects, {} bytes, HEAD at {}",
+        args.organisation,
+        summary.crates,
+        contents.objects,
+        packfile.len(),
+        commit_hash
+    );
+
+    Ok(())
+}
+
+/// What was found in a packfile by [`verify`].
+#[derive(Debug)]
+struct Contents {
+    objects: usize,
+    /// Hex-encoded ids of every commit in the packfile.
+    commits: Vec<String>,
+}
+
+/// Reads back a packfile, checking its trailer and that every object decompresses to the size
+/// its header claims, resolving any deltas against their base. Fails if a tree or commit refers
+/// to an object that isn't in the packfile.
+fn verify(packfile: &[u8], object_format: ObjectFormat) -> Result<Contents, anyhow::Error> {
+    let hash_len = object_format.hash_len();
+
+    if packfile.len() < PackFile::header_size() + hash_len {
+        anyhow::bail!("packfile is only {} bytes", packfile.len());
+    }
+
+    let (body, trailer) = packfile.split_at(packfile.len() - hash_len);
+    if object_format.digest(body) != trailer {
+        anyhow::bail!("packfile trailer doesn't match its contents");
+    }
+
+    let (header, mut rest) = body.split_at(PackFile::header_size());
+    if &header[..8] != b"PACK\0\0\0\x02" {
+        anyhow::bail!("packfile has an invalid header");
+    }
+    let count = usize::try_from(u32::from_be_bytes(header[8..].try_into()?))?;
+
+    let mut ids = HashSet::new();
+    let mut referenced = Vec::new();
+    let mut commits = Vec::new();
+    // every object seen so far by where it starts, so deltas can be resolved
+    let mut objects: HashMap<usize, (&'static str, Vec<u8>)> = HashMap::new();
+
+    for i in 0..count {
+        let object_offset = body.len() - rest.len();
+
+        let (kind, size) = read_object_header(&mut rest)
+            .ok_or_else(|| anyhow::anyhow!("object {} has a truncated header", i))?;
+
+        let base_offset = if kind == 0b110 {
+            let distance = read_ofs_delta_distance(&mut rest)
+                .ok_or_else(|| anyhow::anyhow!("object {} has a truncated delta offset", i))?;
+            Some(object_offset.checked_sub(distance).ok_or_else(|| {
+                anyhow::anyhow!("object {} is a delta against a base before the packfile", i)
+            })?)
+        } else {
+            None
+        };
+
+        let mut data = Vec::with_capacity(size);
+        flate2::bufread::ZlibDecoder::new(&mut rest).read_to_end(&mut data)?;
+
+        if data.len() != size {
+            anyhow::bail!(
+                "object {} is {} bytes but its header says {}",
+                i,
+                data.len(),
+                size
+            );
+        }
+
+        let (kind, data) = match (kind, base_offset) {
+            (1, _) => ("commit", data),
+            (2, _) => ("tree", data),
+            (3, _) => ("blob", data),
+            (_, Some(base_offset)) => {
+                let (base_kind, base) = objects.get(&base_offset).ok_or_else(|| {
+                    anyhow::anyhow!("object {} is a delta against a missing base", i)
+                })?;
+                let data = delta::apply(base, &data)
+                    .ok_or_else(|| anyhow::anyhow!("object {} has an invalid delta", i))?;
+                (*base_kind, data)
+            }
+            (v, None) => anyhow::bail!("object {} has unexpected type {}", i, v),
+        };
+
+        let mut object = format!("{} {}\0", kind, data.len()).into_bytes();
+        object.extend_from_slice(&data);
+        let id = object_format.digest(&object);
+
+        match kind {
+            "commit" => {
+                let tree = data
+                    .strip_prefix(b"tree ")
+                    .and_then(|v| v.get(..hash_len * 2))
+                    .ok_or_else(|| anyhow::anyhow!("commit {} has no tree", i))?;
+                referenced.push(hex::decode(tree)?);
+                commits.push(hex::encode(&id));
+            }
+            "tree" => {
+                let mut items = data.as_slice();
+
+                // `[mode] [name]\0[hash]`
+                while !items.is_empty() {
+                    let hash_start = items
+                        .iter()
+                        .position(|&v| v == 0)
+                        .map(|v| v + 1)
+                        .filter(|v| v + hash_len <= items.len())
+                        .ok_or_else(|| anyhow::anyhow!("tree {} has a truncated item", i))?;
+
+                    referenced.push(items[hash_start..hash_start + hash_len].to_vec());
+                    items = &items[hash_start + hash_len..];
+                }
+            }
+            _ => {}
+        }
+
+        ids.insert(id);
+        objects.insert(object_offset, (kind, data));
+    }
+
+    if !rest.is_empty() {
+        anyhow::bail!(
+            "{} bytes left over after the last object in the packfile",
+            rest.len()
+        );
+    }
+
+    if let Some(missing) = referenced.iter().find(|v| !ids.contains(*v)) {
+        anyhow::bail!(
+            "object {} is referenced but isn't in the packfile",
+            hex::encode(missing)
+        );
+    }
+
+    Ok(Contents {
+        objects: count,
+        commits,
+    })
+}
+
+/// Reads an object's type and uncompressed size from the start of `input`, advancing past them.
+fn read_object_header(input: &mut &[u8]) -> Option<(u8, usize)> {
+    let (&first, rest) = input.split_first()?;
+    *input = rest;
+
+    let kind = (first >> 4) & 0b111;
+    let mut size = usize::from(first & 0b1111);
+    let mut shift = 4;
+    let mut more = first & 0b1000_0000 != 0;
+
+    while more {
+        let (&byte, rest) = input.split_first()?;
+        *input = rest;
+
+        size |= usize::from(byte & 0b111_1111).checked_shl(shift)?;
+        shift += 7;
+        more = byte & 0b1000_0000 != 0;
+    }
+
+    Some((kind, size))
+}
+
+/// Reads how far back from the start of an `OBJ_OFS_DELTA` its base starts, advancing past it.
+fn read_ofs_delta_distance(input: &mut &[u8]) -> Option<usize> {
+    let (&first, rest) = input.split_first()?;
+    *input = rest;
+
+    let mut distance = usize::from(first & 0b111_1111);
+    let mut more = first & 0b1000_0000 != 0;
+
+    while more {
+        let (&byte, rest) = input.split_first()?;
+        *input = rest;
+
+        distance = distance
+            .checked_add(1)?
+            .checked_mul(128)?
+            .checked_add(usize::from(byte & 0b111_1111))?;
+        more = byte & 0b1000_0000 != 0;
+    }
+
+    Some(distance)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{verify, Args};
+    use crate::{
+        build_index,
+        git::packfile::{CommitUserInfo, ObjectFormat, PackFile},
+        IndexTree,
+    };
+    use bytes::BytesMut;
+    use chrono::TimeZone;
+
+    fn args(v: &[&str]) -> Result<Option<Args>, String> {
+        Args::parse(v.iter().map(ToString::to_string))
+    }
+
+    #[test]
+    fn parse_args() {
+        assert_eq!(args(&[]), Ok(None));
+        assert_eq!(
+            args(&[
+                "--selftest",
+                "core",
+                "--user",
+                "admin",
+                "--object-format",
+                "sha256"
+            ]),
+            Ok(Some(Args {
+                organisation: "core".to_string(),
+                username: "admin".to_string(),
+                output: None,
+                object_format: ObjectFormat::Sha256,
+            }))
+        );
+        assert!(args(&["--selftest", "core"]).is_err());
+        assert!(args(&["--selftest"]).is_err());
+        assert!(args(&["--output", "index.pack"]).is_err());
+        assert!(args(&["--verbose"]).is_err());
+    }
+
+    #[test]
+    fn verifies_built_index() {
+        let version = |name: &str, vers: &str| {
+            format!(
+                r#"{{"name":"{}","vers":"{}","deps":[],"cksum":"0000000000000000000000000000000000000000000000000000000000000000","features":{{}},"yanked":false}}"#,
+                name, vers
+            )
+        };
+
+        // two crates with near enough the same files, so one can be sent as a delta of the other
+        let mut tree = IndexTree::default();
+        tree.insert(
+            "se/rd/serde",
+            format!(
+                "{}\n{}\n",
+                version("serde", "1.0.0"),
+                version("serde", "1.0.1")
+            ),
+        );
+        tree.insert(
+            "se/rd/serde_json",
+            format!(
+                "{}\n{}\n",
+                version("serde_json", "1.0.0"),
+                version("serde_json", "1.0.1")
+            ),
+        );
+
+        for object_format in [ObjectFormat::Sha1, ObjectFormat::Sha256] {
+            let mut sizes = Vec::new();
+
+            for ofs_delta in [false, true] {
+                let (entries, commit_hash) = build_index(
+                    br#"{"dl":"","api":""}"#,
+                    &tree,
+                    CommitUserInfo {
+                        name: "chartered",
+                        email: "",
+                        time: chrono::Utc.timestamp(0, 0),
+                    },
+                    "Initial commit",
+                    object_format,
+                    &mut |_, _| {},
+                )
+                .unwrap();
+
+                let mut packfile = BytesMut::new();
+                PackFile::new(entries, object_format)
+                    .with_ofs_delta(ofs_delta)
+                    .encode_to(&mut packfile)
+                    .unwrap();
+
+                let contents = verify(&packfile, object_format).unwrap();
+                assert_eq!(contents.objects, 7);
+                assert_eq!(contents.commits, vec![commit_hash]);
+                sizes.push(packfile.len());
+
+                // flip a bit in the middle of the packfile, after the header
+                let mut corrupted = packfile.to_vec();
+                corrupted[packfile.len() / 2] ^= 1;
+                assert!(verify(&corrupted, object_format).is_err());
+            }
+
+            assert!(sizes[1] < sizes[0], "{:?}", sizes);
+        }
+    }
+}
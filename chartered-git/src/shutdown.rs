@@ -0,0 +1,100 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::sync::Notify;
+
+/// Counts the connections currently being served, so shutdown can wait for them to finish.
+#[derive(Default)]
+pub struct ActiveConnections {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+impl ActiveConnections {
+    /// Marks a connection as active until the returned guard is dropped.
+    pub fn track(self: &Arc<Self>) -> ConnectionGuard {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard(self.clone())
+    }
+
+    #[must_use]
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once there are no active connections left.
+    pub async fn wait_idle(&self) {
+        loop {
+            // created before checking the count so a connection finishing in between
+            // isn't missed
+            let idle = self.idle.notified();
+
+            if self.count() == 0 {
+                return;
+            }
+
+            idle.await;
+        }
+    }
+}
+
+pub struct ConnectionGuard(Arc<ActiveConnections>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Resolves when the process is asked to stop, either by `SIGTERM` or `SIGINT`.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("failed to install SIGTERM handler");
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            log::error!("Failed to listen for shutdown signal: {}", e);
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ActiveConnections;
+    use std::{sync::Arc, time::Duration};
+
+    #[tokio::test]
+    async fn wait_idle() {
+        let connections = Arc::new(ActiveConnections::default());
+        connections.wait_idle().await;
+
+        let first = connections.track();
+        let second = connections.track();
+        assert_eq!(connections.count(), 2);
+
+        drop(first);
+        let wait = tokio::time::timeout(Duration::from_millis(10), connections.wait_idle());
+        assert!(wait.await.is_err());
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(second);
+        });
+        connections.wait_idle().await;
+        assert_eq!(connections.count(), 0);
+    }
+}
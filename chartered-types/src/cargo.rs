@@ -35,6 +35,10 @@ pub struct CrateVersionMetadata {
     pub repository: Option<String>,
     pub homepage: Option<String>,
     pub documentation: Option<String>,
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    #[serde(default)]
+    pub categories: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -72,3 +76,86 @@ impl CrateDependency<'_> {
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct CrateFeatures(pub BTreeMap<String, Vec<String>>);
+
+/// The `config.json` at the root of an index, telling cargo where to find the registry.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RegistryConfig {
+    /// URL crates are downloaded from.
+    pub dl: String,
+    /// Base URL of the registry's web API.
+    pub api: String,
+    /// Tells cargo to send its token on every request to the registry, including downloads.
+    /// Left out entirely when `false` so older versions of cargo aren't sent anything new.
+    #[serde(
+        rename = "auth-required",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub auth_required: bool,
+}
+
+/// Path of the file cargo looks a crate up under in the index, relative to the index's root.
+/// Names are lowercased and, depending on their length, sorted into directories:
+///
+/// - 1 character: `1/{name}`
+/// - 2 characters: `2/{name}`
+/// - 3 characters: `3/{first character}/{name}`
+/// - anything longer: `{first two characters}/{next two characters}/{name}`
+///
+/// Returns `None` for names that are empty or contain anything other than ASCII, neither of
+/// which can be published.
+#[must_use]
+pub fn index_path(name: &str) -> Option<String> {
+    if !name.is_ascii() {
+        return None;
+    }
+
+    let name = name.to_ascii_lowercase();
+
+    Some(match name.len() {
+        0 => return None,
+        1 => format!("1/{}", name),
+        2 => format!("2/{}", name),
+        3 => format!("3/{}/{}", &name[..1], name),
+        _ => format!("{}/{}/{}", &name[..2], &name[2..4], name),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{index_path, RegistryConfig};
+
+    #[test]
+    fn index_paths() {
+        assert_eq!(index_path("a").as_deref(), Some("1/a"));
+        assert_eq!(index_path("ab").as_deref(), Some("2/ab"));
+        assert_eq!(index_path("abc").as_deref(), Some("3/a/abc"));
+        assert_eq!(index_path("mycrate").as_deref(), Some("my/cr/mycrate"));
+        assert_eq!(
+            index_path("Serde_JSON").as_deref(),
+            Some("se/rd/serde_json")
+        );
+        assert_eq!(index_path(""), None);
+        assert_eq!(index_path("crâte"), None);
+    }
+
+    #[test]
+    fn registry_config() {
+        let mut config = RegistryConfig {
+            dl: "https://example.com/api/v1/crates".to_string(),
+            api: "https://example.com".to_string(),
+            auth_required: false,
+        };
+
+        assert_eq!(
+            serde_json::to_string(&config).unwrap(),
+            r#"{"dl":"https://example.com/api/v1/crates","api":"https://example.com"}"#
+        );
+
+        config.auth_required = true;
+        assert_eq!(
+            serde_json::to_string(&config).unwrap(),
+            r#"{"dl":"https://example.com/api/v1/crates","api":"https://example.com","auth-required":true}"#
+        );
+    }
+}
@@ -0,0 +1,405 @@
+//! Runtime configuration for chartered-web, read from the environment on startup.
+
+use axum::http::Method;
+use chartered_db::crates::SearchWeights;
+use std::{fmt::Display, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid value for `{0}`: {1}")]
+    InvalidValue(&'static str, String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Allows a yanked version to be overwritten by publishing the same version again. This
+    /// breaks any lockfile that pinned the original checksum, so it's off by default and every
+    /// overwrite is logged.
+    pub allow_yanked_version_overwrite: bool,
+    /// Rejects publishes of a version lower than one the crate already has, so versions only
+    /// ever go up.
+    pub require_increasing_versions: bool,
+    /// Maximum amount of publishes that will be processed at the same time.
+    pub max_concurrent_publishes: usize,
+    /// What to do with a publish when `max_concurrent_publishes` has been hit.
+    pub publish_overflow: PublishOverflow,
+    /// Publishes with a declared `Content-Length` over this many bytes, or without one at all,
+    /// are streamed into storage as they're received rather than being buffered in memory.
+    pub publish_spill_threshold: u64,
+    /// Largest publish body accepted, metadata and tarball included. Anything bigger is
+    /// rejected with a `413 Payload Too Large`, before it's read if the client declared its
+    /// `Content-Length` or as soon as it goes over otherwise.
+    pub max_publish_size: u64,
+    /// How long a user has to wait between requesting exports of their data.
+    pub data_export_interval: Duration,
+    /// Logs the bodies of requests and responses, for debugging. Bodies can contain all sorts
+    /// of sensitive information so this is off by default, credentials are redacted and crate
+    /// tarballs are left out.
+    pub debug_log_bodies: bool,
+    /// Bodies over this many bytes aren't logged even if `debug_log_bodies` is enabled.
+    pub debug_log_body_limit: usize,
+    /// How each request is written to the logs.
+    pub log_format: LogFormat,
+    /// Amount of random bytes that go into each newly generated session key.
+    pub session_key_bytes: usize,
+    /// How long the user a session key belongs to is remembered for before it's looked up
+    /// again, `Duration::ZERO` to look it up on every request. Revoking a session clears it
+    /// straight away on the instance that revoked it, others will keep accepting it for up to
+    /// this long.
+    pub session_cache_ttl: Duration,
+    /// Categories crates are allowed to be published with, `None` if any category is fine.
+    pub categories: Option<Vec<String>>,
+    /// What to do with a publish using a category that isn't in `categories`.
+    pub category_validation: CategoryValidation,
+    /// How often to check the tarballs recorded in the database against those in storage,
+    /// `None` if the check shouldn't run at all.
+    ///
+    /// Publishes in flight are only known to the instance serving them, so with
+    /// `reconcile_reclaim` on this should only be set on a deployment running a single
+    /// instance. Otherwise a tarball another instance is publishing can be deleted from
+    /// under it.
+    pub reconcile_interval: Option<Duration>,
+    /// Yanks versions whose tarballs have gone missing when reconciling, rather than only
+    /// logging them.
+    pub reconcile_repair: bool,
+    /// Deletes storage objects no version has referred to for a whole run when reconciling,
+    /// reclaiming the space used by versions that have been removed or replaced.
+    pub reconcile_reclaim: bool,
+    /// How much a search term matching a crate's name, description or keywords counts
+    /// towards its position in the search results.
+    pub search_weights: SearchWeights,
+    /// Which other sites are allowed to call the API from the browser, such as the frontend
+    /// when it's served from a different host.
+    pub cors: CorsConfig,
+    /// Address to serve `/metrics` and `/status/db-pool` on, away from the rest of the API.
+    /// Neither is served if this is `None`.
+    pub metrics_bind_address: Option<SocketAddr>,
+    /// Where crate tarballs are written to.
+    pub storage: StorageConfig,
+    /// Directory the local backend keeps tarballs in, created on startup if it doesn't exist.
+    /// Still read from when using S3, for anything published before switching over.
+    pub local_storage_root: PathBuf,
+    /// Hashes tarballs as they're downloaded and cuts the download short if they don't match
+    /// the checksum they were published with, rather than serving whatever storage has
+    /// corrupted them into. Costs a hash of every download, so it's off by default.
+    pub verify_on_read: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum StorageConfig {
+    /// The local disk of whichever machine handled the publish.
+    Local,
+    S3(chartered_fs::S3Config),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StorageBackend {
+    Local,
+    S3,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(Self::Local),
+            "s3" => Ok(Self::S3),
+            _ => Err(format!("expected `local` or `s3`, got `{}`", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    /// Origins allowed to make cross-origin requests, matched exactly against the `Origin`
+    /// header. Wildcards aren't supported, every origin has to be listed. There's no default,
+    /// startup fails if `CHARTERED_CORS_ALLOWED_ORIGINS` isn't set.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<Method>,
+    pub allowed_headers: Vec<String>,
+    /// Whether the browser is allowed to send cookies and the like along with requests.
+    pub allow_credentials: bool,
+    /// How long browsers can cache the response to a preflight request for.
+    pub max_age: Duration,
+}
+
+/// Comma separated list of values, such as `GET,POST`. Empty entries are skipped.
+struct CommaSeparated<T>(Vec<T>);
+
+impl<T: FromStr> FromStr for CommaSeparated<T> {
+    type Err = T::Err;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// An origin as sent by browsers in the `Origin` header, such as `https://example.com:8080`.
+struct Origin(String);
+
+impl FromStr for Origin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Err("wildcard origins aren't supported, list each origin instead".to_string());
+        }
+
+        let host = s
+            .strip_prefix("https://")
+            .or_else(|| s.strip_prefix("http://"))
+            .ok_or_else(|| format!("expected an http or https origin, got `{}`", s))?;
+
+        if host.is_empty() || host.contains('/') {
+            return Err(format!(
+                "`{}` isn't an origin, it should be a scheme and host with no path",
+                s
+            ));
+        }
+
+        Ok(Self(s.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublishOverflow {
+    /// Wait for one of the in-flight publishes to finish.
+    Queue,
+    /// Reject the publish with a `429 Too Many Requests`.
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// A line of text, similar to a web server's access log.
+    Text,
+    /// A JSON object per request, for log aggregators that want structured logs.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("expected `text` or `json`, got `{}`", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CategoryValidation {
+    /// Accept the publish, but tell the user which categories weren't recognised.
+    Warn,
+    /// Reject the publish.
+    Enforce,
+}
+
+impl FromStr for CategoryValidation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(Self::Warn),
+            "enforce" => Ok(Self::Enforce),
+            _ => Err(format!("expected `warn` or `enforce`, got `{}`", s)),
+        }
+    }
+}
+
+impl FromStr for PublishOverflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queue" => Ok(Self::Queue),
+            "reject" => Ok(Self::Reject),
+            _ => Err(format!("expected `queue` or `reject`, got `{}`", s)),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, Error> {
+        Ok(Self {
+            allow_yanked_version_overwrite: env_or(
+                "CHARTERED_ALLOW_YANKED_VERSION_OVERWRITE",
+                false,
+            )?,
+            require_increasing_versions: env_or("CHARTERED_REQUIRE_INCREASING_VERSIONS", false)?,
+            max_concurrent_publishes: match env_or("CHARTERED_MAX_CONCURRENT_PUBLISHES", 8)? {
+                0 => {
+                    return Err(Error::InvalidValue(
+                        "CHARTERED_MAX_CONCURRENT_PUBLISHES",
+                        "must be at least 1".to_string(),
+                    ))
+                }
+                v => v,
+            },
+            publish_overflow: env_or("CHARTERED_PUBLISH_OVERFLOW", PublishOverflow::Queue)?,
+            publish_spill_threshold: env_or(
+                "CHARTERED_PUBLISH_SPILL_THRESHOLD_BYTES",
+                10 * 1024 * 1024,
+            )?,
+            max_publish_size: match env_or("CHARTERED_MAX_PUBLISH_SIZE_BYTES", 50 * 1024 * 1024)? {
+                0 => {
+                    return Err(Error::InvalidValue(
+                        "CHARTERED_MAX_PUBLISH_SIZE_BYTES",
+                        "must be at least 1".to_string(),
+                    ))
+                }
+                v => v,
+            },
+            data_export_interval: Duration::from_secs(env_or(
+                "CHARTERED_DATA_EXPORT_INTERVAL_SECS",
+                60 * 60,
+            )?),
+            debug_log_bodies: env_or("CHARTERED_DEBUG_LOG_BODIES", false)?,
+            debug_log_body_limit: env_or("CHARTERED_DEBUG_LOG_BODY_LIMIT_BYTES", 16 * 1024)?,
+            log_format: env_or("CHARTERED_LOG_FORMAT", LogFormat::Text)?,
+            session_key_bytes: match env_or(
+                "CHARTERED_SESSION_KEY_BYTES",
+                chartered_db::users::DEFAULT_SESSION_KEY_BYTES,
+            )? {
+                v if v < chartered_db::users::MIN_SESSION_KEY_BYTES => {
+                    return Err(Error::InvalidValue(
+                        "CHARTERED_SESSION_KEY_BYTES",
+                        format!(
+                            "must be at least {}",
+                            chartered_db::users::MIN_SESSION_KEY_BYTES
+                        ),
+                    ))
+                }
+                v => v,
+            },
+            session_cache_ttl: Duration::from_secs(env_or("CHARTERED_SESSION_CACHE_TTL_SECS", 30)?),
+            categories: std::env::var("CHARTERED_CATEGORIES").ok().map(|v| {
+                v.split(',')
+                    .map(str::trim)
+                    .filter(|v| !v.is_empty())
+                    .map(ToString::to_string)
+                    .collect()
+            }),
+            category_validation: env_or("CHARTERED_CATEGORY_VALIDATION", CategoryValidation::Warn)?,
+            reconcile_interval: match env_or("CHARTERED_RECONCILE_INTERVAL_SECS", 0)? {
+                0 => None,
+                v => Some(Duration::from_secs(v)),
+            },
+            reconcile_repair: env_or("CHARTERED_RECONCILE_REPAIR", false)?,
+            reconcile_reclaim: env_or("CHARTERED_RECONCILE_RECLAIM", true)?,
+            search_weights: SearchWeights {
+                name: env_or(
+                    "CHARTERED_SEARCH_NAME_WEIGHT",
+                    SearchWeights::default().name,
+                )?,
+                description: env_or(
+                    "CHARTERED_SEARCH_DESCRIPTION_WEIGHT",
+                    SearchWeights::default().description,
+                )?,
+                keywords: env_or(
+                    "CHARTERED_SEARCH_KEYWORDS_WEIGHT",
+                    SearchWeights::default().keywords,
+                )?,
+            },
+            cors: CorsConfig {
+                // this used to allow any origin, guessing at a narrower default would quietly
+                // break every deployment serving the frontend from somewhere else
+                allowed_origins: std::env::var("CHARTERED_CORS_ALLOWED_ORIGINS")
+                    .map_err(|_| {
+                        Error::InvalidValue(
+                            "CHARTERED_CORS_ALLOWED_ORIGINS",
+                            "must be set to the origins the frontend is served from, such as \
+                             `https://chartered.example.com`"
+                                .to_string(),
+                        )
+                    })?
+                    .parse::<CommaSeparated<Origin>>()
+                    .map_err(|e| Error::InvalidValue("CHARTERED_CORS_ALLOWED_ORIGINS", e))?
+                    .0
+                    .into_iter()
+                    .map(|v| v.0)
+                    .collect(),
+                allowed_methods: env_or(
+                    "CHARTERED_CORS_ALLOWED_METHODS",
+                    CommaSeparated(vec![
+                        Method::GET,
+                        Method::POST,
+                        Method::PATCH,
+                        Method::DELETE,
+                        Method::PUT,
+                    ]),
+                )?
+                .0,
+                allowed_headers: env_or(
+                    "CHARTERED_CORS_ALLOWED_HEADERS",
+                    CommaSeparated(vec![
+                        "authorization".to_string(),
+                        "content-type".to_string(),
+                    ]),
+                )?
+                .0,
+                allow_credentials: env_or("CHARTERED_CORS_ALLOW_CREDENTIALS", false)?,
+                max_age: Duration::from_secs(env_or("CHARTERED_CORS_MAX_AGE_SECS", 60 * 60)?),
+            },
+            metrics_bind_address: match std::env::var("CHARTERED_METRICS_BIND_ADDRESS") {
+                Ok(v) => Some(v.parse().map_err(|e: std::net::AddrParseError| {
+                    Error::InvalidValue("CHARTERED_METRICS_BIND_ADDRESS", e.to_string())
+                })?),
+                Err(_) => None,
+            },
+            storage: match env_or("CHARTERED_STORAGE_BACKEND", StorageBackend::Local)? {
+                StorageBackend::Local => StorageConfig::Local,
+                StorageBackend::S3 => StorageConfig::S3(chartered_fs::S3Config {
+                    bucket: std::env::var("CHARTERED_S3_BUCKET").map_err(|_| {
+                        Error::InvalidValue(
+                            "CHARTERED_S3_BUCKET",
+                            "must be set when using S3 storage".to_string(),
+                        )
+                    })?,
+                    region: env_or("CHARTERED_S3_REGION", "us-east-1".to_string())?,
+                    endpoint: std::env::var("CHARTERED_S3_ENDPOINT").ok(),
+                    credentials: match (
+                        std::env::var("CHARTERED_S3_ACCESS_KEY_ID"),
+                        std::env::var("CHARTERED_S3_SECRET_ACCESS_KEY"),
+                    ) {
+                        (Ok(access_key_id), Ok(secret_access_key)) => {
+                            Some((access_key_id, secret_access_key))
+                        }
+                        (Err(_), Err(_)) => None,
+                        _ => {
+                            return Err(Error::InvalidValue(
+                                "CHARTERED_S3_ACCESS_KEY_ID",
+                                "must be given along with `CHARTERED_S3_SECRET_ACCESS_KEY`"
+                                    .to_string(),
+                            ))
+                        }
+                    },
+                    prefix: env_or("CHARTERED_S3_PREFIX", String::new())?,
+                }),
+            },
+            local_storage_root: env_or("CHARTERED_LOCAL_STORAGE_ROOT", PathBuf::from("/tmp"))?,
+            verify_on_read: env_or("CHARTERED_VERIFY_ON_READ", false)?,
+        })
+    }
+}
+
+fn env_or<T: FromStr>(key: &'static str, default: T) -> Result<T, Error>
+where
+    T::Err: Display,
+{
+    match std::env::var(key) {
+        Ok(v) => v
+            .parse()
+            .map_err(|e: T::Err| Error::InvalidValue(key, e.to_string())),
+        Err(_) => Ok(default),
+    }
+}
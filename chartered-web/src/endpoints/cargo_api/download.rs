@@ -1,8 +1,29 @@
-use axum::extract;
-use chartered_db::{crates::Crate, users::User, ConnectionPool};
+use axum::{
+    body::Body,
+    extract,
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE, ETAG},
+        Method, Response,
+    },
+};
+use chartered_db::{
+    crates::{Crate, CrateVersion},
+    users::User,
+    ConnectionPool,
+};
 use chartered_fs::FileSystem;
+use log::warn;
 use std::{str::FromStr, sync::Arc};
 use thiserror::Error;
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    config::Config,
+    middleware::{
+        auth::{Read, RequireScope},
+        logging::RequestId,
+    },
+};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -29,24 +50,112 @@ impl Error {
 define_error_response!(Error);
 
 pub async fn handle(
-    extract::Path((_session_key, name, organisation, version)): extract::Path<(
+    _scope: RequireScope<Read>,
+    extract::Path((_session_key, organisation, name, version)): extract::Path<(
         String,
         String,
         String,
         String,
     )>,
+    method: Method,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(storage): extract::Extension<Arc<dyn FileSystem>>,
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(request_id): extract::Extension<RequestId>,
+) -> Result<Response<Body>, Error> {
+    download(
+        db,
+        user,
+        storage.as_ref(),
+        config.verify_on_read,
+        method,
+        organisation,
+        name,
+        version,
+        request_id,
+    )
+    .await
+}
+
+/// Same as [`handle`] but without the session key in the path, so links to a download can be
+/// shared around a team. The user's session key is instead given in the `Authorization` header.
+pub async fn handle_permalink(
+    _scope: RequireScope<Read>,
+    extract::Path((organisation, name, version)): extract::Path<(String, String, String)>,
+    method: Method,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
-) -> Result<Vec<u8>, Error> {
+    extract::Extension(storage): extract::Extension<Arc<dyn FileSystem>>,
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(request_id): extract::Extension<RequestId>,
+) -> Result<Response<Body>, Error> {
+    download(
+        db,
+        user,
+        storage.as_ref(),
+        config.verify_on_read,
+        method,
+        organisation,
+        name,
+        version,
+        request_id,
+    )
+    .await
+}
+
+/// Streams the crate's tarball out of storage rather than reading it into memory first, so
+/// large crates being pulled by a whole CI fleet at once don't have to fit in memory.
+#[allow(clippy::too_many_arguments)]
+async fn download(
+    db: ConnectionPool,
+    user: Arc<User>,
+    storage: &dyn FileSystem,
+    verify: bool,
+    method: Method,
+    organisation: String,
+    name: String,
+    version: String,
+    request_id: RequestId,
+) -> Result<Response<Body>, Error> {
     let crate_with_permissions =
         Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
 
     let version = crate_with_permissions
-        .version(db, version)
+        .version(db.clone(), version)
         .await?
         .ok_or(Error::NoVersion)?;
 
-    let file_ref = chartered_fs::FileReference::from_str(&version.filesystem_object).unwrap();
+    let file_ref = chartered_fs::FileReference::from_str(&version.filesystem_object)?;
+    // by the time a mismatch is found the headers and most of the body have already gone out,
+    // all that's left to do is to cut the download short so the client never sees it complete
+    let (file, len) = if verify {
+        storage.open_verified(file_ref, &version.checksum).await?
+    } else {
+        storage.open(file_ref).await?
+    };
+
+    // a `HEAD` is only checking the crate's there, nothing's actually being downloaded. the
+    // count isn't worth holding up the download for so it's recorded in the background
+    if method == Method::GET {
+        let version_id = version.id;
+
+        tokio::spawn(async move {
+            if let Err(e) = CrateVersion::record_download(db, version_id).await {
+                warn!(
+                    "Failed to record download of version {}: {} (request {})",
+                    version_id, e, request_id
+                );
+            }
+        });
+    }
 
-    Ok(chartered_fs::Local.read(file_ref).await?)
+    // `get` routes also answer `HEAD` requests with the body stripped, so anything a client
+    // might want to check without downloading the whole crate needs to be sent as a header
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/gzip")
+        .header(CONTENT_LENGTH, len)
+        .header(ETAG, format!("\"{}\"", version.checksum))
+        .body(Body::wrap_stream(ReaderStream::new(file)))
+        .unwrap())
 }
@@ -1,10 +1,14 @@
 mod download;
 mod owners;
 mod publish;
+mod search;
 mod yank;
 
-pub use download::handle as download;
-pub use owners::handle_get as get_owners;
-pub use publish::handle as publish;
+pub use download::{handle as download, handle_permalink as download_permalink};
+pub use owners::{
+    handle_delete as delete_owners, handle_get as get_owners, handle_put as put_owners,
+};
+pub use publish::{handle as publish, PublishLimiter};
+pub use search::handle as search;
 pub use yank::handle_unyank as unyank;
 pub use yank::handle_yank as yank;
@@ -1,19 +1,45 @@
+//! Backs `cargo owner`. Cargo's idea of an owner is anyone able to manage the crate's members,
+//! so adding an owner gives them every permission needed to maintain the crate and removing
+//! one takes away their ability to manage members while leaving the rest of their permissions
+//! alone.
+
 use axum::{extract, Json};
-use chartered_db::{crates::Crate, users::User, ConnectionPool};
-use serde::Serialize;
-use std::sync::Arc;
+use chartered_db::{
+    crates::Crate,
+    users::{User, UserCratePermissionValue as Permission},
+    ConnectionPool,
+};
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, sync::Arc};
 use thiserror::Error;
 
+use crate::middleware::auth::{Manage, Read, RequireScope};
+use crate::webhooks::{self, MemberAction};
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("{0}")]
     Database(#[from] chartered_db::Error),
+    #[error("No user named `{0}` exists")]
+    UnknownUser(String),
+    #[error("`{0}` isn't an owner of this crate")]
+    NotAnOwner(String),
+    #[error("`{0}` owns this crate through its organisation, their permissions need to be changed there")]
+    OrganisationOwner(String),
+    #[error("Removing every owner would leave nobody able to manage this crate")]
+    LastOwner,
 }
 
 impl Error {
     pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
         match self {
             Self::Database(e) => e.status_code(),
+            Self::UnknownUser(_) | Self::NotAnOwner(_) | Self::OrganisationOwner(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            Self::LastOwner => StatusCode::CONFLICT,
         }
     }
 }
@@ -27,13 +53,15 @@ pub struct GetResponse {
 
 #[derive(Serialize)]
 pub struct GetResponseUser {
-    // cargo spec says this should be an unsigned 32-bit integer
-    // uuid: chartered_db::uuid::Uuid,
+    // cargo spec says this should be an unsigned 32-bit integer, so we can't give out the
+    // uuid here
+    id: u32,
     login: String,
     name: Option<String>,
 }
 
 pub async fn handle_get(
+    _scope: RequireScope<Read>,
     extract::Path((_session_key, organisation, name)): extract::Path<(String, String, String)>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
@@ -46,7 +74,7 @@ pub async fn handle_get(
         .await?
         .into_iter()
         .map(|user| GetResponseUser {
-            // uuid: user.uuid.0,
+            id: u32::try_from(user.id).unwrap_or_default(),
             login: user.username,
             name: None,
         })
@@ -54,3 +82,197 @@ pub async fn handle_get(
 
     Ok(Json(GetResponse { users }))
 }
+
+/// Permissions given to users added as an owner through cargo.
+fn owner_permissions() -> Permission {
+    Permission::VISIBLE
+        | Permission::PUBLISH_VERSION
+        | Permission::YANK_VERSION
+        | Permission::MANAGE_USERS
+}
+
+/// Names of each of the permissions in `permissions`, as they're named everywhere else
+/// permissions are shown.
+fn permission_names(permissions: Permission) -> Vec<&'static str> {
+    Permission::describe()
+        .into_iter()
+        .filter(|(_, flag, _)| permissions.contains(*flag))
+        .map(|(name, _, _)| name)
+        .collect()
+}
+
+#[derive(Deserialize)]
+pub struct ChangeRequest {
+    users: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct ChangeResponse {
+    ok: bool,
+    msg: String,
+}
+
+async fn find_users(db: &ConnectionPool, usernames: Vec<String>) -> Result<Vec<User>, Error> {
+    let mut users = Vec::with_capacity(usernames.len());
+
+    for username in usernames {
+        match User::find_by_username(db.clone(), username.clone()).await? {
+            Some(user) => users.push(user),
+            None => return Err(Error::UnknownUser(username)),
+        }
+    }
+
+    Ok(users)
+}
+
+pub async fn handle_put(
+    _scope: RequireScope<Manage>,
+    extract::Path((_session_key, organisation, name)): extract::Path<(String, String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Json(req): extract::Json<ChangeRequest>,
+) -> Result<Json<ChangeResponse>, Error> {
+    let crate_with_permissions = Arc::new(
+        Crate::find_by_name(db.clone(), user.id, organisation.clone(), name.clone()).await?,
+    );
+
+    // every user is looked up before anything is changed so a typo doesn't leave the owners
+    // half updated
+    let new_owners = find_users(&db, req.users).await?;
+    let members = crate_with_permissions.clone().members(db.clone()).await?;
+
+    for new_owner in &new_owners {
+        let existing = members
+            .iter()
+            .find(|(member, _)| member.id == new_owner.id)
+            .map(|(_, permissions)| *permissions);
+        let permissions = existing.unwrap_or_else(Permission::empty) | owner_permissions();
+
+        let added = crate_with_permissions
+            .clone()
+            .grant_permissions(db.clone(), new_owner.id, permissions)
+            .await?;
+
+        webhooks::dispatch(
+            db.clone(),
+            crate_with_permissions.crate_.organisation_id,
+            webhooks::Event::PermissionChange {
+                organisation: organisation.clone(),
+                crate_name: name.clone(),
+                action: if added {
+                    MemberAction::Added
+                } else {
+                    MemberAction::Updated
+                },
+                actor: user.username.clone(),
+                user: new_owner.username.clone(),
+                permissions: Some(permissions),
+            },
+        );
+    }
+
+    Ok(Json(ChangeResponse {
+        ok: true,
+        msg: format!(
+            "{} now own{} {} with the permissions {}",
+            new_owners
+                .iter()
+                .map(|v| v.username.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            if new_owners.len() == 1 { "s" } else { "" },
+            name,
+            permission_names(owner_permissions()).join(", "),
+        ),
+    }))
+}
+
+pub async fn handle_delete(
+    _scope: RequireScope<Manage>,
+    extract::Path((_session_key, organisation, name)): extract::Path<(String, String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Json(req): extract::Json<ChangeRequest>,
+) -> Result<Json<ChangeResponse>, Error> {
+    let crate_with_permissions = Arc::new(
+        Crate::find_by_name(db.clone(), user.id, organisation.clone(), name.clone()).await?,
+    );
+
+    let removed_owners = find_users(&db, req.users).await?;
+    let members = crate_with_permissions.clone().members(db.clone()).await?;
+    let owners = crate_with_permissions.clone().owners(db.clone()).await?;
+
+    let mut changes = Vec::with_capacity(removed_owners.len());
+
+    for removed_owner in &removed_owners {
+        if !owners.iter().any(|v| v.id == removed_owner.id) {
+            return Err(Error::NotAnOwner(removed_owner.username.clone()));
+        }
+
+        match members
+            .iter()
+            .find(|(member, _)| member.id == removed_owner.id)
+        {
+            Some((_, permissions)) if permissions.contains(Permission::MANAGE_USERS) => {
+                changes.push((removed_owner, *permissions - Permission::MANAGE_USERS));
+            }
+            _ => return Err(Error::OrganisationOwner(removed_owner.username.clone())),
+        }
+    }
+
+    if owners
+        .iter()
+        .all(|owner| removed_owners.iter().any(|v| v.id == owner.id))
+    {
+        return Err(Error::LastOwner);
+    }
+
+    for (removed_owner, permissions) in changes {
+        crate_with_permissions
+            .clone()
+            .update_permissions(db.clone(), removed_owner.id, permissions)
+            .await?;
+
+        webhooks::dispatch(
+            db.clone(),
+            crate_with_permissions.crate_.organisation_id,
+            webhooks::Event::PermissionChange {
+                organisation: organisation.clone(),
+                crate_name: name.clone(),
+                action: MemberAction::Updated,
+                actor: user.username.clone(),
+                user: removed_owner.username.clone(),
+                permissions: Some(permissions),
+            },
+        );
+    }
+
+    Ok(Json(ChangeResponse {
+        ok: true,
+        msg: format!(
+            "{} no longer own{} {}",
+            removed_owners
+                .iter()
+                .map(|v| v.username.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            if removed_owners.len() == 1 { "s" } else { "" },
+            name,
+        ),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{owner_permissions, permission_names, Permission};
+
+    #[test]
+    fn owners_can_manage_members() {
+        assert!(owner_permissions().contains(Permission::MANAGE_USERS));
+        assert!(!owner_permissions().contains(Permission::CREATE_CRATE));
+
+        let names = permission_names(owner_permissions());
+        assert_eq!(names.len(), 4);
+        assert!(names.iter().all(|v| Permission::names().contains(v)));
+    }
+}
@@ -1,11 +1,46 @@
-use axum::extract;
-use bytes::Bytes;
-use chartered_db::{crates::Crate, users::User, ConnectionPool};
-use chartered_fs::FileSystem;
+use axum::extract::{self, BodyStream, TypedHeader};
+use bytes::{Bytes, BytesMut};
+use chartered_db::{
+    crates::{Crate, PublishedVersion},
+    users::User,
+    ConnectionPool,
+};
+use chartered_fs::{FileReference, FileSystem};
+use futures::{stream::BoxStream, Stream, StreamExt};
+use headers::ContentLength;
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::{borrow::Cow, convert::TryInto, sync::Arc};
+use std::{
+    borrow::Cow,
+    convert::TryInto,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Instant,
+};
 use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, ReadBuf},
+    sync::{Semaphore, SemaphorePermit},
+};
+use tokio_util::io::StreamReader;
+
+use crate::middleware::{
+    auth::{Publish, RequireScope},
+    logging::RequestId,
+};
+use crate::{
+    config::{Config, PublishOverflow},
+    metrics::Metrics,
+    reconcile::{InFlight, InFlightGuard},
+    storage,
+    validation::{
+        check_categories, check_dependencies, check_keywords, check_version_increases, validate,
+        Violation,
+    },
+    webhooks,
+};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -15,6 +50,18 @@ pub enum Error {
     JsonParse(#[from] serde_json::Error),
     #[error("Invalid body")]
     MetadataParse,
+    #[error("{0}")]
+    Invalid(#[from] Violation),
+    #[error("Failed to read body from client")]
+    BodyRead,
+    #[error("Failed to store crate")]
+    Storage(#[from] std::io::Error),
+    #[error("Too many crates are being published right now, please try again later")]
+    TooManyPublishes,
+    #[error("Crate is too large, the most that can be published is {0} bytes")]
+    PayloadTooLarge(u64),
+    #[error("Checksum of the uploaded crate ({actual}) doesn't match the expected {expected}, the upload may have been truncated")]
+    ChecksumMismatch { expected: String, actual: String },
 }
 
 impl Error {
@@ -23,13 +70,60 @@ impl Error {
 
         match self {
             Self::Database(e) => e.status_code(),
-            Self::JsonParse(_) | Self::MetadataParse => StatusCode::BAD_REQUEST,
+            Self::JsonParse(_)
+            | Self::MetadataParse
+            | Self::BodyRead
+            | Self::Invalid(_)
+            | Self::ChecksumMismatch { .. } => StatusCode::BAD_REQUEST,
+            Self::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::TooManyPublishes => StatusCode::TOO_MANY_REQUESTS,
+            Self::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
         }
     }
 }
 
 define_error_response!(Error);
 
+/// Limits the amount of publishes processed at once, so a burst of publishes (ie. from a CI
+/// fan-out) doesn't overwhelm the storage backend or the database.
+pub struct PublishLimiter {
+    semaphore: Semaphore,
+    overflow: PublishOverflow,
+}
+
+impl PublishLimiter {
+    pub fn new(max_concurrent: usize, overflow: PublishOverflow) -> Self {
+        Self {
+            semaphore: Semaphore::new(max_concurrent),
+            overflow,
+        }
+    }
+
+    async fn acquire(&self, request_id: &RequestId) -> Result<SemaphorePermit<'_>, Error> {
+        if let Ok(permit) = self.semaphore.try_acquire() {
+            return Ok(permit);
+        }
+
+        if self.overflow == PublishOverflow::Reject {
+            return Err(Error::TooManyPublishes);
+        }
+
+        let start = Instant::now();
+        let permit = self
+            .semaphore
+            .acquire()
+            .await
+            .map_err(|_| Error::TooManyPublishes)?;
+        info!(
+            "Publish waited {:?} for a free slot (request {})",
+            start.elapsed(),
+            request_id
+        );
+
+        Ok(permit)
+    }
+}
+
 #[derive(Serialize, Debug, Default)]
 pub struct PublishCrateResponse {
     warnings: PublishCrateResponseWarnings,
@@ -43,14 +137,66 @@ pub struct PublishCrateResponseWarnings {
 }
 
 pub async fn handle(
+    _scope: RequireScope<Publish>,
     extract::Path((_session_key, organisation)): extract::Path<(String, String)>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
-    body: Bytes,
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(limiter): extract::Extension<Arc<PublishLimiter>>,
+    extract::Extension(metrics): extract::Extension<Arc<Metrics>>,
+    extract::Extension(storage): extract::Extension<Arc<dyn FileSystem>>,
+    extract::Extension(in_flight): extract::Extension<Arc<InFlight>>,
+    extract::Extension(request_id): extract::Extension<RequestId>,
+    content_length: Option<TypedHeader<ContentLength>>,
+    body: BodyStream,
 ) -> Result<axum::response::Json<PublishCrateResponse>, Error> {
-    let (_, (metadata_bytes, crate_bytes)) =
-        parse(body.as_ref()).map_err(|_| Error::MetadataParse)?;
-    let metadata: Metadata = serde_json::from_slice(metadata_bytes)?;
+    let content_length = content_length.map(|TypedHeader(ContentLength(len))| len);
+
+    // checked before waiting on a slot, there's no point queueing a publish we'll never take
+    if content_length.map_or(false, |len| len > config.max_publish_size) {
+        return Err(Error::PayloadTooLarge(config.max_publish_size));
+    }
+
+    let _permit = limiter.acquire(&request_id).await?;
+
+    // bodies that don't say how large they are could be anything up to `max_publish_size`, so
+    // they're streamed rather than risking buffering all of it
+    let stream = content_length.map_or(true, |len| len > config.publish_spill_threshold);
+
+    let (metadata_bytes, crate_body) = if stream {
+        read_streamed(body, config.max_publish_size).await?
+    } else {
+        let body = collect(body, config.max_publish_size).await?;
+        let (_, (metadata_bytes, crate_bytes)) =
+            parse(body.as_ref()).map_err(|_| Error::MetadataParse)?;
+
+        (
+            body.slice_ref(metadata_bytes),
+            CrateBody::InMemory(body.slice_ref(crate_bytes)),
+        )
+    };
+    let mut metadata: Metadata = serde_json::from_slice(&metadata_bytes)?;
+
+    if let Some(violation) = validate(&metadata.inner).into_iter().next() {
+        return Err(violation.into());
+    }
+
+    let invalid_categories = check_categories(
+        &metadata.meta.categories,
+        config.categories.as_deref(),
+        config.category_validation,
+    )?;
+
+    let (keywords, keyword_warnings) = check_keywords(std::mem::take(&mut metadata.meta.keywords));
+    metadata.meta.keywords = keywords;
+
+    if let Some(violation) = check_dependencies(&db, &user, &organisation, &metadata.inner.deps)
+        .await?
+        .into_iter()
+        .next()
+    {
+        return Err(violation.into());
+    }
 
     let crate_with_permissions = Crate::find_by_name(
         db.clone(),
@@ -66,7 +212,7 @@ pub async fn handle(
             let new_crate = Crate::create(
                 db.clone(),
                 user.id,
-                organisation,
+                organisation.clone(),
                 metadata.inner.name.to_string(),
             )
             .await?;
@@ -75,21 +221,329 @@ pub async fn handle(
         Err(e) => return Err(e.into()),
     };
 
-    let file_ref = chartered_fs::Local.write(crate_bytes).await.unwrap();
+    let name = metadata.inner.name.to_string();
+    let version = metadata.inner.vers.to_string();
+
+    if config.require_increasing_versions {
+        let existing = crate_with_permissions
+            .clone()
+            .version_numbers(db.clone())
+            .await?;
+        check_version_increases(&version, &existing)?;
+    }
 
+    // turn away republishes before the tarball is written, rather than leaving it orphaned
+    // in storage once `publish_version` rejects it
     crate_with_permissions
+        .clone()
+        .check_version_available(
+            db.clone(),
+            version.clone(),
+            config.allow_yanked_version_overwrite,
+        )
+        .await?;
+
+    let (file_ref, checksum, _in_flight) = crate_body
+        .store(
+            storage.as_ref(),
+            &in_flight,
+            metadata.cksum.as_deref(),
+            &request_id,
+        )
+        .await?;
+
+    let published = crate_with_permissions
         .publish_version(
-            db,
-            user,
+            db.clone(),
+            user.clone(),
             file_ref,
-            hex::encode(Sha256::digest(crate_bytes)),
+            checksum.clone(),
             metadata_bytes.len().try_into().unwrap(),
             metadata.inner.into_owned(),
             metadata.meta,
+            config.allow_yanked_version_overwrite,
         )
         .await?;
 
-    Ok(axum::response::Json(PublishCrateResponse::default()))
+    if let PublishedVersion::ReplacedYanked {
+        previous_checksum,
+        previous_filesystem_object,
+    } = published
+    {
+        warn!(
+            "User {} overwrote yanked version {}#{} (checksum {} -> {}) (request {})",
+            user.username, name, version, previous_checksum, checksum, request_id
+        );
+
+        storage::release(storage.as_ref(), &previous_filesystem_object).await;
+    }
+
+    metrics.record_publish();
+
+    webhooks::dispatch(
+        db,
+        crate_with_permissions.crate_.organisation_id,
+        webhooks::Event::Publish {
+            organisation,
+            crate_name: name,
+            version,
+            user: user.username.clone(),
+        },
+    );
+
+    Ok(axum::response::Json(PublishCrateResponse {
+        warnings: PublishCrateResponseWarnings {
+            invalid_categories,
+            other: keyword_warnings,
+            ..PublishCrateResponseWarnings::default()
+        },
+    }))
+}
+
+/// Buffers the whole body in memory, giving up as soon as it's gone over `max_size` bytes as
+/// the `Content-Length` can't be relied on.
+async fn collect(mut body: BodyStream, max_size: u64) -> Result<Bytes, Error> {
+    let mut buf = BytesMut::new();
+
+    while let Some(chunk) = body.next().await {
+        buf.extend_from_slice(&chunk.map_err(|_| Error::BodyRead)?);
+        check_size(buf.len() as u64, max_size)?;
+    }
+
+    Ok(buf.freeze())
+}
+
+fn check_size(read: u64, max_size: u64) -> Result<(), Error> {
+    if read > max_size {
+        Err(Error::PayloadTooLarge(max_size))
+    } else {
+        Ok(())
+    }
+}
+
+/// The `.crate` file from a publish, either held in memory or still to be read from the
+/// client.
+enum CrateBody {
+    InMemory(Bytes),
+    Streamed { reader: BodyReader, len: u64 },
+}
+
+impl CrateBody {
+    /// Writes the crate out to `storage`, returning a reference to it along with its checksum.
+    /// If the client sent the checksum it `expected` the crate is compared against it, and
+    /// nothing is kept in storage if they differ.
+    ///
+    /// The object is claimed in `in_flight` so the reconciler leaves it alone, the returned
+    /// guard should be held until the version referencing it has been inserted.
+    async fn store(
+        self,
+        storage: &dyn FileSystem,
+        in_flight: &Arc<InFlight>,
+        expected: Option<&str>,
+        request_id: &RequestId,
+    ) -> Result<(FileReference, String, InFlightGuard), Error> {
+        match self {
+            Self::InMemory(bytes) => {
+                let checksum = hex::encode(Sha256::digest(&bytes));
+                verify_checksum(expected, &checksum)?;
+
+                // the same tarball published again, such as to another organisation, is only
+                // stored once. that might mean nothing is written at all so it has to be
+                // claimed beforehand, the existing object could be orphaned and about to go
+                let guard = in_flight
+                    .claim(storage.create_content_addressed_ref(&bytes))
+                    .await;
+                let file_ref = storage
+                    .write_content_addressed(&bytes)
+                    .await
+                    .map_err(|e| log_storage_error(e, request_id))?;
+                Ok((file_ref, checksum, guard))
+            }
+            Self::Streamed { mut reader, len } => {
+                let mut hashing = HashingReader::new((&mut reader).take(len));
+
+                let written = storage.write_reader(&mut hashing).await;
+                let file_ref = match written {
+                    Ok(file_ref) => file_ref,
+                    Err(_) if hashing.failed => return Err(Error::BodyRead),
+                    Err(e) => return Err(log_storage_error(e, request_id)),
+                };
+                // always a brand new object, which can't have been orphaned before now
+                let written_guard = in_flight.claim(file_ref.clone()).await;
+
+                let (read, checksum) = hashing.finish();
+
+                // the crate's only seen in full once it's already been written, so it has to be
+                // taken back out of storage if it turns out to be wrong
+                let verified = if read == len {
+                    verify_checksum(expected, &checksum)
+                } else {
+                    Err(Error::MetadataParse)
+                };
+
+                if let Err(e) = verified {
+                    if let Err(e) = storage.delete(file_ref.clone()).await {
+                        warn!(
+                            "Failed to remove rejected crate {}: {} (request {})",
+                            file_ref, e, request_id
+                        );
+                    }
+
+                    return Err(e);
+                }
+
+                // now the contents are known it can be moved to where the same tarball published
+                // before would be, claimed first for the same reason as above
+                let content_addressed = storage
+                    .content_addressed_ref(&checksum)
+                    .map_err(|e| log_storage_error(e, request_id))?;
+                let guard = in_flight.claim(content_addressed.clone()).await;
+
+                if let Err(e) = storage
+                    .rename_content_addressed(file_ref.clone(), &content_addressed)
+                    .await
+                {
+                    if let Err(e) = storage.delete(file_ref.clone()).await {
+                        warn!(
+                            "Failed to remove unstored crate {}: {} (request {})",
+                            file_ref, e, request_id
+                        );
+                    }
+
+                    return Err(log_storage_error(e, request_id));
+                }
+
+                drop(written_guard);
+
+                Ok((content_addressed, checksum, guard))
+            }
+        }
+    }
+}
+
+fn verify_checksum(expected: Option<&str>, actual: &str) -> Result<(), Error> {
+    match expected {
+        Some(expected) if !expected.eq_ignore_ascii_case(actual) => Err(Error::ChecksumMismatch {
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// The client only ever sees "Failed to store crate", so the reason is logged for whoever's
+/// running the registry.
+fn log_storage_error(e: std::io::Error, request_id: &RequestId) -> Error {
+    error!(
+        "Failed to write crate to storage: {} (request {})",
+        e, request_id
+    );
+    Error::Storage(e)
+}
+
+/// A publish body being read as it's received.
+type BodyReader = StreamReader<BoxStream<'static, Result<Bytes, std::io::Error>>, Bytes>;
+
+/// Reads the metadata from the front of `body`, leaving the crate itself to be streamed
+/// straight into storage. The declared lengths are checked against `max_size` before anything
+/// is read, and never more than they declare is read, so a bogus length can't cause a huge
+/// allocation or an oversized crate.
+async fn read_streamed<S, E>(body: S, max_size: u64) -> Result<(Bytes, CrateBody), Error>
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Send + 'static,
+{
+    let body = body.map(|chunk| {
+        chunk.map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::Other, "failed to read body from client")
+        })
+    });
+    let mut reader: BodyReader = StreamReader::new(body.boxed());
+
+    let metadata_len = u64::from(
+        reader
+            .read_u32_le()
+            .await
+            .map_err(|_| Error::MetadataParse)?,
+    );
+    check_size(4 + metadata_len + 4, max_size)?;
+
+    let mut metadata_bytes = Vec::new();
+    (&mut reader)
+        .take(metadata_len)
+        .read_to_end(&mut metadata_bytes)
+        .await
+        .map_err(|_| Error::BodyRead)?;
+    if metadata_bytes.len() as u64 != metadata_len {
+        return Err(Error::MetadataParse);
+    }
+
+    let crate_len = u64::from(
+        reader
+            .read_u32_le()
+            .await
+            .map_err(|_| Error::MetadataParse)?,
+    );
+    check_size(4 + metadata_len + 4 + crate_len, max_size)?;
+
+    Ok((
+        Bytes::from(metadata_bytes),
+        CrateBody::Streamed {
+            reader,
+            len: crate_len,
+        },
+    ))
+}
+
+/// Hashes everything read through it, so a crate's checksum can be worked out as it's
+/// streamed into storage.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    read: u64,
+    /// Whether `inner` returned an error, as opposed to whatever was reading from this.
+    failed: bool,
+}
+
+impl<R> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            read: 0,
+            failed: false,
+        }
+    }
+
+    /// Returns how many bytes were read along with their hex encoded sha256.
+    fn finish(self) -> (u64, String) {
+        (self.read, hex::encode(self.hasher.finalize()))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let already_filled = buf.filled().len();
+
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                let read = &buf.filled()[already_filled..];
+                this.hasher.update(read);
+                this.read += read.len() as u64;
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => {
+                this.failed = true;
+                Poll::Ready(Err(e))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
 }
 
 fn parse(body: &[u8]) -> nom::IResult<&[u8], (&[u8], &[u8])> {
@@ -115,15 +569,151 @@ pub struct Metadata<'a> {
     #[serde(borrow)]
     readme_file: Option<Cow<'a, str>>,
     #[serde(borrow)]
-    keywords: Vec<Cow<'a, str>>,
-    #[serde(borrow)]
-    categories: Vec<Cow<'a, str>>,
-    #[serde(borrow)]
     license: Option<Cow<'a, str>>,
     #[serde(borrow)]
     license_file: Option<Cow<'a, str>>,
+    /// sha256 of the `.crate` file, cargo doesn't send this itself but other clients may so
+    /// truncated uploads can be caught.
+    #[serde(default, borrow)]
+    cksum: Option<Cow<'a, str>>,
     #[serde(flatten)]
     meta: chartered_types::cargo::CrateVersionMetadata,
     #[serde(flatten)]
     inner: chartered_types::cargo::CrateVersion<'a>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::{check_size, read_streamed, verify_checksum, Error};
+    use crate::{middleware::logging::RequestId, reconcile::InFlight};
+    use bytes::Bytes;
+    use chartered_fs::{FileSystem, Memory};
+    use futures::stream;
+    use sha2::{Digest, Sha256};
+    use std::{convert::Infallible, sync::Arc};
+
+    /// Builds a publish body the way cargo does, split into `chunk_size` chunks as it'd arrive
+    /// from the client.
+    fn body(
+        metadata: &[u8],
+        crate_bytes: &[u8],
+        chunk_size: usize,
+    ) -> impl futures::Stream<Item = Result<Bytes, Infallible>> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+        body.extend_from_slice(metadata);
+        body.extend_from_slice(&(crate_bytes.len() as u32).to_le_bytes());
+        body.extend_from_slice(crate_bytes);
+
+        let chunks: Vec<_> = body
+            .chunks(chunk_size)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        stream::iter(chunks)
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn streamed_body() {
+        let storage = Memory::new();
+        // large enough that buffering it would be noticed
+        let crate_bytes: Vec<u8> = (0..32 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let expected = hex::encode(Sha256::digest(&crate_bytes));
+
+        let (metadata, crate_body) = read_streamed(
+            body(br#"{"name":"foo"}"#, &crate_bytes, 64 * 1024),
+            64 * 1024 * 1024,
+        )
+        .await
+        .unwrap();
+        assert_eq!(metadata, &br#"{"name":"foo"}"#[..]);
+
+        let in_flight = Arc::new(InFlight::new());
+        let request_id = RequestId("test".to_string());
+        let (file_ref, checksum, _guard) = crate_body
+            .store(&storage, &in_flight, Some(&expected), &request_id)
+            .await
+            .unwrap();
+        assert_eq!(checksum, expected);
+        assert!(file_ref.is_content_addressed());
+        assert!(storage.read(file_ref.clone()).await.unwrap() == crate_bytes);
+
+        // the same crate streamed again ends up in the same object
+        let (_, crate_body) = read_streamed(body(b"{}", &crate_bytes, 64 * 1024), 64 * 1024 * 1024)
+            .await
+            .unwrap();
+        let (again, _, _guard) = crate_body
+            .store(&storage, &in_flight, None, &request_id)
+            .await
+            .unwrap();
+        assert_eq!(again, file_ref);
+        assert_eq!(storage.list().await.unwrap(), [file_ref]);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn streamed_body_rejections() {
+        let storage = Memory::new();
+        let in_flight = Arc::new(InFlight::new());
+        let request_id = RequestId("test".to_string());
+
+        // declares more than it's allowed to send, turned away before anything's read
+        assert!(matches!(
+            read_streamed(body(b"{}", &[0; 1024], 100), 512).await,
+            Err(Error::PayloadTooLarge(512))
+        ));
+
+        // the crate doesn't match its checksum, so it's taken back out of storage
+        let (_, crate_body) = read_streamed(body(b"{}", b"abc", 2), 1024).await.unwrap();
+        assert!(matches!(
+            crate_body
+                .store(&storage, &in_flight, Some("abcd"), &request_id)
+                .await,
+            Err(Error::ChecksumMismatch { .. })
+        ));
+        assert!(storage.list().await.unwrap().is_empty());
+
+        // cut off partway through the crate
+        let mut truncated = Vec::new();
+        truncated.extend_from_slice(&2_u32.to_le_bytes());
+        truncated.extend_from_slice(b"{}");
+        truncated.extend_from_slice(&100_u32.to_le_bytes());
+        truncated.extend_from_slice(b"abc");
+        let (_, crate_body) = read_streamed(
+            stream::iter(vec![Ok::<_, Infallible>(Bytes::from(truncated))]),
+            1024,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            crate_body
+                .store(&storage, &in_flight, None, &request_id)
+                .await,
+            Err(Error::MetadataParse)
+        ));
+        assert!(storage.list().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn size_limit_is_inclusive() {
+        assert!(check_size(0, 10).is_ok());
+        assert!(check_size(10, 10).is_ok());
+        assert!(matches!(
+            check_size(11, 10),
+            Err(Error::PayloadTooLarge(10))
+        ));
+    }
+
+    #[test]
+    fn checksum_is_only_checked_when_given() {
+        let actual = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+        assert!(verify_checksum(None, actual).is_ok());
+        assert!(verify_checksum(Some(actual), actual).is_ok());
+        assert!(verify_checksum(Some(&actual.to_uppercase()), actual).is_ok());
+        assert!(matches!(
+            verify_checksum(Some("abcd"), actual),
+            Err(Error::ChecksumMismatch { expected, .. }) if expected == "abcd"
+        ));
+    }
+}
@@ -0,0 +1,100 @@
+//! Backs `cargo search`, see [`chartered_db::crates::Crate::search`] for how crates are
+//! matched and [`crate::config::Config::search_weights`] for how they're ranked.
+
+use axum::{extract, Json};
+use chartered_db::{
+    crates::{Crate, CrateVersion},
+    users::User,
+    ConnectionPool,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::config::Config;
+use crate::middleware::auth::{Read, RequireScope};
+
+/// Most results cargo can ask for in a single request.
+const MAX_PER_PAGE: usize = 100;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        match self {
+            Self::Database(e) => e.status_code(),
+        }
+    }
+}
+
+define_error_response!(Error);
+
+#[derive(Deserialize)]
+pub struct RequestParams {
+    q: String,
+    per_page: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct Response {
+    crates: Vec<ResponseCrate>,
+    meta: ResponseMeta,
+}
+
+#[derive(Serialize)]
+pub struct ResponseCrate {
+    name: String,
+    max_version: String,
+    description: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ResponseMeta {
+    total: usize,
+}
+
+pub async fn handle(
+    _scope: RequireScope<Read>,
+    extract::Path((_session_key, organisation)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Query(req): extract::Query<RequestParams>,
+) -> Result<Json<Response>, Error> {
+    let results = Crate::search(db, user.id, organisation, req.q, config.search_weights).await?;
+    let total = results.len();
+
+    let crates = results
+        .into_iter()
+        .take(req.per_page.unwrap_or(10).min(MAX_PER_PAGE))
+        .map(|(crate_, versions)| ResponseCrate {
+            max_version: max_version(&versions),
+            name: crate_.name,
+            description: crate_.description,
+        })
+        .collect();
+
+    Ok(Json(Response {
+        crates,
+        meta: ResponseMeta { total },
+    }))
+}
+
+/// The highest version that hasn't been yanked, falling back to the highest version if they've
+/// all been yanked. Versions published before they were required to be semver sort below any
+/// that are, and amongst themselves by when they were published.
+fn max_version(versions: &[CrateVersion<'_>]) -> String {
+    let key = |v: &&CrateVersion<'_>| (semver::Version::parse(&v.version).ok(), v.id);
+
+    versions
+        .iter()
+        .filter(|v| !v.yanked)
+        .max_by_key(key)
+        .or_else(|| versions.iter().max_by_key(key))
+        .map(|v| v.version.clone())
+        .unwrap_or_default()
+}
@@ -4,6 +4,9 @@ use serde::Serialize;
 use std::sync::Arc;
 use thiserror::Error;
 
+use crate::middleware::auth::{Publish, RequireScope};
+use crate::webhooks;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("{0}")]
@@ -26,7 +29,8 @@ pub struct Response {
 }
 
 pub async fn handle_yank(
-    extract::Path((_session_key, name, organisation, version)): extract::Path<(
+    _scope: RequireScope<Publish>,
+    extract::Path((_session_key, organisation, name, version)): extract::Path<(
         String,
         String,
         String,
@@ -35,18 +39,12 @@ pub async fn handle_yank(
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
 ) -> Result<Json<Response>, Error> {
-    let crate_with_permissions =
-        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
-
-    crate_with_permissions
-        .yank_version(db, version, true)
-        .await?;
-
-    Ok(Json(Response { ok: true }))
+    set_yanked(db, user, organisation, name, version, true).await
 }
 
 pub async fn handle_unyank(
-    extract::Path((_session_key, name, organisation, version)): extract::Path<(
+    _scope: RequireScope<Publish>,
+    extract::Path((_session_key, organisation, name, version)): extract::Path<(
         String,
         String,
         String,
@@ -55,12 +53,37 @@ pub async fn handle_unyank(
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
 ) -> Result<Json<Response>, Error> {
-    let crate_with_permissions =
-        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
+    set_yanked(db, user, organisation, name, version, false).await
+}
+
+async fn set_yanked(
+    db: ConnectionPool,
+    user: Arc<User>,
+    organisation: String,
+    name: String,
+    version: String,
+    yanked: bool,
+) -> Result<Json<Response>, Error> {
+    let crate_with_permissions = Arc::new(
+        Crate::find_by_name(db.clone(), user.id, organisation.clone(), name.clone()).await?,
+    );
 
     crate_with_permissions
-        .yank_version(db, version, false)
+        .clone()
+        .yank_version(db.clone(), version.clone(), yanked)
         .await?;
 
+    webhooks::dispatch(
+        db,
+        crate_with_permissions.crate_.organisation_id,
+        webhooks::Event::Yank {
+            organisation,
+            crate_name: name,
+            version,
+            yanked,
+            user: user.username.clone(),
+        },
+    );
+
     Ok(Json(Response { ok: true }))
 }
@@ -3,6 +3,18 @@ pub struct ErrorResponse {
     error: Option<String>,
 }
 
+/// Fallback for any route that doesn't exist, so clients always get back the same JSON error
+/// envelope instead of an empty body.
+#[allow(clippy::unused_async)]
+pub async fn not_found() -> (axum::http::StatusCode, axum::Json<ErrorResponse>) {
+    (
+        axum::http::StatusCode::NOT_FOUND,
+        axum::Json(ErrorResponse {
+            error: Some("not found".to_string()),
+        }),
+    )
+}
+
 macro_rules! define_error_response {
     ($error:ty) => {
         impl crate::middleware::logging::GenericError for $error {}
@@ -1,22 +1,32 @@
-use axum::{body::Full, extract, response::IntoResponse, Json};
+use axum::{
+    body::Full,
+    extract,
+    http::header::{CONTENT_LENGTH, CONTENT_TYPE, ETAG},
+};
 use bytes::Bytes;
 use chartered_db::{crates::Crate, users::User, ConnectionPool};
 use chartered_types::cargo::CrateVersion;
 use chrono::TimeZone;
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::sync::Arc;
 use thiserror::Error;
 
+use crate::middleware::auth::{Read, RequireScope};
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("{0}")]
     Database(#[from] chartered_db::Error),
+    #[error("Failed to serialise response")]
+    Serialise(#[from] serde_json::Error),
 }
 
 impl Error {
     pub fn status_code(&self) -> axum::http::StatusCode {
         match self {
             Self::Database(e) => e.status_code(),
+            Self::Serialise(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
@@ -24,6 +34,7 @@ impl Error {
 define_error_response!(Error);
 
 pub async fn handle(
+    _scope: RequireScope<Read>,
     extract::Path((_session_key, organisation, name)): extract::Path<(String, String, String)>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
@@ -41,25 +52,37 @@ pub async fn handle(
     // version of each but we're using `spawn_blocking` in chartered-db for
     // diesel which requires `'static' which basically forces us to use Arc
     // if we want to keep a reference to anything ourselves.
-    Ok(Json(Response {
+    let body = serde_json::to_vec(&Response {
         info: (&crate_with_permissions.crate_).into(),
+        downloads: versions.iter().map(|(v, _)| i64::from(v.downloads)).sum(),
         versions: versions
             .into_iter()
             .map(|(v, user)| ResponseVersion {
                 size: v.size,
+                downloads: v.downloads,
                 created_at: chrono::Utc.from_local_datetime(&v.created_at).unwrap(),
                 inner: v.into_cargo_format(&crate_with_permissions.crate_),
                 uploader: user.username,
             })
             .collect(),
-    })
-    .into_response())
+    })?;
+
+    // `get` routes also answer `HEAD` requests with the body stripped, so the
+    // headers have to be set explicitly rather than derived from the body
+    Ok(axum::http::Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .header(CONTENT_LENGTH, body.len())
+        .header(ETAG, format!("\"{}\"", hex::encode(Sha256::digest(&body))))
+        .body(Full::from(body))
+        .unwrap())
 }
 
 #[derive(Serialize)]
 pub struct Response<'a> {
     #[serde(flatten)]
     info: ResponseInfo<'a>,
+    /// Downloads across every version of the crate.
+    downloads: i64,
     versions: Vec<ResponseVersion<'a>>,
 }
 
@@ -68,6 +91,7 @@ pub struct ResponseVersion<'a> {
     #[serde(flatten)]
     inner: CrateVersion<'a>,
     size: i32,
+    downloads: i32,
     created_at: chrono::DateTime<chrono::Utc>,
     uploader: String,
 }
@@ -9,12 +9,38 @@ use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use thiserror::Error;
 
-use crate::endpoints::ErrorResponse;
+use crate::middleware::auth::{Manage, Read, RequireScope};
+use crate::{
+    endpoints::ErrorResponse,
+    webhooks::{self, MemberAction},
+};
+
+/// Most members that can be asked for in a single request.
+const MAX_PER_PAGE: u32 = 100;
+
+/// Every member is returned if neither of these are given, as they were before members
+/// were paginated.
+#[derive(Deserialize)]
+pub struct GetRequestParams {
+    /// Which page of members to return, starting from 1.
+    page: Option<u32>,
+    per_page: Option<u32>,
+}
 
 #[derive(Serialize)]
 pub struct GetResponse {
     allowed_permissions: &'static [&'static str],
     members: Vec<GetResponseMember>,
+    meta: GetResponseMeta,
+}
+
+#[derive(Serialize)]
+pub struct GetResponseMeta {
+    /// Members across every page.
+    total: i64,
+    page: u32,
+    /// `None` if every member was returned.
+    per_page: Option<u32>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -25,16 +51,26 @@ pub struct GetResponseMember {
 }
 
 pub async fn handle_get(
+    _scope: RequireScope<Read>,
     extract::Path((_session_key, organisation, name)): extract::Path<(String, String, String)>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Query(req): extract::Query<GetRequestParams>,
 ) -> Result<Json<GetResponse>, Error> {
+    let page = req.page.unwrap_or(1).max(1);
+    let per_page = match (req.page, req.per_page) {
+        (None, None) => None,
+        (_, per_page) => Some(per_page.unwrap_or(MAX_PER_PAGE).clamp(1, MAX_PER_PAGE)),
+    };
+
     let crate_with_permissions =
         Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
 
-    let members = crate_with_permissions
-        .members(db)
-        .await?
+    let (members, total) = crate_with_permissions
+        .paginated_members(db, page - 1, per_page)
+        .await?;
+
+    let members = members
         .into_iter()
         .map(|(user, permissions)| GetResponseMember {
             uuid: user.uuid.0,
@@ -46,6 +82,11 @@ pub async fn handle_get(
     Ok(Json(GetResponse {
         allowed_permissions: Permission::names(),
         members,
+        meta: GetResponseMeta {
+            total,
+            page,
+            per_page,
+        },
     }))
 }
 
@@ -56,45 +97,77 @@ pub struct PutOrPatchRequest {
 }
 
 pub async fn handle_patch(
+    _scope: RequireScope<Manage>,
     extract::Path((_session_key, organisation, name)): extract::Path<(String, String, String)>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
     extract::Json(req): extract::Json<PutOrPatchRequest>,
 ) -> Result<Json<ErrorResponse>, Error> {
-    let crate_with_permissions =
-        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
+    let crate_with_permissions = Arc::new(
+        Crate::find_by_name(db.clone(), user.id, organisation.clone(), name.clone()).await?,
+    );
 
     let action_user = User::find_by_uuid(db.clone(), req.user_uuid)
         .await?
         .ok_or(Error::InvalidUserId)?;
 
     let affected_rows = crate_with_permissions
-        .update_permissions(db, action_user.id, req.permissions)
+        .clone()
+        .update_permissions(db.clone(), action_user.id, req.permissions)
         .await?;
     if affected_rows == 0 {
         return Err(Error::UpdateConflictRemoved);
     }
 
+    webhooks::dispatch(
+        db,
+        crate_with_permissions.crate_.organisation_id,
+        webhooks::Event::PermissionChange {
+            organisation,
+            crate_name: name,
+            action: MemberAction::Updated,
+            actor: user.username.clone(),
+            user: action_user.username,
+            permissions: Some(req.permissions),
+        },
+    );
+
     Ok(Json(ErrorResponse { error: None }))
 }
 
 pub async fn handle_put(
+    _scope: RequireScope<Manage>,
     extract::Path((_session_key, organisation, name)): extract::Path<(String, String, String)>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
     extract::Json(req): extract::Json<PutOrPatchRequest>,
 ) -> Result<Json<ErrorResponse>, Error> {
-    let crate_with_permissions =
-        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
+    let crate_with_permissions = Arc::new(
+        Crate::find_by_name(db.clone(), user.id, organisation.clone(), name.clone()).await?,
+    );
 
     let action_user = User::find_by_uuid(db.clone(), req.user_uuid)
         .await?
         .ok_or(Error::InvalidUserId)?;
 
     crate_with_permissions
-        .insert_permissions(db, action_user.id, req.permissions)
+        .clone()
+        .insert_permissions(db.clone(), action_user.id, req.permissions)
         .await?;
 
+    webhooks::dispatch(
+        db,
+        crate_with_permissions.crate_.organisation_id,
+        webhooks::Event::PermissionChange {
+            organisation,
+            crate_name: name,
+            action: MemberAction::Added,
+            actor: user.username.clone(),
+            user: action_user.username,
+            permissions: Some(req.permissions),
+        },
+    );
+
     Ok(Json(ErrorResponse { error: None }))
 }
 
@@ -104,22 +177,38 @@ pub struct DeleteRequest {
 }
 
 pub async fn handle_delete(
+    _scope: RequireScope<Manage>,
     extract::Path((_session_key, organisation, name)): extract::Path<(String, String, String)>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
     extract::Json(req): extract::Json<DeleteRequest>,
 ) -> Result<Json<ErrorResponse>, Error> {
-    let crate_with_permissions =
-        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
+    let crate_with_permissions = Arc::new(
+        Crate::find_by_name(db.clone(), user.id, organisation.clone(), name.clone()).await?,
+    );
 
     let action_user = User::find_by_uuid(db.clone(), req.user_uuid)
         .await?
         .ok_or(Error::InvalidUserId)?;
 
     crate_with_permissions
-        .delete_member(db, action_user.id)
+        .clone()
+        .delete_member(db.clone(), action_user.id)
         .await?;
 
+    webhooks::dispatch(
+        db,
+        crate_with_permissions.crate_.organisation_id,
+        webhooks::Event::PermissionChange {
+            organisation,
+            crate_name: name,
+            action: MemberAction::Removed,
+            actor: user.username.clone(),
+            user: action_user.username,
+            permissions: None,
+        },
+    );
+
     Ok(Json(ErrorResponse { error: None }))
 }
 
@@ -146,3 +235,31 @@ impl Error {
 }
 
 define_error_response!(Error);
+
+#[cfg(test)]
+mod test {
+    use super::Error;
+    use axum::http::StatusCode;
+    use chartered_db::users::UserCratePermissionValue as Permission;
+
+    #[test]
+    fn missing_crates_are_not_found() {
+        assert_eq!(
+            Error::from(chartered_db::Error::MissingCrate).status_code(),
+            StatusCode::NOT_FOUND
+        );
+
+        // crates the user can't see are indistinguishable from those that don't exist
+        assert_eq!(
+            Error::from(chartered_db::Error::MissingPermission(Permission::VISIBLE)).status_code(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            Error::from(chartered_db::Error::MissingPermission(
+                Permission::MANAGE_USERS
+            ))
+            .status_code(),
+            StatusCode::FORBIDDEN
+        );
+    }
+}
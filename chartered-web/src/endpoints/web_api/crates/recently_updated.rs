@@ -4,6 +4,8 @@ use serde::Serialize;
 use std::sync::Arc;
 use thiserror::Error;
 
+use crate::middleware::auth::{Read, RequireScope};
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("{0}")]
@@ -21,6 +23,7 @@ impl Error {
 define_error_response!(Error);
 
 pub async fn handle(
+    _scope: RequireScope<Read>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
 ) -> Result<Json<Response>, Error> {
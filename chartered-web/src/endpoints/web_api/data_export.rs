@@ -0,0 +1,336 @@
+//! Lets a user download everything we hold about them as a single JSON document. Only the
+//! authenticated user's own data is ever included.
+
+use axum::{
+    body::Body,
+    extract,
+    http::{header, Response},
+};
+use bytes::Bytes;
+use chartered_db::{
+    crates::CrateVersion,
+    users::{User, UserCratePermissionValue as Permission},
+    uuid::Uuid,
+    ConnectionPool,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use futures::{future, stream, StreamExt, TryStreamExt};
+use log::warn;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+use crate::middleware::{
+    auth::{Manage, RequireScope},
+    logging::RequestId,
+};
+
+/// Stops a user from requesting more than one export every `interval`, an export touches
+/// every table the user appears in so they're comparatively expensive to build.
+pub struct DataExportLimiter {
+    interval: Duration,
+    last_export: Mutex<HashMap<i32, Instant>>,
+}
+
+impl DataExportLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_export: Mutex::default(),
+        }
+    }
+
+    fn check(&self, user_id: i32) -> Result<(), Error> {
+        let now = Instant::now();
+        let mut last_export = self.last_export.lock().unwrap();
+
+        // forget about anyone that's allowed to export again so this doesn't grow forever
+        last_export.retain(|_, at| now.duration_since(*at) < self.interval);
+
+        if let Some(at) = last_export.get(&user_id) {
+            let remaining = self.interval - now.duration_since(*at);
+            return Err(Error::TooManyRequests(remaining.as_secs().max(1)));
+        }
+
+        last_export.insert(user_id, now);
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct Profile {
+    uuid: Uuid,
+    username: String,
+}
+
+#[derive(Serialize)]
+pub struct SshKey {
+    uuid: Uuid,
+    name: String,
+    fingerprint: String,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+/// Session keys are deliberately left out, the export is a file that'll be passed around
+/// and they're as good as a password.
+#[derive(Serialize)]
+pub struct Session {
+    ssh_key: Option<Uuid>,
+    expires_at: Option<DateTime<Utc>>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct Organisation {
+    name: String,
+    permissions: Permission,
+}
+
+#[derive(Serialize)]
+pub struct Crate {
+    organisation: String,
+    name: String,
+    permissions: Permission,
+}
+
+#[derive(Serialize)]
+pub struct PublishedVersion {
+    organisation: String,
+    #[serde(rename = "crate")]
+    crate_name: String,
+    version: String,
+    checksum: String,
+    size: i32,
+    yanked: bool,
+    published_at: DateTime<Utc>,
+}
+
+impl From<(String, String, CrateVersion<'static>)> for PublishedVersion {
+    fn from((organisation, crate_name, version): (String, String, CrateVersion<'static>)) -> Self {
+        Self {
+            organisation,
+            crate_name,
+            version: version.version,
+            checksum: version.checksum,
+            size: version.size,
+            yanked: version.yanked,
+            published_at: Utc.from_local_datetime(&version.created_at).unwrap(),
+        }
+    }
+}
+
+/// How many published versions are read from the database at once while they're being
+/// streamed out.
+const PUBLISHED_VERSIONS_PAGE_SIZE: u32 = 100;
+
+/// Everything in the export except for the published versions, which are streamed out
+/// after it a page at a time as there's no bound on how many there are.
+#[derive(Serialize)]
+struct Head {
+    profile: Profile,
+    ssh_keys: Vec<SshKey>,
+    sessions: Vec<Session>,
+    organisations: Vec<Organisation>,
+    crates: Vec<Crate>,
+}
+
+pub async fn handle(
+    _scope: RequireScope<Manage>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(limiter): extract::Extension<Arc<DataExportLimiter>>,
+    extract::Extension(request_id): extract::Extension<RequestId>,
+) -> Result<Response<Body>, Error> {
+    limiter.check(user.id)?;
+
+    let keys = user.clone().list_ssh_keys(db.clone()).await?;
+    let key_uuids: HashMap<_, _> = keys.iter().map(|key| (key.id, key.uuid.0)).collect();
+
+    let sessions = user
+        .clone()
+        .list_sessions(db.clone())
+        .await?
+        .into_iter()
+        .map(|session| Session {
+            ssh_key: session
+                .user_ssh_key_id
+                .and_then(|id| key_uuids.get(&id).copied()),
+            expires_at: session
+                .expires_at
+                .and_then(|v| Utc.from_local_datetime(&v).single()),
+            user_agent: session.user_agent,
+            ip: session.ip,
+            last_used_at: session
+                .last_used_at
+                .and_then(|v| Utc.from_local_datetime(&v).single()),
+        })
+        .collect();
+
+    let ssh_keys = keys
+        .into_iter()
+        .map(|key| SshKey {
+            uuid: key.uuid.0,
+            fingerprint: key.fingerprint().unwrap_or_else(|e| {
+                warn!(
+                    "Failed to parse key with id {}: {} (request {})",
+                    key.id, e, request_id
+                );
+                "INVALID".to_string()
+            }),
+            name: key.name,
+            created_at: Utc.from_local_datetime(&key.created_at).unwrap(),
+            last_used_at: key
+                .last_used_at
+                .and_then(|v| Utc.from_local_datetime(&v).single()),
+        })
+        .collect();
+
+    let organisations = user
+        .clone()
+        .list_organisations(db.clone())
+        .await?
+        .into_iter()
+        .map(|(permissions, organisation)| Organisation {
+            name: organisation.name,
+            permissions,
+        })
+        .collect();
+
+    let crates = user
+        .clone()
+        .accessible_crates_with_organisation(db.clone())
+        .await?
+        .into_iter()
+        .map(|(permissions, crate_, organisation)| Crate {
+            organisation: organisation.name,
+            name: crate_.name,
+            permissions,
+        })
+        .collect();
+
+    let mut head = serde_json::to_vec(&Head {
+        profile: Profile {
+            uuid: user.uuid.0,
+            username: user.username.clone(),
+        },
+        ssh_keys,
+        sessions,
+        organisations,
+        crates,
+    })?;
+
+    // reopen the object so the published versions can be appended to it
+    head.pop();
+    head.extend_from_slice(br#","published_versions":["#);
+
+    // each page is only fetched once the last has been sent, so the versions are never all
+    // held in memory at once. the response has already started by the time a page fails to
+    // load, so all that can be done then is to cut it short
+    let versions = published_versions(db, user)
+        .enumerate()
+        .map(|(i, version)| {
+            let mut out = if i == 0 { Vec::new() } else { vec![b','] };
+            serde_json::to_writer(&mut out, &PublishedVersion::from(version?))?;
+            Ok::<_, Error>(Bytes::from(out))
+        });
+
+    let body = stream::once(future::ok(Bytes::from(head)))
+        .chain(versions)
+        .chain(stream::once(future::ok(Bytes::from_static(b"]}"))));
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"chartered-data-export.json\"",
+        )
+        .body(Body::wrap_stream(body))
+        .unwrap())
+}
+
+/// Streams every version `user` has published, fetching them from the database a page at a
+/// time.
+fn published_versions(
+    db: ConnectionPool,
+    user: Arc<User>,
+) -> impl futures::Stream<Item = Result<(String, String, CrateVersion<'static>), Error>> {
+    // `None` once the last page has been fetched, otherwise the id of the version to
+    // continue on from
+    stream::try_unfold(Some(None), move |after| {
+        let db = db.clone();
+        let user = user.clone();
+
+        async move {
+            let after = match after {
+                Some(after) => after,
+                None => return Ok(None),
+            };
+
+            let page = user
+                .published_versions(db, after, PUBLISHED_VERSIONS_PAGE_SIZE)
+                .await?;
+            let next = if page.len() < PUBLISHED_VERSIONS_PAGE_SIZE as usize {
+                None
+            } else {
+                page.last().map(|(_, _, version)| Some(version.id))
+            };
+
+            Ok::<_, Error>(Some((
+                stream::iter(page.into_iter().map(Ok::<_, Error>)),
+                next,
+            )))
+        }
+    })
+    .try_flatten()
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to query database")]
+    Database(#[from] chartered_db::Error),
+    #[error("Failed to serialise data export")]
+    Serialise(#[from] serde_json::Error),
+    #[error("A data export was requested too recently, try again in {0} seconds")]
+    TooManyRequests(u64),
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Database(e) => e.status_code(),
+            Self::Serialise(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+define_error_response!(Error);
+
+#[cfg(test)]
+mod test {
+    use super::{DataExportLimiter, Error};
+    use std::time::Duration;
+
+    #[test]
+    fn limiter_is_per_user() {
+        let limiter = DataExportLimiter::new(Duration::from_secs(60));
+
+        assert!(limiter.check(1).is_ok());
+        assert!(matches!(limiter.check(1), Err(Error::TooManyRequests(_))));
+        assert!(limiter.check(2).is_ok());
+
+        let limiter = DataExportLimiter::new(Duration::from_secs(0));
+        assert!(limiter.check(1).is_ok());
+        assert!(limiter.check(1).is_ok());
+    }
+}
@@ -0,0 +1,84 @@
+use axum::{extract, Json};
+use chartered_db::{users::User, ConnectionPool};
+use log::info;
+use serde::Deserialize;
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::{
+    endpoints::ErrorResponse,
+    middleware::{
+        auth::{Manage, RequireScope, SessionCache},
+        logging::RequestId,
+    },
+};
+
+#[derive(Deserialize)]
+pub struct RequestParams {
+    /// Username of the user to hand over any crates the deleted user is the last manager of.
+    reassign_to: Option<String>,
+}
+
+pub async fn handle(
+    _scope: RequireScope<Manage>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(cache): extract::Extension<Arc<SessionCache>>,
+    extract::Extension(request_id): extract::Extension<RequestId>,
+    extract::Query(req): extract::Query<RequestParams>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let reassign_to = match req.reassign_to {
+        Some(username) => {
+            let reassign_to = User::find_by_username(db.clone(), username.clone())
+                .await?
+                .ok_or(Error::UnknownUser(username))?;
+
+            if reassign_to.id == user.id {
+                return Err(Error::ReassignToSelf);
+            }
+
+            Some(Arc::new(reassign_to))
+        }
+        None => None,
+    };
+
+    let reassigned = user.clone().delete(db, reassign_to.clone()).await?;
+    cache.invalidate_user(user.id);
+
+    info!(
+        "User {} ({}) deleted their account (request {})",
+        user.username, user.uuid.0, request_id
+    );
+
+    if let Some(reassign_to) = reassign_to.filter(|_| !reassigned.is_empty()) {
+        info!(
+            "Crates {:?} last managed by {} were reassigned to {} ({}) (request {})",
+            reassigned, user.username, reassign_to.username, reassign_to.uuid.0, request_id
+        );
+    }
+
+    Ok(Json(ErrorResponse { error: None }))
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+    #[error("User {0} does not exist")]
+    UnknownUser(String),
+    #[error("Crates can't be reassigned to the user being deleted")]
+    ReassignToSelf,
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Database(e) => e.status_code(),
+            Self::UnknownUser(_) | Self::ReassignToSelf => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+define_error_response!(Error);
@@ -0,0 +1,46 @@
+//! Shows where in the index cargo will look a crate up, which isn't obvious for crates with
+//! names shorter than four characters.
+
+use axum::{extract, Json};
+use serde::Serialize;
+use thiserror::Error;
+
+#[derive(Serialize)]
+pub struct Response {
+    #[serde(rename = "crate")]
+    crate_name: String,
+    path: String,
+}
+
+#[allow(clippy::unused_async)]
+pub async fn handle(extract::Path(name): extract::Path<String>) -> Result<Json<Response>, Error> {
+    if !crate::validation::is_valid_crate_name(&name) {
+        return Err(Error::InvalidCrateName(name));
+    }
+
+    let path = chartered_types::cargo::index_path(&name)
+        .ok_or_else(|| Error::InvalidCrateName(name.clone()))?;
+
+    Ok(Json(Response {
+        crate_name: name,
+        path,
+    }))
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid crate name `{0}`, names must start with a letter and only contain ASCII letters, numbers, `-` or `_`")]
+    InvalidCrateName(String),
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::InvalidCrateName(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+define_error_response!(Error);
@@ -1,11 +1,14 @@
 use axum::{extract, Json};
 use chartered_db::{
-    users::{User, UserSession},
+    users::{SessionScopes, User, UserSession},
     ConnectionPool,
 };
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 use thiserror::Error;
 
+use crate::config::Config;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Failed to query database")]
@@ -29,6 +32,7 @@ define_error_response!(Error);
 
 pub async fn handle(
     extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(config): extract::Extension<Arc<Config>>,
     extract::Json(req): extract::Json<Request>,
     user_agent: Option<extract::TypedHeader<headers::UserAgent>>,
     extract::ConnectInfo(addr): extract::ConnectInfo<std::net::SocketAddr>,
@@ -48,11 +52,13 @@ pub async fn handle(
     let expires = chrono::Utc::now() + chrono::Duration::hours(1);
     let key = UserSession::generate(
         db,
+        config.session_key_bytes,
         user.id,
         None,
         Some(expires.naive_utc()),
         user_agent,
         Some(addr.to_string()),
+        SessionScopes::all(),
     )
     .await?;
 
@@ -1,10 +1,26 @@
 pub mod crates;
+mod data_export;
+mod delete_account;
+mod index_path;
 mod login;
+pub mod organisations;
+mod permissions;
+mod pool_stats;
 mod search_users;
+mod sessions;
 mod ssh_key;
 
+pub use data_export::{handle as data_export, DataExportLimiter};
+pub use delete_account::handle as delete_account;
+pub use index_path::handle as index_path;
 pub use login::handle as login;
+pub use permissions::handle as permissions;
+pub use pool_stats::handle as pool_stats;
 pub use search_users::handle as search_users;
+pub use sessions::{
+    handle_create as create_session, handle_delete as delete_session, handle_get as get_sessions,
+    handle_logout as logout, handle_rotate as rotate_session,
+};
 pub use ssh_key::{
     handle_delete as delete_ssh_key, handle_get as get_ssh_keys, handle_put as add_ssh_key,
 };
@@ -0,0 +1,223 @@
+//! Grants a user the same permissions on several crates in an organisation at once. Each
+//! crate is checked independently, so one that can't be granted, such as one the caller isn't
+//! able to manage, doesn't stop the rest from being granted. Those that can are then granted
+//! together, so either all of them land or none do. The outcome of every crate is given back
+//! so the caller can tell exactly which grants landed.
+
+use axum::{extract, Json};
+use chartered_db::{
+    crates::{Crate, CrateWithPermissions},
+    users::{Membership, Organisation, User, UserCratePermissionValue as Permission},
+    ConnectionPool,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::middleware::auth::{Manage, RequireScope};
+use crate::webhooks::{self, MemberAction};
+
+/// Most crates that can be granted in a single request.
+const MAX_CRATES: usize = 100;
+
+#[derive(Deserialize)]
+pub struct PutRequest {
+    user_uuid: chartered_db::uuid::Uuid,
+    permissions: Permission,
+    crates: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct PutResponse {
+    results: Vec<PutResponseResult>,
+}
+
+#[derive(Serialize)]
+pub struct PutResponseResult {
+    #[serde(rename = "crate")]
+    crate_name: String,
+    status: GrantStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GrantStatus {
+    /// The user wasn't a member of the crate before and has been added.
+    Added,
+    /// The user was already a member of the crate and has had their permissions replaced.
+    Updated,
+    Failed,
+}
+
+pub async fn handle_put(
+    _scope: RequireScope<Manage>,
+    extract::Path((_session_key, organisation)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Json(req): extract::Json<PutRequest>,
+) -> Result<Json<PutResponse>, Error> {
+    if req.crates.is_empty() {
+        return Err(Error::NoCrates);
+    } else if req.crates.len() > MAX_CRATES {
+        return Err(Error::TooManyCrates);
+    }
+
+    // the organisation has to be visible to the caller before anything about its members is
+    // given away
+    Organisation::find_by_name_with_permissions(db.clone(), user.id, organisation.clone()).await?;
+
+    let action_user = User::find_by_uuid(db.clone(), req.user_uuid)
+        .await?
+        .ok_or(Error::InvalidUserId)?;
+
+    match Organisation::membership(db.clone(), action_user.id, organisation.clone()).await? {
+        Membership::Member(_) => {}
+        Membership::NotMember => return Err(Error::NotOrganisationMember),
+        Membership::Missing => return Err(chartered_db::Error::MissingOrganisation.into()),
+    }
+
+    let mut results = Vec::with_capacity(req.crates.len());
+    let mut grantable = Vec::new();
+
+    for crate_name in req.crates {
+        match check_grantable(
+            db.clone(),
+            &user,
+            &organisation,
+            &crate_name,
+            req.permissions,
+        )
+        .await
+        {
+            Ok(crate_with_permissions) => {
+                grantable.push((results.len(), crate_with_permissions));
+                // replaced with the outcome once the grants have been made
+                results.push(PutResponseResult {
+                    crate_name,
+                    status: GrantStatus::Failed,
+                    error: None,
+                });
+            }
+            Err(e) => results.push(PutResponseResult {
+                crate_name,
+                status: GrantStatus::Failed,
+                error: Some(e.to_string()),
+            }),
+        }
+    }
+
+    if grantable.is_empty() {
+        return Ok(Json(PutResponse { results }));
+    }
+
+    let granted = CrateWithPermissions::grant_permissions_on_all(
+        db.clone(),
+        grantable.iter().map(|(_, v)| v.clone()).collect(),
+        action_user.id,
+        req.permissions,
+    )
+    .await;
+
+    match granted {
+        Ok(added) => {
+            for ((i, crate_with_permissions), added) in grantable.into_iter().zip(added) {
+                results[i].status = if added {
+                    GrantStatus::Added
+                } else {
+                    GrantStatus::Updated
+                };
+
+                webhooks::dispatch(
+                    db.clone(),
+                    crate_with_permissions.crate_.organisation_id,
+                    webhooks::Event::PermissionChange {
+                        organisation: organisation.clone(),
+                        crate_name: results[i].crate_name.clone(),
+                        action: if added {
+                            MemberAction::Added
+                        } else {
+                            MemberAction::Updated
+                        },
+                        actor: user.username.clone(),
+                        user: action_user.username.clone(),
+                        permissions: Some(req.permissions),
+                    },
+                );
+            }
+        }
+        // the grants are made together, so none of them have landed
+        Err(e) => {
+            for (i, _) in grantable {
+                results[i].error = Some(e.to_string());
+            }
+        }
+    }
+
+    Ok(Json(PutResponse { results }))
+}
+
+/// Looks up a crate the user is to be granted `permissions` on, checking the caller is able
+/// to grant them. Callers can't hand out permissions on a crate they don't hold themselves.
+async fn check_grantable(
+    db: ConnectionPool,
+    user: &User,
+    organisation: &str,
+    crate_name: &str,
+    permissions: Permission,
+) -> Result<Arc<CrateWithPermissions>, Error> {
+    let crate_with_permissions = Crate::find_by_name(
+        db,
+        user.id,
+        organisation.to_string(),
+        crate_name.to_string(),
+    )
+    .await?;
+
+    if !crate_with_permissions
+        .permissions
+        .contains(Permission::MANAGE_USERS)
+    {
+        return Err(chartered_db::Error::MissingPermission(Permission::MANAGE_USERS).into());
+    }
+
+    if !crate_with_permissions.permissions.contains(permissions) {
+        return Err(Error::PermissionsNotHeld);
+    }
+
+    Ok(Arc::new(crate_with_permissions))
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+    #[error("An invalid user id was given")]
+    InvalidUserId,
+    #[error("The user given isn't a member of the organisation")]
+    NotOrganisationMember,
+    #[error("Permissions can't be granted on a crate by a user that doesn't hold them")]
+    PermissionsNotHeld,
+    #[error("At least one crate must be given")]
+    NoCrates,
+    #[error("No more than {} crates can be given at once", MAX_CRATES)]
+    TooManyCrates,
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Database(e) => e.status_code(),
+            Self::InvalidUserId
+            | Self::NotOrganisationMember
+            | Self::NoCrates
+            | Self::TooManyCrates => StatusCode::BAD_REQUEST,
+            Self::PermissionsNotHeld => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+define_error_response!(Error);
@@ -0,0 +1,10 @@
+mod members;
+mod validate;
+mod webhooks;
+
+pub use members::handle_put as grant_members;
+pub use validate::handle as validate;
+pub use webhooks::{
+    handle_delete as delete_webhook, handle_get as get_webhooks,
+    handle_get_deliveries as get_webhook_deliveries, handle_put as insert_webhook,
+};
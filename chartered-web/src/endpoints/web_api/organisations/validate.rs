@@ -0,0 +1,121 @@
+//! Re-runs publish validation over every version already published to an organisation, so
+//! operators can find out what falls foul of any rules that have changed since.
+
+use axum::{extract, Json};
+use chartered_db::{
+    crates::Crate,
+    users::{Organisation, User, UserCratePermissionValue as Permission},
+    ConnectionPool,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::middleware::auth::{Read, RequireScope};
+use crate::{
+    config::Config,
+    validation::{check_categories, check_dependencies, validate},
+};
+
+#[derive(Serialize)]
+pub struct Response {
+    crates: Vec<ResponseCrate>,
+}
+
+#[derive(Serialize)]
+pub struct ResponseCrate {
+    name: String,
+    valid: bool,
+    /// Only versions with at least one violation are listed.
+    versions: Vec<ResponseVersion>,
+}
+
+#[derive(Serialize)]
+pub struct ResponseVersion {
+    version: String,
+    violations: Vec<String>,
+}
+
+pub async fn handle(
+    _scope: RequireScope<Read>,
+    extract::Path((_session_key, organisation)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+) -> Result<Json<Response>, Error> {
+    let found =
+        Organisation::find_by_name_with_permissions(db.clone(), user.id, organisation.clone())
+            .await?;
+
+    if !found.permissions.contains(Permission::MANAGE_USERS) {
+        return Err(chartered_db::Error::MissingPermission(Permission::MANAGE_USERS).into());
+    }
+
+    let mut crates = Vec::new();
+
+    for (crate_, versions) in
+        Crate::list_with_versions(db.clone(), user.id, organisation.clone()).await?
+    {
+        // categories are stored against the crate rather than each version, so they're only
+        // known for the most recently published one and are checked against that alone
+        let crate_categories: Vec<String> = crate_
+            .categories
+            .as_deref()
+            .map(|v| v.split(',').map(ToString::to_string).collect())
+            .unwrap_or_default();
+        let mut category_violation = check_categories(
+            &crate_categories,
+            config.categories.as_deref(),
+            config.category_validation,
+        )
+        .err();
+        let latest = versions.iter().map(|v| v.id).max();
+
+        let mut response_versions = Vec::new();
+
+        for version in versions {
+            let is_latest = Some(version.id) == latest;
+            let version = version.into_cargo_format(&crate_);
+
+            let mut violations = validate(&version);
+            violations.extend(check_dependencies(&db, &user, &organisation, &version.deps).await?);
+
+            if is_latest {
+                violations.extend(category_violation.take());
+            }
+
+            if !violations.is_empty() {
+                response_versions.push(ResponseVersion {
+                    version: version.vers.into_owned(),
+                    violations: violations.iter().map(ToString::to_string).collect(),
+                });
+            }
+        }
+
+        crates.push(ResponseCrate {
+            valid: response_versions.is_empty(),
+            versions: response_versions,
+            name: crate_.name,
+        });
+    }
+
+    crates.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(Json(Response { crates }))
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        match self {
+            Self::Database(e) => e.status_code(),
+        }
+    }
+}
+
+define_error_response!(Error);
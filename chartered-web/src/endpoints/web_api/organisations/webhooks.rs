@@ -0,0 +1,186 @@
+use axum::{extract, Json};
+use chartered_db::{
+    users::{Organisation, User},
+    uuid::Uuid,
+    webhooks::WebhookEvents,
+    ConnectionPool,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::endpoints::ErrorResponse;
+use crate::middleware::auth::{Manage, Read, RequireScope};
+use crate::webhooks::{check_destination, DestinationError};
+
+#[derive(Serialize)]
+pub struct GetResponse {
+    allowed_events: &'static [&'static str],
+    webhooks: Vec<GetResponseWebhook>,
+}
+
+#[derive(Serialize)]
+pub struct GetResponseWebhook {
+    uuid: Uuid,
+    url: String,
+    events: WebhookEvents,
+    created_at: DateTime<Utc>,
+}
+
+pub async fn handle_get(
+    _scope: RequireScope<Read>,
+    extract::Path((_session_key, organisation)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+) -> Result<Json<GetResponse>, Error> {
+    let organisation = Arc::new(
+        Organisation::find_by_name_with_permissions(db.clone(), user.id, organisation).await?,
+    );
+
+    let webhooks = organisation
+        .webhooks(db)
+        .await?
+        .into_iter()
+        .map(|webhook| GetResponseWebhook {
+            uuid: webhook.uuid.0,
+            url: webhook.url,
+            events: webhook.events,
+            created_at: Utc.from_local_datetime(&webhook.created_at).unwrap(),
+        })
+        .collect();
+
+    Ok(Json(GetResponse {
+        allowed_events: WebhookEvents::names(),
+        webhooks,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct PutRequest {
+    url: String,
+    secret: String,
+    events: WebhookEvents,
+}
+
+#[derive(Serialize)]
+pub struct PutResponse {
+    uuid: Uuid,
+}
+
+pub async fn handle_put(
+    _scope: RequireScope<Manage>,
+    extract::Path((_session_key, organisation)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Json(req): extract::Json<PutRequest>,
+) -> Result<Json<PutResponse>, Error> {
+    check_destination(&req.url).await?;
+
+    if req.secret.is_empty() {
+        return Err(Error::MissingSecret);
+    }
+
+    if req.events.is_empty() {
+        return Err(Error::NoEvents);
+    }
+
+    let organisation = Arc::new(
+        Organisation::find_by_name_with_permissions(db.clone(), user.id, organisation).await?,
+    );
+
+    let uuid = organisation
+        .insert_webhook(db, req.url, req.secret, req.events)
+        .await?;
+
+    Ok(Json(PutResponse { uuid }))
+}
+
+pub async fn handle_delete(
+    _scope: RequireScope<Manage>,
+    extract::Path((_session_key, organisation, webhook_id)): extract::Path<(String, String, Uuid)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let organisation = Arc::new(
+        Organisation::find_by_name_with_permissions(db.clone(), user.id, organisation).await?,
+    );
+
+    if organisation.delete_webhook(db, webhook_id).await? {
+        Ok(Json(ErrorResponse { error: None }))
+    } else {
+        Err(Error::NonExistentWebhook)
+    }
+}
+
+#[derive(Serialize)]
+pub struct GetDeliveriesResponse {
+    deliveries: Vec<GetDeliveriesResponseDelivery>,
+}
+
+#[derive(Serialize)]
+pub struct GetDeliveriesResponseDelivery {
+    webhook_uuid: Uuid,
+    url: String,
+    event: String,
+    status_code: Option<i32>,
+    error: Option<String>,
+    delivered_at: DateTime<Utc>,
+}
+
+pub async fn handle_get_deliveries(
+    _scope: RequireScope<Read>,
+    extract::Path((_session_key, organisation)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+) -> Result<Json<GetDeliveriesResponse>, Error> {
+    let organisation = Arc::new(
+        Organisation::find_by_name_with_permissions(db.clone(), user.id, organisation).await?,
+    );
+
+    let deliveries = organisation
+        .webhook_deliveries(db, 100)
+        .await?
+        .into_iter()
+        .map(|(delivery, webhook)| GetDeliveriesResponseDelivery {
+            webhook_uuid: webhook.uuid.0,
+            url: webhook.url,
+            event: delivery.event,
+            status_code: delivery.status_code,
+            error: delivery.error,
+            delivered_at: Utc.from_local_datetime(&delivery.delivered_at).unwrap(),
+        })
+        .collect();
+
+    Ok(Json(GetDeliveriesResponse { deliveries }))
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+    #[error("{0}")]
+    Destination(#[from] DestinationError),
+    #[error("A secret is required to sign webhook payloads")]
+    MissingSecret,
+    #[error("At least one event must be selected")]
+    NoEvents,
+    #[error("The webhook given does not exist")]
+    NonExistentWebhook,
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Database(e) => e.status_code(),
+            Self::Destination(_)
+            | Self::MissingSecret
+            | Self::NoEvents
+            | Self::NonExistentWebhook => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+define_error_response!(Error);
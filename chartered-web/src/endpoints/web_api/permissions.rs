@@ -0,0 +1,32 @@
+use axum::Json;
+use chartered_db::users::UserCratePermissionValue as Permission;
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct Response {
+    permissions: Vec<ResponsePermission>,
+}
+
+#[derive(Serialize)]
+pub struct ResponsePermission {
+    /// Stable name of the permission, as used everywhere else permissions are sent or
+    /// received by the API.
+    name: &'static str,
+    /// Value of the permission's flag in the permissions bitset.
+    bit: i32,
+    description: &'static str,
+}
+
+#[allow(clippy::unused_async)]
+pub async fn handle() -> Json<Response> {
+    Json(Response {
+        permissions: Permission::describe()
+            .into_iter()
+            .map(|(name, flag, description)| ResponsePermission {
+                name,
+                bit: flag.bits(),
+                description,
+            })
+            .collect(),
+    })
+}
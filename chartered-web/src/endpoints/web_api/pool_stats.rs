@@ -0,0 +1,7 @@
+use axum::{extract, Json};
+use chartered_db::{ConnectionPool, PoolStats};
+
+#[allow(clippy::unused_async)]
+pub async fn handle(extract::Extension(db): extract::Extension<ConnectionPool>) -> Json<PoolStats> {
+    Json(chartered_db::pool_stats(&db))
+}
@@ -3,6 +3,8 @@ use chartered_db::{users::User, ConnectionPool};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::middleware::auth::{Read, RequireScope};
+
 #[derive(Deserialize)]
 pub struct RequestParams {
     q: String,
@@ -20,6 +22,7 @@ pub struct ResponseUser {
 }
 
 pub async fn handle(
+    _scope: RequireScope<Read>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Query(req): extract::Query<RequestParams>,
 ) -> Result<Json<Response>, Error> {
@@ -0,0 +1,261 @@
+//! Lets a user see where they're logged in and revoke any sessions they no longer trust, along
+//! with rotating the key of the session they're currently using or logging out of it. Users
+//! can also create sessions limited to a subset of scopes, for handing to CI and the like.
+
+use axum::{extract, Json};
+use chartered_db::{
+    users::{SessionScopes, User, UserSession},
+    uuid::Uuid,
+    ConnectionPool,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+use thiserror::Error;
+
+use crate::{
+    config::Config,
+    endpoints::ErrorResponse,
+    middleware::auth::{Manage, Read, RequireScope, SessionCache},
+};
+
+#[derive(Serialize)]
+pub struct GetResponse {
+    sessions: Vec<GetResponseSession>,
+}
+
+/// Session keys themselves are never given back out, only enough to tell the sessions apart.
+#[derive(Serialize)]
+pub struct GetResponseSession {
+    id: i32,
+    /// Whether this is the session the request was made with.
+    current: bool,
+    ssh_key: Option<Uuid>,
+    expires_at: Option<DateTime<Utc>>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    last_used_at: Option<DateTime<Utc>>,
+    scopes: SessionScopes,
+}
+
+pub async fn handle_get(
+    _scope: RequireScope<Read>,
+    extract::Path(session_key): extract::Path<String>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+) -> Result<Json<GetResponse>, Error> {
+    let key_uuids: HashMap<_, _> = user
+        .clone()
+        .list_ssh_keys(db.clone())
+        .await?
+        .into_iter()
+        .map(|key| (key.id, key.uuid.0))
+        .collect();
+
+    let now = Utc::now().naive_utc();
+
+    let sessions = user
+        .list_sessions(db)
+        .await?
+        .into_iter()
+        .filter(|session| session.expires_at.map_or(true, |v| v > now))
+        .map(|session| GetResponseSession {
+            id: session.id,
+            current: session.session_key == session_key,
+            ssh_key: session
+                .user_ssh_key_id
+                .and_then(|id| key_uuids.get(&id).copied()),
+            expires_at: session
+                .expires_at
+                .and_then(|v| Utc.from_local_datetime(&v).single()),
+            user_agent: session.user_agent,
+            ip: session.ip,
+            last_used_at: session
+                .last_used_at
+                .and_then(|v| Utc.from_local_datetime(&v).single()),
+            scopes: session.scopes,
+        })
+        .collect();
+
+    Ok(Json(GetResponse { sessions }))
+}
+
+#[derive(Deserialize)]
+pub struct CreateRequest {
+    scopes: SessionScopes,
+    /// How many seconds the key is valid for, `None` if it should never expire.
+    expires_in: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct CreateResponse {
+    key: String,
+    expires: Option<DateTime<Utc>>,
+    scopes: SessionScopes,
+}
+
+/// Creates a new session for the user with only the given scopes, such as a key CI can publish
+/// with but not manage members with. The new session can't be given any scope the session
+/// creating it doesn't have.
+pub async fn handle_create(
+    _scope: RequireScope<Manage>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(current_scopes): extract::Extension<SessionScopes>,
+    user_agent: Option<extract::TypedHeader<headers::UserAgent>>,
+    extract::ConnectInfo(addr): extract::ConnectInfo<std::net::SocketAddr>,
+    extract::Json(req): extract::Json<CreateRequest>,
+) -> Result<Json<CreateResponse>, Error> {
+    if req.scopes.is_empty() {
+        return Err(Error::NoScopes);
+    }
+
+    if !current_scopes.contains(req.scopes) {
+        return Err(Error::ExcessScopes);
+    }
+
+    let expires = match req.expires_in {
+        Some(secs) => Some(
+            chrono::Duration::from_std(std::time::Duration::from_secs(secs))
+                .ok()
+                .and_then(|v| Utc::now().checked_add_signed(v))
+                .ok_or(Error::InvalidExpiry)?,
+        ),
+        None => None,
+    };
+
+    let session = UserSession::generate(
+        db,
+        config.session_key_bytes,
+        user.id,
+        None,
+        expires.map(|v| v.naive_utc()),
+        user_agent.map(|extract::TypedHeader(v)| v.as_str().to_string()),
+        Some(addr.to_string()),
+        req.scopes,
+    )
+    .await?;
+
+    Ok(Json(CreateResponse {
+        key: session.session_key,
+        expires,
+        scopes: session.scopes,
+    }))
+}
+
+pub async fn handle_delete(
+    _scope: RequireScope<Manage>,
+    extract::Path((_session_key, session_id)): extract::Path<(String, i32)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(cache): extract::Extension<Arc<SessionCache>>,
+) -> Result<Json<ErrorResponse>, Error> {
+    if user.clone().delete_session(db, session_id).await? {
+        // we don't know which key the session had, so none of the user's can be trusted
+        cache.invalidate_user(user.id);
+        Ok(Json(ErrorResponse { error: None }))
+    } else {
+        Err(Error::NonExistentSession)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LogoutParams {
+    /// Revokes every one of the user's sessions rather than only the current one.
+    #[serde(default)]
+    all: bool,
+}
+
+#[derive(Serialize)]
+pub struct LogoutResponse {
+    revoked: usize,
+}
+
+/// Revokes the session the request was made with, or every session the user has if `all` is
+/// given. Any further requests made with the revoked keys are unauthorised.
+pub async fn handle_logout(
+    extract::Path(session_key): extract::Path<String>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(cache): extract::Extension<Arc<SessionCache>>,
+    extract::Extension(scopes): extract::Extension<SessionScopes>,
+    extract::Query(params): extract::Query<LogoutParams>,
+) -> Result<Json<LogoutResponse>, Error> {
+    // any key can log itself out, but a CI key shouldn't be able to log the user out everywhere
+    if params.all && !scopes.contains(SessionScopes::MANAGE) {
+        return Err(Error::ManageScopeRequired);
+    }
+
+    // the cache is only cleared once the keys are gone from the database, otherwise a request
+    // arriving in between could look a key up again and cache it for another whole TTL
+    let revoked = if params.all {
+        let revoked = user.clone().delete_all_sessions(db).await?;
+        cache.invalidate_user(user.id);
+        revoked
+    } else {
+        let revoked = UserSession::delete_by_key(db, session_key.clone()).await?;
+        cache.invalidate(&session_key);
+        revoked
+    };
+
+    Ok(Json(LogoutResponse { revoked }))
+}
+
+#[derive(Serialize)]
+pub struct RotateResponse {
+    key: String,
+    expires: Option<DateTime<Utc>>,
+}
+
+/// Replaces the key the request was made with, the old key stops working as soon as this
+/// returns.
+pub async fn handle_rotate(
+    extract::Path(session_key): extract::Path<String>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(cache): extract::Extension<Arc<SessionCache>>,
+) -> Result<Json<RotateResponse>, Error> {
+    let session = UserSession::rotate(db, config.session_key_bytes, session_key.clone()).await?;
+    cache.invalidate(&session_key);
+    let session = session.ok_or(Error::NonExistentSession)?;
+
+    Ok(Json(RotateResponse {
+        key: session.session_key,
+        expires: session
+            .expires_at
+            .and_then(|v| Utc.from_local_datetime(&v).single()),
+    }))
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to query database")]
+    Database(#[from] chartered_db::Error),
+    #[error("The session given does not exist")]
+    NonExistentSession,
+    #[error("A session needs at least one scope")]
+    NoScopes,
+    #[error("A session can't be given scopes the session creating it doesn't have")]
+    ExcessScopes,
+    #[error("The expiry given is too far in the future")]
+    InvalidExpiry,
+    #[error("Revoking every session requires the `manage` scope")]
+    ManageScopeRequired,
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::NonExistentSession | Self::NoScopes | Self::InvalidExpiry => {
+                StatusCode::BAD_REQUEST
+            }
+            Self::ExcessScopes | Self::ManageScopeRequired => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+define_error_response!(Error);
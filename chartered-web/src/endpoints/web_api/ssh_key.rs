@@ -8,7 +8,13 @@ use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use thiserror::Error;
 
-use crate::endpoints::ErrorResponse;
+use crate::{
+    endpoints::ErrorResponse,
+    middleware::{
+        auth::{Manage, Read, RequireScope, SessionCache},
+        logging::RequestId,
+    },
+};
 
 #[derive(Serialize)]
 pub struct GetResponse {
@@ -25,8 +31,10 @@ pub struct GetResponseKey {
 }
 
 pub async fn handle_get(
+    _scope: RequireScope<Read>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(request_id): extract::Extension<RequestId>,
 ) -> Result<Json<GetResponse>, Error> {
     let keys = user
         .list_ssh_keys(db)
@@ -35,7 +43,10 @@ pub async fn handle_get(
         .map(|key| GetResponseKey {
             uuid: key.uuid.0,
             fingerprint: key.fingerprint().unwrap_or_else(|e| {
-                warn!("Failed to parse key with id {}: {}", key.id, e);
+                warn!(
+                    "Failed to parse key with id {}: {} (request {})",
+                    key.id, e, request_id
+                );
                 "INVALID".to_string()
             }),
             name: key.name,
@@ -55,6 +66,7 @@ pub struct PutRequest {
 }
 
 pub async fn handle_put(
+    _scope: RequireScope<Manage>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
     extract::Json(req): extract::Json<PutRequest>,
@@ -66,14 +78,35 @@ pub async fn handle_put(
     }
 }
 
+#[derive(Deserialize)]
+pub struct DeleteParams {
+    /// Deleting the user's only key leaves them unable to fetch the index, so they have to
+    /// explicitly say that's what they want.
+    #[serde(default)]
+    allow_last: bool,
+}
+
 pub async fn handle_delete(
+    _scope: RequireScope<Manage>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(cache): extract::Extension<Arc<SessionCache>>,
     extract::Path((_session_key, ssh_key_id)): extract::Path<(String, Uuid)>,
+    extract::Query(params): extract::Query<DeleteParams>,
 ) -> Result<Json<ErrorResponse>, Error> {
-    let deleted = user.delete_user_ssh_key_by_uuid(db, ssh_key_id).await?;
+    let deleted = match user
+        .clone()
+        .delete_user_ssh_key_by_uuid(db, ssh_key_id, params.allow_last)
+        .await
+    {
+        Ok(v) => v,
+        Err(chartered_db::Error::LastSshKey) => return Err(Error::LastKey),
+        Err(e) => return Err(Error::Database(e)),
+    };
 
     if deleted {
+        // the key's sessions went with it
+        cache.invalidate_user(user.id);
         Ok(Json(ErrorResponse { error: None }))
     } else {
         Err(Error::NonExistentKey)
@@ -88,6 +121,8 @@ pub enum Error {
     KeyParse(chartered_db::Error),
     #[error("The key given does not exist")]
     NonExistentKey,
+    #[error("This is your only SSH key, deleting it would leave you unable to fetch the index. Pass `allow_last=true` to delete it anyway")]
+    LastKey,
 }
 
 impl Error {
@@ -97,6 +132,7 @@ impl Error {
         match self {
             Self::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::KeyParse(_) | Self::NonExistentKey => StatusCode::BAD_REQUEST,
+            Self::LastKey => StatusCode::CONFLICT,
         }
     }
 }
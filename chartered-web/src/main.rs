@@ -1,16 +1,23 @@
 #![deny(clippy::pedantic)]
 #![allow(clippy::module_name_repetitions)]
 
+mod config;
+#[macro_use]
 mod endpoints;
+mod metrics;
 mod middleware;
+mod reconcile;
+mod storage;
+mod validation;
+mod webhooks;
 
 use axum::{
-    handler::{delete, get, patch, post, put},
-    http::Method,
+    handler::{delete, get, patch, post, put, Handler},
     AddExtensionLayer, Router,
 };
+use log::{error, warn};
+use std::sync::Arc;
 use tower::ServiceBuilder;
-use tower_http::cors::{Any, CorsLayer};
 
 #[allow(clippy::unused_async)]
 async fn hello_world() -> &'static str {
@@ -36,17 +43,65 @@ macro_rules! axum_box_after_every_route {
 async fn main() {
     env_logger::init();
 
-    let pool = chartered_db::init().unwrap();
+    let config = Arc::new(config::Config::from_env().unwrap());
+
+    if config.allow_yanked_version_overwrite {
+        warn!("Yanked versions are allowed to be overwritten, every overwrite will be logged");
+    }
+
+    if config.debug_log_bodies {
+        warn!("Request and response bodies are being logged, these may contain sensitive data");
+    }
+
+    let pool = chartered_db::init(&chartered_db::PoolConfig::from_env().unwrap()).unwrap();
+    let authenticators = Arc::new(
+        chartered_db::auth::Authenticators::from_env(
+            "CHARTERED_WEB_AUTHENTICATORS",
+            &[chartered_db::auth::AuthenticatorKind::SessionKey],
+        )
+        .unwrap(),
+    );
+    let publish_limiter = Arc::new(endpoints::cargo_api::PublishLimiter::new(
+        config.max_concurrent_publishes,
+        config.publish_overflow,
+    ));
+    let data_export_limiter = Arc::new(endpoints::web_api::DataExportLimiter::new(
+        config.data_export_interval,
+    ));
+    let session_cache = Arc::new(middleware::auth::SessionCache::new(
+        config.session_cache_ttl,
+    ));
+    let cors = Arc::new(config.cors.clone());
+    let metrics = Arc::new(metrics::Metrics::new());
+    let storage = storage::from_config(&config.storage, &config.local_storage_root).unwrap();
+
+    let in_flight = Arc::new(reconcile::InFlight::new());
+
+    if let Some(interval) = config.reconcile_interval {
+        reconcile::Reconciler::new(
+            pool.clone(),
+            config.reconcile_repair,
+            config.reconcile_reclaim,
+            in_flight.clone(),
+        )
+        .spawn(storage.clone(), interval);
+    }
 
     let api_authenticated = axum_box_after_every_route!(Router::new()
         .route("/crates/new", put(endpoints::cargo_api::publish))
-        .route("/crates/search", get(hello_world))
+        .route("/crates", get(endpoints::cargo_api::search))
         .route(
             "/crates/:crate/owners",
             get(endpoints::cargo_api::get_owners)
         )
-        .route("/crates/:crate/owners", put(hello_world))
-        .route("/crates/:crate/owners", delete(hello_world))
+        .route(
+            "/crates/:crate/owners",
+            put(endpoints::cargo_api::put_owners)
+        )
+        .route(
+            "/crates/:crate/owners",
+            delete(endpoints::cargo_api::delete_owners)
+        )
         .route(
             "/crates/:crate/:version/yank",
             delete(endpoints::cargo_api::yank)
@@ -65,6 +120,16 @@ async fn main() {
             .into_inner(),
     );
 
+    let permalinks = axum_box_after_every_route!(Router::new().route(
+        "/:organisation/:crate/:version",
+        get(endpoints::cargo_api::download_permalink)
+    ))
+    .layer(
+        ServiceBuilder::new()
+            .layer_fn(middleware::auth::AuthMiddleware)
+            .into_inner(),
+    );
+
     let web_unauthenticated =
         axum_box_after_every_route!(Router::new().route("/login", post(endpoints::web_api::login)));
 
@@ -90,10 +155,43 @@ async fn main() {
             "/crates/recently-updated",
             get(endpoints::web_api::crates::list_recently_updated)
         )
+        .route(
+            "/organisations/:org/members/bulk",
+            put(endpoints::web_api::organisations::grant_members)
+        )
+        .route(
+            "/organisations/:org/validate",
+            get(endpoints::web_api::organisations::validate)
+        )
+        .route(
+            "/organisations/:org/webhooks",
+            get(endpoints::web_api::organisations::get_webhooks)
+        )
+        .route(
+            "/organisations/:org/webhooks",
+            put(endpoints::web_api::organisations::insert_webhook)
+        )
+        .route(
+            "/organisations/:org/webhooks/deliveries",
+            get(endpoints::web_api::organisations::get_webhook_deliveries)
+        )
+        .route(
+            "/organisations/:org/webhooks/:id",
+            delete(endpoints::web_api::organisations::delete_webhook)
+        )
+        .route("/permissions", get(endpoints::web_api::permissions))
         .route("/users/search", get(endpoints::web_api::search_users))
+        .route("/index-path/:crate", get(endpoints::web_api::index_path))
+        .route("/data-export", get(endpoints::web_api::data_export))
+        .route("/account", delete(endpoints::web_api::delete_account))
         .route("/ssh-key", get(endpoints::web_api::get_ssh_keys))
         .route("/ssh-key", put(endpoints::web_api::add_ssh_key))
-        .route("/ssh-key/:id", delete(endpoints::web_api::delete_ssh_key)))
+        .route("/ssh-key/:id", delete(endpoints::web_api::delete_ssh_key))
+        .route("/sessions", get(endpoints::web_api::get_sessions))
+        .route("/sessions", post(endpoints::web_api::create_session))
+        .route("/sessions", delete(endpoints::web_api::logout))
+        .route("/sessions/rotate", post(endpoints::web_api::rotate_session))
+        .route("/sessions/:id", delete(endpoints::web_api::delete_session)))
     .layer(
         ServiceBuilder::new()
             .layer_fn(middleware::auth::AuthMiddleware)
@@ -104,27 +202,50 @@ async fn main() {
         .layer_fn(middleware::logging::LoggingMiddleware)
         .into_inner();
 
+    // metrics and the pool's internals are only served on their own address, which can be
+    // kept off the public network, and not at all if one hasn't been given
+    if let Some(metrics_bind_address) = config.metrics_bind_address {
+        let metrics_app = Router::new()
+            .route("/metrics", get(metrics::handle))
+            .route("/status/db-pool", get(endpoints::web_api::pool_stats))
+            .layer(AddExtensionLayer::new(metrics.clone()))
+            .layer(AddExtensionLayer::new(pool.clone()));
+
+        // bound up front so a bad address stops startup rather than going unnoticed
+        let metrics_server = axum::Server::try_bind(&metrics_bind_address).unwrap_or_else(|e| {
+            panic!("Failed to bind metrics to {}: {}", metrics_bind_address, e)
+        });
+
+        tokio::spawn(async move {
+            if let Err(e) = metrics_server.serve(metrics_app.into_make_service()).await {
+                error!("Metrics server stopped: {}", e);
+            }
+        });
+    }
+
     let app = Router::new()
         .route("/", get(hello_world))
+        .boxed()
         .nest("/a/:key/web/v1", web_authenticated)
         .nest("/a/-/web/v1", web_unauthenticated)
         .nest("/a/:key/o/:organisation/api/v1", api_authenticated)
+        .nest("/dl/v1", permalinks)
+        .or(endpoints::not_found.into_service())
         .layer(middleware_stack)
-        // TODO!!!
         .layer(
-            CorsLayer::new()
-                .allow_methods(vec![
-                    Method::GET,
-                    Method::POST,
-                    Method::PATCH,
-                    Method::DELETE,
-                    Method::PUT,
-                    Method::OPTIONS,
-                ])
-                .allow_origin(Any)
-                .allow_credentials(false),
-        )
-        .layer(AddExtensionLayer::new(pool));
+            ServiceBuilder::new()
+                .layer_fn(move |inner| middleware::cors::CorsMiddleware::new(inner, cors.clone()))
+                .into_inner(),
+        )
+        .layer(AddExtensionLayer::new(pool))
+        .layer(AddExtensionLayer::new(config))
+        .layer(AddExtensionLayer::new(authenticators))
+        .layer(AddExtensionLayer::new(publish_limiter))
+        .layer(AddExtensionLayer::new(data_export_limiter))
+        .layer(AddExtensionLayer::new(session_cache))
+        .layer(AddExtensionLayer::new(metrics))
+        .layer(AddExtensionLayer::new(storage))
+        .layer(AddExtensionLayer::new(in_flight));
 
     axum::Server::bind(&"0.0.0.0:8888".parse().unwrap())
         .serve(app.into_make_service_with_connect_info::<std::net::SocketAddr, _>())
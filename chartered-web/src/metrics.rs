@@ -0,0 +1,207 @@
+//! Prometheus metrics, served in the text exposition format from `/metrics`. The endpoint
+//! isn't authenticated, so it's only served on its own address once
+//! `CHARTERED_METRICS_BIND_ADDRESS` is set, which can then be kept off the public network.
+//!
+//! Metrics exposed:
+//!
+//! - `chartered_http_requests_total{method, status}`: counter of requests served, by method
+//!   and response status code.
+//! - `chartered_http_request_duration_seconds{method}`: histogram of how long requests took
+//!   to serve, measured the same way as the duration in the access log.
+//! - `chartered_crate_publishes_total`: counter of crate versions successfully published.
+//! - `chartered_db_pool_connections`: gauge of connections currently open in the database
+//!   pool, sampled on every scrape.
+//! - `chartered_db_pool_idle_connections`: gauge of how many of those connections are idle.
+//! - `chartered_db_pool_max_connections`: gauge of the most connections the pool will open.
+
+use axum::{
+    body::Body,
+    extract,
+    http::{header, Method, Response},
+};
+use chartered_db::{ConnectionPool, PoolStats};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::{sync::Arc, time::Duration};
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    publishes: IntCounter,
+    pool_connections: IntGauge,
+    pool_idle_connections: IntGauge,
+    pool_max_connections: IntGauge,
+}
+
+impl Metrics {
+    #[must_use]
+    pub fn new() -> Self {
+        // none of these can fail, names are all valid and nothing is registered twice
+        let requests = IntCounterVec::new(
+            Opts::new("chartered_http_requests_total", "Requests served"),
+            &["method", "status"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "chartered_http_request_duration_seconds",
+                "How long requests took to serve",
+            ),
+            &["method"],
+        )
+        .unwrap();
+        let publishes = IntCounter::new(
+            "chartered_crate_publishes_total",
+            "Crate versions successfully published",
+        )
+        .unwrap();
+        let pool_connections = IntGauge::new(
+            "chartered_db_pool_connections",
+            "Connections open in the database pool",
+        )
+        .unwrap();
+        let pool_idle_connections = IntGauge::new(
+            "chartered_db_pool_idle_connections",
+            "Idle connections in the database pool",
+        )
+        .unwrap();
+        let pool_max_connections = IntGauge::new(
+            "chartered_db_pool_max_connections",
+            "Most connections the database pool will open",
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(publishes.clone())).unwrap();
+        registry
+            .register(Box::new(pool_connections.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(pool_idle_connections.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(pool_max_connections.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            requests,
+            request_duration,
+            publishes,
+            pool_connections,
+            pool_idle_connections,
+            pool_max_connections,
+        }
+    }
+
+    pub fn record_request(&self, method: &Method, status: u16, duration: Duration) {
+        let method = method_label(method);
+
+        self.requests
+            .with_label_values(&[method, &status.to_string()])
+            .inc();
+        self.request_duration
+            .with_label_values(&[method])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn record_publish(&self) {
+        self.publishes.inc();
+    }
+
+    /// Renders every metric in the text exposition format, after updating the pool gauges
+    /// with `pool`.
+    #[must_use]
+    pub fn render(&self, pool: &PoolStats) -> String {
+        self.pool_connections.set(i64::from(pool.connections));
+        self.pool_idle_connections
+            .set(i64::from(pool.idle_connections));
+        self.pool_max_connections.set(i64::from(pool.max_size));
+
+        let mut out = Vec::new();
+        // writing to a vec can't fail, and every metric we register is well formed
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut out)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+}
+
+/// Clients can send any method they like, each one would be its own series forever if they
+/// were used as-is.
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        Method::OPTIONS => "OPTIONS",
+        Method::CONNECT => "CONNECT",
+        Method::TRACE => "TRACE",
+        _ => "other",
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(clippy::unused_async)]
+pub async fn handle(
+    extract::Extension(metrics): extract::Extension<Arc<Metrics>>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, TextEncoder::new().format_type())
+        .body(Body::from(metrics.render(&chartered_db::pool_stats(&db))))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::Metrics;
+    use axum::http::Method;
+    use chartered_db::PoolStats;
+    use std::time::Duration;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new();
+        metrics.record_request(&Method::GET, 200, Duration::from_millis(20));
+        metrics.record_request(&Method::GET, 200, Duration::from_millis(40));
+        metrics.record_request(&Method::PUT, 403, Duration::from_millis(5));
+        metrics.record_request(
+            &Method::from_bytes(b"MADEUP").unwrap(),
+            405,
+            Duration::from_millis(1),
+        );
+        metrics.record_publish();
+
+        let out = metrics.render(&PoolStats {
+            max_size: 10,
+            connections: 3,
+            idle_connections: 2,
+        });
+
+        assert!(out.contains(r#"chartered_http_requests_total{method="GET",status="200"} 2"#));
+        assert!(out.contains(r#"chartered_http_requests_total{method="PUT",status="403"} 1"#));
+        assert!(out.contains(r#"chartered_http_request_duration_seconds_count{method="GET"} 2"#));
+        assert!(out.contains(r#"chartered_http_requests_total{method="other",status="405"} 1"#));
+        assert!(!out.contains("MADEUP"));
+        assert!(out.contains("chartered_crate_publishes_total 1"));
+        assert!(out.contains("chartered_db_pool_connections 3"));
+        assert!(out.contains("chartered_db_pool_idle_connections 2"));
+        assert!(out.contains("chartered_db_pool_max_connections 10"));
+    }
+}
@@ -1,24 +1,135 @@
 use axum::{
+    async_trait,
+    body::{box_body, BoxBody},
     extract::{self, FromRequest, RequestParts},
-    http::{Request, Response, StatusCode},
+    http::{header::AUTHORIZATION, Request, Response},
+    response::IntoResponse,
+};
+use chartered_db::{
+    auth::{Authenticators, Credential},
+    users::{SessionScopes, User, UserSession},
+    ConnectionPool,
 };
-use chartered_db::ConnectionPool;
 use futures::future::BoxFuture;
+use log::{error, warn};
 use std::{
     collections::HashMap,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
+use thiserror::Error;
 use tower::Service;
 
+/// Remembers which user each session key belongs to for a short while, so most requests can
+/// be authenticated without going to the database. Entries never outlive the session they
+/// came from, and any endpoint revoking a session has to invalidate it here.
+pub struct SessionCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, CachedSession>>,
+    /// When stale entries should next be swept out, only ever locked whilst `entries` is.
+    next_sweep: Mutex<Instant>,
+}
+
+struct CachedSession {
+    user: Arc<User>,
+    scopes: SessionScopes,
+    valid_until: Instant,
+}
+
+impl SessionCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::default(),
+            next_sweep: Mutex::new(Instant::now() + ttl),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<(Arc<User>, SessionScopes)> {
+        let mut entries = self.entries.lock().unwrap();
+
+        match entries.get(key) {
+            Some(cached) if cached.valid_until > Instant::now() => {
+                Some((cached.user.clone(), cached.scopes))
+            }
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Caches `user` against `key` for the cache's TTL, or until `expires_at` if the session
+    /// expires before then.
+    pub(crate) fn insert(
+        &self,
+        key: String,
+        user: Arc<User>,
+        scopes: SessionScopes,
+        expires_at: Option<chrono::NaiveDateTime>,
+    ) {
+        let now = Instant::now();
+
+        let ttl = match expires_at {
+            // an expiry in the past fails to convert, there's no point caching those
+            Some(expires_at) => match (expires_at - chrono::Utc::now().naive_utc()).to_std() {
+                Ok(remaining) => remaining.min(self.ttl),
+                Err(_) => return,
+            },
+            None => self.ttl,
+        };
+
+        if ttl == Duration::ZERO {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+
+        // forget about anything that's gone stale so this doesn't grow forever, at most once
+        // per TTL so a run of misses doesn't walk the whole cache each time
+        let mut next_sweep = self.next_sweep.lock().unwrap();
+        if *next_sweep <= now {
+            entries.retain(|_, cached| cached.valid_until > now);
+            *next_sweep = now + self.ttl;
+        }
+        drop(next_sweep);
+
+        entries.insert(
+            key,
+            CachedSession {
+                user,
+                scopes,
+                valid_until: now + ttl,
+            },
+        );
+    }
+
+    /// Stops `key` from being accepted without going back to the database.
+    pub fn invalidate(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    /// Stops every one of `user_id`'s session keys from being accepted without going back to
+    /// the database, for when sessions are revoked without knowing their keys.
+    pub fn invalidate_user(&self, user_id: i32) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, cached| cached.user.id != user_id);
+    }
+}
+
 #[derive(Clone)]
 pub struct AuthMiddleware<S>(pub S);
 
-impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for AuthMiddleware<S>
+impl<S, ReqBody> Service<Request<ReqBody>> for AuthMiddleware<S>
 where
-    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S: Service<Request<ReqBody>, Response = Response<BoxBody>> + Clone + Send + 'static,
     S::Future: Send + 'static,
     ReqBody: Send + 'static,
-    ResBody: Default + Send + 'static,
 {
     type Response = S::Response;
     type Error = S::Error;
@@ -37,37 +148,379 @@ where
         Box::pin(async move {
             let mut req = RequestParts::new(req);
 
-            let params = extract::Path::<HashMap<String, String>>::from_request(&mut req)
-                .await
-                .unwrap();
-
-            let key = params.get("key").map(String::as_str).unwrap_or_default();
-
-            let db = req
-                .extensions()
-                .unwrap()
-                .get::<ConnectionPool>()
-                .unwrap()
-                .clone();
-
-            let user = match chartered_db::users::User::find_by_session_key(db, String::from(key))
-                .await
-                .unwrap()
-            {
-                Some(user) => std::sync::Arc::new(user),
-                None => {
-                    return Ok(Response::builder()
-                        .status(StatusCode::UNAUTHORIZED)
-                        .body(ResBody::default())
-                        .unwrap())
+            let (user, scopes) = match authenticate(&mut req).await {
+                Ok(v) => v,
+                Err(e) => return Ok(e.into_response().map(box_body)),
+            };
+
+            let req = match req.extensions_mut() {
+                Some(extensions) => {
+                    extensions.insert(user.clone());
+                    extensions.insert(scopes);
+                    req.try_into_request()
                 }
+                None => return Ok(Error::MissingExtensions.into_response().map(box_body)),
             };
 
-            req.extensions_mut().unwrap().insert(user);
+            match req {
+                Ok(req) => {
+                    let mut response = inner.call(req).await?;
+                    // so the user can be logged, the logger only gets to see the response
+                    response.extensions_mut().insert(user);
+                    Ok(response)
+                }
+                Err(_) => Ok(Error::MissingExtensions.into_response().map(box_body)),
+            }
+        })
+    }
+}
+
+async fn authenticate<ReqBody: Send>(
+    req: &mut RequestParts<ReqBody>,
+) -> Result<(Arc<User>, SessionScopes), Error> {
+    let params = extract::Path::<HashMap<String, String>>::from_request(req)
+        .await
+        .map_err(|_| Error::InvalidPath)?;
+
+    // routes that can't have the session key in their path, such as download
+    // permalinks, take it from the `Authorization` header instead
+    let key = match params.get("key") {
+        Some(key) => key.clone(),
+        None => req
+            .headers()
+            .and_then(|headers| headers.get(AUTHORIZATION))
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.strip_prefix("Bearer ").unwrap_or(v).to_string())
+            .unwrap_or_default(),
+    };
+
+    // no need to go to the database for a key that was never given
+    if key.is_empty() {
+        return Err(Error::Unauthorized);
+    }
+
+    let extensions = req.extensions().ok_or(Error::MissingExtensions)?;
+    let cache = extensions
+        .get::<Arc<SessionCache>>()
+        .ok_or(Error::MissingExtensions)?
+        .clone();
+
+    // the session's last used time is only updated when it's looked up, so it can lag behind
+    // by up to the cache's TTL
+    if let Some(cached) = cache.get(&key) {
+        return Ok(cached);
+    }
+
+    let db = extensions
+        .get::<ConnectionPool>()
+        .ok_or(Error::MissingExtensions)?
+        .clone();
+    let authenticators = extensions
+        .get::<Arc<Authenticators>>()
+        .ok_or(Error::MissingExtensions)?
+        .clone();
+
+    match authenticators
+        .authenticate(db.clone(), &Credential::SessionKey(key.clone()))
+        .await
+    {
+        Ok(Some(authenticated)) => {
+            let user = Arc::new(authenticated.user);
+            cache.insert(
+                key.clone(),
+                user.clone(),
+                authenticated.scopes,
+                authenticated.expires_at,
+            );
+
+            // nothing's waiting on this, it's only shown to the user
+            tokio::spawn(async move {
+                if let Err(e) = UserSession::update_last_used(db, key).await {
+                    warn!("Failed to update session last used time: {}", e);
+                }
+            });
+
+            Ok((user, authenticated.scopes))
+        }
+        Ok(None) => Err(Error::Unauthorized),
+        Err(e) => {
+            error!("Failed to authenticate session key: {}", e);
+            Err(Error::Database(e))
+        }
+    }
+}
+
+/// A scope a handler can require of the session key it was called with through
+/// [`RequireScope`].
+pub trait Scope {
+    const SCOPE: SessionScopes;
+    const NAME: &'static str;
+}
+
+pub struct Read;
+pub struct Publish;
+pub struct Manage;
+
+impl Scope for Read {
+    const SCOPE: SessionScopes = SessionScopes::READ;
+    const NAME: &'static str = "read";
+}
+
+impl Scope for Publish {
+    const SCOPE: SessionScopes = SessionScopes::PUBLISH;
+    const NAME: &'static str = "publish";
+}
+
+impl Scope for Manage {
+    const SCOPE: SessionScopes = SessionScopes::MANAGE;
+    const NAME: &'static str = "manage";
+}
+
+/// Rejects the request with a `403` unless the session key it was made with has scope `S`,
+/// only usable behind [`AuthMiddleware`].
+pub struct RequireScope<S>(PhantomData<S>);
+
+#[async_trait]
+impl<B: Send, S: Scope> FromRequest<B> for RequireScope<S> {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let scopes = req
+            .extensions()
+            .and_then(|extensions| extensions.get::<SessionScopes>())
+            .ok_or(Error::MissingExtensions)?;
+
+        if scopes.contains(S::SCOPE) {
+            Ok(Self(PhantomData))
+        } else {
+            Err(Error::MissingScope(S::NAME))
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to query database")]
+    Database(chartered_db::Error),
+    #[error("Invalid or expired session key")]
+    Unauthorized,
+    #[error("Invalid path parameters")]
+    InvalidPath,
+    #[error("Failed to authenticate request")]
+    MissingExtensions,
+    #[error("This session key doesn't have the `{0}` scope required for this request")]
+    MissingScope(&'static str),
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            // whatever went wrong, it wasn't the user's fault they couldn't be authenticated
+            Self::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::InvalidPath => StatusCode::BAD_REQUEST,
+            // the router's been put together without the extensions we depend on
+            Self::MissingExtensions => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::MissingScope(_) => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+define_error_response!(Error);
+
+#[cfg(test)]
+mod test {
+    use super::{AuthMiddleware, Error, Publish, RequireScope, SessionCache};
+    use axum::{
+        body::{Body, HttpBody},
+        handler::get,
+        http::{header::AUTHORIZATION, Request, StatusCode},
+        response::IntoResponse,
+        AddExtensionLayer, Router,
+    };
+    use chartered_db::{
+        users::{SessionScopes, User},
+        uuid::SqlUuid,
+    };
+    use std::{sync::Arc, time::Duration};
+    use tower::{ServiceBuilder, ServiceExt};
 
-            let response: Response<ResBody> = inner.call(req.try_into_request().unwrap()).await?;
+    /// Sends a request to `uri` through a router protected by the middleware. There's no
+    /// database behind it, so only keys that are in `cache` can be authenticated.
+    async fn request(
+        cache: Arc<SessionCache>,
+        uri: &str,
+        authorization: Option<&str>,
+    ) -> StatusCode {
+        let app = Router::new()
+            .route("/a/:key/:organisation", get(|| async { "ok" }))
+            .boxed()
+            .route("/:organisation/:crate", get(|| async { "ok" }))
+            .boxed()
+            .route("/p/:key", get(|_: RequireScope<Publish>| async { "ok" }))
+            .boxed()
+            .layer(ServiceBuilder::new().layer_fn(AuthMiddleware).into_inner())
+            .layer(AddExtensionLayer::new(cache));
 
-            Ok(response)
+        let mut req = Request::builder().uri(uri);
+        if let Some(authorization) = authorization {
+            req = req.header(AUTHORIZATION, authorization);
+        }
+
+        app.oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    fn user(id: i32) -> Arc<User> {
+        Arc::new(User {
+            id,
+            uuid: SqlUuid::random(),
+            username: format!("user{}", id),
+            deleted_at: None,
         })
     }
+
+    fn cache() -> Arc<SessionCache> {
+        Arc::new(SessionCache::new(Duration::from_secs(60)))
+    }
+
+    #[tokio::test]
+    async fn missing_key_is_unauthorized() {
+        assert_eq!(
+            request(cache(), "/core/foo", None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            request(cache(), "/core/foo", Some("Bearer ")).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_extensions_are_a_clean_response() {
+        assert_eq!(
+            request(cache(), "/a/abc/core", None).await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            request(cache(), "/core/foo", Some("Bearer abc")).await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn database_error_is_a_clean_response() {
+        let mut res = Error::Database(chartered_db::Error::InvalidConfig("test")).into_response();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // the underlying error isn't given out to whoever's trying to authenticate
+        let body = res.data().await.unwrap().unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "Failed to query database");
+    }
+
+    #[tokio::test]
+    async fn cached_sessions_skip_the_database() {
+        let cache = cache();
+        cache.insert("abc".to_string(), user(1), SessionScopes::all(), None);
+        cache.insert("def".to_string(), user(2), SessionScopes::all(), None);
+        cache.insert("ghi".to_string(), user(2), SessionScopes::all(), None);
+
+        // without a database to go to, these can only succeed through the cache
+        assert_eq!(
+            request(cache.clone(), "/a/abc/core", None).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            request(cache.clone(), "/core/foo", Some("Bearer def")).await,
+            StatusCode::OK
+        );
+
+        cache.invalidate("abc");
+        assert_eq!(
+            request(cache.clone(), "/a/abc/core", None).await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+
+        cache.invalidate_user(2);
+        assert!(cache.get("def").is_none());
+        assert!(cache.get("ghi").is_none());
+    }
+
+    #[test]
+    fn cached_sessions_dont_outlive_their_expiry() {
+        let now = chrono::Utc::now().naive_utc();
+
+        let cache = cache();
+        cache.insert(
+            "expired".to_string(),
+            user(1),
+            SessionScopes::all(),
+            Some(now - chrono::Duration::seconds(1)),
+        );
+        cache.insert(
+            "expiring".to_string(),
+            user(1),
+            SessionScopes::all(),
+            Some(now + chrono::Duration::seconds(1)),
+        );
+        assert!(cache.get("expired").is_none());
+        assert!(cache.get("expiring").is_some());
+
+        // the session expires well before the cache's TTL is up
+        let valid_until = cache.entries.lock().unwrap()["expiring"].valid_until;
+        assert!(valid_until <= std::time::Instant::now() + Duration::from_secs(1));
+
+        let cache = SessionCache::new(Duration::ZERO);
+        cache.insert("abc".to_string(), user(1), SessionScopes::all(), None);
+        assert!(cache.get("abc").is_none());
+    }
+
+    #[test]
+    fn stale_sessions_are_swept_once_per_ttl() {
+        let cache = SessionCache::new(Duration::from_millis(50));
+        cache.insert("abc".to_string(), user(1), SessionScopes::all(), None);
+        std::thread::sleep(Duration::from_millis(60));
+
+        // the sweep is due, so the stale entry goes as the new one comes in
+        cache.insert("def".to_string(), user(1), SessionScopes::all(), None);
+        assert!(!cache.entries.lock().unwrap().contains_key("abc"));
+
+        // but the next one isn't due until another TTL has passed
+        cache.insert(
+            "ghi".to_string(),
+            user(1),
+            SessionScopes::all(),
+            Some(chrono::Utc::now().naive_utc() + chrono::Duration::milliseconds(5)),
+        );
+        std::thread::sleep(Duration::from_millis(10));
+        cache.insert("jkl".to_string(), user(1), SessionScopes::all(), None);
+        assert!(cache.entries.lock().unwrap().contains_key("ghi"));
+        assert!(cache.get("ghi").is_none());
+    }
+
+    #[tokio::test]
+    async fn handlers_can_require_scopes() {
+        let cache = cache();
+        cache.insert("reader".to_string(), user(1), SessionScopes::READ, None);
+        cache.insert(
+            "publisher".to_string(),
+            user(1),
+            SessionScopes::READ | SessionScopes::PUBLISH,
+            None,
+        );
+
+        assert_eq!(
+            request(cache.clone(), "/p/reader", None).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            request(cache.clone(), "/p/publisher", None).await,
+            StatusCode::OK
+        );
+    }
 }
@@ -0,0 +1,219 @@
+//! Lets the frontend call the API from the browser when it's served from a different host.
+//!
+//! Preflight requests are answered here directly rather than being passed on, they never
+//! carry the session key so they'd never get past [`super::auth::AuthMiddleware`].
+
+use axum::{
+    body::{box_body, BoxBody, Empty},
+    http::{
+        header::{
+            ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+            ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE,
+            ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+        },
+        HeaderMap, HeaderValue, Method, Request, Response, StatusCode,
+    },
+};
+use futures::future::BoxFuture;
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower::Service;
+
+use crate::config::CorsConfig;
+
+#[derive(Clone)]
+pub struct CorsMiddleware<S> {
+    inner: S,
+    config: Arc<CorsConfig>,
+}
+
+impl<S> CorsMiddleware<S> {
+    pub fn new(inner: S, config: Arc<CorsConfig>) -> Self {
+        Self { inner, config }
+    }
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for CorsMiddleware<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // best practice is to clone the inner service like this
+        // see https://github.com/tower-rs/tower/issues/547 for details
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let config = self.config.clone();
+
+        Box::pin(async move {
+            let origin = req.headers().get(ORIGIN).cloned();
+            let allowed = origin.as_ref().map_or(false, |origin| {
+                config
+                    .allowed_origins
+                    .iter()
+                    .any(|allowed| allowed.as_bytes() == origin.as_bytes())
+            });
+
+            let is_preflight = req.method() == Method::OPTIONS
+                && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+
+            if is_preflight {
+                let mut response = Response::new(box_body(Empty::new()));
+
+                match origin.filter(|_| allowed) {
+                    Some(origin) => {
+                        *response.status_mut() = StatusCode::NO_CONTENT;
+                        preflight_headers(&config, response.headers_mut());
+                        allow_origin(&config, origin, response.headers_mut());
+                    }
+                    None => *response.status_mut() = StatusCode::FORBIDDEN,
+                }
+
+                return Ok(response);
+            }
+
+            let mut response = inner.call(req).await?;
+
+            if let Some(origin) = origin.filter(|_| allowed) {
+                allow_origin(&config, origin, response.headers_mut());
+            }
+
+            Ok(response)
+        })
+    }
+}
+
+fn allow_origin(config: &CorsConfig, origin: HeaderValue, headers: &mut HeaderMap) {
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    // the response differs depending on who asked for it, so caches can't share it
+    headers.append(VARY, HeaderValue::from_static("origin"));
+
+    if config.allow_credentials {
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+}
+
+fn preflight_headers(config: &CorsConfig, headers: &mut HeaderMap) {
+    let methods: Vec<_> = config.allowed_methods.iter().map(Method::as_str).collect();
+    if let Ok(v) = HeaderValue::from_str(&methods.join(",")) {
+        headers.insert(ACCESS_CONTROL_ALLOW_METHODS, v);
+    }
+
+    if let Ok(v) = HeaderValue::from_str(&config.allowed_headers.join(",")) {
+        headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, v);
+    }
+
+    headers.insert(
+        ACCESS_CONTROL_MAX_AGE,
+        HeaderValue::from(config.max_age.as_secs()),
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::CorsMiddleware;
+    use crate::{config::CorsConfig, middleware::auth::AuthMiddleware};
+    use axum::{
+        body::Body,
+        handler::get,
+        http::{
+            header::{
+                ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+                ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+            },
+            Method, Request, Response, StatusCode,
+        },
+        Router,
+    };
+    use std::{sync::Arc, time::Duration};
+    use tower::{ServiceBuilder, ServiceExt};
+
+    /// Sends a request through a router protected by both the CORS and auth middleware, the
+    /// auth middleware has none of its extensions so anything that reaches it fails.
+    async fn request(
+        method: Method,
+        origin: &str,
+        preflight: bool,
+    ) -> Response<axum::body::BoxBody> {
+        let config = Arc::new(CorsConfig {
+            allowed_origins: vec!["https://chartered.example.com".to_string()],
+            allowed_methods: vec![Method::GET, Method::PUT],
+            allowed_headers: vec!["content-type".to_string()],
+            allow_credentials: false,
+            max_age: Duration::from_secs(60),
+        });
+
+        let app = Router::new()
+            .route("/a/:key/web/v1/crates", get(|| async { "ok" }))
+            .boxed()
+            .layer(ServiceBuilder::new().layer_fn(AuthMiddleware).into_inner())
+            .layer(
+                ServiceBuilder::new()
+                    .layer_fn(move |inner| CorsMiddleware::new(inner, config.clone()))
+                    .into_inner(),
+            );
+
+        let mut req = Request::builder()
+            .method(method)
+            .uri("/a/abc/web/v1/crates")
+            .header(ORIGIN, origin);
+        if preflight {
+            req = req.header(ACCESS_CONTROL_REQUEST_METHOD, "GET");
+        }
+
+        app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn allowed_origin() {
+        let res = request(Method::OPTIONS, "https://chartered.example.com", true).await;
+        // answered without ever reaching the auth middleware
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://chartered.example.com"
+        );
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_METHODS], "GET,PUT");
+
+        // even errors need the header, or the frontend can't read them
+        let res = request(Method::GET, "https://chartered.example.com", false).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://chartered.example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn disallowed_origin() {
+        let res = request(Method::OPTIONS, "https://evil.example.com", true).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(!res.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let res = request(Method::GET, "https://evil.example.com", false).await;
+        assert!(!res.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        // origins are matched exactly, not by prefix
+        let res = request(
+            Method::OPTIONS,
+            "https://chartered.example.com.evil.com",
+            true,
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -1,32 +1,62 @@
 use axum::{
+    body::{box_body, Body, BoxBody, Bytes, Full, HttpBody},
     extract::{self, FromRequest, RequestParts},
-    http::{Request, Response},
+    http::{
+        header::{HeaderName, CONTENT_TYPE},
+        HeaderMap, HeaderValue, Method, Request, Response, Uri,
+    },
 };
+use bytes::BytesMut;
+use chartered_db::users::User;
 use futures::future::BoxFuture;
-use log::log;
+use log::{info, log};
 use once_cell::sync::Lazy;
 use regex::Regex;
+use serde::Serialize;
 use std::{
-    fmt::Debug,
+    convert::TryInto,
+    fmt::{self, Debug, Display},
+    sync::Arc,
     task::{Context, Poll},
 };
 use tower::Service;
 
+use crate::{
+    config::{Config, LogFormat},
+    metrics::Metrics,
+};
+
 pub trait GenericError: std::error::Error + Debug + Send + Sync {}
 
+/// Header the ID of each request is returned in, so users can give it to operators when
+/// reporting a problem and it can be found in the logs.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest `x-request-id` taken from a client, anything longer is replaced with one of our
+/// own rather than being written to every log line.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// ID of the request being handled, given to handlers as an extension so anything they log
+/// can be matched up with the request's access log line.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub(crate) String);
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
 #[derive(Clone)]
 pub struct LoggingMiddleware<S>(pub S);
 
-impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for LoggingMiddleware<S>
+impl<S> Service<Request<Body>> for LoggingMiddleware<S>
 where
-    S: Service<Request<ReqBody>, Response = Response<ResBody>, Error = std::convert::Infallible>
+    S: Service<Request<Body>, Response = Response<BoxBody>, Error = std::convert::Infallible>
         + Clone
         + Send
         + 'static,
     S::Future: Send + 'static,
-    S::Response: Default + Debug,
-    ReqBody: Send + Debug + 'static,
-    ResBody: Default + Send + 'static,
 {
     type Response = S::Response;
     type Error = S::Error;
@@ -36,7 +66,7 @@ where
         self.0.poll_ready(cx)
     }
 
-    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
         // best practice is to clone the inner service like this
         // see https://github.com/tower-rs/tower/issues/547 for details
         let clone = self.0.clone();
@@ -46,7 +76,36 @@ where
             let start = std::time::Instant::now();
             let user_agent = req.headers_mut().remove(axum::http::header::USER_AGENT);
             let method = req.method().clone();
-            let uri = replace_sensitive_path(req.uri().path());
+            let uri = redact_uri(req.uri());
+            let request_id = request_id(req.headers());
+            req.extensions_mut().insert(RequestId(
+                request_id.to_str().unwrap_or_default().to_string(),
+            ));
+            let metrics = req.extensions().get::<Arc<Metrics>>().cloned();
+
+            let config = req.extensions().get::<Arc<Config>>();
+            let log_format = config.map_or(LogFormat::Text, |config| config.log_format);
+            let body_limit = config
+                .filter(|config| config.debug_log_bodies)
+                .map(|config| config.debug_log_body_limit);
+
+            let req = match body_limit {
+                Some(limit) => {
+                    let is_publish =
+                        method == Method::PUT && req.uri().path().ends_with("/crates/new");
+                    let (parts, body) = req.into_parts();
+                    let (body, logged) = capture_body(body, limit, is_publish, Body::from).await;
+                    info!(
+                        "{} {} request body ({}): {}",
+                        method,
+                        uri,
+                        request_id.to_str().unwrap_or_default(),
+                        logged
+                    );
+                    Request::from_parts(parts, body)
+                }
+                None => req,
+            };
 
             let mut req = RequestParts::new(req);
             let socket_addr = extract::ConnectInfo::<std::net::SocketAddr>::from_request(&mut req)
@@ -54,36 +113,545 @@ where
                 .map_or_else(|_| "0.0.0.0:0".parse().unwrap(), |v| v.0);
 
             // this is infallible because of the type of S::Error
-            let response = inner.call(req.try_into_request().unwrap()).await?;
+            let mut response = inner.call(req.try_into_request().unwrap()).await?;
+
+            if let Some(limit) = body_limit {
+                let is_tarball = response
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .map_or(false, |v| v.as_bytes() == b"application/gzip");
 
-            log!(
-                if response.status().is_server_error() {
-                    log::Level::Error
+                let (parts, body) = response.into_parts();
+                let (body, logged) = if is_tarball {
+                    (body, "<tarball omitted>".to_string())
                 } else {
-                    log::Level::Info
-                },
-                "{ip} - \"{method} {uri}\" {status} {duration:?} \"{user_agent}\" \"{error:?}\"",
-                ip = socket_addr,
-                method = method,
-                uri = uri,
-                status = response.status().as_u16(),
-                duration = start.elapsed(),
-                user_agent = user_agent
-                    .as_ref()
-                    .and_then(|v| v.to_str().ok())
-                    .unwrap_or("unknown"),
-                error = match response.extensions().get::<Box<dyn GenericError>>() {
-                    Some(e) => Err(e),
-                    None => Ok(()),
-                }
-            );
+                    capture_body(body, limit, false, |v| box_body(Full::from(v))).await
+                };
+                info!(
+                    "{} {} response body ({}): {}",
+                    method,
+                    uri,
+                    request_id.to_str().unwrap_or_default(),
+                    logged
+                );
+                response = Response::from_parts(parts, body);
+            }
+
+            let level = if response.status().is_server_error() {
+                log::Level::Error
+            } else {
+                log::Level::Info
+            };
+            let status = response.status().as_u16();
+            let duration = start.elapsed();
+            if let Some(metrics) = &metrics {
+                metrics.record_request(&method, status, duration);
+            }
+            let user_agent = user_agent
+                .as_ref()
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("unknown");
+            let error = response.extensions().get::<Box<dyn GenericError>>();
+            let username = username(&response);
+
+            match log_format {
+                LogFormat::Text => log!(
+                    level,
+                    "{ip} - {username} \"{method} {uri}\" {status} {duration:?} \"{user_agent}\" \"{error:?}\" {request_id}",
+                    ip = socket_addr,
+                    username = username,
+                    method = method,
+                    uri = uri,
+                    status = status,
+                    duration = duration,
+                    user_agent = user_agent,
+                    error = match error {
+                        Some(e) => Err(e),
+                        None => Ok(()),
+                    },
+                    request_id = request_id.to_str().unwrap_or_default(),
+                ),
+                LogFormat::Json => log!(
+                    level,
+                    "{}",
+                    AccessLog {
+                        ip: socket_addr,
+                        username,
+                        method: method.as_str(),
+                        uri: &uri,
+                        status,
+                        duration_ms: duration.as_secs_f64() * 1000.0,
+                        user_agent,
+                        error: error.map(ToString::to_string),
+                        request_id: request_id.to_str().unwrap_or_default(),
+                    }
+                    .to_json()
+                ),
+            }
+
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static(REQUEST_ID_HEADER), request_id);
 
             Ok(response)
         })
     }
 }
 
-fn replace_sensitive_path(uri: &str) -> String {
-    static SENSITIVE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^/a/(.*?)/").unwrap());
-    SENSITIVE_REGEX.replace(uri, "/a/[snip]/").into_owned()
+/// Reuses the request ID the client sent if there's a sensible one, so a request can be
+/// followed through any proxies in front of us, otherwise generates a new one.
+fn request_id(headers: &HeaderMap) -> HeaderValue {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .filter(|v| !v.is_empty() && v.len() <= MAX_REQUEST_ID_LENGTH)
+        // only printable ascii, so it's safe to write to the logs as is
+        .filter(|v| v.to_str().is_ok())
+        .cloned()
+        .unwrap_or_else(|| {
+            HeaderValue::from_str(&chartered_db::uuid::Uuid::new_v4().to_string()).unwrap()
+        })
+}
+
+/// Username of the user the request was authenticated as, or `-` if it wasn't. This is only
+/// known once the request has made it through [`super::auth::AuthMiddleware`], which passes
+/// the user back out on the response.
+fn username<B>(response: &Response<B>) -> &str {
+    response
+        .extensions()
+        .get::<Arc<User>>()
+        .map_or("-", |user| user.username.as_str())
+}
+
+/// Everything logged about each request, for when logs are being shipped somewhere that wants
+/// them structured rather than as a line of text.
+#[derive(Serialize)]
+struct AccessLog<'a> {
+    ip: std::net::SocketAddr,
+    username: &'a str,
+    method: &'a str,
+    uri: &'a str,
+    status: u16,
+    duration_ms: f64,
+    user_agent: &'a str,
+    /// Message of the error the request failed with, if it did.
+    error: Option<String>,
+    request_id: &'a str,
+}
+
+impl AccessLog<'_> {
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Takes anything that could be used to authenticate as the user out of a request's path and
+/// query before it's logged. That's the session key following any `a` segment, wherever it is
+/// in the path so routes mounted under a prefix are covered too, and the values of any query
+/// parameters that look like credentials.
+fn redact_uri(uri: &Uri) -> String {
+    static SENSITIVE_QUERY_REGEX: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r"(?i)(^|&)(key|secret|token|session_key|password)=[^&]*").unwrap()
+    });
+
+    let path = redact_path(uri.path());
+
+    match uri.query() {
+        Some(query) => format!(
+            "{}?{}",
+            path,
+            SENSITIVE_QUERY_REGEX.replace_all(query, "$1$2=[snip]")
+        ),
+        None => path,
+    }
+}
+
+/// Replaces the segment after every `a` segment of `path` with `[snip]`. This is done a
+/// segment at a time rather than with a regex so the key is caught whether or not anything
+/// follows it, and a key containing an encoded slash is still treated as one segment.
+fn redact_path(path: &str) -> String {
+    let mut key_next = false;
+
+    path.split('/')
+        .map(|segment| {
+            let is_key = key_next && !segment.is_empty();
+            key_next = segment == "a" && !is_key;
+
+            // `-` stands in for the key on routes that don't need one, so there's nothing to hide
+            if is_key && segment != "-" {
+                "[snip]"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Reads `body` into memory so it can be logged, `rebuild` is then used to give back a body
+/// with the same contents to pass along. Bodies without a known length or over `limit` bytes
+/// are left alone so streamed bodies aren't held up.
+async fn capture_body<B>(
+    mut body: B,
+    limit: usize,
+    is_publish: bool,
+    rebuild: impl FnOnce(Bytes) -> B,
+) -> (B, String)
+where
+    B: HttpBody<Data = Bytes> + Unpin,
+    B::Error: Display,
+{
+    match body.size_hint().exact() {
+        Some(len) if len <= limit as u64 => {}
+        Some(len) => return (body, format!("<{} bytes, over the logging limit>", len)),
+        None => return (body, "<streamed body omitted>".to_string()),
+    }
+
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => buf.extend_from_slice(&chunk),
+            Err(e) => {
+                return (
+                    rebuild(buf.freeze()),
+                    format!("<failed to read body: {}>", e),
+                )
+            }
+        }
+    }
+    let buf = buf.freeze();
+
+    let logged = if is_publish {
+        describe_publish_body(&buf)
+    } else {
+        describe_body(&buf)
+    };
+
+    (rebuild(buf), logged)
+}
+
+fn describe_body(body: &[u8]) -> String {
+    match std::str::from_utf8(body) {
+        Ok(v) => redact_body(v),
+        Err(_) => format!("<{} bytes of binary data>", body.len()),
+    }
+}
+
+/// Publishes are framed as a length prefixed JSON metadata blob followed by a length prefixed
+/// tarball. The metadata is logged along with the declared lengths of each part, which is
+/// usually enough to see where a malformed publish went wrong, but the tarball itself is left
+/// out.
+fn describe_publish_body(body: &[u8]) -> String {
+    fn split_section(body: &[u8]) -> Result<(&[u8], &[u8]), String> {
+        let declared = body
+            .get(..4)
+            .ok_or_else(|| format!("<truncated length prefix, {} bytes>", body.len()))?;
+        let declared = u32::from_le_bytes(declared.try_into().unwrap()) as usize;
+        let rest = &body[4..];
+
+        if rest.len() < declared {
+            return Err(format!(
+                "<{} bytes declared but only {} remaining>",
+                declared,
+                rest.len()
+            ));
+        }
+
+        Ok(rest.split_at(declared))
+    }
+
+    let (metadata, rest) = match split_section(body) {
+        Ok(v) => v,
+        Err(e) => return format!("malformed metadata {}", e),
+    };
+
+    let tarball = match split_section(rest) {
+        Ok((tarball, trailing)) => format!(
+            "<{} byte tarball omitted, {} trailing bytes>",
+            tarball.len(),
+            trailing.len()
+        ),
+        Err(e) => format!("malformed tarball {}", e),
+    };
+
+    format!("{} {}", describe_body(metadata), tarball)
+}
+
+/// Applies the same redaction as [`redact_uri`] to any paths in the body, along
+/// with the values of any JSON fields that look like credentials.
+fn redact_body(body: &str) -> String {
+    static SENSITIVE_PATH_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r#"/a/[^/"?\s]+"#).unwrap());
+    static SENSITIVE_FIELD_REGEX: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r#""(key|secret|token|session_key|password)"\s*:\s*"(?:[^"\\]|\\.)*""#).unwrap()
+    });
+
+    let body = SENSITIVE_PATH_REGEX.replace_all(body, "/a/[snip]");
+    SENSITIVE_FIELD_REGEX
+        .replace_all(&body, r#""$1":"[snip]""#)
+        .into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        describe_publish_body, redact_body, redact_uri, username, AccessLog, LoggingMiddleware,
+        RequestId, REQUEST_ID_HEADER,
+    };
+    use axum::{
+        body::{Body, HttpBody},
+        extract,
+        handler::get,
+        http::Request,
+        Router,
+    };
+    use tower::{ServiceBuilder, ServiceExt};
+
+    #[test]
+    fn redact() {
+        assert_eq!(
+            redact_body(
+                r#"{"key":"abc\"def","dl":"https://example.com/a/abc/o/core","name":"foo"}"#
+            ),
+            r#"{"key":"[snip]","dl":"https://example.com/a/[snip]/o/core","name":"foo"}"#
+        );
+        assert_eq!(
+            redact_body(r#"{"url":"https://example.com/a/abc"}"#),
+            r#"{"url":"https://example.com/a/[snip]"}"#
+        );
+    }
+
+    #[test]
+    fn redact_login_body() {
+        assert_eq!(
+            redact_body(r#"{"username":"admin","password":"hunter2"}"#),
+            r#"{"username":"admin","password":"[snip]"}"#
+        );
+    }
+
+    #[test]
+    fn redact_session_key_in_path() {
+        for (given, expected) in [
+            (
+                "/a/abc/o/core/api/v1/crates/new",
+                "/a/[snip]/o/core/api/v1/crates/new",
+            ),
+            (
+                "/a/abc/o/core/api/v1/crates/foo/0.1.0/download",
+                "/a/[snip]/o/core/api/v1/crates/foo/0.1.0/download",
+            ),
+            // mounted under a prefix by a proxy
+            (
+                "/registry/a/abc/o/core/api/v1/crates/foo/0.1.0/download",
+                "/registry/a/[snip]/o/core/api/v1/crates/foo/0.1.0/download",
+            ),
+            ("/a/-/web/v1/login", "/a/-/web/v1/login"),
+            ("/dl/v1/core/foo/0.1.0", "/dl/v1/core/foo/0.1.0"),
+            ("/a/abc", "/a/[snip]"),
+            ("/a/abc/", "/a/[snip]/"),
+            (
+                "/a/abc/o/core/api/v1/crates",
+                "/a/[snip]/o/core/api/v1/crates",
+            ),
+            // encoded slashes don't split the key up
+            ("/a/ab%2Fcd%2F/o/core", "/a/[snip]/o/core"),
+            ("/a/ab%2Fcd", "/a/[snip]"),
+            // a key that happens to be `a` doesn't make the next segment look like a key
+            ("/a/a/o/core", "/a/[snip]/o/core"),
+            ("/a/", "/a/"),
+        ] {
+            assert_eq!(redact_uri(&given.parse().unwrap()), expected);
+        }
+    }
+
+    #[test]
+    fn redact_query_secrets() {
+        for (given, expected) in [
+            (
+                "/a/abc/web/v1/crates?token=abc&page=2",
+                "/a/[snip]/web/v1/crates?token=[snip]&page=2",
+            ),
+            (
+                "/a/-/web/v1/login?page=2&Session_Key=abc",
+                "/a/-/web/v1/login?page=2&Session_Key=[snip]",
+            ),
+            ("/?key=&secret=abc", "/?key=[snip]&secret=[snip]"),
+            // only whole parameter names are matched
+            ("/?monkey=abc", "/?monkey=abc"),
+        ] {
+            assert_eq!(redact_uri(&given.parse().unwrap()), expected);
+        }
+    }
+
+    #[test]
+    fn publish_body() {
+        let mut body = Vec::new();
+        body.extend_from_slice(&12_u32.to_le_bytes());
+        body.extend_from_slice(br#"{"name":"a"}"#);
+        body.extend_from_slice(&3_u32.to_le_bytes());
+        body.extend_from_slice(b"tar");
+
+        assert_eq!(
+            describe_publish_body(&body),
+            r#"{"name":"a"} <3 byte tarball omitted, 0 trailing bytes>"#
+        );
+
+        body[0] = 100;
+        assert_eq!(
+            describe_publish_body(&body),
+            "malformed metadata <100 bytes declared but only 19 remaining>"
+        );
+    }
+
+    #[test]
+    fn access_log_json() {
+        let error = crate::middleware::auth::Error::MissingScope("publish");
+
+        let logged: serde_json::Value = serde_json::from_str(
+            &AccessLog {
+                ip: "127.0.0.1:1234".parse().unwrap(),
+                username: "admin",
+                method: "PUT",
+                uri: "/a/[snip]/o/core/api/v1/crates/new",
+                status: 403,
+                duration_ms: 1.5,
+                user_agent: "cargo",
+                error: Some(error.to_string()),
+                request_id: "abc",
+            }
+            .to_json(),
+        )
+        .unwrap();
+
+        assert_eq!(logged["username"], "admin");
+        assert_eq!(logged["status"], 403);
+        assert_eq!(logged["duration_ms"], 1.5);
+        // the message the user saw, rather than the error's debug output
+        assert_eq!(logged["error"], error.to_string());
+        assert_eq!(logged["request_id"], "abc");
+    }
+
+    /// Sends a request through the middleware with `given` as its request ID, returning the ID
+    /// it came back with.
+    async fn request_id(given: Option<&str>) -> String {
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .boxed()
+            .layer(
+                ServiceBuilder::new()
+                    .layer_fn(LoggingMiddleware)
+                    .into_inner(),
+            );
+
+        let mut req = Request::builder().uri("/");
+        if let Some(given) = given {
+            req = req.header(REQUEST_ID_HEADER, given);
+        }
+
+        let res = app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+        res.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn request_ids() {
+        assert_eq!(request_id(Some("abc-123")).await, "abc-123");
+
+        let generated = request_id(None).await;
+        assert!(chartered_db::uuid::Uuid::parse_str(&generated).is_ok());
+        assert_ne!(generated, request_id(None).await);
+
+        // too long to be worth keeping
+        let long = "a".repeat(200);
+        assert_ne!(request_id(Some(&long)).await, long);
+    }
+
+    #[tokio::test]
+    async fn request_id_given_to_handlers() {
+        let app = Router::new()
+            .route(
+                "/",
+                get(
+                    |extract::Extension(request_id): extract::Extension<RequestId>| async move {
+                        request_id.to_string()
+                    },
+                ),
+            )
+            .boxed()
+            .layer(
+                ServiceBuilder::new()
+                    .layer_fn(LoggingMiddleware)
+                    .into_inner(),
+            );
+
+        let mut res = app
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let header = res.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = res.data().await.unwrap().unwrap();
+
+        assert_eq!(body, header.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn authenticated_user_is_logged() {
+        use crate::middleware::auth::{AuthMiddleware, SessionCache};
+        use axum::AddExtensionLayer;
+        use chartered_db::{
+            users::{SessionScopes, User},
+            uuid::SqlUuid,
+        };
+        use std::{sync::Arc, time::Duration};
+
+        let cache = Arc::new(SessionCache::new(Duration::from_secs(60)));
+        cache.insert(
+            "abc".to_string(),
+            Arc::new(User {
+                id: 1,
+                uuid: SqlUuid::random(),
+                username: "admin".to_string(),
+                deleted_at: None,
+            }),
+            SessionScopes::all(),
+            None,
+        );
+
+        let app = Router::new()
+            .route("/a/:key/web/v1/crates", get(|| async { "ok" }))
+            .boxed()
+            .layer(ServiceBuilder::new().layer_fn(AuthMiddleware).into_inner())
+            .layer(
+                ServiceBuilder::new()
+                    .layer_fn(LoggingMiddleware)
+                    .into_inner(),
+            )
+            .layer(AddExtensionLayer::new(cache));
+
+        let res = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/a/abc/web/v1/crates")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(username(&res), "admin");
+
+        let res = app
+            .oneshot(
+                Request::builder()
+                    .uri("/a/def/web/v1/crates")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(username(&res), "-");
+    }
 }
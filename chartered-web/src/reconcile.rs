@@ -0,0 +1,420 @@
+//! Periodically checks the tarballs the database knows about against what's actually in
+//! storage, which can drift apart over time through failed publishes, overwritten yanked
+//! versions or files being removed by hand.
+//!
+//! Anything out of place is always logged. When repairing is enabled, versions whose tarball
+//! has gone missing are yanked so cargo stops resolving to them. When reclaiming is enabled,
+//! storage objects with no version referencing them are deleted. This is how storage is
+//! reclaimed from versions that have been removed or replaced: content addressed objects can
+//! be shared between versions, so they're only ever deleted here, once their last reference
+//! has gone.
+//!
+//! Which objects publishes are about to reference is only tracked in memory by [`InFlight`],
+//! so reclaiming is only safe with a single instance of chartered-web serving publishes.
+
+use chartered_db::{crates::CrateVersion, ConnectionPool};
+use chartered_fs::{FileReference, FileSystem, FileSystemKind};
+use log::{error, info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to query database: {0}")]
+    Database(#[from] chartered_db::Error),
+    #[error("Failed to list storage: {0}")]
+    Storage(#[from] std::io::Error),
+}
+
+/// A version along with the storage object its tarball was written to.
+#[derive(Debug, PartialEq, Eq)]
+pub struct VersionObject {
+    pub version_id: i32,
+    pub crate_name: String,
+    pub version: String,
+    pub filesystem_object: String,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    /// Versions whose tarball couldn't be found in storage.
+    pub missing: Vec<VersionObject>,
+    /// Storage objects no version refers to.
+    pub orphaned: Vec<FileReference>,
+}
+
+/// Storage objects publishes have written, or are about to write, but haven't yet inserted
+/// a version referencing.
+///
+/// Content addressed objects are named after the tarball, so publishing a tarball that's
+/// already in storage doesn't write anything new and the object may well have been orphaned
+/// for several runs already. Being orphaned for a whole run is no guarantee that nothing's
+/// about to start referencing it, so the reconciler never deletes anything a publish has
+/// claimed here since the run started.
+///
+/// Claims are only visible within this process. Another instance sharing the same storage
+/// could have a publish in flight for an object this one sees orphaned, so the reconciler
+/// mustn't reclaim storage when more than one instance is running.
+#[derive(Default)]
+pub struct InFlight {
+    state: Mutex<InFlightState>,
+    /// Held while an object is being deleted, so a publish can't claim the object, find it
+    /// already in storage and then have it deleted from under it.
+    deleting: tokio::sync::Mutex<()>,
+}
+
+#[derive(Default)]
+struct InFlightState {
+    /// How many publishes are currently using each object.
+    publishing: HashMap<FileReference, usize>,
+    /// Every object claimed since the current run started, whether or not the publish has
+    /// finished since.
+    claimed: HashSet<FileReference>,
+}
+
+impl InFlight {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claims `file_ref` for a publish until the returned guard is dropped, which should be
+    /// once the version referencing it has been inserted, or the publish has failed. Waits
+    /// for the object to finish being deleted if the reconciler is currently deleting it.
+    pub async fn claim(self: &Arc<Self>, file_ref: FileReference) -> InFlightGuard {
+        let _deleting = self.deleting.lock().await;
+
+        let mut state = self.state.lock().unwrap();
+        *state.publishing.entry(file_ref.clone()).or_default() += 1;
+        state.claimed.insert(file_ref.clone());
+
+        InFlightGuard {
+            in_flight: self.clone(),
+            file_ref,
+        }
+    }
+
+    /// Forgets everything claimed by publishes that have since finished, called as a run
+    /// starts before anything is read from the database.
+    fn start_run(&self) {
+        let mut state = self.state.lock().unwrap();
+        let InFlightState {
+            publishing,
+            claimed,
+        } = &mut *state;
+
+        claimed.retain(|file_ref| publishing.contains_key(file_ref));
+    }
+
+    fn claimed(&self, file_ref: &FileReference) -> bool {
+        self.state.lock().unwrap().claimed.contains(file_ref)
+    }
+}
+
+pub struct InFlightGuard {
+    in_flight: Arc<InFlight>,
+    file_ref: FileReference,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let mut state = self.in_flight.state.lock().unwrap();
+
+        if let Some(count) = state.publishing.get_mut(&self.file_ref) {
+            *count -= 1;
+
+            if *count == 0 {
+                state.publishing.remove(&self.file_ref);
+            }
+        }
+    }
+}
+
+pub struct Reconciler {
+    db: ConnectionPool,
+    repair: bool,
+    reclaim: bool,
+    in_flight: Arc<InFlight>,
+    /// Objects found orphaned on the previous run. A publish writes its tarball before the
+    /// version is inserted, so an object is only deleted once it's been orphaned for a whole
+    /// run to avoid pulling the tarball out from under a publish that's still in flight.
+    previously_orphaned: HashSet<FileReference>,
+}
+
+impl Reconciler {
+    pub fn new(db: ConnectionPool, repair: bool, reclaim: bool, in_flight: Arc<InFlight>) -> Self {
+        Self {
+            db,
+            repair,
+            reclaim,
+            in_flight,
+            previously_orphaned: HashSet::new(),
+        }
+    }
+
+    /// Runs the reconciliation against `storage` every `interval` in the background, forever.
+    pub fn spawn(mut self, storage: Arc<dyn FileSystem>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+
+            loop {
+                interval.tick().await;
+
+                if let Err(e) = self.run(storage.as_ref()).await {
+                    error!("Failed to reconcile storage with the database: {}", e);
+                }
+            }
+        });
+    }
+
+    pub async fn run(&mut self, fs: &dyn FileSystem) -> Result<Report, Error> {
+        self.in_flight.start_run();
+
+        let versions = CrateVersion::list_all(self.db.clone()).await?;
+        let stored = fs.list().await?;
+
+        let report = compare(
+            versions.into_iter().map(|(crate_, version)| VersionObject {
+                version_id: version.id,
+                crate_name: crate_.name,
+                version: version.version,
+                filesystem_object: version.filesystem_object,
+            }),
+            stored,
+            fs.kind(),
+        );
+
+        for missing in &report.missing {
+            warn!(
+                "Tarball for {}@{} ({}) is missing from storage",
+                missing.crate_name, missing.version, missing.filesystem_object
+            );
+        }
+
+        for orphaned in &report.orphaned {
+            warn!(
+                "Storage object {} isn't referenced by any version",
+                orphaned
+            );
+        }
+
+        if self.repair {
+            self.yank_missing(&report).await?;
+        }
+
+        if self.reclaim {
+            delete_orphaned(
+                fs,
+                &self.in_flight,
+                &self.previously_orphaned,
+                &report.orphaned,
+            )
+            .await;
+        }
+
+        self.previously_orphaned = report.orphaned.iter().cloned().collect();
+
+        Ok(report)
+    }
+
+    async fn yank_missing(&self, report: &Report) -> Result<(), Error> {
+        if report.missing.is_empty() {
+            return Ok(());
+        }
+
+        let yanked = CrateVersion::yank_by_id(
+            self.db.clone(),
+            report.missing.iter().map(|v| v.version_id).collect(),
+        )
+        .await?;
+
+        if yanked > 0 {
+            info!("Yanked {} versions with missing tarballs", yanked);
+        }
+
+        Ok(())
+    }
+}
+
+/// Deletes each of `orphaned` that was also orphaned on the previous run, unless a publish
+/// has claimed it since this run started.
+async fn delete_orphaned(
+    fs: &dyn FileSystem,
+    in_flight: &InFlight,
+    previously_orphaned: &HashSet<FileReference>,
+    orphaned: &[FileReference],
+) {
+    for orphaned in orphaned {
+        if !previously_orphaned.contains(orphaned) {
+            continue;
+        }
+
+        let _deleting = in_flight.deleting.lock().await;
+
+        if in_flight.claimed(orphaned) {
+            info!(
+                "Not deleting orphaned storage object {}, it's being published",
+                orphaned
+            );
+            continue;
+        }
+
+        match fs.delete(orphaned.clone()).await {
+            Ok(()) => info!("Deleted orphaned storage object {}", orphaned),
+            Err(e) => warn!(
+                "Failed to delete orphaned storage object {}: {}",
+                orphaned, e
+            ),
+        }
+    }
+}
+
+/// Compares the objects the database references against those in `kind` storage. References
+/// that can't be parsed are treated as missing, nothing could ever be read from them either,
+/// while those written to some other kind of storage are left alone as they can't be checked.
+///
+/// Each stored object is counted by how many versions refer to it, content addressed objects
+/// are shared between every version with the same tarball, and only those left with no
+/// references at all are orphaned.
+fn compare(
+    versions: impl Iterator<Item = VersionObject>,
+    stored: Vec<FileReference>,
+    kind: FileSystemKind,
+) -> Report {
+    let mut references: HashMap<FileReference, usize> =
+        stored.into_iter().map(|file_ref| (file_ref, 0)).collect();
+    let mut missing = Vec::new();
+
+    for version in versions {
+        match FileReference::from_str(&version.filesystem_object) {
+            Ok(file_ref) => match references.get_mut(&file_ref) {
+                Some(count) => *count += 1,
+                None if file_ref.file_system() != kind => {}
+                None => missing.push(version),
+            },
+            Err(_) => missing.push(version),
+        }
+    }
+
+    let mut orphaned: Vec<_> = references
+        .into_iter()
+        .filter(|(_, count)| *count == 0)
+        .map(|(file_ref, _)| file_ref)
+        .collect();
+    orphaned.sort_by_key(ToString::to_string);
+
+    Report { missing, orphaned }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compare, delete_orphaned, InFlight, VersionObject};
+    use chartered_fs::{FileSystem, FileSystemKind};
+    use std::{collections::HashSet, sync::Arc};
+
+    fn version(version_id: i32, filesystem_object: String) -> VersionObject {
+        VersionObject {
+            version_id,
+            crate_name: "foo".to_string(),
+            version: format!("1.0.{}", version_id),
+            filesystem_object,
+        }
+    }
+
+    #[test]
+    fn compare_finds_missing_and_orphaned() {
+        let fs = chartered_fs::Memory::new();
+        let present = fs.create_ref();
+        let missing = fs.create_ref();
+        let orphaned = fs.create_ref();
+
+        let report = compare(
+            vec![
+                version(1, present.to_string()),
+                version(2, missing.to_string()),
+                version(3, "not a reference".to_string()),
+                // can't be checked against memory storage
+                version(4, "s3:3fa85f64-5717-4562-b3fc-2c963f66afa6".to_string()),
+            ]
+            .into_iter(),
+            vec![present, orphaned.clone()],
+            FileSystemKind::Memory,
+        );
+
+        assert_eq!(
+            report
+                .missing
+                .iter()
+                .map(|v| v.version_id)
+                .collect::<Vec<_>>(),
+            [2, 3]
+        );
+        assert_eq!(report.orphaned, [orphaned]);
+    }
+
+    #[test]
+    fn compare_counts_shared_references() {
+        let fs = chartered_fs::Memory::new();
+        let shared = fs.create_content_addressed_ref(b"tarball");
+        let stored = || vec![shared.clone()];
+
+        // referenced by both versions
+        let report = compare(
+            vec![
+                version(1, shared.to_string()),
+                version(2, shared.to_string()),
+            ]
+            .into_iter(),
+            stored(),
+            FileSystemKind::Memory,
+        );
+        assert!(report.missing.is_empty());
+        assert!(report.orphaned.is_empty());
+
+        // one of them has gone, the other still needs it
+        let report = compare(
+            vec![version(2, shared.to_string())].into_iter(),
+            stored(),
+            FileSystemKind::Memory,
+        );
+        assert!(report.orphaned.is_empty());
+
+        // both have gone
+        let report = compare(std::iter::empty(), stored(), FileSystemKind::Memory);
+        assert_eq!(report.orphaned, [shared]);
+    }
+
+    #[tokio::test]
+    async fn claimed_objects_are_not_deleted() {
+        let fs = chartered_fs::Memory::new();
+        let in_flight = Arc::new(InFlight::new());
+
+        // the tarball was orphaned on the last run, and is still orphaned on this one
+        let file_ref = fs.write_content_addressed(b"tarball").await.unwrap();
+        let orphaned = vec![file_ref.clone()];
+        let previously_orphaned: HashSet<_> = orphaned.iter().cloned().collect();
+
+        // the same tarball is published again, and has been written but the version hasn't
+        // been inserted yet
+        in_flight.start_run();
+        let guard = in_flight.claim(file_ref.clone()).await;
+        delete_orphaned(&fs, &in_flight, &previously_orphaned, &orphaned).await;
+        assert!(fs.read(file_ref.clone()).await.is_ok());
+
+        // the publish finished after the run read the database, so the version referencing
+        // the object might not have been seen
+        drop(guard);
+        delete_orphaned(&fs, &in_flight, &previously_orphaned, &orphaned).await;
+        assert!(fs.read(file_ref.clone()).await.is_ok());
+
+        // a later run would have seen the version if there was one
+        in_flight.start_run();
+        delete_orphaned(&fs, &in_flight, &previously_orphaned, &orphaned).await;
+        assert!(fs.read(file_ref).await.is_err());
+    }
+}
@@ -0,0 +1,179 @@
+//! Where crate tarballs are kept, picked with `CHARTERED_STORAGE_BACKEND` and built once on
+//! startup. Endpoints only ever see the [`FileSystem`] trait object this gives them, so any
+//! backend can be swapped in without them knowing.
+
+use async_trait::async_trait;
+use chartered_fs::{FileReference, FileSystem, FileSystemKind, Local, Reader, S3};
+use log::warn;
+use std::{path::Path, str::FromStr, sync::Arc};
+use tokio::io::AsyncRead;
+
+use crate::config::StorageConfig;
+
+pub fn from_config(
+    config: &StorageConfig,
+    local_root: &Path,
+) -> Result<Arc<dyn FileSystem>, std::io::Error> {
+    Ok(match config {
+        StorageConfig::Local => Arc::new(Local::new(local_root)?),
+        // anything published before switching over to S3 is still sitting on disk
+        StorageConfig::S3(config) => Arc::new(Routed {
+            primary: Box::new(S3::new(config.clone())?),
+            others: vec![Box::new(Local::new(local_root)?)],
+        }),
+    })
+}
+
+/// Frees the storage object a version used to refer to before it was replaced.
+///
+/// Positional objects are only ever written by the one publish, so nothing else can refer to
+/// them and they're deleted straight away. Content addressed objects may be shared with other
+/// versions, or be picked back up by a publish that's in flight, so they're left for
+/// [`crate::reconcile`] to delete once no version has referred to them for a whole run.
+pub async fn release(storage: &dyn FileSystem, filesystem_object: &str) {
+    let file_ref = match FileReference::from_str(filesystem_object) {
+        Ok(file_ref) if !file_ref.is_content_addressed() => file_ref,
+        Ok(_) => return,
+        Err(e) => {
+            warn!(
+                "Not releasing unparseable storage object {}: {}",
+                filesystem_object, e
+            );
+            return;
+        }
+    };
+
+    if let Err(e) = storage.delete(file_ref).await {
+        warn!(
+            "Failed to delete replaced storage object {}: {}",
+            filesystem_object, e
+        );
+    }
+}
+
+/// Writes new files to `primary`, but reads each file back from whichever backend its
+/// reference says it was written to, so switching backends doesn't lose anything published
+/// before the switch.
+struct Routed {
+    primary: Box<dyn FileSystem>,
+    others: Vec<Box<dyn FileSystem>>,
+}
+
+impl Routed {
+    fn backend(&self, file_ref: &FileReference) -> Result<&dyn FileSystem, std::io::Error> {
+        std::iter::once(&self.primary)
+            .chain(&self.others)
+            .find(|fs| fs.kind() == file_ref.file_system())
+            .map(Box::as_ref)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "{} was written to {} storage, which isn't configured",
+                        file_ref,
+                        file_ref.file_system()
+                    ),
+                )
+            })
+    }
+}
+
+#[async_trait]
+impl FileSystem for Routed {
+    fn kind(&self) -> FileSystemKind {
+        self.primary.kind()
+    }
+
+    async fn read(&self, file_ref: FileReference) -> Result<Vec<u8>, std::io::Error> {
+        self.backend(&file_ref)?.read(file_ref).await
+    }
+
+    async fn open(&self, file_ref: FileReference) -> Result<(Reader, u64), std::io::Error> {
+        self.backend(&file_ref)?.open(file_ref).await
+    }
+
+    async fn write(&self, data: &[u8]) -> Result<FileReference, std::io::Error> {
+        self.primary.write(data).await
+    }
+
+    async fn write_content_addressed(&self, data: &[u8]) -> Result<FileReference, std::io::Error> {
+        self.primary.write_content_addressed(data).await
+    }
+
+    async fn write_reader(
+        &self,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<FileReference, std::io::Error> {
+        self.primary.write_reader(reader).await
+    }
+
+    async fn rename_content_addressed(
+        &self,
+        from: FileReference,
+        to: &FileReference,
+    ) -> Result<(), std::io::Error> {
+        self.backend(&from)?
+            .rename_content_addressed(from, to)
+            .await
+    }
+
+    /// Only lists `primary`, the others are only around to read old files from.
+    async fn list(&self) -> Result<Vec<FileReference>, std::io::Error> {
+        self.primary.list().await
+    }
+
+    async fn delete(&self, file_ref: FileReference) -> Result<(), std::io::Error> {
+        self.backend(&file_ref)?.delete(file_ref).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{release, Routed};
+    use chartered_fs::{FileSystem, FileSystemKind, Local, Memory};
+
+    #[tokio::test]
+    async fn routed_reads_from_where_files_were_written() {
+        let root =
+            std::env::temp_dir().join(format!("chartered-web-storage-{}", std::process::id()));
+        let local = Local::new(&root).unwrap();
+        let old = local.write(b"old").await.unwrap();
+
+        let storage = Routed {
+            primary: Box::new(Memory::new()),
+            others: vec![Box::new(Local::new(&root).unwrap())],
+        };
+
+        let new = storage.write(b"new").await.unwrap();
+        assert_eq!(new.file_system(), FileSystemKind::Memory);
+
+        assert_eq!(storage.read(old.clone()).await.unwrap(), b"old");
+        assert_eq!(storage.read(new.clone()).await.unwrap(), b"new");
+        assert_eq!(storage.list().await.unwrap(), [new]);
+
+        storage.delete(old.clone()).await.unwrap();
+        assert!(local.read(old.clone()).await.is_err());
+
+        let storage = Routed {
+            primary: Box::new(Memory::new()),
+            others: vec![],
+        };
+        assert!(storage.read(old).await.is_err());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn release_leaves_content_addressed_objects() {
+        let storage = Memory::new();
+        let positional = storage.write(b"positional").await.unwrap();
+        let content_addressed = storage.write_content_addressed(b"shared").await.unwrap();
+
+        release(&storage, &positional.to_string()).await;
+        release(&storage, &content_addressed.to_string()).await;
+        release(&storage, "not a reference").await;
+
+        assert!(storage.read(positional).await.is_err());
+        assert_eq!(storage.read(content_addressed).await.unwrap(), b"shared");
+    }
+}
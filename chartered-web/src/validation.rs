@@ -0,0 +1,398 @@
+//! Rules every published version has to follow. They're checked on publish and can be re-run
+//! over everything that's already been published whenever they change, so they can only look
+//! at what ends up stored in the database.
+
+use chartered_db::{crates::Crate, users::User, ConnectionPool};
+use chartered_types::cargo::{CrateDependency, CrateVersion};
+use thiserror::Error;
+
+use crate::config::CategoryValidation;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    #[error("Invalid crate name `{0}`, names must start with a letter and only contain ASCII letters, numbers, `-` or `_`")]
+    InvalidCrateName(String),
+    #[error("Invalid version `{0}`, versions must follow semver (https://semver.org)")]
+    InvalidVersion(String),
+    #[error("Version {version} is lower than the latest published version {latest}, versions of this crate can only go up")]
+    VersionNotIncreasing { version: String, latest: String },
+    #[error("Dependency `{name} = \"{req}\"` can't be found in this registry, it needs to be published first")]
+    MissingDependency { name: String, req: String },
+    #[error("Unknown categories: {}, only categories chosen by the registry can be used", .0.join(", "))]
+    UnknownCategories(Vec<String>),
+}
+
+/// Checks `version` against every rule, returning all the rules it breaks.
+#[must_use]
+pub fn validate(version: &CrateVersion<'_>) -> Vec<Violation> {
+    let mut violations = Vec::new();
+
+    if !is_valid_crate_name(&version.name) {
+        violations.push(Violation::InvalidCrateName(version.name.to_string()));
+    }
+
+    if semver::Version::parse(&version.vers).is_err() {
+        violations.push(Violation::InvalidVersion(version.vers.to_string()));
+    }
+
+    violations
+}
+
+/// Checks the categories a crate is being published with against the ones the registry allows,
+/// returning the categories that weren't recognised so the user can be warned about them. If
+/// `mode` is [`CategoryValidation::Enforce`] any unrecognised category is a violation instead.
+pub fn check_categories(
+    categories: &[String],
+    allowed: Option<&[String]>,
+    mode: CategoryValidation,
+) -> Result<Vec<String>, Violation> {
+    let allowed = match allowed {
+        Some(v) => v,
+        None => return Ok(Vec::new()),
+    };
+
+    let unknown: Vec<_> = categories
+        .iter()
+        .filter(|category| !allowed.contains(category))
+        .cloned()
+        .collect();
+
+    if mode == CategoryValidation::Enforce && !unknown.is_empty() {
+        Err(Violation::UnknownCategories(unknown))
+    } else {
+        Ok(unknown)
+    }
+}
+
+/// Checks `version` isn't lower than any of the `existing` versions of the crate, for
+/// registries that require versions to only ever go up. Republishing an existing version is
+/// left to the database to decide on, as is whether `version` is valid semver at all. Existing
+/// versions that aren't valid semver were published before it was checked, and are ignored.
+pub fn check_version_increases(version: &str, existing: &[String]) -> Result<(), Violation> {
+    let parsed = match semver::Version::parse(version) {
+        Ok(v) => v,
+        Err(_) => return Ok(()),
+    };
+
+    let latest = existing
+        .iter()
+        .filter_map(|v| semver::Version::parse(v).ok())
+        .max();
+
+    match latest {
+        Some(latest) if parsed < latest => Err(Violation::VersionNotIncreasing {
+            version: version.to_string(),
+            latest: latest.to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Checks a dependency on another crate in this registry can be resolved against the
+/// `versions` published for it, `None` if the crate doesn't exist or can't be seen by the user
+/// publishing. Version requirements that can't be parsed are left for cargo to complain about,
+/// only whether the crate exists is checked for those.
+pub fn check_dependency(
+    dependency: &CrateDependency<'_>,
+    versions: Option<&[String]>,
+) -> Result<(), Violation> {
+    let missing = || Violation::MissingDependency {
+        name: dependency_crate_name(dependency).to_string(),
+        req: dependency.version_req.to_string(),
+    };
+
+    let versions = versions.ok_or_else(missing)?;
+
+    let req = match semver::VersionReq::parse(&dependency.version_req) {
+        Ok(v) => v,
+        Err(_) => return Ok(()),
+    };
+
+    if versions
+        .iter()
+        .filter_map(|v| semver::Version::parse(v).ok())
+        .any(|v| req.matches(&v))
+    {
+        Ok(())
+    } else {
+        Err(missing())
+    }
+}
+
+/// Runs [`check_dependency`] over every dependency on another crate in `organisation`'s
+/// registry, looking up the versions published for all of them at once as `user` sees them.
+/// Dependencies from other registries (ie. crates.io) are `cargo`'s problem.
+pub async fn check_dependencies(
+    db: &ConnectionPool,
+    user: &User,
+    organisation: &str,
+    dependencies: &[CrateDependency<'_>],
+) -> Result<Vec<Violation>, chartered_db::Error> {
+    let local: Vec<_> = dependencies
+        .iter()
+        .filter(|v| v.registry.is_none())
+        .collect();
+
+    if local.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let versions = Crate::version_numbers_by_name(
+        db.clone(),
+        user.id,
+        organisation.to_string(),
+        local
+            .iter()
+            .map(|dependency| dependency_crate_name(dependency).to_string())
+            .collect(),
+    )
+    .await?;
+
+    Ok(local
+        .into_iter()
+        .filter_map(|dependency| {
+            let versions = versions.get(dependency_crate_name(dependency));
+            check_dependency(dependency, versions.map(Vec::as_slice)).err()
+        })
+        .collect())
+}
+
+/// The name of the crate a dependency refers to, which differs from the name it's given in
+/// `Cargo.toml` if it's been renamed.
+#[must_use]
+pub fn dependency_crate_name<'a>(dependency: &'a CrateDependency<'_>) -> &'a str {
+    dependency.package.as_deref().unwrap_or(&dependency.name)
+}
+
+/// Most keywords a crate can have, the same as crates.io.
+pub const MAX_KEYWORDS: usize = 5;
+
+/// Most characters a single keyword can be.
+pub const MAX_KEYWORD_LENGTH: usize = 20;
+
+/// Checks the keywords a crate is being published with against the rules crates.io uses,
+/// returning the keywords that should be kept along with a warning for each one that wasn't.
+/// Bad keywords are dropped rather than failing the publish, cargo shows the user the warnings.
+#[must_use]
+pub fn check_keywords(keywords: Vec<String>) -> (Vec<String>, Vec<String>) {
+    let mut valid = Vec::new();
+    let mut warnings = Vec::new();
+
+    for keyword in keywords {
+        if !is_valid_keyword(&keyword) {
+            warnings.push(format!(
+                "invalid keyword `{}`, keywords must start with a letter or number, only contain ASCII letters, numbers, `_`, `-` or `+` and be at most {} characters",
+                keyword, MAX_KEYWORD_LENGTH
+            ));
+        } else if valid.len() == MAX_KEYWORDS {
+            warnings.push(format!(
+                "keyword `{}` ignored, crates can have at most {} keywords",
+                keyword, MAX_KEYWORDS
+            ));
+        } else if !valid.contains(&keyword) {
+            valid.push(keyword);
+        }
+    }
+
+    (valid, warnings)
+}
+
+fn is_valid_keyword(keyword: &str) -> bool {
+    keyword.len() <= MAX_KEYWORD_LENGTH
+        && keyword
+            .chars()
+            .next()
+            .map_or(false, |c| c.is_ascii_alphanumeric())
+        && keyword
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '+')
+}
+
+/// Follows the same rules as crates.io, which also guarantees the name is safe to use as a path
+/// in the index.
+pub fn is_valid_crate_name(name: &str) -> bool {
+    name.len() <= 64
+        && name
+            .chars()
+            .next()
+            .map_or(false, |c| c.is_ascii_alphabetic())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        check_categories, check_dependency, check_keywords, check_version_increases,
+        is_valid_crate_name, validate, Violation,
+    };
+    use crate::config::CategoryValidation;
+    use chartered_types::cargo::{CrateDependency, CrateFeatures, CrateVersion};
+
+    #[test]
+    fn crate_names() {
+        assert!(is_valid_crate_name("serde"));
+        assert!(is_valid_crate_name("chartered-web_2"));
+        assert!(is_valid_crate_name("a"));
+        assert!(is_valid_crate_name(&"a".repeat(64)));
+
+        assert!(!is_valid_crate_name(""));
+        assert!(!is_valid_crate_name("1serde"));
+        assert!(!is_valid_crate_name("-serde"));
+        assert!(!is_valid_crate_name("_serde"));
+        assert!(!is_valid_crate_name("ser de"));
+        assert!(!is_valid_crate_name("ser/de"));
+        assert!(!is_valid_crate_name("sérde"));
+        assert!(!is_valid_crate_name(&"a".repeat(65)));
+    }
+
+    #[test]
+    fn categories() {
+        let allowed = vec!["internal-tools".to_string(), "networking".to_string()];
+        let categories = vec!["networking".into(), "web-programming".into()];
+
+        assert_eq!(
+            check_categories(&categories, Some(&allowed), CategoryValidation::Warn),
+            Ok(vec!["web-programming".to_string()])
+        );
+        assert_eq!(
+            check_categories(&categories, Some(&allowed), CategoryValidation::Enforce),
+            Err(Violation::UnknownCategories(vec![
+                "web-programming".to_string()
+            ]))
+        );
+        assert_eq!(
+            check_categories(
+                &categories[..1],
+                Some(&allowed),
+                CategoryValidation::Enforce
+            ),
+            Ok(Vec::new())
+        );
+        assert_eq!(
+            check_categories(&categories, None, CategoryValidation::Enforce),
+            Ok(Vec::new())
+        );
+    }
+
+    #[test]
+    fn versions_increase() {
+        let existing = vec!["1.0.0".to_string(), "1.2.0".to_string(), "junk".to_string()];
+
+        assert!(check_version_increases("1.2.1", &existing).is_ok());
+        assert!(check_version_increases("2.0.0-alpha.1", &existing).is_ok());
+        assert!(check_version_increases("1.2.0", &existing).is_ok());
+        assert!(check_version_increases("1.0.0", &[]).is_ok());
+        assert_eq!(
+            check_version_increases("1.1.5", &existing),
+            Err(Violation::VersionNotIncreasing {
+                version: "1.1.5".to_string(),
+                latest: "1.2.0".to_string(),
+            })
+        );
+        assert!(check_version_increases("1.2.0-rc.1", &existing).is_err());
+    }
+
+    #[test]
+    fn dependencies() {
+        let mut dependency = CrateDependency {
+            name: "serde".into(),
+            version_req: "^1.0.100".into(),
+            features: Vec::new(),
+            optional: false,
+            default_features: true,
+            target: None,
+            kind: "normal".into(),
+            registry: None,
+            package: None,
+        };
+        let versions = vec!["1.0.99".to_string(), "1.0.130".to_string()];
+
+        assert!(check_dependency(&dependency, Some(&versions)).is_ok());
+        assert_eq!(
+            check_dependency(&dependency, None),
+            Err(Violation::MissingDependency {
+                name: "serde".to_string(),
+                req: "^1.0.100".to_string(),
+            })
+        );
+
+        dependency.version_req = "^2".into();
+        assert!(check_dependency(&dependency, Some(&versions)).is_err());
+        assert!(check_dependency(&dependency, Some(&[])).is_err());
+
+        dependency.name = "serde1".into();
+        dependency.package = Some("serde".into());
+        assert!(matches!(
+            check_dependency(&dependency, None),
+            Err(Violation::MissingDependency { name, .. }) if name == "serde"
+        ));
+    }
+
+    #[test]
+    fn keywords() {
+        let (valid, warnings) = check_keywords(
+            [
+                "http",
+                "c++",
+                "no spaces",
+                "",
+                "-dash",
+                "http",
+                "a,b",
+                "web",
+            ]
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+        );
+        assert_eq!(valid, ["http", "c++", "web"]);
+        assert_eq!(warnings.len(), 4);
+        assert!(warnings[0].contains("`no spaces`"));
+        assert!(warnings[3].contains("`a,b`"));
+
+        let (valid, warnings) = check_keywords(
+            ["a", "b", "c", "d", "e", "f", &"g".repeat(21)]
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+        );
+        assert_eq!(valid, ["a", "b", "c", "d", "e"]);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("at most 5 keywords"));
+        assert!(warnings[1].contains("at most 20 characters"));
+    }
+
+    #[test]
+    fn validate_reports_violations() {
+        let mut version = CrateVersion {
+            name: "serde".into(),
+            vers: "1.0.0".into(),
+            deps: Vec::new(),
+            features: CrateFeatures(Default::default()),
+            links: None,
+        };
+        assert!(validate(&version).is_empty());
+
+        for vers in ["1.0.0-alpha.1", "0.0.1+build.5", "10.20.30"] {
+            version.vers = vers.into();
+            assert!(validate(&version).is_empty(), "{}", vers);
+        }
+
+        for vers in ["1.0", "v1.0.0", "1.0.0.0", "01.0.0", "1.0.0-", ""] {
+            version.vers = vers.into();
+            assert_eq!(
+                validate(&version),
+                vec![Violation::InvalidVersion(vers.to_string())]
+            );
+        }
+
+        version.vers = "1.0.0".into();
+        version.name = "ser/de".into();
+        assert_eq!(
+            validate(&version),
+            vec![Violation::InvalidCrateName("ser/de".to_string())]
+        );
+    }
+}
@@ -0,0 +1,319 @@
+//! Delivers events to the webhooks organisations have subscribed. Every payload is signed
+//! with the webhook's secret using HMAC-SHA256 and sent in the `X-Chartered-Signature`
+//! header as `sha256=<hex>`, so receivers can verify it came from us.
+
+use chartered_db::{
+    users::UserCratePermissionValue as Permission,
+    webhooks::{Webhook, WebhookEvents},
+    ConnectionPool,
+};
+use hmac::{Hmac, Mac, NewMac};
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sha2::Sha256;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+    time::Duration,
+};
+use thiserror::Error;
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        // a redirect could send us anywhere, sidestepping `check_destination`
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+});
+
+#[derive(Error, Debug)]
+pub enum DestinationError {
+    #[error("Webhook URL must be a valid http or https URL")]
+    InvalidUrl,
+    #[error("Webhook host could not be resolved: {0}")]
+    Unresolvable(#[source] std::io::Error),
+    #[error("Webhook host resolves to {0}, which webhooks aren't allowed to be sent to")]
+    Forbidden(IpAddr),
+}
+
+/// Makes sure `url` is somewhere webhooks can be sent, resolving its host and refusing any
+/// that point at the server itself or its networks, such as loopback, link-local (including
+/// cloud metadata services) or private addresses. Otherwise anyone able to manage an
+/// organisation's webhooks could have us make requests to services only we can reach.
+///
+/// Checked when a webhook is saved and again before each delivery, as what a host resolves to
+/// can change in between.
+pub async fn check_destination(url: &str) -> Result<(), DestinationError> {
+    let url = reqwest::Url::parse(url).map_err(|_| DestinationError::InvalidUrl)?;
+
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(DestinationError::InvalidUrl);
+    }
+
+    let port = url
+        .port_or_known_default()
+        .ok_or(DestinationError::InvalidUrl)?;
+
+    // IPv6 hosts are given in their URL form, wrapped in brackets. Addresses are returned as-is
+    // without going anywhere near DNS
+    let host = url
+        .host_str()
+        .ok_or(DestinationError::InvalidUrl)?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+
+    let mut addresses = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(DestinationError::Unresolvable)?;
+
+    match addresses.find(|v| is_forbidden(v.ip())) {
+        Some(v) => Err(DestinationError::Forbidden(v.ip())),
+        None => Ok(()),
+    }
+}
+
+fn is_forbidden(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_forbidden_v4(ip),
+        IpAddr::V6(ip) => is_forbidden_v6(ip),
+    }
+}
+
+fn is_forbidden_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        // "this network", 0.0.0.0/8
+        || a == 0
+        // carrier-grade NAT, 100.64.0.0/10
+        || (a == 100 && (b & 0b1100_0000) == 64)
+}
+
+fn is_forbidden_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // unique local, fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // link-local, fe80::/10
+        || (first & 0xffc0) == 0xfe80
+        // IPv4-mapped and -compatible addresses reach the IPv4 address they contain
+        || ip.to_ipv4().map_or(false, is_forbidden_v4)
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Publish {
+        organisation: String,
+        #[serde(rename = "crate")]
+        crate_name: String,
+        version: String,
+        user: String,
+    },
+    Yank {
+        organisation: String,
+        #[serde(rename = "crate")]
+        crate_name: String,
+        version: String,
+        yanked: bool,
+        user: String,
+    },
+    PermissionChange {
+        organisation: String,
+        #[serde(rename = "crate")]
+        crate_name: String,
+        action: MemberAction,
+        /// User that made the change.
+        actor: String,
+        /// User whose permissions were changed.
+        user: String,
+        /// The user's new permissions on the crate, `None` if they were removed.
+        permissions: Option<Permission>,
+    },
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberAction {
+    Added,
+    Updated,
+    Removed,
+}
+
+impl Event {
+    fn kind(&self) -> WebhookEvents {
+        match self {
+            Self::Publish { .. } => WebhookEvents::PUBLISH,
+            Self::Yank { .. } => WebhookEvents::YANK,
+            Self::PermissionChange { .. } => WebhookEvents::PERMISSION_CHANGE,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Publish { .. } => "publish",
+            Self::Yank { .. } => "yank",
+            Self::PermissionChange { .. } => "permission_change",
+        }
+    }
+}
+
+/// Sends `event` to every webhook in the organisation subscribed to it. Delivery happens in
+/// the background so slow receivers don't hold up the request that triggered the event, the
+/// outcome of each attempt is recorded against the webhook instead.
+pub fn dispatch(db: ConnectionPool, organisation_id: i32, event: Event) {
+    tokio::spawn(async move {
+        let webhooks = match Webhook::subscribed_to(db.clone(), organisation_id, event.kind()).await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to look up webhooks for {:?}: {}", event, e);
+                return;
+            }
+        };
+
+        if webhooks.is_empty() {
+            return;
+        }
+
+        let body = match serde_json::to_vec(&event) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to serialise webhook payload {:?}: {}", event, e);
+                return;
+            }
+        };
+
+        for webhook in webhooks {
+            deliver(db.clone(), Arc::new(webhook), event.name(), &body).await;
+        }
+    });
+}
+
+async fn deliver(db: ConnectionPool, webhook: Arc<Webhook>, event: &'static str, body: &[u8]) {
+    let (status_code, error) = match check_destination(&webhook.url).await {
+        Ok(()) => send(&webhook, event, body).await,
+        Err(e) => (None, Some(e.to_string())),
+    };
+
+    if let Some(error) = &error {
+        warn!(
+            "Failed to deliver {} event to webhook {}: {}",
+            event, webhook.uuid, error
+        );
+    }
+
+    if let Err(e) = webhook
+        .clone()
+        .record_delivery(db, event.to_string(), status_code, error)
+        .await
+    {
+        warn!(
+            "Failed to record delivery to webhook {}: {}",
+            webhook.uuid, e
+        );
+    }
+}
+
+async fn send(
+    webhook: &Webhook,
+    event: &'static str,
+    body: &[u8],
+) -> (Option<i32>, Option<String>) {
+    let res = CLIENT
+        .post(&webhook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Chartered-Event", event)
+        .header(
+            "X-Chartered-Signature",
+            format!("sha256={}", sign(&webhook.secret, body)),
+        )
+        .body(body.to_vec())
+        .send()
+        .await;
+
+    match res {
+        Ok(res) if res.status().is_success() => (Some(i32::from(res.status().as_u16())), None),
+        Ok(res) => (
+            Some(i32::from(res.status().as_u16())),
+            Some(format!("Unexpected response status {}", res.status())),
+        ),
+        Err(e) => (None, Some(e.to_string())),
+    }
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_destination, sign, DestinationError};
+
+    #[test]
+    fn signature() {
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn destinations() {
+        for url in [
+            "http://127.0.0.1/",
+            "http://localhost:8080/hook",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://10.0.0.1/",
+            "http://172.16.5.4/",
+            "https://192.168.1.1/",
+            "http://100.64.0.1/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[fe80::1]/",
+            "http://[fd00::1]/",
+            "http://[::ffff:127.0.0.1]/",
+        ] {
+            assert!(
+                matches!(
+                    check_destination(url).await,
+                    Err(DestinationError::Forbidden(_))
+                ),
+                "{}",
+                url
+            );
+        }
+
+        for url in ["ftp://93.184.216.34/", "not a url"] {
+            assert!(
+                matches!(
+                    check_destination(url).await,
+                    Err(DestinationError::InvalidUrl)
+                ),
+                "{}",
+                url
+            );
+        }
+
+        assert!(check_destination("https://93.184.216.34/hook")
+            .await
+            .is_ok());
+        assert!(check_destination("http://[2606:2800:220:1::]/")
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1 @@
+ALTER TABLE organisations ADD COLUMN fetch_message TEXT;
@@ -0,0 +1 @@
+ALTER TABLE organisations ADD COLUMN index_generation INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+DROP TABLE organisation_webhook_deliveries;
+DROP TABLE organisation_webhooks;
@@ -0,0 +1,20 @@
+CREATE TABLE organisation_webhooks (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    uuid BINARY(128) NOT NULL UNIQUE,
+    organisation_id INTEGER NOT NULL,
+    url VARCHAR(255) NOT NULL,
+    secret VARCHAR(255) NOT NULL,
+    events INTEGER NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (organisation_id) REFERENCES organisations (id)
+);
+
+CREATE TABLE organisation_webhook_deliveries (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    webhook_id INTEGER NOT NULL,
+    event VARCHAR(255) NOT NULL,
+    status_code INTEGER,
+    error TEXT,
+    delivered_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (webhook_id) REFERENCES organisation_webhooks (id)
+);
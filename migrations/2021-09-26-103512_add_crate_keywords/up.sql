@@ -0,0 +1 @@
+ALTER TABLE crates ADD COLUMN keywords TEXT;
@@ -0,0 +1 @@
+ALTER TABLE crate_versions DROP COLUMN downloads;
@@ -0,0 +1 @@
+ALTER TABLE crate_versions ADD COLUMN downloads INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE user_sessions ADD COLUMN last_used_at TIMESTAMP;
@@ -0,0 +1 @@
+ALTER TABLE user_sessions ADD COLUMN scopes INTEGER NOT NULL DEFAULT -1;
@@ -0,0 +1,2 @@
+DROP INDEX crates_organisation_normalised_name;
+DROP TABLE crate_name_conflicts;
@@ -0,0 +1,30 @@
+-- crates could be created under names differing only by case or `-`/`_` while the check for
+-- them wasn't atomic, which would stop the index below from being created. The oldest crate
+-- of each set keeps its name, the rest are renamed out of the way and recorded here so
+-- operators can see what was changed and sort them out.
+CREATE TABLE crate_name_conflicts (
+    crate_id INTEGER NOT NULL PRIMARY KEY,
+    original_name VARCHAR(255) NOT NULL,
+    conflicts_with INTEGER NOT NULL,
+    FOREIGN KEY (crate_id) REFERENCES crates (id),
+    FOREIGN KEY (conflicts_with) REFERENCES crates (id)
+);
+
+INSERT INTO crate_name_conflicts (crate_id, original_name, conflicts_with)
+SELECT c.id, c.name, (
+    SELECT MIN(o.id) FROM crates o
+    WHERE o.organisation_id = c.organisation_id
+        AND lower(replace(o.name, '-', '_')) = lower(replace(c.name, '-', '_'))
+)
+FROM crates c
+WHERE EXISTS (
+    SELECT 1 FROM crates o
+    WHERE o.organisation_id = c.organisation_id
+        AND lower(replace(o.name, '-', '_')) = lower(replace(c.name, '-', '_'))
+        AND o.id < c.id
+);
+
+UPDATE crates SET name = name || '-conflict-' || id
+WHERE id IN (SELECT crate_id FROM crate_name_conflicts);
+
+CREATE UNIQUE INDEX crates_organisation_normalised_name ON crates (organisation_id, lower(replace(name, '-', '_')));
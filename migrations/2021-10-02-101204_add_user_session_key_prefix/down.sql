@@ -0,0 +1,2 @@
+DROP INDEX user_sessions_session_key_prefix;
+ALTER TABLE user_sessions DROP COLUMN session_key_prefix;
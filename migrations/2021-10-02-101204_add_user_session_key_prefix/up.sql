@@ -0,0 +1,3 @@
+ALTER TABLE user_sessions ADD COLUMN session_key_prefix VARCHAR(12) NOT NULL DEFAULT '';
+UPDATE user_sessions SET session_key_prefix = substr(session_key, 1, 12);
+CREATE INDEX user_sessions_session_key_prefix ON user_sessions (session_key_prefix);
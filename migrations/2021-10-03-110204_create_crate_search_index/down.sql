@@ -0,0 +1,4 @@
+DROP TRIGGER crates_search_update;
+DROP TRIGGER crates_search_delete;
+DROP TRIGGER crates_search_insert;
+DROP TABLE crates_search;
@@ -0,0 +1,29 @@
+-- needs an SQLite built with FTS5 and at least 3.34 for the trigram tokenizer, trigrams let
+-- the index answer the same substring matches search did with LIKE
+CREATE VIRTUAL TABLE crates_search USING fts5(
+    name,
+    description,
+    keywords,
+    content = 'crates',
+    content_rowid = 'id',
+    tokenize = 'trigram'
+);
+
+INSERT INTO crates_search (crates_search) VALUES ('rebuild');
+
+CREATE TRIGGER crates_search_insert AFTER INSERT ON crates BEGIN
+    INSERT INTO crates_search (rowid, name, description, keywords)
+        VALUES (new.id, new.name, new.description, new.keywords);
+END;
+
+CREATE TRIGGER crates_search_delete AFTER DELETE ON crates BEGIN
+    INSERT INTO crates_search (crates_search, rowid, name, description, keywords)
+        VALUES ('delete', old.id, old.name, old.description, old.keywords);
+END;
+
+CREATE TRIGGER crates_search_update AFTER UPDATE OF name, description, keywords ON crates BEGIN
+    INSERT INTO crates_search (crates_search, rowid, name, description, keywords)
+        VALUES ('delete', old.id, old.name, old.description, old.keywords);
+    INSERT INTO crates_search (rowid, name, description, keywords)
+        VALUES (new.id, new.name, new.description, new.keywords);
+END;